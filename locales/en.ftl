@@ -0,0 +1,81 @@
+# 画面に表示する英語の文言
+read-only-banner = The site is read-only during maintenance.
+read-only-title = Read-only
+read-only-message = Your changes were not saved. Please try again after maintenance.
+previous = Previous
+next = Next
+flash-post-created = Post created.
+
+archive-title = Archive { $period }
+archive-empty = No posts
+
+digest-title = Weekly digest { $from } - { $to }
+digest-summary = { $posts ->
+    [one] 1 post
+   *[other] { $posts } posts
+}, { $words ->
+    [one] 1 word
+   *[other] { $words } words
+}
+digest-highlights = Most read
+digest-views = { $views ->
+    [one] 1 view
+   *[other] { $views } views
+}
+
+calendar-title = Calendar { $period }
+weekday-sun = Sun
+weekday-mon = Mon
+weekday-tue = Tue
+weekday-wed = Wed
+weekday-thu = Thu
+weekday-fri = Fri
+weekday-sat = Sat
+
+draft-preview-title = Draft: { $title }
+draft-preview-notice = This is an unpublished draft shared for review.
+draft-preview-updated = Last saved { $date }
+
+maintenance-title = Maintenance
+maintenance-heading = Under maintenance
+maintenance-message = We are performing maintenance. Please try again in about { $seconds } seconds.
+
+new-post-title = New post
+post-title = Title
+post-content = Content
+post-preview = Preview
+post-visibility = Visibility
+visibility-public = public
+visibility-unlisted = unlisted
+visibility-private = private
+post-submit = Post
+
+search-title = Search
+search-submit = Search
+saved-searches = Saved searches
+search-error = { $error } (at { $offset })
+search-fuzzy = No exact matches. Showing similar titles.
+search-truncated = Only the newest { $scanned } candidate posts were searched. Add filters to search older posts.
+search-empty = No results
+search-length = { $words ->
+    [one] 1 word
+   *[other] { $words } words
+}, { $minutes } min read
+
+site-title = Posts
+site-empty = No posts yet
+site-notebook = Notebook
+site-voice-duration = Length
+site-voice-download = Download the recording
+site-weather = Weather
+site-temperature = Temperature
+site-sunrise = Sunrise
+site-sunset = Sunset
+
+stats-title = Stats
+stats-total = Posts: { $count }
+stats-average = Average length: { $length } characters
+stats-month = Month
+stats-posts = Posts
+stats-tag = Tag
+stats-top-viewed = Most viewed
@@ -0,0 +1,69 @@
+# 画面に表示する日本語の文言
+read-only-banner = メンテナンス中のため，現在は閲覧のみできます．
+read-only-title = 閲覧のみ
+read-only-message = 変更は保存されていません．メンテナンスが終わってからもう一度お試しください．
+previous = 前へ
+next = 次へ
+flash-post-created = 投稿しました．
+
+archive-title = { $period }のアーカイブ
+archive-empty = 投稿はありません
+
+digest-title = 週のまとめ { $from } - { $to }
+digest-summary = { $posts }件の投稿，{ $words }語
+digest-highlights = よく読まれた投稿
+digest-views = { $views }回表示
+
+calendar-title = { $period }のカレンダー
+weekday-sun = 日
+weekday-mon = 月
+weekday-tue = 火
+weekday-wed = 水
+weekday-thu = 木
+weekday-fri = 金
+weekday-sat = 土
+
+draft-preview-title = 下書き: { $title }
+draft-preview-notice = 確認のために共有された，公開前の下書きです．
+draft-preview-updated = { $date }に保存
+
+maintenance-title = メンテナンス
+maintenance-heading = メンテナンス中
+maintenance-message = ただいまメンテナンスを行っています．{ $seconds }秒ほどしてからもう一度お試しください．
+
+new-post-title = 新規投稿
+post-title = タイトル
+post-content = 本文
+post-preview = プレビュー
+post-visibility = 公開範囲
+visibility-public = 公開
+visibility-unlisted = 限定公開
+visibility-private = 非公開
+post-submit = 投稿する
+
+search-title = 検索
+search-submit = 検索
+saved-searches = 保存した検索
+search-error = { $error }（位置 { $offset }）
+search-fuzzy = 完全に一致する投稿はありません．似たタイトルを表示しています．
+search-truncated = 候補のうち新しい{ $scanned }件だけを検索しました．古い投稿を探すには条件を追加してください．
+search-empty = 見つかりませんでした
+search-length = { $words }語・{ $minutes }分で読めます
+
+site-title = 投稿
+site-empty = まだ投稿はありません
+site-notebook = ノートブック
+site-voice-duration = 長さ
+site-voice-download = 録音をダウンロード
+site-weather = 天気
+site-temperature = 気温
+site-sunrise = 日の出
+site-sunset = 日の入り
+
+stats-title = 統計
+stats-total = 投稿数: { $count }
+stats-average = 平均の長さ: { $length }文字
+stats-month = 月
+stats-posts = 投稿数
+stats-tag = タグ
+stats-top-viewed = よく読まれた投稿
@@ -0,0 +1,242 @@
+use std::{collections::HashMap, env, fs};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{header, Body, Request, Response, StatusCode};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+
+// apr1の文字列に使う文字
+const APR1_ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+// /admin以下を利用できる管理者の一覧
+// 通常の利用者とは別に，htpasswdで作ったファイル（1行に1人ずつ「名前:ハッシュ」）で管理する
+// ハッシュはbcrypt（$2y$など），apr1（$apr1$），SHA-1（{SHA}）に対応する
+pub struct AdminAuth {
+  users: Option<HashMap<String, String>>,
+}
+
+impl AdminAuth {
+  // ADMIN_HTPASSWDでファイルを指定する
+  // 指定しない場合は管理用のパスをすべて断る
+  pub fn from_env() -> AdminAuth {
+    let users = env::var("ADMIN_HTPASSWD").ok().map(|path| {
+      fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("cannot read ADMIN_HTPASSWD {}: {}", path, e))
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+          let (name, hash) = line
+            .split_once(':')
+            .expect("ADMIN_HTPASSWD lines must be name:hash");
+          if !supported(hash) {
+            panic!("unsupported hash for {} in ADMIN_HTPASSWD", name);
+          }
+          (name.to_string(), hash.to_string())
+        })
+        .collect()
+    });
+    if users.is_none() {
+      eprintln!("ADMIN_HTPASSWD is not set; /admin is disabled");
+    }
+    AdminAuth { users }
+  }
+
+  // Authorization: Basic の資格情報が一覧と一致するかを判定する関数
+  fn verify(&self, req: &Request<Body>) -> bool {
+    let users = match &self.users {
+      Some(users) => users,
+      None => return false,
+    };
+    let credentials = req
+      .headers()
+      .get(header::AUTHORIZATION)
+      .and_then(|v| v.to_str().ok())
+      .and_then(|v| v.strip_prefix("Basic "))
+      .and_then(|v| STANDARD.decode(v.trim()).ok())
+      .and_then(|v| String::from_utf8(v).ok());
+    let credentials = match credentials {
+      Some(credentials) => credentials,
+      None => return false,
+    };
+    let (name, password) = match credentials.split_once(':') {
+      Some(pair) => pair,
+      None => return false,
+    };
+    users.get(name).is_some_and(|hash| matches(hash, password))
+  }
+
+  // 管理者の一覧を設定しているかどうか
+  pub fn is_enabled(&self) -> bool {
+    self.users.is_some()
+  }
+
+  // 管理用のパスへのリクエストを認証できなければ401のレスポンスを返す関数
+  pub fn challenge(&self, req: &Request<Body>, segments: &[&str]) -> Option<Response<Body>> {
+    if segments.first() != Some(&"admin") {
+      return None;
+    }
+    self.require(req)
+  }
+
+  // パスによらず，リクエストを管理者として認証できなければ401のレスポンスを返す関数
+  // 管理者の一覧を設定していない場合は，認証しようがないので403を返す
+  pub fn require(&self, req: &Request<Body>) -> Option<Response<Body>> {
+    if !self.is_enabled() {
+      return Some(
+        Response::builder()
+          .status(StatusCode::FORBIDDEN)
+          .body("set ADMIN_HTPASSWD to use /admin".into())
+          .unwrap(),
+      );
+    }
+    if self.verify(req) {
+      return None;
+    }
+    Some(
+      Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(
+          header::WWW_AUTHENTICATE,
+          r#"Basic realm="admin", charset="UTF-8""#,
+        )
+        .body(Body::empty())
+        .unwrap(),
+    )
+  }
+}
+
+// 対応しているハッシュの形式かどうか
+fn supported(hash: &str) -> bool {
+  ["$2y$", "$2b$", "$2a$"]
+    .iter()
+    .any(|prefix| hash.starts_with(prefix))
+    || hash.starts_with("$apr1$")
+    || hash.starts_with("{SHA}")
+}
+
+// パスワードがhtpasswdのハッシュと一致するかを判定する関数
+// 比べる時間から一致した長さがわからないように，比較は一定時間で行う
+fn matches(hash: &str, password: &str) -> bool {
+  if hash.starts_with("$2") {
+    return bcrypt::verify(password, hash).unwrap_or(false);
+  }
+  let expected = if let Some(rest) = hash.strip_prefix("$apr1$") {
+    let salt = rest.split('$').next().unwrap_or_default();
+    apr1(password, salt)
+  } else if hash.starts_with("{SHA}") {
+    format!(
+      "{{SHA}}{}",
+      STANDARD.encode(Sha1::digest(password.as_bytes()))
+    )
+  } else {
+    return false;
+  };
+  expected.as_bytes().ct_eq(hash.as_bytes()).into()
+}
+
+// Apacheのapr1（MD5を1000回繰り返す方式）でハッシュを作る関数
+fn apr1(password: &str, salt: &str) -> String {
+  let password = password.as_bytes();
+  let salt = &salt.as_bytes()[..salt.len().min(8)];
+  let alternate = Md5::new()
+    .chain_update(password)
+    .chain_update(salt)
+    .chain_update(password)
+    .finalize();
+  let mut ctx = Md5::new()
+    .chain_update(password)
+    .chain_update(b"$apr1$")
+    .chain_update(salt);
+  for chunk in password.chunks(16) {
+    ctx.update(&alternate[..chunk.len()]);
+  }
+  let mut len = password.len();
+  while len > 0 {
+    if len & 1 == 1 {
+      ctx.update([0]);
+    } else {
+      ctx.update(&password[..1]);
+    }
+    len >>= 1;
+  }
+  let mut digest = ctx.finalize();
+  for i in 0..1000 {
+    let mut ctx = Md5::new();
+    if i % 2 == 1 {
+      ctx.update(password);
+    } else {
+      ctx.update(digest);
+    }
+    if i % 3 != 0 {
+      ctx.update(salt);
+    }
+    if i % 7 != 0 {
+      ctx.update(password);
+    }
+    if i % 2 == 1 {
+      ctx.update(digest);
+    } else {
+      ctx.update(password);
+    }
+    digest = ctx.finalize();
+  }
+  let mut out = format!("$apr1${}$", String::from_utf8_lossy(salt));
+  let mut push = |value: u32, count: usize| {
+    for i in 0..count {
+      out.push(APR1_ALPHABET[(value >> (6 * i) & 0x3f) as usize] as char);
+    }
+  };
+  for [a, b, c] in [[0, 6, 12], [1, 7, 13], [2, 8, 14], [3, 9, 15], [4, 10, 5]] {
+    push(
+      u32::from(digest[a]) << 16 | u32::from(digest[b]) << 8 | u32::from(digest[c]),
+      4,
+    );
+  }
+  push(u32::from(digest[11]), 2);
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // openssl passwd -apr1で作ったもの
+  #[test]
+  fn apr1_matches_openssl() {
+    assert_eq!(
+      apr1("secret", "saltsalt"),
+      "$apr1$saltsalt$LrttParrLPdxvgutaSXWJ0"
+    );
+    assert_eq!(
+      apr1("pässwörd-longer-than-sixteen-bytes", "ab"),
+      "$apr1$ab$McCv6X/HjzUqpau3U01sH/"
+    );
+  }
+
+  #[test]
+  fn matches_htpasswd_formats() {
+    for hash in [
+      "$apr1$saltsalt$LrttParrLPdxvgutaSXWJ0",
+      "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=",
+      "$2y$04$abcdefghijklmnopqrstuu2r9OfJnfCsdneAXAGHnS4UpFFP8WIrW",
+    ] {
+      assert!(supported(hash));
+      assert!(matches(hash, "secret"), "{}", hash);
+      assert!(!matches(hash, "Secret"), "{}", hash);
+    }
+    assert!(!supported(
+      "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+    ));
+  }
+
+  #[test]
+  fn rejects_admin_without_htpasswd() {
+    let auth = AdminAuth { users: None };
+    let req = Request::get("/admin/trash").body(Body::empty()).unwrap();
+    let res = auth.challenge(&req, &["admin", "trash"]).unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    assert!(auth.challenge(&req, &["posts"]).is_none());
+  }
+}
@@ -0,0 +1,205 @@
+use std::sync::Arc;
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use tera::Context;
+use uuid::Uuid;
+
+use crate::{empty, json, templates, wants_html, State, Tenant, Visibility};
+
+#[derive(Serialize, Clone)]
+struct Entry {
+  id: Uuid,
+  title: String,
+  created_at: i64,
+}
+
+#[derive(Serialize)]
+struct Archive {
+  // YYYYかYYYY-MM
+  period: String,
+  // 前後の期間のページのパス
+  prev: String,
+  next: String,
+  posts: Vec<Entry>,
+}
+
+// パスの年と月を解釈する関数
+// 作成日時を記録していない年や範囲外の値は受け付けない
+fn period(year: &str, month: Option<&str>) -> Option<(u32, Option<u32>)> {
+  let year = year
+    .parse()
+    .ok()
+    .filter(|year| (1970..=9999).contains(year))?;
+  let month = match month {
+    Some(month) => Some(
+      month
+        .parse()
+        .ok()
+        .filter(|month| (1..=12).contains(month))?,
+    ),
+    None => None,
+  };
+  Some((year, month))
+}
+
+// 期間内に作成された公開の投稿を古い順に返す関数
+fn posts_in(conn: &Connection, year: u32, month: Option<u32>) -> Vec<Entry> {
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, title, created_at FROM posts
+      WHERE kind = 'text' AND visibility=?1 AND trashed_at IS NULL
+      AND strftime('%Y', created_at, 'unixepoch') = ?2
+      AND (?3 IS NULL OR strftime('%m', created_at, 'unixepoch') = ?3)
+      ORDER BY created_at",
+    )
+    .unwrap();
+  stmt
+    .query_map(
+      params![
+        Visibility::Public,
+        format!("{:04}", year),
+        month.map(|month| format!("{:02}", month))
+      ],
+      |row| {
+        Ok(Entry {
+          id: row.get(0)?,
+          title: row.get(1)?,
+          created_at: row.get(2)?,
+        })
+      },
+    )
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap()
+}
+
+// 年または月ごとの投稿の一覧を返す関数
+pub async fn show(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  year: &str,
+  month: Option<&str>,
+) -> Result<Response<Body>, Error> {
+  let (year, month) = match period(year, month) {
+    Some(period) => period,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let path = |year: u32, month: Option<u32>| match month {
+    Some(month) => format!("{}/archive/{:04}/{:02}", tenant.prefix, year, month),
+    None => format!("{}/archive/{:04}", tenant.prefix, year),
+  };
+  let (period, prev, next) = match month {
+    Some(month) => {
+      let (prev_year, prev_month) = if month == 1 {
+        (year - 1, 12)
+      } else {
+        (year, month - 1)
+      };
+      let (next_year, next_month) = if month == 12 {
+        (year + 1, 1)
+      } else {
+        (year, month + 1)
+      };
+      (
+        format!("{:04}-{:02}", year, month),
+        path(prev_year, Some(prev_month)),
+        path(next_year, Some(next_month)),
+      )
+    }
+    None => (
+      format!("{:04}", year),
+      path(year - 1, None),
+      path(year + 1, None),
+    ),
+  };
+  let archive = Archive {
+    period,
+    prev,
+    next,
+    posts: posts_in(&*tenant.conn.lock().await, year, month),
+  };
+  if wants_html(&req) {
+    let mut ctx = Context::new();
+    ctx.insert("prefix", &tenant.prefix);
+    ctx.insert("archive", &archive);
+    return Ok(Response::new(
+      templates::render(&state, &req, "archive", &mut ctx).into(),
+    ));
+  }
+  Ok(json(&archive))
+}
+
+#[derive(Serialize, Clone)]
+struct Day {
+  day: u32,
+  posts: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+struct Calendar {
+  year: u32,
+  month: u32,
+  // 1日の曜日（0が日曜日）
+  first_weekday: u32,
+  days: Vec<Day>,
+}
+
+// 月の投稿を日ごとにまとめて返す関数
+pub async fn calendar(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  year: &str,
+  month: &str,
+) -> Result<Response<Body>, Error> {
+  let (year, month) = match period(year, Some(month)) {
+    Some((year, Some(month))) => (year, month),
+    _ => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let conn = tenant.conn.lock().await;
+  let first = format!("{:04}-{:02}-01", year, month);
+  // 月の始まりのUNIX秒，日数，1日の曜日
+  let (start, length, first_weekday): (i64, u32, u32) = conn
+    .query_row(
+      "SELECT CAST(strftime('%s', ?1) AS INTEGER),
+      CAST(strftime('%d', ?1, '+1 month', '-1 day') AS INTEGER),
+      CAST(strftime('%w', ?1) AS INTEGER)",
+      params![first],
+      |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )
+    .unwrap();
+  let mut days: Vec<Day> = (1..=length)
+    .map(|day| Day {
+      day,
+      posts: Vec::new(),
+    })
+    .collect();
+  for entry in posts_in(&conn, year, Some(month)) {
+    let day = ((entry.created_at - start) / (24 * 60 * 60)) as usize;
+    days[day].posts.push(entry);
+  }
+  let calendar = Calendar {
+    year,
+    month,
+    first_weekday,
+    days,
+  };
+  if wants_html(&req) {
+    // 週ごとの行に分け，1日より前と末日より後は空欄にする
+    let mut cells: Vec<Option<Day>> = vec![None; first_weekday as usize];
+    cells.extend(calendar.days.iter().cloned().map(Some));
+    cells.resize(cells.len().div_ceil(7) * 7, None);
+    let weeks: Vec<&[Option<Day>]> = cells.chunks(7).collect();
+    let mut ctx = Context::new();
+    ctx.insert("prefix", &tenant.prefix);
+    ctx.insert("calendar", &calendar);
+    ctx.insert("weeks", &weeks);
+    return Ok(Response::new(
+      templates::render(&state, &req, "calendar", &mut ctx).into(),
+    ));
+  }
+  Ok(json(&calendar))
+}
@@ -0,0 +1,177 @@
+use std::{collections::HashMap, env, fs, path::Path, sync::Arc};
+
+use hyper::{body::Bytes, header, Body, Response, StatusCode};
+use include_dir::{include_dir, Dir};
+use sha2::{Digest, Sha256};
+use tera::{Function, Tera, Value};
+
+use crate::{empty, hex};
+
+// 配信するURLの先頭
+const PREFIX: &str = "/static/";
+// URLに含めるハッシュの桁数
+const FINGERPRINT_CHARS: usize = 8;
+// 実行ファイルに組み込んだ標準のファイル
+static EMBEDDED: Dir = include_dir!("$CARGO_MANIFEST_DIR/static");
+
+struct Asset {
+  bytes: Bytes,
+  content_type: &'static str,
+  // ハッシュを含む名前で要求された場合は内容が変わらないので長くキャッシュさせる
+  immutable: bool,
+}
+
+// CSSやJavaScriptなどの静的なファイル
+// 起動時に読み込んで，内容のハッシュを名前に含めたURLでも配信する
+// 内容を変えるとURLが変わるので，ブラウザのキャッシュを手で消す必要がない
+pub struct Assets {
+  // URLのパスの/static/より後ろ
+  files: HashMap<String, Arc<Asset>>,
+  // 元の名前からハッシュを含む名前へ
+  fingerprinted: HashMap<String, String>,
+}
+
+fn content_type(name: &str) -> &'static str {
+  match name.rsplit('.').next() {
+    Some("css") => "text/css; charset=utf-8",
+    Some("js") => "text/javascript; charset=utf-8",
+    Some("svg") => "image/svg+xml",
+    Some("png") => "image/png",
+    Some("ico") => "image/x-icon",
+    Some("woff2") => "font/woff2",
+    _ => "application/octet-stream",
+  }
+}
+
+// style.css を style.0123abcd.css のようにする関数
+fn fingerprint(name: &str, bytes: &[u8]) -> String {
+  let hash = hex::encode(&Sha256::digest(bytes));
+  let hash = &hash[..FINGERPRINT_CHARS];
+  match name.rsplit_once('.') {
+    Some((stem, ext)) if !stem.ends_with('/') => format!("{}.{}.{}", stem, hash, ext),
+    _ => format!("{}.{}", name, hash),
+  }
+}
+
+// 組み込んだディレクトリ以下のファイルを再帰的に集める関数
+fn read_embedded(dir: &Dir, out: &mut HashMap<String, Vec<u8>>) {
+  for file in dir.files() {
+    out.insert(
+      file.path().to_string_lossy().into_owned(),
+      file.contents().to_vec(),
+    );
+  }
+  for dir in dir.dirs() {
+    read_embedded(dir, out);
+  }
+}
+
+// ディレクトリ以下のファイルを再帰的に読み込む関数
+fn read_dir(dir: &Path, base: &str, out: &mut HashMap<String, Vec<u8>>) {
+  for entry in fs::read_dir(dir).unwrap() {
+    let entry = entry.unwrap();
+    let name = format!("{}{}", base, entry.file_name().to_string_lossy());
+    if entry.file_type().unwrap().is_dir() {
+      read_dir(&entry.path(), &format!("{}/", name), out);
+    } else {
+      out.insert(name, fs::read(entry.path()).unwrap());
+    }
+  }
+}
+
+impl Assets {
+  // 組み込みのファイルを読み込み，STATIC_DIRに同じ名前のファイルがあればそれで置き換える
+  pub fn from_env() -> Arc<Assets> {
+    let mut read = HashMap::new();
+    read_embedded(&EMBEDDED, &mut read);
+    if let Ok(dir) = env::var("STATIC_DIR") {
+      read_dir(Path::new(&dir), "", &mut read);
+    }
+    let mut assets = Assets {
+      files: HashMap::new(),
+      fingerprinted: HashMap::new(),
+    };
+    for (name, bytes) in read {
+      let hashed = fingerprint(&name, &bytes);
+      let content_type = content_type(&name);
+      let bytes = Bytes::from(bytes);
+      assets.files.insert(
+        hashed.clone(),
+        Arc::new(Asset {
+          bytes: bytes.clone(),
+          content_type,
+          immutable: true,
+        }),
+      );
+      assets.files.insert(
+        name.clone(),
+        Arc::new(Asset {
+          bytes,
+          content_type,
+          immutable: false,
+        }),
+      );
+      assets.fingerprinted.insert(name, hashed);
+    }
+    Arc::new(assets)
+  }
+
+  // 配信するファイルの元の名前
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    self.fingerprinted.keys().map(String::as_str)
+  }
+
+  // 配信するすべてのファイルをURLのパスの/static/より後ろの名前と一緒に返す関数
+  // ハッシュを含む名前と元の名前の両方が入る
+  pub fn files(&self) -> impl Iterator<Item = (&str, &[u8])> {
+    self
+      .files
+      .iter()
+      .map(|(name, asset)| (name.as_str(), &asset.bytes[..]))
+  }
+
+  // /static/以下へのリクエストであればファイルを返す関数
+  pub fn serve(&self, path: &str) -> Option<Response<Body>> {
+    let name = path.strip_prefix(PREFIX)?;
+    let asset = match self.files.get(name) {
+      Some(asset) => asset,
+      None => return Some(empty(StatusCode::NOT_FOUND)),
+    };
+    let cache = if asset.immutable {
+      "public, max-age=31536000, immutable"
+    } else {
+      "no-cache"
+    };
+    Some(
+      Response::builder()
+        .header(header::CONTENT_TYPE, asset.content_type)
+        .header(header::CACHE_CONTROL, cache)
+        .body(asset.bytes.clone().into())
+        .unwrap(),
+    )
+  }
+}
+
+// テンプレートから{{ asset(path="style.css") }}で呼び出すヘルパー
+// ハッシュを含むURLを返す（知らないファイルの場合は元の名前のURLを返す）
+struct AssetUrl(Arc<Assets>);
+
+impl Function for AssetUrl {
+  fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let path = args
+      .get("path")
+      .and_then(Value::as_str)
+      .ok_or("asset requires a path")?;
+    let name = self.0.fingerprinted.get(path).map_or(path, String::as_str);
+    Ok(Value::String(format!("{}{}", PREFIX, name)))
+  }
+
+  fn is_safe(&self) -> bool {
+    true
+  }
+}
+
+// テンプレートヘルパーを登録する関数
+pub fn register(tera: &mut Tera, assets: Arc<Assets>) {
+  tera.register_function("asset", AssetUrl(assets));
+}
@@ -0,0 +1,699 @@
+use std::{
+  collections::HashMap,
+  env,
+  net::IpAddr,
+  sync::{Arc, Mutex as StdMutex, Weak},
+};
+
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use uuid::Uuid;
+
+use crate::{
+  attachment_policy::{essence, Policy, Rejection},
+  audio, audit,
+  blob::{self, BlobStore},
+  e2ee, empty,
+  epub::percent_encode,
+  header_str, hex, image, json, now, remote_ip, State, Tenant, Visibility,
+};
+
+// 添付ファイルの大きさの上限の初期値
+const DEFAULT_MAX_BYTES: usize = 10 * 1024 * 1024;
+// ファイル名の長さの上限（文字数）
+const MAX_NAME_CHARS: usize = 255;
+// ブラウザの中で開いてよい種類
+// それ以外（HTMLやSVGなど）は同じオリジンでスクリプトが動かないようにダウンロードさせる
+const INLINE_TYPES: &[&str] = &[
+  "image/png",
+  "image/jpeg",
+  "image/gif",
+  "image/webp",
+  "application/pdf",
+  "audio/mpeg",
+  "audio/ogg",
+  "audio/wav",
+  "audio/flac",
+  "audio/mp4",
+  "audio/x-m4a",
+  "audio/webm",
+];
+
+// 添付ファイルの保存先と上限
+pub struct Attachments {
+  store: Box<dyn BlobStore>,
+  pub max_bytes: usize,
+  // 受け付ける種類などの条件
+  pub policy: Policy,
+  // 画像から取り除くメタデータ
+  image_metadata: image::Metadata,
+  // 同じ中身を保存するのと消すのが入れ違わないように，中身ごとに保存先への書き込みと削除を順番に行う
+  // 違う中身の書き込みは待たせない（使われなくなったロックは次に取るときに捨てる）
+  // ロック中にawaitしないので標準ライブラリのMutexで十分
+  locks: StdMutex<HashMap<String, Weak<Mutex<()>>>>,
+}
+
+impl Attachments {
+  // 保存先はATTACHMENT_STOREで，上限はATTACHMENT_MAX_BYTESで指定する
+  pub fn from_env() -> Attachments {
+    Attachments {
+      store: blob::from_env(),
+      max_bytes: env::var("ATTACHMENT_MAX_BYTES")
+        .map(|bytes| {
+          bytes
+            .parse()
+            .expect("ATTACHMENT_MAX_BYTES must be a number")
+        })
+        .unwrap_or(DEFAULT_MAX_BYTES),
+      policy: Policy::from_env(),
+      image_metadata: image::Metadata::from_env(),
+      locks: StdMutex::new(HashMap::new()),
+    }
+  }
+
+  // 保存先の名前に対するロックを返す関数
+  fn lock(&self, key: &str) -> Arc<Mutex<()>> {
+    let mut locks = self.locks.lock().unwrap();
+    locks.retain(|_, lock| lock.strong_count() > 0);
+    if let Some(lock) = locks.get(key).and_then(Weak::upgrade) {
+      return lock;
+    }
+    let lock = Arc::new(Mutex::new(()));
+    locks.insert(key.to_string(), Arc::downgrade(&lock));
+    lock
+  }
+}
+
+#[derive(Deserialize)]
+struct Query {
+  #[serde(default)]
+  name: String,
+}
+
+#[derive(Serialize)]
+pub struct Attachment {
+  pub id: Uuid,
+  name: String,
+  content_type: String,
+  size: u64,
+  // 音声の再生時間（求められたものだけ）
+  duration_ms: Option<u64>,
+  // 画像の表示される大きさとEXIFの向き（求められたものだけ）
+  width: Option<u32>,
+  height: Option<u32>,
+  orientation: Option<u16>,
+  created_at: i64,
+  url: String,
+}
+
+// パスを取り除き，制御文字を含まない長さの限られた名前にする関数
+pub fn file_name(name: &str) -> String {
+  let name = name
+    .rsplit(['/', '\\'])
+    .next()
+    .unwrap_or_default()
+    .chars()
+    .filter(|c| !c.is_control())
+    .take(MAX_NAME_CHARS)
+    .collect::<String>();
+  match name.trim() {
+    "" | "." | ".." => "attachment".to_string(),
+    name => name.to_string(),
+  }
+}
+
+// 中身のSHA-256を名前にして，同じファイルは1つだけ保存する
+// 参照の数はテナントのDBで数えるので，テナントごとに名前が重ならないようにテナント名を前に付ける
+fn storage_key(tenant: &Tenant, data: &[u8]) -> String {
+  format!("{}/{}", tenant.name, hex::encode(&Sha256::digest(data)))
+}
+
+fn stored(conn: &Connection, key: &str) -> bool {
+  conn
+    .query_row(
+      "SELECT 1 FROM blobs WHERE storage_key=?1",
+      params![key],
+      |_| Ok(()),
+    )
+    .optional()
+    .unwrap()
+    .is_some()
+}
+
+// 参照を1つ減らし，誰も参照しなくなった場合は保存先から消すべき名前を返す関数
+fn release(conn: &Connection, key: String) -> Option<String> {
+  conn
+    .execute(
+      "UPDATE blobs SET refs = refs - 1 WHERE storage_key=?1",
+      params![key],
+    )
+    .unwrap();
+  let removed = conn
+    .execute(
+      "DELETE FROM blobs WHERE storage_key=?1 AND refs <= 0",
+      params![key],
+    )
+    .unwrap();
+  if removed > 0 {
+    Some(key)
+  } else {
+    None
+  }
+}
+
+// Content-Typeを添付ファイルの種類にする関数（省略時はapplication/octet-stream）
+pub fn content_type(req: &Request<Body>) -> String {
+  match header_str(req, header::CONTENT_TYPE) {
+    content_type if content_type.is_empty() => "application/octet-stream".to_string(),
+    content_type => content_type,
+  }
+}
+
+// 添付ファイルを付けられる投稿かどうか
+pub fn exists(conn: &Connection, post_id: &Uuid) -> bool {
+  conn
+    .query_row(
+      "SELECT 1 FROM posts WHERE id=?1 AND kind = 'text' AND trashed_at IS NULL",
+      params![post_id],
+      |_| Ok(()),
+    )
+    .optional()
+    .unwrap()
+    .is_some()
+}
+
+fn record(conn: &Connection, action: &'static str, post_id: &Uuid, summary: String, ip: IpAddr) {
+  audit::record(
+    conn,
+    audit::Entry {
+      actor: audit::ANONYMOUS,
+      action,
+      post_id,
+      summary,
+      ip,
+    },
+  );
+}
+
+// POST /posts/{id}/attachments?name=... で本文をそのまま添付ファイルとして保存する関数
+// 種類はContent-Typeで指定する
+pub async fn upload(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  post_id: &str,
+) -> Result<Response<Body>, Error> {
+  let post_id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let ip = remote_ip(&req);
+  let query = match serde_urlencoded::from_str::<Query>(req.uri().query().unwrap_or_default()) {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let name = file_name(&query.name);
+  let content_type = content_type(&req);
+  if !exists(&*tenant.conn.lock().await, &post_id) {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  let limit = state.attachments.max_bytes;
+  let data = match e2ee::read_limited(req.into_body(), limit).await? {
+    Some(data) => data,
+    None => return Ok(e2ee::too_large(limit)),
+  };
+  Ok(attach(&state, &tenant, post_id, name, content_type, data, ip).await)
+}
+
+// 保存先に置いた中身
+// 添付ファイルの行を追加するまでは，同じ中身を消されないようにロックを持っておく
+pub struct Stored {
+  key: String,
+  size: u64,
+  duration_ms: Option<u64>,
+  image: Option<image::Info>,
+  // 今回新しく保存先に置いたかどうか
+  uploaded: bool,
+  _guard: OwnedMutexGuard<()>,
+}
+
+// 条件を確かめてから受け取った中身を保存先に置く関数
+// 断る場合はそのまま返せる応答を返す
+pub async fn store(
+  state: &State,
+  tenant: &Tenant,
+  name: &str,
+  content_type: &str,
+  data: Vec<u8>,
+) -> Result<Stored, Response<Body>> {
+  let kind = essence(content_type);
+  // 検査するものと保存するものが同じになるように，先にメタデータを取り除く
+  let data = image::strip(&kind, data, state.attachments.image_metadata);
+  if let Err(rejection) = state
+    .attachments
+    .policy
+    .check(name, content_type, &data)
+    .await
+  {
+    if let Rejection::ScanFailed(e) = &rejection {
+      eprintln!("attachment scan error {} for {:?}", e, name);
+    }
+    return Err(rejection.response());
+  }
+  let key = storage_key(tenant, &data);
+  let size = data.len() as u64;
+  let duration_ms = audio::duration_ms(&kind, &data);
+  let image = image::info(&kind, &data);
+  let guard = state.attachments.lock(&key).lock_owned().await;
+  // すでに同じ中身を保存していれば参照を増やすだけにする
+  let uploaded = !stored(&*tenant.conn.lock().await, &key);
+  if uploaded {
+    if let Err(e) = state.attachments.store.put(&key, data, content_type).await {
+      eprintln!("attachment store error {} for {}", e, key);
+      return Err(empty(StatusCode::BAD_GATEWAY));
+    }
+  }
+  Ok(Stored {
+    key,
+    size,
+    duration_ms,
+    image,
+    uploaded,
+    _guard: guard,
+  })
+}
+
+// 保存先に置いた中身を投稿の添付ファイルとして記録する関数
+// 投稿を作るのと同じトランザクションの中でも呼び出せる
+pub fn insert(
+  conn: &Connection,
+  tenant: &Tenant,
+  stored: &Stored,
+  post_id: &Uuid,
+  name: String,
+  content_type: String,
+  ip: IpAddr,
+) -> Attachment {
+  let id = Uuid::new_v4();
+  let created_at = now() as i64;
+  let image = stored.image.as_ref();
+  let (width, height, orientation) = (
+    image.map(|image| image.width),
+    image.map(|image| image.height),
+    image.map(|image| image.orientation),
+  );
+  conn
+    .execute(
+      "INSERT INTO attachments(id, post_id, name, content_type, size, storage_key, created_at,
+        duration_ms, width, height, orientation)
+      VALUES (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11)",
+      params![
+        id,
+        post_id,
+        name,
+        content_type,
+        stored.size,
+        stored.key,
+        created_at,
+        stored.duration_ms,
+        width,
+        height,
+        orientation
+      ],
+    )
+    .unwrap();
+  conn
+    .execute(
+      "INSERT INTO blobs(storage_key, refs) VALUES (?1, 1)
+      ON CONFLICT(storage_key) DO UPDATE SET refs = refs + 1",
+      params![stored.key],
+    )
+    .unwrap();
+  record(
+    conn,
+    "attach",
+    post_id,
+    format!("{:?}, {}, {} bytes", name, content_type, stored.size),
+    ip,
+  );
+  Attachment {
+    id,
+    url: format!("{}/attachments/{}", tenant.prefix, id),
+    name,
+    content_type,
+    size: stored.size,
+    duration_ms: stored.duration_ms,
+    width,
+    height,
+    orientation,
+    created_at,
+  }
+}
+
+// 受け取った中身を保存して投稿に添付する関数
+// 分割アップロードを確定するときにも使う
+pub async fn attach(
+  state: &State,
+  tenant: &Tenant,
+  post_id: Uuid,
+  name: String,
+  content_type: String,
+  data: Vec<u8>,
+  ip: IpAddr,
+) -> Response<Body> {
+  let stored = match store(state, tenant, &name, &content_type, data).await {
+    Ok(stored) => stored,
+    Err(res) => return res,
+  };
+  let mut conn = tenant.conn.lock().await;
+  // 保存している間に投稿がゴミ箱に入れられた場合は保存したものを消す
+  if !exists(&conn, &post_id) {
+    drop(conn);
+    if stored.uploaded {
+      let _ = state.attachments.store.delete(&stored.key).await;
+    }
+    return empty(StatusCode::NOT_FOUND);
+  }
+  let tx = conn.transaction().unwrap();
+  let attachment = insert(&tx, tenant, &stored, &post_id, name, content_type, ip);
+  tx.commit().unwrap();
+  json(&attachment)
+}
+
+// 投稿の添付ファイルを古い順に一覧する関数
+pub async fn list(tenant: Arc<Tenant>, post_id: &str) -> Result<Response<Body>, Error> {
+  let post_id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let conn = tenant.conn.lock().await;
+  let mut stmt = conn
+    .prepare(
+      "SELECT attachments.id, name, content_type, size, attachments.created_at, duration_ms,
+        width, height, orientation
+      FROM attachments
+      JOIN posts ON posts.id = attachments.post_id
+      WHERE post_id=?1 AND visibility != ?2 AND trashed_at IS NULL
+      ORDER BY attachments.created_at, attachments.rowid",
+    )
+    .unwrap();
+  let attachments = stmt
+    .query_map(params![post_id, Visibility::Private], |row| {
+      let id: Uuid = row.get(0)?;
+      Ok(Attachment {
+        id,
+        name: row.get(1)?,
+        content_type: row.get(2)?,
+        size: row.get(3)?,
+        created_at: row.get(4)?,
+        duration_ms: row.get(5)?,
+        width: row.get(6)?,
+        height: row.get(7)?,
+        orientation: row.get(8)?,
+        url: format!("{}/attachments/{}", tenant.prefix, id),
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  Ok(json(&attachments))
+}
+
+// GET /attachments/{id} で添付ファイルを返す関数
+// 投稿と同じく非公開の投稿とゴミ箱の投稿のものは返さない
+// 署名付きURLを使える保存先の場合はそこへ転送してサーバを経由させない
+pub async fn download(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  id: &str,
+) -> Result<Response<Body>, Error> {
+  let id = match Uuid::parse_str(id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let found: Option<(String, String, String)> = tenant
+    .conn
+    .lock()
+    .await
+    .query_row(
+      "SELECT name, content_type, storage_key FROM attachments
+      JOIN posts ON posts.id = attachments.post_id
+      WHERE attachments.id=?1 AND visibility != ?2 AND trashed_at IS NULL",
+      params![id, Visibility::Private],
+      |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )
+    .optional()
+    .unwrap();
+  let (name, content_type, key) = match found {
+    Some(found) => found,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let inline = INLINE_TYPES.contains(&essence(&content_type).as_str());
+  let disposition = format!(
+    "{}; filename=\"attachment\"; filename*=UTF-8''{}",
+    if inline { "inline" } else { "attachment" },
+    percent_encode(&name)
+  );
+  let served_type = if inline {
+    content_type.as_str()
+  } else {
+    "application/octet-stream"
+  };
+  if let Some(url) = state
+    .attachments
+    .store
+    .presigned_url(&key, served_type, &disposition)
+  {
+    return Ok(
+      Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, url)
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::empty())
+        .unwrap(),
+    );
+  }
+  match state.attachments.store.get(&key).await {
+    Ok(Some(data)) => {
+      let res = Response::builder()
+        .header(header::CONTENT_TYPE, served_type)
+        .header(header::CONTENT_DISPOSITION, disposition)
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(header::CONTENT_SECURITY_POLICY, "sandbox")
+        .header(header::ACCEPT_RANGES, "bytes");
+      let len = data.len();
+      // 音声の途中から再生できるよう，1つの範囲の指定にだけ応じる
+      Ok(match range(&header_str(&req, header::RANGE), len) {
+        Some(Ok((start, end))) => res
+          .status(StatusCode::PARTIAL_CONTENT)
+          .header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end, len),
+          )
+          .body(data[start..=end].to_vec().into())
+          .unwrap(),
+        Some(Err(())) => res
+          .status(StatusCode::RANGE_NOT_SATISFIABLE)
+          .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+          .body(Body::empty())
+          .unwrap(),
+        None => res.body(data.into()).unwrap(),
+      })
+    }
+    Ok(None) => Ok(empty(StatusCode::NOT_FOUND)),
+    Err(e) => {
+      eprintln!("attachment store error {} for {}", e, key);
+      Ok(empty(StatusCode::BAD_GATEWAY))
+    }
+  }
+}
+
+// Rangeヘッダのbytes=0-99，bytes=100-，bytes=-100を最初と最後の位置にする関数
+// 指定がないか解釈できない（複数の範囲など）場合は全体を返すのでNone，範囲が中身の外ならErr
+fn range(value: &str, len: usize) -> Option<Result<(usize, usize), ()>> {
+  let spec = value.trim().strip_prefix("bytes=")?;
+  if spec.contains(',') {
+    return None;
+  }
+  let (start, end) = spec.split_once('-')?;
+  let (start, end) = (start.trim(), end.trim());
+  let (start, end) = match (start.parse::<usize>(), end.parse::<usize>()) {
+    (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+    (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+    (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+      (len.saturating_sub(suffix), len.saturating_sub(1))
+    }
+    _ => return None,
+  };
+  if start >= len {
+    return Some(Err(()));
+  }
+  Some(Ok((start, end)))
+}
+
+// 添付ファイルを削除する関数
+pub async fn delete(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  id: &str,
+) -> Result<Response<Body>, Error> {
+  let id = match Uuid::parse_str(id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let mut conn = tenant.conn.lock().await;
+  let found: Option<(Uuid, String, String)> = conn
+    .query_row(
+      "SELECT post_id, name, storage_key FROM attachments WHERE id=?1",
+      params![id],
+      |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )
+    .optional()
+    .unwrap();
+  let (post_id, name, key) = match found {
+    Some(found) => found,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let released = {
+    let tx = conn.transaction().unwrap();
+    // 音声メモの音声を消した場合はふつうの投稿として残す
+    tx.execute(
+      "DELETE FROM voice_memos WHERE attachment_id=?1",
+      params![id],
+    )
+    .unwrap();
+    tx.execute("DELETE FROM attachments WHERE id=?1", params![id])
+      .unwrap();
+    let released = release(&tx, key);
+    record(
+      &tx,
+      "detach",
+      &post_id,
+      format!("{:?}", name),
+      remote_ip(&req),
+    );
+    tx.commit().unwrap();
+    released
+  };
+  drop(conn);
+  remove_blobs(&state, &tenant, released.into_iter().collect()).await;
+  Ok(empty(StatusCode::NO_CONTENT))
+}
+
+// 投稿の添付ファイルの行を消し，どの投稿からも参照されなくなった保存先の名前を返す関数
+// 投稿を完全に削除するのと同じトランザクションの中で呼び出す
+pub fn detach_all(conn: &Connection, post_id: &Uuid) -> Vec<String> {
+  let mut stmt = conn
+    .prepare("SELECT storage_key FROM attachments WHERE post_id=?1")
+    .unwrap();
+  let keys = stmt
+    .query_map(params![post_id], |row| row.get(0))
+    .unwrap()
+    .collect::<Result<Vec<String>, _>>()
+    .unwrap();
+  conn
+    .execute("DELETE FROM voice_memos WHERE post_id=?1", params![post_id])
+    .unwrap();
+  conn
+    .execute("DELETE FROM attachments WHERE post_id=?1", params![post_id])
+    .unwrap();
+  keys
+    .into_iter()
+    .filter_map(|key| release(conn, key))
+    .collect()
+}
+
+// 複製した投稿に元の投稿の添付ファイルを付ける関数
+// 中身は保存先で共有し，参照の数だけを増やす
+// 投稿を複製するのと同じトランザクションの中で呼び出す
+pub fn copy_all(conn: &Connection, from: &Uuid, to: &Uuid) {
+  let mut stmt = conn
+    .prepare("SELECT id, storage_key FROM attachments WHERE post_id=?1")
+    .unwrap();
+  let rows = stmt
+    .query_map(params![from], |row| Ok((row.get(0)?, row.get(1)?)))
+    .unwrap()
+    .collect::<Result<Vec<(Uuid, String)>, _>>()
+    .unwrap();
+  for (id, key) in rows {
+    conn
+      .execute(
+        "INSERT INTO attachments(id, post_id, name, content_type, size, storage_key, created_at,
+          duration_ms, width, height, orientation)
+        SELECT ?1, ?2, name, content_type, size, storage_key, created_at,
+          duration_ms, width, height, orientation
+        FROM attachments WHERE id=?3",
+        params![Uuid::new_v4(), to, id],
+      )
+      .unwrap();
+    conn
+      .execute(
+        "INSERT INTO blobs(storage_key, refs) VALUES (?1, 1)
+        ON CONFLICT(storage_key) DO UPDATE SET refs = refs + 1",
+        params![key],
+      )
+      .unwrap();
+  }
+}
+
+// 保存先から中身を読む関数（なければNone）
+pub async fn content(state: &State, key: &str) -> Option<Vec<u8>> {
+  match state.attachments.store.get(key).await {
+    Ok(data) => data,
+    Err(e) => {
+      eprintln!("attachment store error {} for {}", e, key);
+      None
+    }
+  }
+}
+
+// 保存先からファイルを消す関数
+// DBから消した後に呼び出すので，失敗しても参照されないファイルが残るだけになる
+// 消すまでの間に同じ中身がまた添付された場合は消さない
+pub async fn remove_blobs(state: &State, tenant: &Tenant, keys: Vec<String>) {
+  if keys.is_empty() {
+    return;
+  }
+  for key in keys {
+    let lock = state.attachments.lock(&key);
+    let _guard = lock.lock().await;
+    if stored(&*tenant.conn.lock().await, &key) {
+      continue;
+    }
+    if let Err(e) = state.attachments.store.delete(&key).await {
+      eprintln!("attachment store error {} for {}", e, key);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests;
+
+  #[tokio::test]
+  async fn rejects_bad_upload_query() {
+    let state = tests::state();
+    let uri = format!("/posts/{}/attachments?name=a&name=b", Uuid::new_v4());
+    let req = Request::post(uri).body(Body::from("data")).unwrap();
+    let res = tests::send(&state, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+  }
+
+  #[test]
+  fn locks_each_key_separately() {
+    let attachments = Attachments::from_env();
+    let a = attachments.lock("a");
+    assert!(Arc::ptr_eq(&a, &attachments.lock("a")));
+    assert!(!Arc::ptr_eq(&a, &attachments.lock("b")));
+    // 使われなくなったロックは残さない
+    drop(a);
+    attachments.lock("c");
+    assert_eq!(attachments.locks.lock().unwrap().len(), 1);
+  }
+}
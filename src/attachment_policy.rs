@@ -0,0 +1,249 @@
+use std::{env, time::Duration};
+
+use hyper::{body, header, Body, Request, Response, StatusCode};
+
+use crate::{
+  epub::percent_encode,
+  https::{self, HttpsClient},
+};
+
+// 検査の応答を待つ時間
+const SCAN_TIMEOUT_SECONDS: u64 = 30;
+
+// 中身の先頭のバイト列で種類を確かめられるもの
+const KNOWN_TYPES: &[&str] = &[
+  "image/png",
+  "image/jpeg",
+  "image/gif",
+  "image/webp",
+  "application/pdf",
+  "application/zip",
+  "audio/mpeg",
+  "audio/ogg",
+  "audio/wav",
+  "audio/flac",
+  "audio/mp4",
+  "audio/webm",
+  "video/mp4",
+  "video/webm",
+];
+
+// 受け付けない理由
+pub enum Rejection {
+  // 許可していない種類，または宣言した種類と中身が違う
+  Type(String),
+  // 種類ごとの上限を超えている
+  TooLarge(usize),
+  // 検査で見つかった問題
+  Scan(String),
+  // 検査できなかった
+  ScanFailed(String),
+}
+
+impl Rejection {
+  fn status(&self) -> StatusCode {
+    match self {
+      Rejection::Type(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+      Rejection::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+      Rejection::Scan(_) => StatusCode::UNPROCESSABLE_ENTITY,
+      Rejection::ScanFailed(_) => StatusCode::BAD_GATEWAY,
+    }
+  }
+
+  fn message(&self) -> String {
+    match self {
+      Rejection::Type(message) | Rejection::Scan(message) => message.clone(),
+      Rejection::TooLarge(limit) => format!("limit is {} bytes", limit),
+      Rejection::ScanFailed(_) => "scan unavailable".to_string(),
+    }
+  }
+
+  pub fn response(&self) -> Response<Body> {
+    Response::builder()
+      .status(self.status())
+      .body(self.message().into())
+      .unwrap()
+  }
+}
+
+// Content-Typeから引数などを取り除いた小文字の種類
+pub fn essence(content_type: &str) -> String {
+  content_type
+    .split(';')
+    .next()
+    .unwrap_or_default()
+    .trim()
+    .to_ascii_lowercase()
+}
+
+// 種類が中身の先頭のバイト列と合うかどうか
+// 確かめられない種類はNoneを返す
+fn signature_matches(kind: &str, data: &[u8]) -> Option<bool> {
+  let riff = |form: &[u8]| data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == form;
+  Some(match kind {
+    "image/png" => data.starts_with(b"\x89PNG\r\n\x1a\n"),
+    "image/jpeg" => data.starts_with(&[0xff, 0xd8, 0xff]),
+    "image/gif" => data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a"),
+    "image/webp" => riff(b"WEBP"),
+    "application/pdf" => data.starts_with(b"%PDF-"),
+    "application/zip" => data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06"),
+    "audio/mpeg" => {
+      data.starts_with(b"ID3") || (data.len() >= 2 && data[0] == 0xff && data[1] & 0xe0 == 0xe0)
+    }
+    "audio/ogg" => data.starts_with(b"OggS"),
+    "audio/wav" | "audio/wave" | "audio/x-wav" => riff(b"WAVE"),
+    "audio/flac" => data.starts_with(b"fLaC"),
+    "audio/mp4" | "audio/x-m4a" | "video/mp4" => data.len() >= 8 && &data[4..8] == b"ftyp",
+    "audio/webm" | "video/webm" => data.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]),
+    _ => return None,
+  })
+}
+
+// 中身の先頭のバイト列から種類を推定する関数
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+  KNOWN_TYPES
+    .iter()
+    .find(|kind| signature_matches(kind, data) == Some(true))
+    .copied()
+}
+
+// */*やimage/*のような書き方の種類に当てはまるかどうか
+fn type_matches(pattern: &str, kind: &str) -> bool {
+  match pattern.strip_suffix("/*") {
+    Some(prefix) => prefix == "*" || kind.split('/').next() == Some(prefix),
+    None => pattern == kind,
+  }
+}
+
+// 添付ファイルとして受け付ける条件
+pub struct Policy {
+  // 許可する種類（未設定ならすべて）
+  allowed: Option<Vec<String>>,
+  // 種類ごとの大きさの上限（先に書いたものを優先する）
+  limits: Vec<(String, usize)>,
+  // 中身を渡して検査するURL
+  scan_url: Option<String>,
+  client: HttpsClient,
+}
+
+impl Policy {
+  // ATTACHMENT_TYPESで許可する種類を（例: image/*,application/pdf），
+  // ATTACHMENT_TYPE_LIMITSで種類ごとの上限のバイト数を（例: image/*=5242880,audio/*=52428800），
+  // ATTACHMENT_SCAN_URLで保存する前に中身を検査するURLを指定する
+  pub fn from_env() -> Policy {
+    let list = |name: &str| {
+      env::var(name).ok().map(|value| {
+        value
+          .split(',')
+          .map(|item| item.trim().to_ascii_lowercase())
+          .filter(|item| !item.is_empty())
+          .collect::<Vec<_>>()
+      })
+    };
+    Policy {
+      allowed: list("ATTACHMENT_TYPES"),
+      limits: list("ATTACHMENT_TYPE_LIMITS")
+        .unwrap_or_default()
+        .into_iter()
+        .map(|item| {
+          let (pattern, bytes) = item
+            .split_once('=')
+            .expect("ATTACHMENT_TYPE_LIMITS must be type=bytes");
+          (
+            pattern.trim().to_string(),
+            bytes
+              .trim()
+              .parse()
+              .expect("ATTACHMENT_TYPE_LIMITS must be type=bytes"),
+          )
+        })
+        .collect(),
+      scan_url: env::var("ATTACHMENT_SCAN_URL").ok(),
+      client: https::trusted_client(),
+    }
+  }
+
+  // 種類ごとの上限（なければNone）
+  pub fn limit(&self, kind: &str) -> Option<usize> {
+    self
+      .limits
+      .iter()
+      .find(|(pattern, _)| type_matches(pattern, kind))
+      .map(|(_, bytes)| *bytes)
+  }
+
+  // 中身を受け取る前に確かめられること（種類と大きさ）を確かめる関数
+  pub fn precheck(&self, content_type: &str, size: usize) -> Result<(), Rejection> {
+    let kind = essence(content_type);
+    if let Some(allowed) = &self.allowed {
+      if !allowed.iter().any(|pattern| type_matches(pattern, &kind)) {
+        return Err(Rejection::Type(format!("{} is not allowed", kind)));
+      }
+    }
+    match self.limit(&kind) {
+      Some(limit) if size > limit => Err(Rejection::TooLarge(limit)),
+      _ => Ok(()),
+    }
+  }
+
+  // 保存する前にすべての条件を確かめる関数
+  // 宣言した種類と中身の先頭のバイト列が違うものは受け付けない
+  // （種類を指定しなかったapplication/octet-streamは中身を問わない）
+  pub async fn check(&self, name: &str, content_type: &str, data: &[u8]) -> Result<(), Rejection> {
+    self.precheck(content_type, data.len())?;
+    let kind = essence(content_type);
+    let matches = match signature_matches(&kind, data) {
+      Some(matches) => matches,
+      None => kind == "application/octet-stream" || sniff(data).is_none(),
+    };
+    if !matches {
+      return Err(Rejection::Type(format!("content does not match {}", kind)));
+    }
+    match &self.scan_url {
+      Some(url) => self.scan(url, name, content_type, data).await,
+      None => Ok(()),
+    }
+  }
+
+  // 検査するURLに中身をPOSTする関数
+  // 2xxなら問題なし，4xxなら本文を理由として受け付けない
+  // それ以外や接続できない場合も，検査していないものを公開しないように受け付けない
+  async fn scan(
+    &self,
+    url: &str,
+    name: &str,
+    content_type: &str,
+    data: &[u8],
+  ) -> Result<(), Rejection> {
+    let req = Request::post(url)
+      .header(header::CONTENT_TYPE, content_type)
+      .header("x-attachment-name", percent_encode(name))
+      .body(Body::from(data.to_vec()))
+      .unwrap();
+    let res = match tokio::time::timeout(
+      Duration::from_secs(SCAN_TIMEOUT_SECONDS),
+      self.client.request(req),
+    )
+    .await
+    {
+      Ok(Ok(res)) => res,
+      Ok(Err(e)) => return Err(Rejection::ScanFailed(e.to_string())),
+      Err(_) => return Err(Rejection::ScanFailed("timed out".to_string())),
+    };
+    let status = res.status();
+    if status.is_success() {
+      return Ok(());
+    }
+    let body = body::to_bytes(res.into_body()).await.unwrap_or_default();
+    let reason = String::from_utf8_lossy(&body).trim().to_string();
+    if status.is_client_error() {
+      Err(Rejection::Scan(if reason.is_empty() {
+        "rejected by scan".to_string()
+      } else {
+        reason
+      }))
+    } else {
+      Err(Rejection::ScanFailed(format!("{} {}", status, reason)))
+    }
+  }
+}
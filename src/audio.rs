@@ -0,0 +1,292 @@
+use std::convert::TryInto;
+
+// 音声ファイルのヘッダから再生時間（ミリ秒）を求める関数
+// 種類ごとに必要な部分だけを読み，求められない場合はNoneを返す
+pub fn duration_ms(kind: &str, data: &[u8]) -> Option<u64> {
+  match kind {
+    "audio/wav" | "audio/wave" | "audio/x-wav" => wav(data),
+    "audio/mpeg" => mp3(data),
+    "audio/ogg" => ogg(data),
+    "audio/flac" => flac(data),
+    "audio/mp4" | "audio/x-m4a" => mp4(data),
+    "audio/webm" => webm(data),
+    _ => None,
+  }
+}
+
+// 種類に合わせたファイルの拡張子
+pub fn extension(kind: &str) -> &'static str {
+  match kind {
+    "audio/mpeg" => "mp3",
+    "audio/ogg" => "ogg",
+    "audio/wav" | "audio/wave" | "audio/x-wav" => "wav",
+    "audio/flac" => "flac",
+    "audio/mp4" | "audio/x-m4a" => "m4a",
+    "audio/webm" => "webm",
+    _ => "audio",
+  }
+}
+
+// 1:05や1:02:03のように表示する関数
+pub fn format_duration(ms: u64) -> String {
+  let seconds = ms / 1000;
+  if seconds >= 60 * 60 {
+    format!(
+      "{}:{:02}:{:02}",
+      seconds / 3600,
+      seconds / 60 % 60,
+      seconds % 60
+    )
+  } else {
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+  }
+}
+
+fn u16_le(data: &[u8], at: usize) -> Option<u16> {
+  Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_le(data: &[u8], at: usize) -> Option<u32> {
+  Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u32_be(data: &[u8], at: usize) -> Option<u32> {
+  Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_le(data: &[u8], at: usize) -> Option<u64> {
+  Some(u64::from_le_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+fn u64_be(data: &[u8], at: usize) -> Option<u64> {
+  Some(u64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+// 総数と1秒あたりの数からミリ秒を求める関数
+fn ms(count: u64, per_second: u64) -> Option<u64> {
+  if per_second == 0 {
+    return None;
+  }
+  Some((u128::from(count) * 1000 / u128::from(per_second)) as u64)
+}
+
+// WAV: fmtチャンクの1秒あたりのバイト数とdataチャンクの大きさから求める
+fn wav(data: &[u8]) -> Option<u64> {
+  let mut at = 12;
+  let mut byte_rate = None;
+  while at + 8 <= data.len() {
+    let id = &data[at..at + 4];
+    let size = u32_le(data, at + 4)? as usize;
+    match id {
+      b"fmt " => byte_rate = u32_le(data, at + 8 + 8),
+      // 録音しながら書き出したファイルは大きさが未確定のことがあるので，残りの長さを上限にする
+      b"data" => {
+        let size = size.min(data.len() - at - 8) as u64;
+        return ms(size, u64::from(byte_rate?));
+      }
+      _ => {}
+    }
+    // チャンクは偶数バイトに揃える
+    at = at.checked_add(8 + size + size % 2)?;
+  }
+  None
+}
+
+// MP3: 最初のフレームのXing/Info/VBRIヘッダにあるフレーム数から求める
+// ヘッダがなければ固定ビットレートとみなしてファイルの大きさから求める
+fn mp3(data: &[u8]) -> Option<u64> {
+  let mut start = 0;
+  if data.starts_with(b"ID3") {
+    let size = data
+      .get(6..10)?
+      .iter()
+      .fold(0usize, |size, b| size << 7 | usize::from(b & 0x7f));
+    let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+    start = 10 + size + footer;
+  }
+  // タグの後にある最初のフレームを探す
+  let at = (start..data.len().saturating_sub(4))
+    .take(64 * 1024)
+    .find(|&at| data[at] == 0xff && data[at + 1] & 0xe0 == 0xe0)?;
+  let header = &data[at..at + 4];
+  let version = (header[1] >> 3) & 3;
+  let layer = (header[1] >> 1) & 3;
+  // レイヤー3だけを扱う
+  if layer != 1 || version == 1 {
+    return None;
+  }
+  let mpeg1 = version == 3;
+  const MPEG1: [u64; 15] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+  ];
+  const MPEG2: [u64; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+  let bitrates = if mpeg1 { &MPEG1 } else { &MPEG2 };
+  let kbps = *bitrates.get(usize::from(header[2] >> 4))?;
+  let sample_rate = *[44100u64, 48000, 32000].get(usize::from((header[2] >> 2) & 3))?
+    / match version {
+      3 => 1,
+      2 => 2,
+      _ => 4,
+    };
+  let samples_per_frame = if mpeg1 { 1152 } else { 576 };
+  let mono = header[3] >> 6 == 3;
+  let side_info = match (mpeg1, mono) {
+    (true, false) => 32,
+    (true, true) | (false, false) => 17,
+    (false, true) => 9,
+  };
+  let xing = at + 4 + side_info;
+  let frames = match data.get(xing..xing + 4) {
+    Some(b"Xing") | Some(b"Info") if u32_be(data, xing + 4)? & 1 != 0 => u32_be(data, xing + 8),
+    _ => match data.get(at + 36..at + 40) {
+      Some(b"VBRI") => u32_be(data, at + 36 + 14),
+      _ => None,
+    },
+  };
+  match frames {
+    Some(frames) => ms(u64::from(frames) * samples_per_frame, sample_rate),
+    None => {
+      let mut end = data.len();
+      if end >= 128 && &data[end - 128..end - 125] == b"TAG" {
+        end -= 128;
+      }
+      ms(((end - at) * 8) as u64, kbps * 1000)
+    }
+  }
+}
+
+// Ogg: 最初のページの識別ヘッダにある標本化周波数と，最後のページの位置（標本数）から求める
+fn ogg(data: &[u8]) -> Option<u64> {
+  if !data.starts_with(b"OggS") {
+    return None;
+  }
+  let packet = 27 + usize::from(*data.get(26)?);
+  let (rate, skip) = if data.get(packet..packet + 7)? == b"\x01vorbis" {
+    (u64::from(u32_le(data, packet + 12)?), 0)
+  } else if data.get(packet..packet + 8)? == b"OpusHead" {
+    // Opusは常に48kHzで，先頭の読み飛ばす標本数を差し引く
+    (48000, u64::from(u16_le(data, packet + 10)?))
+  } else {
+    return None;
+  };
+  let last = (0..data.len().saturating_sub(14))
+    .rev()
+    .find(|&at| &data[at..at + 4] == b"OggS" && u64_le(data, at + 6) != Some(u64::MAX))?;
+  ms(u64_le(data, last + 6)?.saturating_sub(skip), rate)
+}
+
+// FLAC: STREAMINFOの標本化周波数と総標本数から求める
+fn flac(data: &[u8]) -> Option<u64> {
+  if !data.starts_with(b"fLaC") || data.get(4)? & 0x7f != 0 {
+    return None;
+  }
+  let info = u64_be(data, 18)?;
+  ms(info & ((1 << 36) - 1), info >> 44)
+}
+
+// MP4: moovの中のmvhdにある時間の単位と長さから求める
+fn mp4(data: &[u8]) -> Option<u64> {
+  let moov = find_box(data, b"moov")?;
+  let mvhd = find_box(moov, b"mvhd")?;
+  if *mvhd.first()? == 1 {
+    ms(u64_be(mvhd, 24)?, u64::from(u32_be(mvhd, 20)?))
+  } else {
+    ms(u64::from(u32_be(mvhd, 16)?), u64::from(u32_be(mvhd, 12)?))
+  }
+}
+
+// 同じ階層にあるボックスから名前で探し，その中身を返す関数
+fn find_box<'a>(data: &'a [u8], name: &[u8; 4]) -> Option<&'a [u8]> {
+  let mut at = 0;
+  while at + 8 <= data.len() {
+    let (header, size) = match u32_be(data, at)? {
+      0 => (8, data.len() - at),
+      1 => (16, u64_be(data, at + 8)? as usize),
+      size => (8, size as usize),
+    };
+    if size < header {
+      return None;
+    }
+    if &data[at + 4..at + 8] == name {
+      return data.get(at + header..(at + size).min(data.len()));
+    }
+    at = at.checked_add(size)?;
+  }
+  None
+}
+
+// EBMLの可変長の整数を読む関数
+// 長さと，目印のビットを残した値（要素のID）または取り除いた値（大きさ）を返す
+fn vint(data: &[u8], at: usize, keep_marker: bool) -> Option<(usize, u64)> {
+  let first = *data.get(at)?;
+  let len = first.leading_zeros() as usize + 1;
+  if len > 8 {
+    return None;
+  }
+  let value = data
+    .get(at..at + len)?
+    .iter()
+    .fold(0u64, |value, b| value << 8 | u64::from(*b));
+  if keep_marker {
+    Some((len, value))
+  } else {
+    Some((len, value & !(1 << (7 * len))))
+  }
+}
+
+// WebM: SegmentのInfoにあるDurationとTimecodeScaleから求める
+// 録音しながら書き出したファイルにはDurationがないことが多く，その場合はNoneになる
+fn webm(data: &[u8]) -> Option<u64> {
+  const SEGMENT: u64 = 0x1853_8067;
+  const INFO: u64 = 0x1549_a966;
+  const TIMECODE_SCALE: u64 = 0x2a_d7b1;
+  const DURATION: u64 = 0x4489;
+  let (mut at, mut end) = (0, data.len());
+  let mut scale = 1_000_000u64;
+  let mut duration = None;
+  while at < end {
+    let (id_len, id) = vint(data, at, true)?;
+    let (size_len, size) = vint(data, at + id_len, false)?;
+    let body = at + id_len + size_len;
+    // 大きさが未確定の要素はデータの終わりまで続くものとする
+    let unknown = size == (1 << (7 * size_len)) - 1;
+    let size = if unknown {
+      end.saturating_sub(body)
+    } else {
+      size as usize
+    };
+    match id {
+      // SegmentとInfoは中の要素を読む
+      SEGMENT | INFO => {
+        if id == INFO {
+          end = (body + size).min(end);
+        }
+        at = body;
+        continue;
+      }
+      TIMECODE_SCALE => {
+        scale = data
+          .get(body..body + size)?
+          .iter()
+          .fold(0u64, |value, b| value << 8 | u64::from(*b));
+      }
+      DURATION => {
+        let value = data.get(body..body + size)?;
+        duration = Some(match size {
+          4 => f64::from(f32::from_be_bytes(value.try_into().ok()?)),
+          8 => f64::from_be_bytes(value.try_into().ok()?),
+          _ => return None,
+        });
+      }
+      _ => {}
+    }
+    if unknown {
+      break;
+    }
+    at = body.checked_add(size)?;
+  }
+  // Durationの単位はTimecodeScaleナノ秒
+  duration
+    .filter(|duration| duration.is_finite() && *duration >= 0.0)
+    .map(|duration| (duration * scale as f64 / 1_000_000.0) as u64)
+}
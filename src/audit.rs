@@ -0,0 +1,119 @@
+use std::{net::IpAddr, sync::Arc};
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{empty, json, webhook, Tenant};
+
+// 認証の仕組みがないため操作した人はすべてanonymousとして記録する
+pub const ANONYMOUS: &str = "anonymous";
+// 定期的な処理による操作
+pub const SYSTEM: &str = "system";
+
+// 一覧で返す件数の初期値
+const DEFAULT_LIMIT: u32 = 100;
+
+// 記録する操作の内容
+pub struct Entry<'a> {
+  pub actor: &'a str,
+  pub action: &'a str,
+  pub post_id: &'a Uuid,
+  // 何が変わったかの短い説明
+  pub summary: String,
+  pub ip: IpAddr,
+}
+
+// 操作を記録する関数
+// 変更と同じロックの中で呼び出して記録漏れを防ぐ
+// 記録した操作はWebhookのイベントとしても送信待ちにする
+pub fn record(conn: &Connection, entry: Entry) {
+  webhook::enqueue(conn, entry.action, entry.post_id, &entry.summary);
+  conn
+    .execute(
+      "INSERT INTO audit_log(actor, action, post_id, summary, ip) VALUES (?1,?2,?3,?4,?5)",
+      params![
+        entry.actor,
+        entry.action,
+        entry.post_id,
+        entry.summary,
+        entry.ip.to_string()
+      ],
+    )
+    .unwrap();
+}
+
+// 一覧の絞り込み条件
+#[derive(Deserialize)]
+struct Query {
+  post_id: Option<Uuid>,
+  action: Option<String>,
+  limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct Record {
+  id: i64,
+  at: i64,
+  actor: String,
+  action: String,
+  post_id: Uuid,
+  summary: String,
+  ip: String,
+}
+
+// 記録を新しい順に返す関数
+pub async fn list(req: Request<Body>, tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let query = match serde_urlencoded::from_str::<Query>(req.uri().query().unwrap_or_default()) {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let conn = tenant.conn.lock().await;
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, at, actor, action, post_id, summary, ip FROM audit_log
+      WHERE (?1 IS NULL OR post_id = ?1) AND (?2 IS NULL OR action = ?2)
+      ORDER BY id DESC LIMIT ?3",
+    )
+    .unwrap();
+  let records = stmt
+    .query_map(
+      params![
+        query.post_id,
+        query.action,
+        query.limit.unwrap_or(DEFAULT_LIMIT)
+      ],
+      |row| {
+        Ok(Record {
+          id: row.get(0)?,
+          at: row.get(1)?,
+          actor: row.get(2)?,
+          action: row.get(3)?,
+          post_id: row.get(4)?,
+          summary: row.get(5)?,
+          ip: row.get(6)?,
+        })
+      },
+    )
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  Ok(json(&records))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests;
+
+  #[tokio::test]
+  async fn rejects_bad_query() {
+    let state = tests::state();
+    let req = Request::get("/admin/audit?limit=many")
+      .body(Body::empty())
+      .unwrap();
+    let res = list(req, tests::tenant(&state)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+  }
+}
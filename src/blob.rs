@@ -0,0 +1,348 @@
+use std::{env, fs, io, path::PathBuf};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use hyper::{body, header, Body, Method, Request, StatusCode};
+use sha2::{Digest, Sha256};
+
+use crate::{
+  hex,
+  https::{self, HttpsClient},
+  spam::BoxFuture,
+};
+
+// 署名付きURLの有効期間の初期値（秒）
+const DEFAULT_URL_SECONDS: u64 = 5 * 60;
+
+// 添付ファイルの中身を置く場所の共通インターフェース
+// keyは/で区切った名前（例: テナント名/添付ファイルのid）
+pub trait BlobStore: Send + Sync {
+  fn put<'a>(
+    &'a self,
+    key: &'a str,
+    data: Vec<u8>,
+    content_type: &'a str,
+  ) -> BoxFuture<'a, Result<(), String>>;
+
+  // なければNoneを返す
+  fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, String>>;
+
+  // なくても失敗にしない
+  fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), String>>;
+
+  // 保存先から直接ダウンロードさせるURL
+  // 対応しない保存先はNoneを返し，サーバが中身を読んで返す
+  fn presigned_url(&self, _key: &str, _content_type: &str, _disposition: &str) -> Option<String> {
+    None
+  }
+}
+
+// ATTACHMENT_STOREで保存先を選ぶ関数（localかs3，省略時はlocal）
+pub fn from_env() -> Box<dyn BlobStore> {
+  match env::var("ATTACHMENT_STORE").as_deref() {
+    Ok("s3") => Box::new(S3::from_env()),
+    Ok("local") | Err(_) => Box::new(Local::from_env()),
+    Ok(other) => panic!("unknown ATTACHMENT_STORE {}", other),
+  }
+}
+
+// ローカルのディレクトリに置く
+pub struct Local {
+  dir: PathBuf,
+}
+
+impl Local {
+  // ATTACHMENT_DIRで置き場所を指定する（省略時はattachments）
+  fn from_env() -> Local {
+    Local {
+      dir: env::var("ATTACHMENT_DIR")
+        .unwrap_or_else(|_| "attachments".to_string())
+        .into(),
+    }
+  }
+}
+
+impl BlobStore for Local {
+  // 書きかけのファイルを読まれないように，別の名前で書いてから置き換える
+  fn put<'a>(
+    &'a self,
+    key: &'a str,
+    data: Vec<u8>,
+    _content_type: &'a str,
+  ) -> BoxFuture<'a, Result<(), String>> {
+    Box::pin(async move {
+      let path = self.dir.join(key);
+      let partial = path.with_extension("partial");
+      fs::create_dir_all(path.parent().unwrap()).map_err(|e| e.to_string())?;
+      fs::write(&partial, data).map_err(|e| e.to_string())?;
+      fs::rename(&partial, &path).map_err(|e| e.to_string())
+    })
+  }
+
+  fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, String>> {
+    Box::pin(async move {
+      match fs::read(self.dir.join(key)) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.to_string()),
+      }
+    })
+  }
+
+  fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), String>> {
+    Box::pin(async move {
+      match fs::remove_file(self.dir.join(key)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.to_string()),
+        _ => Ok(()),
+      }
+    })
+  }
+}
+
+// S3互換のストレージに置く
+// バケットはパス形式（{endpoint}/{bucket}/{key}）で指定するので，MinIOなどでもそのまま使える
+pub struct S3 {
+  // https://s3.ap-northeast-1.amazonaws.com のような接続先
+  endpoint: String,
+  bucket: String,
+  region: String,
+  access_key: String,
+  secret_key: String,
+  // 署名付きURLの有効期間
+  url_seconds: u64,
+  client: HttpsClient,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn sha256_hex(data: &[u8]) -> String {
+  hex::encode(&Sha256::digest(data))
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+  let mut mac = HmacSha256::new_from_slice(key).unwrap();
+  mac.update(data.as_bytes());
+  mac.finalize().into_bytes().to_vec()
+}
+
+// 署名に使うパーセントエンコーディング
+// パスの/はそのまま残す
+pub fn uri_encode(text: &str, path: bool) -> String {
+  text
+    .bytes()
+    .map(|b| match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+        (b as char).to_string()
+      }
+      b'/' if path => "/".to_string(),
+      _ => format!("%{:02X}", b),
+    })
+    .collect()
+}
+
+impl S3 {
+  // S3_ENDPOINT，S3_BUCKET，S3_REGION（省略時はus-east-1），S3_ACCESS_KEY_ID，S3_SECRET_ACCESS_KEYで指定する
+  // S3_URL_SECONDSで署名付きURLの有効期間を変えられる
+  fn from_env() -> S3 {
+    let required = |name: &str| env::var(name).unwrap_or_else(|_| panic!("{} is required", name));
+    S3 {
+      endpoint: required("S3_ENDPOINT").trim_end_matches('/').to_string(),
+      bucket: required("S3_BUCKET"),
+      region: env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+      access_key: required("S3_ACCESS_KEY_ID"),
+      secret_key: required("S3_SECRET_ACCESS_KEY"),
+      url_seconds: env::var("S3_URL_SECONDS")
+        .map(|seconds| seconds.parse().expect("S3_URL_SECONDS must be a number"))
+        .unwrap_or(DEFAULT_URL_SECONDS),
+      client: https::trusted_client(),
+    }
+  }
+
+  fn host(&self) -> &str {
+    self
+      .endpoint
+      .split_once("://")
+      .map_or(self.endpoint.as_str(), |(_, host)| host)
+  }
+
+  fn path(&self, key: &str) -> String {
+    format!(
+      "/{}/{}",
+      uri_encode(&self.bucket, false),
+      uri_encode(key, true)
+    )
+  }
+
+  fn scope(&self, date: &str) -> String {
+    format!("{}/{}/s3/aws4_request", date, self.region)
+  }
+
+  // 署名バージョン4の署名を求める関数
+  // queryは名前の順に並べてエンコード済みのもの，headersは小文字の名前の順に並べたもの
+  fn signature(
+    &self,
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &[(&str, &str)],
+    payload_hash: &str,
+    amz_date: &str,
+  ) -> String {
+    let canonical_headers = headers
+      .iter()
+      .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+      .collect::<String>();
+    let signed_headers = headers
+      .iter()
+      .map(|(name, _)| *name)
+      .collect::<Vec<_>>()
+      .join(";");
+    let canonical_request = format!(
+      "{}\n{}\n{}\n{}\n{}\n{}",
+      method, path, query, canonical_headers, signed_headers, payload_hash
+    );
+    let date = &amz_date[..8];
+    let string_to_sign = format!(
+      "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+      amz_date,
+      self.scope(date),
+      sha256_hex(canonical_request.as_bytes())
+    );
+    let key = [date, self.region.as_str(), "s3", "aws4_request"]
+      .iter()
+      .fold(
+        format!("AWS4{}", self.secret_key).into_bytes(),
+        |key, part| hmac(&key, part),
+      );
+    hex::encode(&hmac(&key, &string_to_sign))
+  }
+
+  async fn request(
+    &self,
+    method: Method,
+    key: &str,
+    data: Vec<u8>,
+    content_type: Option<&str>,
+  ) -> Result<(StatusCode, Vec<u8>), String> {
+    let path = self.path(key);
+    let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let payload_hash = sha256_hex(&data);
+    let headers = [
+      ("host", self.host()),
+      ("x-amz-content-sha256", payload_hash.as_str()),
+      ("x-amz-date", amz_date.as_str()),
+    ];
+    let signature = self.signature(
+      method.as_str(),
+      &path,
+      "",
+      &headers,
+      &payload_hash,
+      &amz_date,
+    );
+    let mut req = Request::builder()
+      .method(method)
+      .uri(format!("{}{}", self.endpoint, path))
+      .header(header::HOST, self.host())
+      .header("x-amz-content-sha256", &payload_hash)
+      .header("x-amz-date", &amz_date)
+      .header(
+        header::AUTHORIZATION,
+        format!(
+          "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+          self.access_key,
+          self.scope(&amz_date[..8]),
+          signature
+        ),
+      );
+    if let Some(content_type) = content_type {
+      req = req.header(header::CONTENT_TYPE, content_type);
+    }
+    let res = self
+      .client
+      .request(req.body(Body::from(data)).unwrap())
+      .await
+      .map_err(|e| e.to_string())?;
+    let status = res.status();
+    let body = body::to_bytes(res.into_body())
+      .await
+      .map_err(|e| e.to_string())?;
+    Ok((status, body.to_vec()))
+  }
+}
+
+// 失敗した応答をエラーの文字列にする関数
+fn failed(status: StatusCode, body: &[u8]) -> String {
+  format!("{} {}", status, String::from_utf8_lossy(body))
+}
+
+impl BlobStore for S3 {
+  fn put<'a>(
+    &'a self,
+    key: &'a str,
+    data: Vec<u8>,
+    content_type: &'a str,
+  ) -> BoxFuture<'a, Result<(), String>> {
+    Box::pin(async move {
+      match self
+        .request(Method::PUT, key, data, Some(content_type))
+        .await?
+      {
+        (status, _) if status.is_success() => Ok(()),
+        (status, body) => Err(failed(status, &body)),
+      }
+    })
+  }
+
+  fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, String>> {
+    Box::pin(async move {
+      match self.request(Method::GET, key, Vec::new(), None).await? {
+        (status, body) if status.is_success() => Ok(Some(body)),
+        (StatusCode::NOT_FOUND, _) => Ok(None),
+        (status, body) => Err(failed(status, &body)),
+      }
+    })
+  }
+
+  fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), String>> {
+    Box::pin(async move {
+      match self.request(Method::DELETE, key, Vec::new(), None).await? {
+        (status, _) if status.is_success() || status == StatusCode::NOT_FOUND => Ok(()),
+        (status, body) => Err(failed(status, &body)),
+      }
+    })
+  }
+
+  // ダウンロードするときのContent-TypeとContent-Dispositionも署名に含めて指定する
+  fn presigned_url(&self, key: &str, content_type: &str, disposition: &str) -> Option<String> {
+    let path = self.path(key);
+    let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let credential = format!("{}/{}", self.access_key, self.scope(&amz_date[..8]));
+    let mut query = [
+      ("X-Amz-Algorithm", "AWS4-HMAC-SHA256".to_string()),
+      ("X-Amz-Credential", credential),
+      ("X-Amz-Date", amz_date.clone()),
+      ("X-Amz-Expires", self.url_seconds.to_string()),
+      ("X-Amz-SignedHeaders", "host".to_string()),
+      ("response-content-disposition", disposition.to_string()),
+      ("response-content-type", content_type.to_string()),
+    ]
+    .iter()
+    .map(|(name, value)| format!("{}={}", uri_encode(name, false), uri_encode(value, false)))
+    .collect::<Vec<_>>();
+    query.sort();
+    let query = query.join("&");
+    let signature = self.signature(
+      "GET",
+      &path,
+      &query,
+      &[("host", self.host())],
+      "UNSIGNED-PAYLOAD",
+      &amz_date,
+    );
+    Some(format!(
+      "{}{}?{}&X-Amz-Signature={}",
+      self.endpoint, path, query, signature
+    ))
+  }
+}
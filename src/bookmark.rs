@@ -0,0 +1,298 @@
+use std::sync::Arc;
+
+use hyper::{header, Body, Error, Request, Response, StatusCode, Uri};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+  audit, duplicate, empty, events::Event, json, now, remote_ip, tags, unfurl, wants_html,
+  wordcount, State, Tenant,
+};
+
+// 本文を取り出すために読み込むページの大きさ
+const MAX_PAGE_BYTES: usize = 2 * 1024 * 1024;
+// 投稿に残す本文の抜粋の長さ（文字数）
+const MAX_EXTRACT_CHARS: usize = 20_000;
+
+// 中身を読まずに飛ばす要素（メニューや広告などの本文以外の部分）
+const SKIPPED: &[&str] = &[
+  "script", "style", "noscript", "template", "svg", "nav", "header", "footer", "aside", "form",
+];
+// 前後で段落を区切る要素
+const BLOCKS: &[&str] = &[
+  "p",
+  "div",
+  "br",
+  "li",
+  "h1",
+  "h2",
+  "h3",
+  "h4",
+  "h5",
+  "h6",
+  "section",
+  "article",
+  "blockquote",
+  "pre",
+  "tr",
+  "dt",
+  "dd",
+  "figcaption",
+];
+
+#[derive(Deserialize)]
+struct NewBookmark {
+  url: String,
+}
+
+#[derive(Serialize)]
+struct Bookmark {
+  id: Uuid,
+  url: String,
+  title: String,
+  captured_at: u64,
+}
+
+// 要素の名前を返す関数（<p class="x">ならp，</p>なら/p）
+fn tag_name(tag: &str) -> String {
+  tag
+    .chars()
+    .take_while(|c| c.is_ascii_alphanumeric() || *c == '/')
+    .collect::<String>()
+    .to_ascii_lowercase()
+}
+
+// ページから読むべき本文をテキストで取り出す関数
+// <article>，<main>，<body>の順に見つかったものの中から，本文以外の要素を除いて段落ごとに並べる
+fn readable(html: &str) -> String {
+  let lower = html.to_ascii_lowercase();
+  let (start, end) = ["article", "main", "body"]
+    .iter()
+    .find_map(|name| {
+      let open = lower.find(&format!("<{}", name))?;
+      let start = open + lower[open..].find('>')? + 1;
+      let end = lower[start..]
+        .find(&format!("</{}", name))
+        .map_or(lower.len(), |i| start + i);
+      Some((start, end))
+    })
+    .unwrap_or((0, lower.len()));
+  let mut text = String::new();
+  let mut at = start;
+  while at < end {
+    let open = match lower[at..end].find('<') {
+      Some(i) => at + i,
+      None => {
+        text.push_str(&html[at..end]);
+        break;
+      }
+    };
+    text.push_str(&html[at..open]);
+    // コメントは閉じるまで飛ばす
+    if lower[open..].starts_with("<!--") {
+      at = lower[open..].find("-->").map_or(end, |i| open + i + 3);
+      continue;
+    }
+    let close = match lower[open..].find('>') {
+      Some(i) => open + i,
+      None => break,
+    };
+    let name = tag_name(&lower[open + 1..close]);
+    at = close + 1;
+    if SKIPPED.contains(&name.as_str()) {
+      at = lower[at..]
+        .find(&format!("</{}", name))
+        .map_or(end, |i| at + i);
+    } else if BLOCKS.contains(&name.trim_start_matches('/')) {
+      text.push('\n');
+    }
+  }
+  unfurl::unescape(&text)
+    .lines()
+    .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+    .filter(|line| !line.is_empty())
+    .collect::<Vec<_>>()
+    .join("\n\n")
+    .chars()
+    .take(MAX_EXTRACT_CHARS)
+    .collect()
+}
+
+// 投稿のタイトルにするページのタイトル（見つからなければURL）
+fn title(page: &unfurl::Page, url: &str) -> String {
+  unfurl::parse(&page.html, &page.url).map_or(url.to_string(), |page| page.title)
+}
+
+// URLだけを受け取ってページを保存する関数（あとで読むための取り込み）
+// ページのタイトルと本文の抜粋を投稿にして，ブックマークとして記録する
+// 同じURLを取り込み済みであれば，既存の投稿のidを返して断る
+pub async fn create(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+) -> Result<Response<Body>, Error> {
+  let ip = remote_ip(&req);
+  let html = wants_html(&req);
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let form = match serde_urlencoded::from_bytes::<NewBookmark>(&body) {
+    Ok(form) if form.url.parse::<Uri>().is_ok() => form,
+    _ => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let existing: Option<Uuid> = tenant
+    .conn
+    .lock()
+    .await
+    .query_row(
+      "SELECT post_id FROM bookmarks JOIN posts ON posts.id = bookmarks.post_id
+      WHERE url=?1 AND trashed_at IS NULL",
+      params![form.url],
+      |row| row.get(0),
+    )
+    .optional()
+    .unwrap();
+  if let Some(existing) = existing {
+    return Ok(
+      Response::builder()
+        .status(StatusCode::CONFLICT)
+        .body(existing.to_string().into())
+        .unwrap(),
+    );
+  }
+  // 公開アドレスのページだけを取得する
+  let page = match state.unfurl.page(&form.url, MAX_PAGE_BYTES).await {
+    Some(page) => page,
+    None => {
+      return Ok(
+        Response::builder()
+          .status(StatusCode::BAD_GATEWAY)
+          .body("could not fetch the page".into())
+          .unwrap(),
+      )
+    }
+  };
+  let title = title(&page, &form.url);
+  let content = format!("{}\n\n{}", form.url, readable(&page.html));
+  let mut warnings = Vec::new();
+  let (title, content) = match state.limits.apply(&title, &content, &mut warnings) {
+    Ok(fitted) => fitted,
+    Err(reason) => {
+      return Ok(
+        Response::builder()
+          .status(StatusCode::UNPROCESSABLE_ENTITY)
+          .body(reason.into())
+          .unwrap(),
+      )
+    }
+  };
+  let id = Uuid::new_v4();
+  let stored = state.codec.encode(content);
+  let mut conn = tenant.conn.lock().await;
+  let tx = conn.transaction().unwrap();
+  tx.execute(
+    "INSERT INTO posts(id, title, content, encrypted, compressed, content_hash, created_at)
+    VALUES (?1,?2,?3,?4,?5,?6,?7)",
+    params![
+      id,
+      title,
+      stored.content,
+      stored.encrypted,
+      stored.compressed,
+      duplicate::hash(&state.signer, content),
+      now()
+    ],
+  )
+  .unwrap();
+  wordcount::record(&tx, &id, content);
+  tags::apply(&tx, &id, content);
+  tx.execute(
+    "INSERT INTO bookmarks(post_id, url, captured_at) VALUES (?1,?2,?3)",
+    params![id, form.url, now()],
+  )
+  .unwrap();
+  audit::record(
+    &tx,
+    audit::Entry {
+      actor: audit::ANONYMOUS,
+      action: "create",
+      post_id: &id,
+      summary: format!(
+        "bookmark url={}, {} chars",
+        form.url,
+        content.chars().count()
+      ),
+      ip,
+    },
+  );
+  tx.commit().unwrap();
+  drop(conn);
+  tenant.publish(Event::Created { id });
+  let mut res = Response::builder();
+  for warning in &warnings {
+    res = res.header(header::WARNING, format!("299 - {:?}", warning));
+  }
+  if html {
+    return Ok(
+      res
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, format!("{}/posts/{}", tenant.prefix, id))
+        .body(Body::empty())
+        .unwrap(),
+    );
+  }
+  Ok(res.body(id.to_string().into()).unwrap())
+}
+
+// 取り込んだブックマークを新しい順に一覧する関数
+pub async fn list(tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let conn = tenant.conn.lock().await;
+  let mut stmt = conn
+    .prepare(
+      "SELECT post_id, url, title, captured_at
+      FROM bookmarks JOIN posts ON posts.id = bookmarks.post_id
+      WHERE trashed_at IS NULL ORDER BY captured_at DESC",
+    )
+    .unwrap();
+  let bookmarks = stmt
+    .query_map([], |row| {
+      Ok(Bookmark {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        title: row.get(2)?,
+        captured_at: row.get(3)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  Ok(json(&bookmarks))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn page(html: &str) -> unfurl::Page {
+    unfurl::Page {
+      url: "https://example.com/".parse().unwrap(),
+      html: html.to_string(),
+    }
+  }
+
+  #[test]
+  fn title_survives_head_end_in_comment_or_attribute() {
+    let url = "https://example.com/";
+    let commented =
+      page("<head><title>Title</title><!-- </head> --></head><body><p>Body</p></body>");
+    assert_eq!(title(&commented, url), "Title");
+    assert_eq!(readable(&commented.html), "Body");
+    let attribute = page(r#"<head><meta content="</head>"><body><p>Body</p></body>"#);
+    assert_eq!(title(&attribute, url), url);
+  }
+
+  #[test]
+  fn readable_skips_navigation() {
+    let html = "<body><nav>Menu</nav><article><h1>Head</h1><p>One &amp; two</p></article></body>";
+    assert_eq!(readable(html), "Head\n\nOne & two");
+  }
+}
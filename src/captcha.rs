@@ -0,0 +1,133 @@
+use std::{collections::HashMap, env, net::IpAddr, sync::Arc};
+
+use hyper::{Body, Method, Request};
+use serde::Deserialize;
+use tera::{Function, Tera, Value};
+
+use crate::https::{self, HttpsClient};
+
+// 対応しているCAPTCHAサービス
+#[derive(Clone, Copy)]
+pub enum Provider {
+  HCaptcha,
+  Turnstile,
+}
+
+impl Provider {
+  fn script(self) -> &'static str {
+    match self {
+      Provider::HCaptcha => "https://js.hcaptcha.com/1/api.js",
+      Provider::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/api.js",
+    }
+  }
+
+  // ウィジェットを埋め込む要素のクラス名
+  fn class(self) -> &'static str {
+    match self {
+      Provider::HCaptcha => "h-captcha",
+      Provider::Turnstile => "cf-turnstile",
+    }
+  }
+
+  fn verify_url(self) -> &'static str {
+    match self {
+      Provider::HCaptcha => "https://api.hcaptcha.com/siteverify",
+      Provider::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+    }
+  }
+}
+
+// siteverifyのレスポンスのうち必要な部分
+#[derive(Deserialize)]
+struct SiteVerify {
+  success: bool,
+}
+
+pub struct Captcha {
+  provider: Provider,
+  site_key: String,
+  secret: String,
+  client: HttpsClient,
+}
+
+impl Captcha {
+  // 環境変数が設定されている場合のみCAPTCHAを有効にする関数
+  pub fn from_env() -> Option<Captcha> {
+    let provider = match env::var("CAPTCHA_PROVIDER").ok()?.as_str() {
+      "hcaptcha" => Provider::HCaptcha,
+      "turnstile" => Provider::Turnstile,
+      other => panic!("unknown CAPTCHA_PROVIDER {}", other),
+    };
+    Some(Captcha {
+      provider,
+      site_key: env::var("CAPTCHA_SITE_KEY").unwrap(),
+      secret: env::var("CAPTCHA_SECRET").unwrap(),
+      client: https::client(),
+    })
+  }
+
+  // フォームに埋め込むウィジェットのHTML
+  fn widget(&self) -> String {
+    format!(
+      r#"<script src="{}" async defer></script><div class="{}" data-sitekey="{}"></div>"#,
+      self.provider.script(),
+      self.provider.class(),
+      self.site_key
+    )
+  }
+
+  // フォームから送られたトークンをサービスに問い合わせて検証する関数
+  pub async fn verify(&self, token: &str, ip: IpAddr) -> bool {
+    if token.is_empty() {
+      return false;
+    }
+    let ip = ip.to_string();
+    let form = serde_urlencoded::to_string([
+      ("secret", self.secret.as_str()),
+      ("response", token),
+      ("remoteip", ip.as_str()),
+    ])
+    .unwrap();
+    let req = Request::builder()
+      .method(Method::POST)
+      .uri(self.provider.verify_url())
+      .header("content-type", "application/x-www-form-urlencoded")
+      .body(Body::from(form))
+      .unwrap();
+    // 問い合わせに失敗した場合は投稿を受け付けない
+    let res = match self.client.request(req).await {
+      Ok(res) => res,
+      Err(e) => {
+        eprintln!("captcha error {}", e);
+        return false;
+      }
+    };
+    match hyper::body::to_bytes(res.into_body()).await {
+      Ok(body) => serde_json::from_slice::<SiteVerify>(&body)
+        .map(|v| v.success)
+        .unwrap_or(false),
+      Err(_) => false,
+    }
+  }
+}
+
+// テンプレートから{{ captcha_widget() }}で呼び出すヘルパー
+// CAPTCHAが無効な場合は何も出力しない
+struct Widget(Option<Arc<Captcha>>);
+
+impl Function for Widget {
+  fn call(&self, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    Ok(Value::String(
+      self.0.as_ref().map(|c| c.widget()).unwrap_or_default(),
+    ))
+  }
+
+  fn is_safe(&self) -> bool {
+    true
+  }
+}
+
+// テンプレートヘルパーを登録する関数
+pub fn register(tera: &mut Tera, captcha: Option<Arc<Captcha>>) {
+  tera.register_function("captcha_widget", Widget(captcha));
+}
@@ -0,0 +1,404 @@
+use std::sync::Arc;
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+  audit, conflict, duplicate, empty, events::Event, geo, json, now, remote_ip, tags, wordcount,
+  State, Tenant, Visibility,
+};
+
+// 1回で返す変更の数の初期値と上限
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+// 1回で送れる変更の数
+const MAX_BATCH: usize = 100;
+
+#[derive(Deserialize)]
+struct Query {
+  // 前回受け取った最後の通し番号（初回は省略して最初から受け取る）
+  #[serde(default)]
+  since: i64,
+  limit: Option<u32>,
+}
+
+// 変更した時点の投稿の内容
+#[derive(Serialize)]
+struct PostState {
+  title: String,
+  // E2EEの投稿は暗号文なのでGET /posts/{id}で取り出す
+  content: Option<String>,
+  kind: String,
+  visibility: String,
+  notebook: Option<String>,
+  trashed_at: Option<u64>,
+  created_at: Option<u64>,
+  lat: Option<f64>,
+  lon: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct Change {
+  seq: i64,
+  id: Uuid,
+  // 完全に削除された（非公開の投稿は見えないので削除として扱う）
+  deleted: bool,
+  post: Option<PostState>,
+}
+
+#[derive(Serialize)]
+struct Feed {
+  changes: Vec<Change>,
+  // 次にsinceに渡す番号
+  next: i64,
+  // まだ続きがあるかどうか
+  more: bool,
+}
+
+// クライアントがオフラインの間に行った変更
+#[derive(Deserialize)]
+struct Upsert {
+  id: Uuid,
+  // 変更の元にした版の通し番号（新しく作った投稿は省略する）
+  base_seq: Option<i64>,
+  #[serde(default)]
+  title: String,
+  #[serde(default)]
+  content: String,
+  // trueの場合はゴミ箱に入れる
+  #[serde(default)]
+  deleted: bool,
+  // 投稿した場所（省略した場合は場所をなくす）
+  lat: Option<f64>,
+  lon: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct Batch {
+  changes: Vec<Upsert>,
+}
+
+#[derive(Serialize)]
+struct Applied {
+  id: Uuid,
+  seq: i64,
+}
+
+#[derive(Serialize)]
+struct Conflict {
+  id: Uuid,
+  reason: String,
+  // サーバにある版（版が食い違った場合のみ）
+  #[serde(skip_serializing_if = "Option::is_none")]
+  server: Option<Change>,
+  // 反映できなかった変更を保存した投稿
+  #[serde(skip_serializing_if = "Option::is_none")]
+  copy: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+struct BatchResult {
+  applied: Vec<Applied>,
+  conflicts: Vec<Conflict>,
+}
+
+// 変更の一覧を取り出すSELECT（WHERE以降を呼び出し側で付ける）
+const SELECT: &str = "SELECT changes.seq, changes.post_id, changes.deleted, posts.title,
+    posts.content, posts.encrypted, posts.compressed, posts.kind, posts.visibility,
+    notebooks.name, posts.trashed_at, posts.created_at, posts.lat, posts.lon
+  FROM changes
+  LEFT JOIN posts ON posts.id = changes.post_id
+  LEFT JOIN notebooks ON notebooks.id = posts.notebook_id";
+
+// SELECTの順に並んだ行から変更を作る関数
+fn from_row(row: &Row, state: &State) -> rusqlite::Result<Change> {
+  let kind: Option<String> = row.get(7)?;
+  let visibility: Option<String> = row.get(8)?;
+  let visible = kind.is_some() && visibility.as_deref() != Some(Visibility::Private.as_str());
+  let post = match (kind, visibility) {
+    (Some(kind), Some(visibility)) if visible => Some(PostState {
+      title: row.get(3)?,
+      content: if kind == "text" {
+        Some(state.codec.decode(row.get(4)?, row.get(5)?, row.get(6)?))
+      } else {
+        None
+      },
+      kind,
+      visibility,
+      notebook: row.get(9)?,
+      trashed_at: row.get(10)?,
+      created_at: row.get(11)?,
+      lat: row.get(12)?,
+      lon: row.get(13)?,
+    }),
+    _ => None,
+  };
+  Ok(Change {
+    seq: row.get(0)?,
+    id: row.get(1)?,
+    deleted: post.is_none(),
+    post,
+  })
+}
+
+// 投稿の最後の変更を返す関数
+fn latest(conn: &Connection, state: &State, id: &Uuid) -> Option<Change> {
+  conn
+    .query_row(
+      &format!("{} WHERE changes.post_id=?1", SELECT),
+      params![id],
+      |row| from_row(row, state),
+    )
+    .optional()
+    .unwrap()
+}
+
+fn exists(conn: &Connection, id: &Uuid) -> bool {
+  conn
+    .query_row("SELECT 1 FROM posts WHERE id=?1", params![id], |_| Ok(()))
+    .optional()
+    .unwrap()
+    .is_some()
+}
+
+// 指定した通し番号より後の変更を古い順に返す関数
+// 投稿ごとに最後の変更だけが残るので，途中の版は飛ばして最新の内容を受け取る
+pub async fn feed(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+) -> Result<Response<Body>, Error> {
+  let query = match serde_urlencoded::from_str::<Query>(req.uri().query().unwrap_or_default()) {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+  let conn = tenant.conn.lock().await;
+  let mut stmt = conn
+    .prepare(&format!(
+      "{} WHERE changes.seq > ?1 ORDER BY changes.seq LIMIT ?2",
+      SELECT
+    ))
+    .unwrap();
+  // 続きがあるかを知るために1件多く取り出す
+  let mut changes = stmt
+    .query_map(params![query.since, limit + 1], |row| from_row(row, &state))
+    .unwrap()
+    .map(Result::unwrap)
+    .collect::<Vec<_>>();
+  let more = changes.len() > limit as usize;
+  changes.truncate(limit as usize);
+  let next = changes.last().map_or(query.since, |change| change.seq);
+  Ok(json(&Feed {
+    changes,
+    next,
+    more,
+  }))
+}
+
+// オフラインの間の変更をまとめて反映する関数
+// base_seqがサーバの最後の変更と一致するものだけを反映し，食い違うものはサーバの版を付けて返す
+// 食い違った変更は競合した複製として保存する
+// 一部が食い違っても他の変更は反映する
+pub async fn upsert(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+) -> Result<Response<Body>, Error> {
+  let ip = remote_ip(&req);
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let batch = match serde_json::from_slice::<Batch>(&body) {
+    Ok(batch) => batch,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  if batch.changes.len() > MAX_BATCH {
+    return Ok(
+      Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .body(format!("limit is {} changes", MAX_BATCH).into())
+        .unwrap(),
+    );
+  }
+  let mut result = BatchResult {
+    applied: Vec::new(),
+    conflicts: Vec::new(),
+  };
+  let mut conn = tenant.conn.lock().await;
+  let tx = conn.transaction().unwrap();
+  // コミットした後に知らせる出来事
+  let mut events = Vec::new();
+  for change in batch.changes {
+    let current = latest(&tx, &state, &change.id);
+    if current.as_ref().map(|current| current.seq) != change.base_seq {
+      // 手元の変更を捨てないように元の投稿の複製として残す
+      let copy = match &current {
+        Some(Change { post: Some(_), .. }) if !change.deleted => state
+          .limits
+          .apply(&change.title, &change.content, &mut Vec::new())
+          .ok()
+          .and_then(|(title, content)| conflict::copy(&tx, &state, &change.id, title, content, ip)),
+        _ => None,
+      };
+      if let Some(copy) = copy {
+        events.push(Event::Created { id: copy });
+      }
+      result.conflicts.push(Conflict {
+        id: change.id,
+        reason: "changed".to_string(),
+        server: current,
+        copy,
+      });
+      continue;
+    }
+    let post = current.and_then(|current| current.post);
+    // E2EEの投稿はサーバで暗号文を作れないので扱わない
+    if post.as_ref().is_some_and(|post| post.kind != "text") {
+      result.conflicts.push(Conflict {
+        id: change.id,
+        reason: "e2ee".to_string(),
+        server: None,
+        copy: None,
+      });
+      continue;
+    }
+    // 非公開の投稿は削除されたように見えるが，同じidでは作り直せない
+    if post.is_none() && exists(&tx, &change.id) {
+      result.conflicts.push(Conflict {
+        id: change.id,
+        reason: "private".to_string(),
+        server: None,
+        copy: None,
+      });
+      continue;
+    }
+    if change.deleted {
+      // 手元にない投稿を消した場合は何もしない
+      if post.is_none() {
+        continue;
+      }
+      tx.execute(
+        "UPDATE posts SET trashed_at=?1 WHERE id=?2",
+        params![now(), change.id],
+      )
+      .unwrap();
+      events.push(Event::Trashed { id: change.id });
+      audit::record(
+        &tx,
+        audit::Entry {
+          actor: audit::ANONYMOUS,
+          action: "trash",
+          post_id: &change.id,
+          summary: "offline sync".to_string(),
+          ip,
+        },
+      );
+    } else {
+      let (title, content) =
+        match state
+          .limits
+          .apply(&change.title, &change.content, &mut Vec::new())
+        {
+          Ok(fitted) => fitted,
+          Err(reason) => {
+            result.conflicts.push(Conflict {
+              id: change.id,
+              reason,
+              server: None,
+              copy: None,
+            });
+            continue;
+          }
+        };
+      let location = match geo::Location::new(change.lat, change.lon) {
+        Ok(location) => location,
+        Err(reason) => {
+          result.conflicts.push(Conflict {
+            id: change.id,
+            reason,
+            server: None,
+            copy: None,
+          });
+          continue;
+        }
+      };
+      let (lat, lon) = (
+        location.map(|location| location.lat),
+        location.map(|location| location.lon),
+      );
+      let stored = state.codec.encode(content);
+      let content_hash = duplicate::hash(&state.signer, content);
+      let action = if post.is_some() {
+        tx.execute(
+          "UPDATE posts SET title=?1, content=?2, encrypted=?3, compressed=?4, content_hash=?5,
+            lat=?6, lon=?7
+          WHERE id=?8",
+          params![
+            title,
+            stored.content,
+            stored.encrypted,
+            stored.compressed,
+            content_hash,
+            lat,
+            lon,
+            change.id
+          ],
+        )
+        .unwrap();
+        events.push(Event::Updated { id: change.id });
+        "update"
+      } else {
+        tx.execute(
+          "INSERT INTO posts(id, title, content, encrypted, compressed, content_hash, created_at,
+            lat, lon)
+          VALUES (?1,?2,?3,?4,?5,?6,?7,?8,?9)",
+          params![
+            change.id,
+            title,
+            stored.content,
+            stored.encrypted,
+            stored.compressed,
+            content_hash,
+            now(),
+            lat,
+            lon
+          ],
+        )
+        .unwrap();
+        events.push(Event::Created { id: change.id });
+        "create"
+      };
+      wordcount::record(&tx, &change.id, content);
+      tags::apply(&tx, &change.id, content);
+      audit::record(
+        &tx,
+        audit::Entry {
+          actor: audit::ANONYMOUS,
+          action,
+          post_id: &change.id,
+          summary: format!(
+            "offline sync, title={:?}, {} chars",
+            title,
+            content.chars().count()
+          ),
+          ip,
+        },
+      );
+    }
+    let seq = tx
+      .query_row(
+        "SELECT seq FROM changes WHERE post_id=?1",
+        params![change.id],
+        |row| row.get(0),
+      )
+      .unwrap();
+    result.applied.push(Applied { id: change.id, seq });
+  }
+  tx.commit().unwrap();
+  drop(conn);
+  for event in events {
+    tenant.publish(event);
+  }
+  Ok(json(&result))
+}
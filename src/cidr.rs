@@ -0,0 +1,119 @@
+use std::net::IpAddr;
+
+// 10.0.0.0/8 や fd00::/8 のようなアドレスの範囲
+#[derive(Clone, Copy)]
+pub struct Cidr {
+  addr: IpAddr,
+  prefix: u32,
+}
+
+// IPv4射影アドレス（::ffff:10.0.0.1）はIPv4として扱う
+fn canonical(ip: IpAddr) -> IpAddr {
+  match ip {
+    IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+    v4 => v4,
+  }
+}
+
+impl Cidr {
+  // /がない場合は1つのアドレスだけの範囲にする
+  pub fn parse(s: &str) -> Option<Cidr> {
+    let (addr, prefix): (IpAddr, _) = match s.split_once('/') {
+      Some((addr, prefix)) => (addr.parse().ok()?, Some(prefix.parse().ok()?)),
+      None => (s.parse().ok()?, None),
+    };
+    let bits: u32 = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(bits);
+    if prefix > bits {
+      return None;
+    }
+    // IPv4射影アドレスの範囲は先頭の96ビットを除いたIPv4の範囲にする
+    let canonical = canonical(addr);
+    let prefix = if canonical != addr {
+      prefix.checked_sub(96)?
+    } else {
+      prefix
+    };
+    Some(Cidr {
+      addr: canonical,
+      prefix,
+    })
+  }
+
+  pub fn contains(&self, ip: IpAddr) -> bool {
+    let (net, ip, bits) = match (self.addr, canonical(ip)) {
+      (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net).into(), u32::from(ip).into(), 32),
+      (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+      _ => return false,
+    };
+    // 上位prefixビットだけを比べる（/0はすべてに一致する）
+    self.prefix == 0 || (net ^ ip) >> (bits - self.prefix) == 0
+  }
+}
+
+// カンマ区切りの範囲の一覧を環境変数から読み込む関数
+pub fn list_from_env(name: &str) -> Vec<Cidr> {
+  std::env::var(name)
+    .unwrap_or_default()
+    .split(',')
+    .map(str::trim)
+    .filter(|s| !s.is_empty())
+    .map(|s| Cidr::parse(s).unwrap_or_else(|| panic!("invalid CIDR {} in {}", s, name)))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn contains(range: &str, ip: &str) -> bool {
+    Cidr::parse(range).unwrap().contains(ip.parse().unwrap())
+  }
+
+  #[test]
+  fn matches_ipv4_prefixes() {
+    assert!(contains("10.0.0.0/8", "10.255.255.255"));
+    assert!(!contains("10.0.0.0/8", "11.0.0.0"));
+    assert!(contains("192.0.2.1", "192.0.2.1"));
+    assert!(!contains("192.0.2.1/32", "192.0.2.2"));
+    assert!(contains("0.0.0.0/0", "255.255.255.255"));
+    assert!(!contains("0.0.0.0/0", "::1"));
+    assert!(Cidr::parse("10.0.0.0/33").is_none());
+    assert!(Cidr::parse("10.0.0.0/").is_none());
+    assert!(Cidr::parse("example.com").is_none());
+  }
+
+  #[test]
+  fn matches_ipv6_prefixes() {
+    assert!(contains("::/0", "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"));
+    assert!(!contains("::/0", "10.0.0.1"));
+    assert!(contains("::1/128", "::1"));
+    assert!(!contains("::1/128", "::2"));
+    assert!(contains(
+      "2001:db8:0:1::/64",
+      "2001:db8:0:1:ffff:ffff:ffff:ffff"
+    ));
+    assert!(!contains("2001:db8:0:1::/64", "2001:db8:0:2::"));
+    assert!(!contains(
+      "2001:db8:0:1::/64",
+      "2001:db8:0:0:ffff:ffff:ffff:ffff"
+    ));
+    assert!(contains("fd00::/8", "fdff::1"));
+    assert!(!contains("fd00::/8", "fe00::1"));
+    assert!(contains("2001:db8::/127", "2001:db8::1"));
+    assert!(!contains("2001:db8::/127", "2001:db8::2"));
+    assert!(Cidr::parse("::/129").is_none());
+  }
+
+  #[test]
+  fn treats_mapped_addresses_as_ipv4() {
+    assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+    assert!(!contains("10.0.0.0/8", "::ffff:11.1.2.3"));
+    assert!(contains("::ffff:10.0.0.0/104", "10.1.2.3"));
+    assert!(!contains("::ffff:10.0.0.0/104", "11.0.0.0"));
+    assert!(contains("::ffff:192.0.2.1", "192.0.2.1"));
+    assert!(contains("::ffff:0.0.0.0/96", "203.0.113.1"));
+    // 射影アドレスの範囲より広いものはIPv4として表せない
+    assert!(Cidr::parse("::ffff:0.0.0.0/95").is_none());
+  }
+}
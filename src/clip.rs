@@ -0,0 +1,293 @@
+use std::{env, sync::Arc};
+
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+  audit, duplicate, empty, events::Event, header_str, hex, json, now, remote_ip, tags, wordcount,
+  State, Tenant,
+};
+
+// 許可するオリジンを指定しない場合に受け付けるブラウザ拡張のスキーム
+const EXTENSION_SCHEMES: &[&str] = &[
+  "chrome-extension://",
+  "moz-extension://",
+  "safari-web-extension://",
+];
+// プリフライトの結果をブラウザが覚えておく期間
+const PREFLIGHT_MAX_AGE_SECONDS: u64 = 24 * 60 * 60;
+
+// ブラウザ拡張からの取り込みを受け付けるオリジン
+pub struct Clip {
+  origins: Option<Vec<String>>,
+}
+
+impl Clip {
+  // CLIP_ORIGINS=chrome-extension://abcdef,moz-extension://1234 のように拡張のオリジンを並べる
+  // 指定しない場合はどの拡張からも受け付ける（トークンがなければ取り込めない）
+  pub fn from_env() -> Clip {
+    let origins = env::var("CLIP_ORIGINS").ok().map(|origins| {
+      origins
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(str::to_string)
+        .collect()
+    });
+    Clip { origins }
+  }
+
+  fn allows(&self, origin: &str) -> bool {
+    match &self.origins {
+      Some(origins) => origins.iter().any(|allowed| allowed == origin),
+      None => EXTENSION_SCHEMES
+        .iter()
+        .any(|scheme| origin.starts_with(scheme)),
+    }
+  }
+
+  // 許可したオリジンからのリクエストにCORSのヘッダを付ける関数
+  fn cors(&self, origin: &str, res: &mut Response<Body>) {
+    let headers = res.headers_mut();
+    headers.insert(header::VARY, "Origin".parse().unwrap());
+    if !self.allows(origin) {
+      return;
+    }
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.parse().unwrap());
+  }
+}
+
+#[derive(Deserialize)]
+struct Clipping {
+  #[serde(default)]
+  title: String,
+  url: String,
+  // ページで選択していた部分（省略した場合はURLだけを保存する）
+  #[serde(default)]
+  selection: String,
+}
+
+#[derive(Deserialize)]
+struct NewToken {
+  name: String,
+}
+
+#[derive(Serialize)]
+struct Issued {
+  id: i64,
+  // 発行したときにだけ返す（DBにはハッシュしか残さない）
+  token: String,
+}
+
+#[derive(Serialize)]
+struct Token {
+  id: i64,
+  name: String,
+  created_at: u64,
+  last_used_at: Option<u64>,
+}
+
+fn digest(token: &str) -> String {
+  hex::encode(&Sha256::digest(token.as_bytes()))
+}
+
+// Authorization: Bearer のトークンが有効であればそのidを返す関数
+// 取り込みのためだけのトークンなので，/admin以下などには使えない
+fn authenticate(conn: &Connection, req: &Request<Body>) -> Option<i64> {
+  let token = header_str(req, header::AUTHORIZATION);
+  let token = token.strip_prefix("Bearer ")?.trim();
+  conn
+    .query_row(
+      "SELECT id FROM clip_tokens WHERE token_hash=?1 AND revoked_at IS NULL",
+      params![digest(token)],
+      |row| row.get(0),
+    )
+    .optional()
+    .unwrap()
+}
+
+// ブラウザ拡張のプリフライトに答える関数
+pub async fn preflight(req: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Error> {
+  let mut res = empty(StatusCode::NO_CONTENT);
+  state.clip.cors(&header_str(&req, header::ORIGIN), &mut res);
+  if res
+    .headers()
+    .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+  {
+    let headers = res.headers_mut();
+    headers.insert(
+      header::ACCESS_CONTROL_ALLOW_METHODS,
+      "POST".parse().unwrap(),
+    );
+    headers.insert(
+      header::ACCESS_CONTROL_ALLOW_HEADERS,
+      "authorization, content-type".parse().unwrap(),
+    );
+    headers.insert(
+      header::ACCESS_CONTROL_MAX_AGE,
+      PREFLIGHT_MAX_AGE_SECONDS.into(),
+    );
+  }
+  Ok(res)
+}
+
+// ブラウザ拡張から送られたページの一部を投稿にする関数
+// 本文は選択していた部分とURLで，タイトルを省略した場合はURLをタイトルにする
+pub async fn create(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+) -> Result<Response<Body>, Error> {
+  // 失敗した場合も拡張が理由を読めるようにCORSのヘッダを付ける
+  let origin = header_str(&req, header::ORIGIN);
+  let mut res = clip(req, &state, &tenant).await?;
+  state.clip.cors(&origin, &mut res);
+  Ok(res)
+}
+
+async fn clip(req: Request<Body>, state: &State, tenant: &Tenant) -> Result<Response<Body>, Error> {
+  let token = match authenticate(&*tenant.conn.lock().await, &req) {
+    Some(token) => token,
+    None => return Ok(empty(StatusCode::UNAUTHORIZED)),
+  };
+  let ip = remote_ip(&req);
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let clipping = match serde_json::from_slice::<Clipping>(&body) {
+    Ok(clipping) if !clipping.url.is_empty() => clipping,
+    _ => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let title = if clipping.title.trim().is_empty() {
+    clipping.url.clone()
+  } else {
+    clipping.title
+  };
+  let content = if clipping.selection.trim().is_empty() {
+    clipping.url.clone()
+  } else {
+    format!("{}\n\n{}", clipping.selection.trim(), clipping.url)
+  };
+  let mut warnings = Vec::new();
+  let (title, content) = match state.limits.apply(&title, &content, &mut warnings) {
+    Ok(fitted) => fitted,
+    Err(reason) => {
+      return Ok(
+        Response::builder()
+          .status(StatusCode::UNPROCESSABLE_ENTITY)
+          .body(reason.into())
+          .unwrap(),
+      )
+    }
+  };
+  let id = Uuid::new_v4();
+  let stored = state.codec.encode(content);
+  let mut conn = tenant.conn.lock().await;
+  let tx = conn.transaction().unwrap();
+  tx.execute(
+    "INSERT INTO posts(id, title, content, encrypted, compressed, content_hash, created_at)
+    VALUES (?1,?2,?3,?4,?5,?6,?7)",
+    params![
+      id,
+      title,
+      stored.content,
+      stored.encrypted,
+      stored.compressed,
+      duplicate::hash(&state.signer, content),
+      now()
+    ],
+  )
+  .unwrap();
+  wordcount::record(&tx, &id, content);
+  tags::apply(&tx, &id, content);
+  tx.execute(
+    "UPDATE clip_tokens SET last_used_at=?1 WHERE id=?2",
+    params![now(), token],
+  )
+  .unwrap();
+  audit::record(
+    &tx,
+    audit::Entry {
+      actor: audit::ANONYMOUS,
+      action: "create",
+      post_id: &id,
+      summary: format!("clip token={}, url={}", token, clipping.url),
+      ip,
+    },
+  );
+  tx.commit().unwrap();
+  drop(conn);
+  tenant.publish(Event::Created { id });
+  let mut res = Response::builder().status(StatusCode::CREATED);
+  for warning in &warnings {
+    res = res.header(header::WARNING, format!("299 - {:?}", warning));
+  }
+  Ok(res.body(id.to_string().into()).unwrap())
+}
+
+// 取り込み用のトークンを発行する関数
+pub async fn issue(req: Request<Body>, tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let form = match serde_urlencoded::from_bytes::<NewToken>(&body) {
+    Ok(form) if !form.name.is_empty() => form,
+    _ => return Ok(empty(StatusCode::UNPROCESSABLE_ENTITY)),
+  };
+  let token = hex::encode(&rand::random::<[u8; 32]>());
+  let conn = tenant.conn.lock().await;
+  conn
+    .execute(
+      "INSERT INTO clip_tokens(name, token_hash, created_at) VALUES (?1,?2,?3)",
+      params![form.name, digest(&token), now()],
+    )
+    .unwrap();
+  Ok(json(&Issued {
+    id: conn.last_insert_rowid(),
+    token,
+  }))
+}
+
+// 失効していないトークンを一覧する関数
+pub async fn list(tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let conn = tenant.conn.lock().await;
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, name, created_at, last_used_at FROM clip_tokens
+      WHERE revoked_at IS NULL ORDER BY id",
+    )
+    .unwrap();
+  let tokens = stmt
+    .query_map([], |row| {
+      Ok(Token {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        created_at: row.get(2)?,
+        last_used_at: row.get(3)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  Ok(json(&tokens))
+}
+
+// トークンを失効させる関数
+pub async fn revoke(tenant: Arc<Tenant>, id: &str) -> Result<Response<Body>, Error> {
+  let id: i64 = match id.parse() {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let revoked = tenant
+    .conn
+    .lock()
+    .await
+    .execute(
+      "UPDATE clip_tokens SET revoked_at=?1 WHERE id=?2 AND revoked_at IS NULL",
+      params![now(), id],
+    )
+    .unwrap();
+  if revoked == 0 {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  Ok(empty(StatusCode::NO_CONTENT))
+}
@@ -0,0 +1,172 @@
+use std::{net::IpAddr, sync::Arc};
+
+use hyper::{Body, Error, Response, StatusCode};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+  audit, duplicate, empty, json, notify, now, tags, wordcount, State, Tenant, Visibility,
+};
+
+#[derive(Serialize)]
+struct Unresolved {
+  // 競合した変更を保存した投稿
+  id: Uuid,
+  original: Uuid,
+  title: String,
+  detected_at: u64,
+}
+
+// 同時に編集されて反映できなかった変更を，元の投稿の複製として保存する関数
+// 公開範囲とノートブックは元の投稿と同じにする
+// 複製のidを返す（元の投稿がなくなっていればNone）
+pub fn copy(
+  conn: &Connection,
+  state: &State,
+  original: &Uuid,
+  title: &str,
+  content: &str,
+  ip: IpAddr,
+) -> Option<Uuid> {
+  let id = Uuid::new_v4();
+  let title = format!("{} (conflicted copy)", title);
+  let stored = state.codec.encode(content);
+  let copied = conn
+    .execute(
+      "INSERT INTO posts(id, title, content, encrypted, compressed, visibility, notebook_id,
+        content_hash, created_at)
+      SELECT ?1,?2,?3,?4,?5, visibility, notebook_id, ?6, ?7 FROM posts
+      WHERE id=?8 AND kind = 'text' AND trashed_at IS NULL",
+      params![
+        id,
+        title,
+        stored.content,
+        stored.encrypted,
+        stored.compressed,
+        duplicate::hash(&state.signer, content),
+        now(),
+        original
+      ],
+    )
+    .unwrap();
+  if copied == 0 {
+    return None;
+  }
+  wordcount::record(conn, &id, content);
+  tags::apply(conn, &id, content);
+  conn
+    .execute(
+      "INSERT INTO conflicts(post_id, original_id, detected_at) VALUES (?1,?2,?3)",
+      params![id, original, now()],
+    )
+    .unwrap();
+  audit::record(
+    conn,
+    audit::Entry {
+      actor: audit::ANONYMOUS,
+      action: "conflict",
+      post_id: &id,
+      summary: format!("copy of={}, {} chars", original, content.chars().count()),
+      ip,
+    },
+  );
+  notify::enqueue(
+    conn,
+    &state.notifications,
+    notify::Topic::Conflict,
+    &format!(
+      "Conflicted copy of {}",
+      title.trim_end_matches(" (conflicted copy)")
+    ),
+    &format!(
+      "A change to post {} could not be applied because it was edited at the same time.\n\
+      It was saved as post {}. Merge it or move it to the trash to resolve the conflict.",
+      original, id
+    ),
+  );
+  Some(id)
+}
+
+// 解決していない競合を新しい順に一覧する関数
+// 複製を元の投稿に統合する（POST /posts/{id}/merge）かゴミ箱に入れると一覧から消える
+// 複製は元の公開範囲を引き継ぐので，非公開の投稿の複製は一覧に含めない
+pub async fn list(tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let conn = tenant.conn.lock().await;
+  let mut stmt = conn
+    .prepare(
+      "SELECT conflicts.post_id, original_id, title, detected_at
+      FROM conflicts JOIN posts ON posts.id = conflicts.post_id
+      WHERE resolved_at IS NULL AND visibility != ?1 AND trashed_at IS NULL
+      ORDER BY detected_at DESC",
+    )
+    .unwrap();
+  let conflicts = stmt
+    .query_map(params![Visibility::Private], |row| {
+      Ok(Unresolved {
+        id: row.get(0)?,
+        original: row.get(1)?,
+        title: row.get(2)?,
+        detected_at: row.get(3)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  Ok(json(&conflicts))
+}
+
+// 複製を別の投稿として残すことにして，競合を解決済みにする関数
+pub async fn resolve(tenant: Arc<Tenant>, id: &str) -> Result<Response<Body>, Error> {
+  let id = match Uuid::parse_str(id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let resolved = tenant
+    .conn
+    .lock()
+    .await
+    .execute(
+      "UPDATE conflicts SET resolved_at=?1 WHERE post_id=?2 AND resolved_at IS NULL
+      AND post_id IN (SELECT id FROM posts WHERE visibility != ?3)",
+      params![now(), id, Visibility::Private],
+    )
+    .unwrap();
+  if resolved == 0 {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  Ok(empty(StatusCode::NO_CONTENT))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests;
+  use hyper::Request;
+
+  #[tokio::test]
+  async fn hides_copies_of_private_posts() {
+    let state = tests::state();
+    let original = tests::insert_post(&state, "secret", Visibility::Private).await;
+    let tenant = tests::tenant(&state);
+    let id = copy(
+      &*tenant.conn.lock().await,
+      &state,
+      &original,
+      "secret",
+      "edited",
+      [127, 0, 0, 1].into(),
+    )
+    .unwrap();
+    let get = Request::get("/conflicts").body(Body::empty()).unwrap();
+    let body = hyper::body::to_bytes(tests::send(&state, get).await.into_body())
+      .await
+      .unwrap();
+    assert_eq!(&body[..], b"[]");
+    let resolve = Request::post(format!("/conflicts/{}/resolve", id))
+      .body(Body::empty())
+      .unwrap();
+    let res = tests::send(&state, resolve).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+  }
+}
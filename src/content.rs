@@ -0,0 +1,115 @@
+use std::{env, fs, process::Command};
+
+use aes_gcm::{
+  aead::{Aead, AeadCore, KeyInit, OsRng},
+  Aes256Gcm, Key, Nonce,
+};
+use rusqlite::types::{ToSqlOutput, Value, ValueRef};
+
+use crate::hex;
+
+// 暗号文の先頭に付けるnonceの長さ
+const NONCE_LEN: usize = 12;
+// これより長い本文は圧縮して保存する（バイト数）
+const COMPRESS_THRESHOLD: usize = 4096;
+const COMPRESS_LEVEL: i32 = 3;
+
+// 投稿の本文をDBに保存する形式と相互に変換する構造体
+// 長い本文は圧縮し，鍵が設定されている場合は暗号化してから保存する
+pub struct Codec {
+  cipher: Option<Aes256Gcm>,
+}
+
+// DBに保存する形式の本文
+pub struct Stored<'a> {
+  pub content: ToSqlOutput<'a>,
+  pub encrypted: bool,
+  pub compressed: bool,
+}
+
+impl Codec {
+  // 鍵はCONTENT_KEY_FILEのファイルか，CONTENT_KEY_COMMANDのコマンドの出力から読む
+  // どちらも64桁の16進数で，どちらもなければ暗号化しない
+  pub fn from_env() -> Codec {
+    let key = if let Ok(path) = env::var("CONTENT_KEY_FILE") {
+      Some(fs::read_to_string(path).unwrap())
+    } else if let Ok(command) = env::var("CONTENT_KEY_COMMAND") {
+      // KMSなど外部の鍵管理から鍵を取り出すためのフック
+      let output = Command::new("sh").arg("-c").arg(command).output().unwrap();
+      assert!(output.status.success(), "CONTENT_KEY_COMMAND failed");
+      Some(String::from_utf8(output.stdout).unwrap())
+    } else {
+      None
+    };
+    let cipher = key.map(|key| {
+      let key = hex::decode(key.trim())
+        .filter(|key| key.len() == 32)
+        .expect("content key must be 64 hex characters");
+      Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+    });
+    Codec { cipher }
+  }
+
+  // 本文を保存する形式に変換する関数
+  // 圧縮も暗号化もしない場合は参照のまま渡すのでアロケーションは発生しない
+  pub fn encode<'a>(&self, text: &'a str) -> Stored<'a> {
+    // 暗号文は圧縮できないので先に圧縮する
+    let compressed = if text.len() >= COMPRESS_THRESHOLD {
+      zstd::bulk::compress(text.as_bytes(), COMPRESS_LEVEL)
+        .ok()
+        .filter(|data| data.len() < text.len())
+    } else {
+      None
+    };
+    let is_compressed = compressed.is_some();
+    match (&self.cipher, compressed) {
+      (Some(cipher), compressed) => {
+        let data = compressed.as_deref().unwrap_or(text.as_bytes());
+        // nonceは暗号化のたびに新しく作り，暗号文と一緒に保存する
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(cipher.encrypt(&nonce, data).unwrap());
+        Stored {
+          content: ToSqlOutput::Owned(Value::Blob(sealed)),
+          encrypted: true,
+          compressed: is_compressed,
+        }
+      }
+      (None, Some(data)) => Stored {
+        content: ToSqlOutput::Owned(Value::Blob(data)),
+        encrypted: false,
+        compressed: true,
+      },
+      (None, None) => Stored {
+        content: ToSqlOutput::Borrowed(ValueRef::Text(text.as_bytes())),
+        encrypted: false,
+        compressed: false,
+      },
+    }
+  }
+
+  // 保存された本文を元に戻す関数
+  pub fn decode(&self, content: Value, encrypted: bool, compressed: bool) -> String {
+    let data = match (content, encrypted) {
+      (Value::Text(text), false) if !compressed => return text,
+      (Value::Blob(data), false) => data,
+      (Value::Blob(data), true) => {
+        let cipher = self
+          .cipher
+          .as_ref()
+          .expect("encrypted content requires a content key");
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        cipher
+          .decrypt(Nonce::from_slice(nonce), ciphertext)
+          .unwrap()
+      }
+      _ => panic!("unexpected content type"),
+    };
+    let data = if compressed {
+      zstd::stream::decode_all(data.as_slice()).unwrap()
+    } else {
+      data
+    };
+    String::from_utf8(data).unwrap()
+  }
+}
@@ -0,0 +1,28 @@
+// PNGやZIPで使うCRC-32（ISO 3309）を計算する関数
+pub fn checksum(data: &[u8]) -> u32 {
+  let mut crc = !0_u32;
+  for byte in data {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 == 1 {
+        (crc >> 1) ^ 0xedb8_8320
+      } else {
+        crc >> 1
+      };
+    }
+  }
+  !crc
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn matches_check_value() {
+    assert_eq!(checksum(b"123456789"), 0xcbf4_3926);
+    assert_eq!(checksum(b""), 0);
+    // PNGのIENDチャンク
+    assert_eq!(checksum(b"IEND"), 0xae42_6082);
+  }
+}
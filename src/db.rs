@@ -0,0 +1,296 @@
+use rusqlite::{functions::FunctionFlags, types::ValueRef, Connection};
+
+// スキーマの変更履歴
+// DBのuser_versionに適用済みの数を記録し，未適用のものだけを順に実行する
+// 既存のDBファイルがあるため，一度追加したものは書き換えずに新しいものを末尾に足す
+const MIGRATIONS: &[&str] = &[
+  "CREATE TABLE posts (
+    id BLOB PRIMARY KEY,
+    title TEXT NOT NULL,
+    -- 暗号化した本文はBLOBのまま保存される
+    content TEXT NOT NULL,
+    encrypted INTEGER NOT NULL DEFAULT 0,
+    visibility TEXT NOT NULL DEFAULT 'public',
+    -- e2eeの投稿は本文にクライアントが暗号化したデータを持つ
+    kind TEXT NOT NULL DEFAULT 'text',
+    client_metadata TEXT NOT NULL DEFAULT ''
+  );
+  -- スパム判定の結果を監査用に残すテーブル
+  CREATE TABLE spam_verdicts (
+    id INTEGER PRIMARY KEY,
+    post_id BLOB NOT NULL,
+    checker TEXT NOT NULL,
+    spam INTEGER NOT NULL,
+    reason TEXT NOT NULL,
+    checked_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+  );
+  -- 共有リンクの発行状況と失効を管理するテーブル
+  CREATE TABLE shares (
+    id BLOB PRIMARY KEY,
+    post_id BLOB NOT NULL REFERENCES posts(id),
+    expires_at INTEGER NOT NULL,
+    revoked INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+  );
+  -- 投稿に対する変更をすべて記録するテーブル
+  CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY,
+    at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    actor TEXT NOT NULL,
+    action TEXT NOT NULL,
+    post_id BLOB NOT NULL,
+    summary TEXT NOT NULL,
+    ip TEXT NOT NULL
+  );",
+  // 投稿をまとめるノートブック
+  "CREATE TABLE notebooks (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+  );
+  -- ノートブックに属さない投稿はNULLのまま
+  ALTER TABLE posts ADD COLUMN notebook_id INTEGER REFERENCES notebooks(id);",
+  // ノートブックの入れ子（最上位はNULL）
+  "ALTER TABLE notebooks ADD COLUMN parent_id INTEGER REFERENCES notebooks(id);",
+  // ゴミ箱に入れた時刻（UNIX秒）で，NULLなら通常の投稿
+  "ALTER TABLE posts ADD COLUMN trashed_at INTEGER;",
+  // 重複を見つけるための正規化した本文のハッシュ（E2EEの投稿はNULL）
+  "ALTER TABLE posts ADD COLUMN content_hash TEXT;
+  CREATE INDEX posts_content_hash ON posts(content_hash);",
+  // ALTER TABLEでは現在時刻を初期値にできないので作成時に設定する（追加前の投稿はNULL）
+  "ALTER TABLE posts ADD COLUMN created_at INTEGER;",
+  // 名前を付けて保存した検索式
+  "CREATE TABLE saved_searches (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    query TEXT NOT NULL,
+    pinned INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL
+  );",
+  // 投稿ごとの閲覧数（誰が見たかは記録しない）
+  "CREATE TABLE post_views (
+    post_id BLOB PRIMARY KEY REFERENCES posts(id),
+    views INTEGER NOT NULL
+  );",
+  // 長い本文を圧縮して保存したかどうか
+  "ALTER TABLE posts ADD COLUMN compressed INTEGER NOT NULL DEFAULT 0;",
+  // 機能の有効・無効をテナントごとに設定より優先させる値
+  "CREATE TABLE feature_overrides (
+    name TEXT PRIMARY KEY,
+    enabled INTEGER NOT NULL
+  );",
+  // 編集中の投稿の下書き（公開している本文とは別に投稿ごとに1件だけ持つ）
+  "CREATE TABLE drafts (
+    post_id BLOB PRIMARY KEY REFERENCES posts(id),
+    title TEXT NOT NULL,
+    content TEXT NOT NULL,
+    encrypted INTEGER NOT NULL DEFAULT 0,
+    compressed INTEGER NOT NULL DEFAULT 0,
+    updated_at INTEGER NOT NULL
+  );",
+  // 投稿ごとの最後の変更の通し番号（オフラインのクライアントが差分を取り出すのに使う）
+  // 書き込みの経路によらず漏れなく更新されるようにトリガーで記録する
+  // AUTOINCREMENTなので番号は削除した行の分も含めて再利用されず単調に増える
+  "CREATE TABLE changes (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    post_id BLOB NOT NULL UNIQUE,
+    -- 投稿を完全に削除した場合は1にして残す
+    deleted INTEGER NOT NULL DEFAULT 0
+  );
+  INSERT INTO changes(post_id) SELECT id FROM posts ORDER BY rowid;
+  CREATE TRIGGER posts_insert_change AFTER INSERT ON posts BEGIN
+    INSERT OR REPLACE INTO changes(post_id) VALUES (NEW.id);
+  END;
+  CREATE TRIGGER posts_update_change AFTER UPDATE ON posts BEGIN
+    INSERT OR REPLACE INTO changes(post_id) VALUES (NEW.id);
+  END;
+  CREATE TRIGGER posts_delete_change AFTER DELETE ON posts BEGIN
+    INSERT OR REPLACE INTO changes(post_id, deleted) VALUES (OLD.id, 1);
+  END;",
+  // 同時に編集されて反映できなかった変更を保存した複製と元の投稿
+  "CREATE TABLE conflicts (
+    post_id BLOB PRIMARY KEY REFERENCES posts(id),
+    original_id BLOB NOT NULL REFERENCES posts(id),
+    detected_at INTEGER NOT NULL,
+    resolved_at INTEGER
+  );",
+  // 保存時に数えた本文の語数・文字数と読了時間の目安（分）
+  // 記録する前からある投稿とE2EEの投稿はNULLのまま
+  "ALTER TABLE posts ADD COLUMN word_count INTEGER;
+  ALTER TABLE posts ADD COLUMN char_count INTEGER;
+  ALTER TABLE posts ADD COLUMN reading_minutes INTEGER;",
+  // 本文にあるURLのページから取り出したプレビュー（取得に失敗したURLはtitleがNULL）
+  "CREATE TABLE link_previews (
+    url TEXT PRIMARY KEY,
+    title TEXT,
+    description TEXT,
+    image TEXT,
+    fetched_at INTEGER NOT NULL
+  );",
+  // URLから取り込んだ投稿（あとで読むためのブックマーク）
+  "CREATE TABLE bookmarks (
+    post_id BLOB PRIMARY KEY REFERENCES posts(id),
+    url TEXT NOT NULL,
+    captured_at INTEGER NOT NULL
+  );
+  CREATE INDEX bookmarks_url ON bookmarks(url);",
+  // ブラウザ拡張から投稿を取り込むためのトークン（SHA-256のハッシュだけを持つ）
+  "CREATE TABLE clip_tokens (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL,
+    last_used_at INTEGER,
+    revoked_at INTEGER
+  );",
+  // 投稿の短縮リンク（/r/{code}）とクリック数
+  "CREATE TABLE short_links (
+    code TEXT PRIMARY KEY,
+    post_id BLOB NOT NULL UNIQUE REFERENCES posts(id),
+    clicks INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL,
+    last_clicked_at INTEGER
+  );",
+  // 送信待ちのWebhookのイベント（送信に成功したら消す）
+  "CREATE TABLE outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    event TEXT NOT NULL,
+    post_id BLOB NOT NULL,
+    summary TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at INTEGER NOT NULL,
+    last_error TEXT
+  );",
+  // 自動でタグを付ける規則と，規則によって投稿に付いたタグ
+  "CREATE TABLE tag_rules (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    pattern TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+  );
+  CREATE TABLE post_tags (
+    post_id BLOB NOT NULL REFERENCES posts(id),
+    tag TEXT NOT NULL,
+    PRIMARY KEY (post_id, tag)
+  );
+  CREATE INDEX post_tags_tag ON post_tags(tag);",
+  // 送信待ちの通知（経路ごとに1行，送信に成功したら消す）
+  "CREATE TABLE notifications (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    topic TEXT NOT NULL,
+    channel TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    body TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at INTEGER NOT NULL,
+    last_error TEXT
+  );",
+  // 1週間ごとのまとめ（period_startは週の始まり）
+  "CREATE TABLE digests (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    period_start INTEGER NOT NULL UNIQUE,
+    period_end INTEGER NOT NULL,
+    data TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+  );",
+  // 投稿の添付ファイル（中身はstorage_keyの名前で保存先に置く）
+  "CREATE TABLE attachments (
+    id BLOB PRIMARY KEY,
+    post_id BLOB NOT NULL REFERENCES posts(id),
+    name TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    storage_key TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+  );
+  CREATE INDEX attachments_post_id ON attachments(post_id);",
+  // 添付ファイルの中身を何件の添付ファイルが参照しているか
+  // 同じ中身は1つだけ保存し，参照がなくなったときに消す
+  "CREATE TABLE blobs (
+    storage_key TEXT PRIMARY KEY,
+    refs INTEGER NOT NULL
+  );
+  INSERT INTO blobs(storage_key, refs)
+    SELECT storage_key, COUNT(*) FROM attachments GROUP BY storage_key;",
+  // 分割アップロードの途中の状態（断片はUPLOAD_DIRに置く）
+  "CREATE TABLE upload_sessions (
+    id BLOB PRIMARY KEY,
+    post_id BLOB NOT NULL,
+    name TEXT NOT NULL,
+    content_type TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    received INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+  );",
+  // 音声の添付ファイルの再生時間と，音声メモとして作った投稿
+  "ALTER TABLE attachments ADD COLUMN duration_ms INTEGER;
+  CREATE TABLE voice_memos (
+    post_id BLOB PRIMARY KEY REFERENCES posts(id),
+    attachment_id BLOB NOT NULL REFERENCES attachments(id)
+  );",
+  // 画像の添付ファイルの表示される大きさとEXIFの向き
+  "ALTER TABLE attachments ADD COLUMN width INTEGER;
+  ALTER TABLE attachments ADD COLUMN height INTEGER;
+  ALTER TABLE attachments ADD COLUMN orientation INTEGER;",
+  // 投稿した場所（緯度と経度）
+  "ALTER TABLE posts ADD COLUMN lat REAL;
+  ALTER TABLE posts ADD COLUMN lon REAL;
+  CREATE INDEX posts_location ON posts(lat, lon) WHERE lat IS NOT NULL;",
+  // 場所と日付から補った天気などの情報と，問い合わせた記録
+  // 場所が変わった投稿は補い直す
+  "CREATE TABLE post_metadata (
+    post_id BLOB NOT NULL REFERENCES posts(id),
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (post_id, key)
+  );
+  CREATE TABLE enrichments (
+    post_id BLOB PRIMARY KEY REFERENCES posts(id),
+    provider TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    attempted_at INTEGER NOT NULL,
+    error TEXT
+  );
+  CREATE TRIGGER posts_location_enrichment AFTER UPDATE OF lat, lon ON posts
+  WHEN OLD.lat IS NOT NEW.lat OR OLD.lon IS NOT NEW.lon BEGIN
+    DELETE FROM post_metadata WHERE post_id = NEW.id;
+    DELETE FROM enrichments WHERE post_id = NEW.id;
+  END;",
+];
+
+// 未適用のスキーマ変更を適用する関数
+pub fn migrate(conn: &mut Connection) {
+  let version: usize = conn
+    .query_row("PRAGMA user_version", [], |row| row.get(0))
+    .unwrap();
+  for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+    // 途中で失敗しても中途半端な状態が残らないように1件ずつトランザクションで実行する
+    let tx = conn.transaction().unwrap();
+    tx.execute_batch(migration).unwrap();
+    tx.pragma_update(None, "user_version", &(i + 1)).unwrap();
+    tx.commit().unwrap();
+  }
+}
+
+// SQLから使う関数を登録する関数（接続ごとに必要）
+// fold_case(text)はRustのto_lowercaseと同じく小文字にする（SQLiteのlowerはASCIIしか変えない）
+// 文字列以外にはNULLを返す
+pub fn register_functions(conn: &Connection) {
+  conn
+    .create_scalar_function(
+      "fold_case",
+      1,
+      FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+      |ctx| {
+        Ok(match ctx.get_raw(0) {
+          ValueRef::Text(text) => Some(String::from_utf8_lossy(text).to_lowercase()),
+          _ => None,
+        })
+      },
+    )
+    .unwrap();
+}
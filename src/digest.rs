@@ -0,0 +1,208 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{Datelike, Duration as Days, TimeZone, Utc};
+use chrono_tz::Tz;
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tera::Context;
+use uuid::Uuid;
+
+use crate::{empty, json, notify, now, templates, wants_html, State, Tenant, Visibility};
+
+// 前の週のまとめを作ったかどうかを確かめる間隔
+const CHECK_SECONDS: u64 = 60 * 60;
+// よく読まれた投稿として載せる数
+const HIGHLIGHTS: u32 = 5;
+
+#[derive(Serialize, Deserialize)]
+struct Highlight {
+  id: Uuid,
+  title: String,
+  views: u64,
+}
+
+// 1週間のまとめ
+// 作った時点の内容をJSONで保存しておき，/digest/latestではそれを表示する
+#[derive(Serialize, Deserialize)]
+struct Digest {
+  // 週の最初と最後の日（既定のタイムゾーンでのYYYY-MM-DD）
+  from: String,
+  to: String,
+  posts: u64,
+  words: u64,
+  // 週に作った公開の投稿のうち，よく読まれたもの
+  highlights: Vec<Highlight>,
+}
+
+// 既定のタイムゾーンで，その時刻を含む週の月曜日0時のUNIX秒を返す関数
+fn week_start(at: i64, tz: Tz) -> i64 {
+  let local = Utc.timestamp(at, 0).with_timezone(&tz).date();
+  let monday = local - Days::days(i64::from(local.weekday().num_days_from_monday()));
+  // 0時がない日（夏時間の切り替え）は1時からとする
+  monday
+    .and_hms_opt(0, 0, 0)
+    .unwrap_or_else(|| monday.and_hms(1, 0, 0))
+    .timestamp()
+}
+
+// [start, end)に作った投稿をまとめる関数
+// 件数と語数には非公開の投稿も含めるが，タイトルを載せるのは公開の投稿だけにする
+fn compile(conn: &Connection, start: i64, end: i64, tz: Tz) -> Digest {
+  let (posts, words): (u64, u64) = conn
+    .query_row(
+      "SELECT COUNT(*), COALESCE(SUM(word_count), 0) FROM posts
+      WHERE kind = 'text' AND trashed_at IS NULL AND created_at >= ?1 AND created_at < ?2",
+      params![start, end],
+      |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .unwrap();
+  let mut stmt = conn
+    .prepare(
+      "SELECT posts.id, posts.title, COALESCE(post_views.views, 0) AS views FROM posts
+      LEFT JOIN post_views ON post_views.post_id = posts.id
+      WHERE kind = 'text' AND visibility=?1 AND trashed_at IS NULL
+      AND created_at >= ?2 AND created_at < ?3
+      ORDER BY views DESC, word_count DESC, created_at LIMIT ?4",
+    )
+    .unwrap();
+  let highlights = stmt
+    .query_map(params![Visibility::Public, start, end, HIGHLIGHTS], |row| {
+      Ok(Highlight {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        views: row.get(2)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  let day = |at: i64| {
+    Utc
+      .timestamp(at, 0)
+      .with_timezone(&tz)
+      .format("%Y-%m-%d")
+      .to_string()
+  };
+  Digest {
+    from: day(start),
+    to: day(end - 1),
+    posts,
+    words,
+    highlights,
+  }
+}
+
+// 通知の本文
+// 公開URLを設定していればリンクを付ける
+fn text(digest: &Digest, state: &State, tenant: &Tenant) -> String {
+  let mut text = format!(
+    "{} - {}\nPosts: {} ({} words)\n",
+    digest.from, digest.to, digest.posts, digest.words
+  );
+  if !digest.highlights.is_empty() {
+    text.push_str("\nHighlights:\n");
+  }
+  for highlight in &digest.highlights {
+    text.push_str(&format!(
+      "- {} ({} views)",
+      highlight.title, highlight.views
+    ));
+    if let Some(base) = state.base_url.configured() {
+      text.push_str(&format!(
+        " {}{}/posts/{}",
+        base, tenant.prefix, highlight.id
+      ));
+    }
+    text.push('\n');
+  }
+  text
+}
+
+// 前の週のまとめがなければ作って通知する関数
+// 週の始まりで区切るので，止まっていた間に週をまたいでも同じ週のまとめを二重に作らない
+// 投稿のなかった週は作らない
+async fn run_for(state: &State, tenant: &Tenant) {
+  let tz = state.timezone.default();
+  let end = week_start(now() as i64, tz);
+  let start = week_start(end - 1, tz);
+  let mut conn = tenant.conn.lock().await;
+  let tx = conn.transaction().unwrap();
+  let exists = tx
+    .query_row(
+      "SELECT 1 FROM digests WHERE period_start=?1",
+      params![start],
+      |_| Ok(()),
+    )
+    .optional()
+    .unwrap()
+    .is_some();
+  if exists {
+    return;
+  }
+  let digest = compile(&tx, start, end, tz);
+  if digest.posts == 0 {
+    return;
+  }
+  tx.execute(
+    "INSERT INTO digests(period_start, period_end, data, created_at) VALUES (?1,?2,?3,?4)",
+    params![start, end, serde_json::to_string(&digest).unwrap(), now()],
+  )
+  .unwrap();
+  notify::enqueue(
+    &tx,
+    &state.notifications,
+    notify::Topic::Digest,
+    &format!("Weekly digest {} - {}", digest.from, digest.to),
+    &text(&digest, state, tenant),
+  );
+  tx.commit().unwrap();
+}
+
+// 開いているすべてのテナントで前の週のまとめを作り続ける関数
+pub fn spawn_scheduler(state: Arc<State>) {
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(CHECK_SECONDS));
+    loop {
+      interval.tick().await;
+      if state.read_only.is_on() {
+        continue;
+      }
+      for tenant in state.tenants.all() {
+        run_for(&state, &tenant).await;
+      }
+    }
+  });
+}
+
+// GET /digest/latest で最後に作ったまとめを返す関数
+pub async fn latest(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+) -> Result<Response<Body>, Error> {
+  let data: Option<String> = tenant
+    .conn
+    .lock()
+    .await
+    .query_row(
+      "SELECT data FROM digests ORDER BY period_start DESC LIMIT 1",
+      [],
+      |row| row.get(0),
+    )
+    .optional()
+    .unwrap();
+  let digest = match data {
+    Some(data) => serde_json::from_str::<Digest>(&data).unwrap(),
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  if wants_html(&req) {
+    let mut ctx = Context::new();
+    ctx.insert("prefix", &tenant.prefix);
+    ctx.insert("digest", &digest);
+    return Ok(Response::new(
+      templates::render(&state, &req, "digest", &mut ctx).into(),
+    ));
+  }
+  Ok(json(&digest))
+}
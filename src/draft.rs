@@ -0,0 +1,347 @@
+use std::sync::Arc;
+
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tera::Context;
+use uuid::Uuid;
+
+use crate::{empty, json, now, preview, templates, State, Tenant, Visibility};
+
+// 下書きのプレビューURLの有効期間の初期値（3日）
+const DEFAULT_PREVIEW_TTL: u64 = 3 * 24 * 60 * 60;
+// 指定できる有効期間の上限（30日）
+const MAX_PREVIEW_TTL: u64 = 30 * 24 * 60 * 60;
+// 共有リンクの署名と取り違えないようにpayloadの先頭に付ける
+const PREVIEW_PAYLOAD: &str = "draft";
+
+#[derive(Deserialize)]
+struct DraftForm {
+  // 省略した場合は保存済みの下書きか投稿のタイトルのまま
+  title: Option<String>,
+  content: String,
+}
+
+#[derive(Serialize)]
+struct Draft {
+  title: String,
+  content: String,
+  updated_at: u64,
+}
+
+#[derive(Serialize)]
+struct Saved {
+  updated_at: u64,
+}
+
+#[derive(Deserialize)]
+struct NewPreview {
+  // 有効期間（秒，上限より長い場合は上限にする）
+  expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct PreviewUrl {
+  url: String,
+  expires_at: u64,
+}
+
+// 下書きを持てる投稿のタイトルを返す関数
+// E2EEの投稿はサーバが平文を持たないので下書きも扱わない
+// 非公開の投稿は詳細と同じく存在しないものとして扱う
+fn post_title(conn: &Connection, id: &Uuid) -> Option<String> {
+  conn
+    .query_row(
+      "SELECT title FROM posts
+      WHERE id=?1 AND kind = 'text' AND visibility != ?2 AND trashed_at IS NULL",
+      params![id, Visibility::Private],
+      |row| row.get(0),
+    )
+    .optional()
+    .unwrap()
+}
+
+// 編集中の下書きを保存する関数
+// 投稿ごとに1件だけ持ち，保存するたびに上書きするので数秒ごとに自動保存しても増えない
+// 公開している本文は変更しない
+pub async fn save(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  post_id: &str,
+) -> Result<Response<Body>, Error> {
+  let post_id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let form = match serde_urlencoded::from_bytes::<DraftForm>(&body) {
+    Ok(form) => form,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let conn = tenant.conn.lock().await;
+  let title = match post_title(&conn, &post_id) {
+    Some(title) => title,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let saved_title: Option<String> = conn
+    .query_row(
+      "SELECT title FROM drafts WHERE post_id=?1",
+      params![post_id],
+      |row| row.get(0),
+    )
+    .optional()
+    .unwrap();
+  let title = form.title.or(saved_title).unwrap_or(title);
+  // 投稿と同じ上限を適用する
+  let (title, content) = match state.limits.apply(&title, &form.content, &mut Vec::new()) {
+    Ok(fitted) => fitted,
+    Err(reason) => {
+      return Ok(
+        Response::builder()
+          .status(StatusCode::UNPROCESSABLE_ENTITY)
+          .body(reason.into())
+          .unwrap(),
+      )
+    }
+  };
+  let stored = state.codec.encode(content);
+  let updated_at = now();
+  conn
+    .execute(
+      "INSERT INTO drafts(post_id, title, content, encrypted, compressed, updated_at)
+      VALUES (?1,?2,?3,?4,?5,?6)
+      ON CONFLICT(post_id) DO UPDATE SET title=excluded.title, content=excluded.content,
+        encrypted=excluded.encrypted, compressed=excluded.compressed, updated_at=excluded.updated_at",
+      params![
+        post_id,
+        title,
+        stored.content,
+        stored.encrypted,
+        stored.compressed,
+        updated_at
+      ],
+    )
+    .unwrap();
+  Ok(json(&Saved { updated_at }))
+}
+
+// 保存した下書きを返す関数
+pub async fn show(
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  post_id: &str,
+) -> Result<Response<Body>, Error> {
+  let post_id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let draft = tenant
+    .conn
+    .lock()
+    .await
+    .query_row(
+      "SELECT drafts.title, drafts.content, drafts.encrypted, drafts.compressed, updated_at
+      FROM drafts JOIN posts ON posts.id = drafts.post_id
+      WHERE post_id=?1 AND visibility != ?2 AND trashed_at IS NULL",
+      params![post_id, Visibility::Private],
+      |row| {
+        Ok(Draft {
+          title: row.get(0)?,
+          content: state.codec.decode(row.get(1)?, row.get(2)?, row.get(3)?),
+          updated_at: row.get(4)?,
+        })
+      },
+    )
+    .optional()
+    .unwrap();
+  match draft {
+    Some(draft) => Ok(json(&draft)),
+    None => Ok(empty(StatusCode::NOT_FOUND)),
+  }
+}
+
+// 下書きを破棄する関数
+pub async fn discard(tenant: Arc<Tenant>, post_id: &str) -> Result<Response<Body>, Error> {
+  let post_id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let deleted = tenant
+    .conn
+    .lock()
+    .await
+    .execute(
+      "DELETE FROM drafts WHERE post_id=?1
+      AND post_id IN (SELECT id FROM posts WHERE visibility != ?2)",
+      params![post_id, Visibility::Private],
+    )
+    .unwrap();
+  if deleted == 0 {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  Ok(empty(StatusCode::NO_CONTENT))
+}
+
+// 下書きを確認してもらうためのプレビューURLを発行する関数
+// 投稿のidと期限に署名を付けるだけなので，DBには何も保存しない
+// URLを開くたびにその時点の下書きを表示する
+pub async fn issue_preview(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  post_id: &str,
+) -> Result<Response<Body>, Error> {
+  let post_id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let origin = state.base_url.origin(&req);
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let new_preview = match serde_urlencoded::from_bytes::<NewPreview>(&body) {
+    Ok(new_preview) => new_preview,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let exists = tenant
+    .conn
+    .lock()
+    .await
+    .query_row(
+      "SELECT 1 FROM drafts JOIN posts ON posts.id = drafts.post_id
+      WHERE post_id=?1 AND visibility != ?2 AND trashed_at IS NULL",
+      params![post_id, Visibility::Private],
+      |_| Ok(()),
+    )
+    .optional()
+    .unwrap();
+  if exists.is_none() {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  let ttl = new_preview
+    .expires_in
+    .unwrap_or(DEFAULT_PREVIEW_TTL)
+    .min(MAX_PREVIEW_TTL);
+  let expires_at = match now().checked_add(ttl) {
+    Some(expires_at) => expires_at,
+    None => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let token = state
+    .signer
+    .sign(&format!("{}.{}.{}", PREVIEW_PAYLOAD, post_id, expires_at));
+  Ok(json(&PreviewUrl {
+    url: format!("{}{}/preview/{}", origin, tenant.prefix, token),
+    expires_at,
+  }))
+}
+
+// 署名を検証してトークンから投稿のidを取り出す関数
+// 期限が切れたトークンは受け付けない
+fn parse_preview_token(state: &State, token: &str) -> Option<Uuid> {
+  let payload = state.signer.verify(token)?.strip_prefix(PREVIEW_PAYLOAD)?;
+  let (id, expires_at) = payload.strip_prefix('.')?.split_once('.')?;
+  if expires_at.parse::<u64>().ok()? <= now() {
+    return None;
+  }
+  Uuid::parse_str(id).ok()
+}
+
+// プレビューURLから下書きを表示する関数
+// 公開していない内容なので，検索エンジンやキャッシュには残させない
+pub async fn show_preview(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  token: &str,
+) -> Result<Response<Body>, Error> {
+  let post_id = match parse_preview_token(&state, token) {
+    Some(post_id) => post_id,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let draft = tenant
+    .conn
+    .lock()
+    .await
+    .query_row(
+      "SELECT drafts.title, drafts.content, drafts.encrypted, drafts.compressed, updated_at
+      FROM drafts JOIN posts ON posts.id = drafts.post_id
+      WHERE post_id=?1 AND visibility != ?2 AND trashed_at IS NULL",
+      params![post_id, Visibility::Private],
+      |row| {
+        Ok(Draft {
+          title: row.get(0)?,
+          content: state.codec.decode(row.get(1)?, row.get(2)?, row.get(3)?),
+          updated_at: row.get(4)?,
+        })
+      },
+    )
+    .optional()
+    .unwrap();
+  let draft = match draft {
+    Some(draft) => draft,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let mut ctx = Context::new();
+  ctx.insert("html", &preview::render(&draft.content));
+  ctx.insert("draft", &draft);
+  Ok(
+    Response::builder()
+      .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+      .header(header::CACHE_CONTROL, "no-store")
+      .header("x-robots-tag", "noindex")
+      .body(templates::render(&state, &req, "draft_preview", &mut ctx).into())
+      .unwrap(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests;
+
+  fn request(method: &str, uri: String, body: &str) -> Request<Body> {
+    Request::builder()
+      .method(method)
+      .uri(uri)
+      .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+      .body(body.to_string().into())
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn hides_drafts_of_private_posts() {
+    let state = tests::state();
+    let id = tests::insert_post(&state, "secret", Visibility::Private).await;
+    let draft = format!("/posts/{}/draft", id);
+    for (method, uri) in [
+      ("PUT", draft.clone()),
+      ("GET", draft.clone()),
+      ("POST", format!("{}/preview", draft)),
+    ] {
+      let res = tests::send(&state, request(method, uri, "content=x")).await;
+      assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", method);
+    }
+  }
+
+  #[tokio::test]
+  async fn caps_preview_lifetime() {
+    let state = tests::state();
+    let id = tests::insert_post(&state, "open", Visibility::Public).await;
+    let draft = format!("/posts/{}/draft", id);
+    let res = tests::send(&state, request("PUT", draft.clone(), "content=x")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = tests::send(
+      &state,
+      request(
+        "POST",
+        format!("{}/preview", draft),
+        "expires_in=18446744073709551615",
+      ),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let preview: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let expires_at = preview["expires_at"].as_u64().unwrap();
+    assert!(expires_at > now() && expires_at <= now() + MAX_PREVIEW_TTL);
+  }
+}
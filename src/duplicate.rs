@@ -0,0 +1,35 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use uuid::Uuid;
+
+use crate::signer::Signer;
+
+// 大文字小文字，空白の量，記号の違いを無視するように本文を正規化してハッシュを求める関数
+// 本文を暗号化していても内容を推測されないように鍵付きのハッシュにする
+// SECRET_KEYを設定していない場合は再起動前に作成した投稿との重複は見つけられない
+pub fn hash(signer: &Signer, content: &str) -> String {
+  let normalized = content
+    .split_whitespace()
+    .map(|word| {
+      word
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect::<String>()
+    })
+    .filter(|word| !word.is_empty())
+    .collect::<Vec<_>>()
+    .join(" ");
+  signer.digest(&normalized)
+}
+
+// 同じハッシュを持つ投稿を探す関数
+pub fn find(conn: &Connection, hash: &str) -> Option<Uuid> {
+  conn
+    .query_row(
+      "SELECT id FROM posts WHERE content_hash=?1 AND trashed_at IS NULL LIMIT 1",
+      params![hash],
+      |row| row.get(0),
+    )
+    .optional()
+    .unwrap()
+}
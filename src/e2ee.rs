@@ -0,0 +1,122 @@
+use std::sync::Arc;
+
+use hyper::body::HttpBody;
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{audit, empty, events::Event, header_str, now, remote_ip, Tenant, Visibility};
+
+// E2EEクライアントが暗号文を送受信するときのContent-Type
+pub const CONTENT_TYPE: &str = "application/vnd.web-memory.e2ee";
+// クライアント側の鍵idやアルゴリズムなどを入れるヘッダ（サーバは中身を解釈しない）
+pub const METADATA_HEADER: &str = "x-e2ee-metadata";
+
+// 暗号文とメタデータの最大サイズ
+const MAX_CIPHERTEXT_BYTES: usize = 1024 * 1024;
+const MAX_METADATA_BYTES: usize = 4 * 1024;
+
+#[derive(Deserialize)]
+struct Query {
+  #[serde(default)]
+  visibility: Visibility,
+}
+
+// 上限を超えた時点で読み込みをやめてボディを取り出す関数
+pub async fn read_limited(mut body: Body, limit: usize) -> Result<Option<Vec<u8>>, Error> {
+  let mut data = Vec::new();
+  while let Some(chunk) = body.data().await {
+    let chunk = chunk?;
+    if data.len() + chunk.len() > limit {
+      return Ok(None);
+    }
+    data.extend_from_slice(&chunk);
+  }
+  Ok(Some(data))
+}
+
+pub fn too_large(limit: usize) -> Response<Body> {
+  Response::builder()
+    .status(StatusCode::PAYLOAD_TOO_LARGE)
+    .body(format!("limit is {} bytes", limit).into())
+    .unwrap()
+}
+
+// E2EEの投稿を作成する関数
+// 暗号文はサーバでは読めないのでスパム判定や暗号化は行わずにそのまま保存する
+pub async fn create(req: Request<Body>, tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let ip = remote_ip(&req);
+  let metadata = header_str(&req, header::HeaderName::from_static(METADATA_HEADER));
+  if metadata.len() > MAX_METADATA_BYTES {
+    return Ok(too_large(MAX_METADATA_BYTES));
+  }
+  let query = match serde_urlencoded::from_str::<Query>(req.uri().query().unwrap_or_default()) {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let ciphertext = match read_limited(req.into_body(), MAX_CIPHERTEXT_BYTES).await? {
+    Some(ciphertext) => ciphertext,
+    None => return Ok(too_large(MAX_CIPHERTEXT_BYTES)),
+  };
+  let id = Uuid::new_v4();
+  let conn = tenant.conn.lock().await;
+  conn
+    .execute(
+      "INSERT INTO posts(id, title, content, kind, client_metadata, visibility, created_at)
+      VALUES (?1, '', ?2, 'e2ee', ?3, ?4, ?5)",
+      params![id, ciphertext, metadata, query.visibility, now()],
+    )
+    .unwrap();
+  audit::record(
+    &conn,
+    audit::Entry {
+      actor: audit::ANONYMOUS,
+      action: "create",
+      post_id: &id,
+      summary: format!("e2ee, {} bytes", ciphertext.len()),
+      ip,
+    },
+  );
+  drop(conn);
+  tenant.publish(Event::Created { id });
+  Ok(Response::new(id.to_string().into()))
+}
+
+// E2EEの投稿であれば暗号文をそのまま返すレスポンスを作る関数
+// テンプレートでの描画は決して行わない
+pub fn find(conn: &Connection, id: &Uuid) -> Option<Response<Body>> {
+  let (ciphertext, metadata): (Vec<u8>, String) = conn
+    .query_row(
+      "SELECT content, client_metadata FROM posts
+      WHERE id=?1 AND kind = 'e2ee' AND visibility != ?2 AND trashed_at IS NULL",
+      params![id, Visibility::Private],
+      |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+    .unwrap()?;
+  Some(
+    Response::builder()
+      .header(header::CONTENT_TYPE, CONTENT_TYPE)
+      .header(METADATA_HEADER, metadata)
+      .body(ciphertext.into())
+      .unwrap(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests;
+
+  #[tokio::test]
+  async fn rejects_bad_query() {
+    let state = tests::state();
+    let req = Request::post("/posts?visibility=everyone")
+      .header(header::CONTENT_TYPE, CONTENT_TYPE)
+      .body(Body::from("ciphertext"))
+      .unwrap();
+    let res = tests::send(&state, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+  }
+}
@@ -0,0 +1,360 @@
+use std::{collections::BTreeMap, env, sync::Arc, time::Duration};
+
+use chrono::{TimeZone, Utc};
+use hyper::{body, Uri};
+use rusqlite::{params, Connection};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+  geo::Location,
+  https::{self, HttpsClient},
+  now,
+  spam::BoxFuture,
+  State, Tenant,
+};
+
+// 補う投稿を探す間隔
+const CHECK_SECONDS: u64 = 10 * 60;
+// 1回でテナントごとに問い合わせる投稿の数
+const BATCH: u32 = 20;
+// 失敗した投稿を問い合わせ直す回数と，次に問い合わせるまでの時間（回数に比例して延ばす）
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_SECONDS: u64 = 60 * 60;
+// 問い合わせの応答を待つ時間
+const TIMEOUT_SECONDS: u64 = 30;
+// 投稿してから問い合わせるまでの時間の初期値
+// open-meteoの過去の天気は数日遅れて揃うので1週間待つ
+const DEFAULT_DELAY_HOURS: u64 = 7 * 24;
+const OPEN_METEO_URL: &str = "https://archive-api.open-meteo.com/v1/archive";
+
+// 場所と日付から天気などを調べる共通インターフェース
+// 日付は投稿した日（サイトの既定のタイムゾーン）で，YYYY-MM-DDの形で渡す
+pub trait Provider: Send + Sync {
+  // 記録に残す名前
+  fn name(&self) -> &'static str;
+
+  fn lookup<'a>(
+    &'a self,
+    location: Location,
+    date: &'a str,
+  ) -> BoxFuture<'a, Result<BTreeMap<String, String>, String>>;
+}
+
+async fn get_json(client: &HttpsClient, url: &str) -> Result<Value, String> {
+  let uri = url.parse::<Uri>().map_err(|e| e.to_string())?;
+  let res = tokio::time::timeout(Duration::from_secs(TIMEOUT_SECONDS), client.get(uri))
+    .await
+    .map_err(|_| "timed out".to_string())?
+    .map_err(|e| e.to_string())?;
+  let status = res.status();
+  let bytes = body::to_bytes(res.into_body())
+    .await
+    .map_err(|e| e.to_string())?;
+  if !status.is_success() {
+    return Err(format!("{} {}", status, String::from_utf8_lossy(&bytes)));
+  }
+  serde_json::from_slice(&bytes).map_err(|e| e.to_string())
+}
+
+// 値を文字列にする関数（数，文字列，真偽値だけを扱う）
+fn text(value: &Value) -> Option<String> {
+  match value {
+    Value::String(value) => Some(value.clone()),
+    Value::Number(value) => Some(value.to_string()),
+    Value::Bool(value) => Some(value.to_string()),
+    _ => None,
+  }
+}
+
+// WMOの天気コードの説明
+fn weather(code: u64) -> Option<&'static str> {
+  Some(match code {
+    0 => "Clear sky",
+    1 => "Mainly clear",
+    2 => "Partly cloudy",
+    3 => "Overcast",
+    45 | 48 => "Fog",
+    51 | 53 | 55 => "Drizzle",
+    56 | 57 => "Freezing drizzle",
+    61 | 63 | 65 => "Rain",
+    66 | 67 => "Freezing rain",
+    71 | 73 | 75 => "Snow",
+    77 => "Snow grains",
+    80..=82 => "Rain showers",
+    85 | 86 => "Snow showers",
+    95 => "Thunderstorm",
+    96 | 99 => "Thunderstorm with hail",
+    _ => return None,
+  })
+}
+
+// open-meteoの過去の天気のAPIで，その日の天気，最高と最低の気温（℃），降水量（mm），
+// 日の出と日の入り（現地の時刻）を調べる
+pub struct OpenMeteo {
+  url: String,
+  client: HttpsClient,
+}
+
+impl Provider for OpenMeteo {
+  fn name(&self) -> &'static str {
+    "open-meteo"
+  }
+
+  fn lookup<'a>(
+    &'a self,
+    location: Location,
+    date: &'a str,
+  ) -> BoxFuture<'a, Result<BTreeMap<String, String>, String>> {
+    Box::pin(async move {
+      let url = format!(
+        "{}?latitude={}&longitude={}&start_date={}&end_date={}&timezone=auto&daily={}",
+        self.url,
+        location.lat,
+        location.lon,
+        date,
+        date,
+        "weather_code,temperature_2m_max,temperature_2m_min,precipitation_sum,sunrise,sunset"
+      );
+      let value = get_json(&self.client, &url).await?;
+      let daily = |name: &str| value["daily"][name].get(0).filter(|value| !value.is_null());
+      let mut metadata = BTreeMap::new();
+      if let Some(code) = daily("weather_code").and_then(Value::as_u64) {
+        metadata.insert("weather_code".to_string(), code.to_string());
+        if let Some(weather) = weather(code) {
+          metadata.insert("weather".to_string(), weather.to_string());
+        }
+      }
+      for (name, key) in [
+        ("temperature_2m_max", "temperature_max"),
+        ("temperature_2m_min", "temperature_min"),
+        ("precipitation_sum", "precipitation"),
+      ] {
+        if let Some(value) = daily(name).and_then(text) {
+          metadata.insert(key.to_string(), value);
+        }
+      }
+      // 2026-10-14T05:44のような現地の日時から時刻だけを残す
+      for name in ["sunrise", "sunset"] {
+        if let Some(time) = daily(name).and_then(Value::as_str) {
+          let time = time.split_once('T').map_or(time, |(_, time)| time);
+          metadata.insert(name.to_string(), time.to_string());
+        }
+      }
+      // まだ揃っていない日はすべてnullになる
+      if metadata.is_empty() {
+        return Err("no data yet".to_string());
+      }
+      Ok(metadata)
+    })
+  }
+}
+
+// 任意のURLにGETで問い合わせ，応答のJSONのオブジェクトの値をそのまま記録する
+// URLの{lat}，{lon}，{date}を投稿の場所と日付に置き換える
+pub struct Json {
+  url: String,
+  client: HttpsClient,
+}
+
+impl Provider for Json {
+  fn name(&self) -> &'static str {
+    "json"
+  }
+
+  fn lookup<'a>(
+    &'a self,
+    location: Location,
+    date: &'a str,
+  ) -> BoxFuture<'a, Result<BTreeMap<String, String>, String>> {
+    Box::pin(async move {
+      let url = self
+        .url
+        .replace("{lat}", &location.lat.to_string())
+        .replace("{lon}", &location.lon.to_string())
+        .replace("{date}", date);
+      match get_json(&self.client, &url).await? {
+        // 入れ子の値は扱わない
+        Value::Object(object) => Ok(
+          object
+            .iter()
+            .filter_map(|(key, value)| Some((key.clone(), text(value)?)))
+            .collect(),
+        ),
+        _ => Err("response is not an object".to_string()),
+      }
+    })
+  }
+}
+
+// 場所のある投稿に天気などを補う設定
+pub struct Enrichment {
+  provider: Box<dyn Provider>,
+  delay_seconds: u64,
+}
+
+impl Enrichment {
+  // ENRICH_PROVIDERが設定されている場合のみ有効にする関数（open-meteoかjson）
+  // ENRICH_URLで問い合わせ先を（jsonでは必須），ENRICH_DELAY_HOURSで投稿してから問い合わせるまでの時間を指定する
+  // 投稿の場所を外部のサービスに送るので，既定では無効にしている
+  pub fn from_env() -> Option<Enrichment> {
+    let url = env::var("ENRICH_URL").ok();
+    let client = https::trusted_client();
+    let provider: Box<dyn Provider> = match env::var("ENRICH_PROVIDER").ok()?.as_str() {
+      "open-meteo" => Box::new(OpenMeteo {
+        url: url.unwrap_or_else(|| OPEN_METEO_URL.to_string()),
+        client,
+      }),
+      "json" => Box::new(Json {
+        url: url.expect("ENRICH_URL is required for the json provider"),
+        client,
+      }),
+      other => panic!("unknown ENRICH_PROVIDER {}", other),
+    };
+    let hours = env::var("ENRICH_DELAY_HOURS")
+      .map(|hours| hours.parse().expect("ENRICH_DELAY_HOURS must be a number"))
+      .unwrap_or(DEFAULT_DELAY_HOURS);
+    Some(Enrichment {
+      provider,
+      delay_seconds: hours * 60 * 60,
+    })
+  }
+}
+
+// 投稿に記録した情報（テンプレートではmetadata.weatherのように使う）
+pub fn for_post(conn: &Connection, post_id: &Uuid) -> BTreeMap<String, String> {
+  let mut stmt = conn
+    .prepare("SELECT key, value FROM post_metadata WHERE post_id=?1")
+    .unwrap();
+  stmt
+    .query_map(params![post_id], |row| Ok((row.get(0)?, row.get(1)?)))
+    .unwrap()
+    .collect::<Result<_, _>>()
+    .unwrap()
+}
+
+// まだ補っていない（または失敗して問い合わせ直す）投稿を新しい順に返す関数
+fn pending(conn: &Connection, enrichment: &Enrichment) -> Vec<(Uuid, Location, i64)> {
+  let now = now();
+  let mut stmt = conn
+    .prepare(
+      "SELECT posts.id, lat, lon, created_at FROM posts
+      LEFT JOIN enrichments ON enrichments.post_id = posts.id
+      WHERE lat IS NOT NULL AND lon IS NOT NULL AND created_at IS NOT NULL
+        AND trashed_at IS NULL AND created_at <= ?1
+        AND (enrichments.post_id IS NULL
+          OR (error IS NOT NULL AND attempts < ?2 AND attempted_at <= ?3 - attempts * ?4))
+      ORDER BY created_at DESC, posts.rowid DESC
+      LIMIT ?5",
+    )
+    .unwrap();
+  stmt
+    .query_map(
+      params![
+        now.saturating_sub(enrichment.delay_seconds),
+        MAX_ATTEMPTS,
+        now,
+        RETRY_SECONDS,
+        BATCH
+      ],
+      |row| {
+        Ok((
+          row.get(0)?,
+          Location::from_row(row, 1)?.unwrap(),
+          row.get(3)?,
+        ))
+      },
+    )
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap()
+}
+
+// 問い合わせた結果を記録する関数
+// 問い合わせている間に場所が変わった投稿は記録せず，次の回で新しい場所について問い合わせる
+fn save(
+  conn: &mut Connection,
+  provider: &str,
+  post_id: &Uuid,
+  location: Location,
+  result: Result<BTreeMap<String, String>, String>,
+) {
+  let tx = conn.transaction().unwrap();
+  let unchanged = tx
+    .query_row(
+      "SELECT 1 FROM posts WHERE id=?1 AND lat=?2 AND lon=?3",
+      params![post_id, location.lat, location.lon],
+      |_| Ok(()),
+    )
+    .is_ok();
+  if !unchanged {
+    return;
+  }
+  let error = match result {
+    Ok(metadata) => {
+      tx.execute(
+        "DELETE FROM post_metadata WHERE post_id=?1",
+        params![post_id],
+      )
+      .unwrap();
+      for (key, value) in metadata {
+        tx.execute(
+          "INSERT INTO post_metadata(post_id, key, value) VALUES (?1,?2,?3)",
+          params![post_id, key, value],
+        )
+        .unwrap();
+      }
+      None
+    }
+    Err(e) => Some(e),
+  };
+  tx.execute(
+    "INSERT INTO enrichments(post_id, provider, attempts, attempted_at, error)
+    VALUES (?1,?2,1,?3,?4)
+    ON CONFLICT(post_id) DO UPDATE SET
+      provider=excluded.provider, attempts=attempts + 1, attempted_at=excluded.attempted_at,
+      error=excluded.error",
+    params![post_id, provider, now(), error],
+  )
+  .unwrap();
+  tx.commit().unwrap();
+}
+
+async fn run_for(enrichment: &Enrichment, state: &State, tenant: &Tenant) {
+  let posts = pending(&*tenant.conn.lock().await, enrichment);
+  let tz = state.timezone.default();
+  for (post_id, location, created_at) in posts {
+    let date = Utc
+      .timestamp(created_at, 0)
+      .with_timezone(&tz)
+      .format("%Y-%m-%d")
+      .to_string();
+    let result = enrichment.provider.lookup(location, &date).await;
+    if let Err(e) = &result {
+      eprintln!("enrich error {} for {}", e, post_id);
+    }
+    save(
+      &mut *tenant.conn.lock().await,
+      enrichment.provider.name(),
+      &post_id,
+      location,
+      result,
+    );
+  }
+}
+
+// 一定の間隔で開いているすべてのテナントの投稿に天気などを補う関数
+pub fn spawn(enrichment: Enrichment, state: Arc<State>) {
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(CHECK_SECONDS));
+    loop {
+      interval.tick().await;
+      if state.read_only.is_on() {
+        continue;
+      }
+      for tenant in state.tenants.all() {
+        run_for(&enrichment, &state, &tenant).await;
+      }
+    }
+  });
+}
@@ -0,0 +1,359 @@
+use std::sync::Arc;
+
+use chrono::{TimeZone, Utc};
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use rusqlite::params;
+
+use crate::{empty, notebook, preview, zip::Zip, State, Tenant, Visibility};
+
+const STYLE: &str = "body { line-height: 1.7; }
+h1 { font-size: 1.4em; margin-bottom: 0.2em; }
+p.date { color: #777; font-size: 0.8em; margin-top: 0; }
+pre { white-space: pre-wrap; font-size: 0.85em; }
+blockquote { color: #555; margin-left: 1em; }
+";
+
+struct Chapter {
+  file: String,
+  title: String,
+  date: Option<String>,
+  html: String,
+}
+
+// XMLの本文や属性に入れる文字をエスケープする関数
+fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+// Content-Dispositionのfilename*に使うパーセントエンコーディング
+pub fn percent_encode(text: &str) -> String {
+  text
+    .bytes()
+    .map(|b| match b {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+        (b as char).to_string()
+      }
+      _ => format!("%{:02X}", b),
+    })
+    .collect()
+}
+
+fn chapter(chapter: &Chapter, lang: &str) -> String {
+  let date = chapter
+    .date
+    .as_ref()
+    .map(|date| format!("<p class=\"date\">{}</p>\n", date))
+    .unwrap_or_default();
+  format!(
+    r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" lang="{lang}" xml:lang="{lang}">
+<head>
+<meta charset="UTF-8"/>
+<title>{title}</title>
+<link rel="stylesheet" type="text/css" href="style.css"/>
+</head>
+<body>
+<section epub:type="chapter">
+<h1>{title}</h1>
+{date}{html}</section>
+</body>
+</html>
+"#,
+    lang = lang,
+    title = escape(&chapter.title),
+    date = date,
+    html = chapter.html
+  )
+}
+
+// 目次（EPUB 3のナビゲーション文書）
+fn nav(title: &str, chapters: &[Chapter], lang: &str) -> String {
+  let items = chapters
+    .iter()
+    .map(|chapter| {
+      format!(
+        "<li><a href=\"{}\">{}</a></li>\n",
+        chapter.file,
+        escape(&chapter.title)
+      )
+    })
+    .collect::<String>();
+  format!(
+    r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" lang="{lang}" xml:lang="{lang}">
+<head>
+<meta charset="UTF-8"/>
+<title>{title}</title>
+</head>
+<body>
+<nav epub:type="toc" id="toc">
+<h1>{title}</h1>
+<ol>
+{items}</ol>
+</nav>
+</body>
+</html>
+"#,
+    lang = lang,
+    title = escape(title),
+    items = items
+  )
+}
+
+// EPUB 2のリーダー向けの目次
+fn ncx(identifier: &str, title: &str, chapters: &[Chapter]) -> String {
+  let points = chapters
+    .iter()
+    .enumerate()
+    .map(|(i, chapter)| {
+      format!(
+        "<navPoint id=\"p{n}\" playOrder=\"{n}\"><navLabel><text>{}</text></navLabel><content src=\"{}\"/></navPoint>\n",
+        escape(&chapter.title),
+        chapter.file,
+        n = i + 1
+      )
+    })
+    .collect::<String>();
+  format!(
+    r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+<head>
+<meta name="dtb:uid" content="{identifier}"/>
+<meta name="dtb:depth" content="1"/>
+</head>
+<docTitle><text>{title}</text></docTitle>
+<navMap>
+{points}</navMap>
+</ncx>
+"#,
+    identifier = escape(identifier),
+    title = escape(title),
+    points = points
+  )
+}
+
+fn package(identifier: &str, title: &str, chapters: &[Chapter], lang: &str) -> String {
+  let manifest = chapters
+    .iter()
+    .enumerate()
+    .map(|(i, chapter)| {
+      // 画像はURLのまま参照するので，外部の資源を使う章として宣言する
+      let properties = if chapter.html.contains("<img src=\"http") {
+        " properties=\"remote-resources\""
+      } else {
+        ""
+      };
+      format!(
+        "<item id=\"c{}\" href=\"{}\" media-type=\"application/xhtml+xml\"{}/>\n",
+        i + 1,
+        chapter.file,
+        properties
+      )
+    })
+    .collect::<String>();
+  let spine = (1..=chapters.len())
+    .map(|i| format!("<itemref idref=\"c{}\"/>\n", i))
+    .collect::<String>();
+  format!(
+    r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id" xml:lang="{lang}">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+<dc:identifier id="id">{identifier}</dc:identifier>
+<dc:title>{title}</dc:title>
+<dc:language>{lang}</dc:language>
+<meta property="dcterms:modified">{modified}</meta>
+</metadata>
+<manifest>
+<item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+<item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+<item id="style" href="style.css" media-type="text/css"/>
+{manifest}</manifest>
+<spine toc="ncx">
+<itemref idref="nav"/>
+{spine}</spine>
+</package>
+"#,
+    lang = lang,
+    identifier = escape(identifier),
+    title = escape(title),
+    modified = Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+    manifest = manifest,
+    spine = spine
+  )
+}
+
+// 章をまとめたEPUBのファイルを作る関数
+fn book(identifier: &str, name: &str, chapters: &[Chapter], lang: &str) -> Vec<u8> {
+  let mut zip = Zip::new();
+  // mimetypeは先頭に置く決まり
+  zip.add("mimetype", b"application/epub+zip");
+  zip.add(
+    "META-INF/container.xml",
+    br#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+<rootfiles>
+<rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+</rootfiles>
+</container>
+"#,
+  );
+  zip.add(
+    "OEBPS/content.opf",
+    package(identifier, name, chapters, lang).as_bytes(),
+  );
+  zip.add("OEBPS/nav.xhtml", nav(name, chapters, lang).as_bytes());
+  zip.add("OEBPS/toc.ncx", ncx(identifier, name, chapters).as_bytes());
+  zip.add("OEBPS/style.css", STYLE.as_bytes());
+  for chapter in chapters {
+    zip.add(
+      &format!("OEBPS/{}", chapter.file),
+      self::chapter(chapter, lang).as_bytes(),
+    );
+  }
+  zip.finish()
+}
+
+// ノートブックの公開している投稿を1冊のEPUBにまとめる関数
+// 投稿を作成した順に章として並べ，目次を付ける
+pub async fn export(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  name: &str,
+) -> Result<Response<Body>, Error> {
+  let tz = state.timezone.current(&req);
+  let lang = state.i18n.locale(&req).to_string();
+  let conn = tenant.conn.lock().await;
+  let id = match notebook::id_by_name(&conn, name) {
+    Some(id) => id,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let mut stmt = conn
+    .prepare(
+      "SELECT title, content, encrypted, compressed, created_at FROM posts
+      WHERE notebook_id=?1 AND kind = 'text' AND visibility=?2 AND trashed_at IS NULL
+      ORDER BY created_at IS NULL, created_at, rowid",
+    )
+    .unwrap();
+  let chapters = stmt
+    .query_map(params![id, Visibility::Public], |row| {
+      let content = state.codec.decode(row.get(1)?, row.get(2)?, row.get(3)?);
+      let created_at: Option<i64> = row.get(4)?;
+      Ok((row.get::<_, String>(0)?, content, created_at))
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap()
+    .into_iter()
+    .enumerate()
+    .map(|(i, (title, content, created_at))| Chapter {
+      file: format!("post-{:04}.xhtml", i + 1),
+      title,
+      date: created_at.map(|created_at| {
+        Utc
+          .timestamp(created_at, 0)
+          .with_timezone(&tz)
+          .format("%Y-%m-%d")
+          .to_string()
+      }),
+      html: preview::render(&content),
+    })
+    .collect::<Vec<_>>();
+  drop(stmt);
+  drop(conn);
+  // 目次が空のEPUBは作れない
+  if chapters.is_empty() {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  let identifier = format!(
+    "{}{}/notebooks/{}",
+    state.base_url.origin(&req),
+    tenant.prefix,
+    percent_encode(name)
+  );
+  Ok(
+    Response::builder()
+      .header(header::CONTENT_TYPE, "application/epub+zip")
+      .header(
+        header::CONTENT_DISPOSITION,
+        format!(
+          "attachment; filename=\"notebook.epub\"; filename*=UTF-8''{}.epub",
+          percent_encode(name)
+        ),
+      )
+      .body(book(&identifier, name, &chapters, &lang).into())
+      .unwrap(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn chapters() -> Vec<Chapter> {
+    vec![
+      Chapter {
+        file: "post-0001.xhtml".to_string(),
+        title: "A & <B>".to_string(),
+        date: Some("2026-01-02".to_string()),
+        html: "<p>one</p>\n".to_string(),
+      },
+      Chapter {
+        file: "post-0002.xhtml".to_string(),
+        title: "Two".to_string(),
+        date: None,
+        html: "<p><img src=\"https://example.com/a.png\" /></p>\n".to_string(),
+      },
+    ]
+  }
+
+  #[test]
+  fn escapes_titles_and_marks_remote_resources() {
+    let chapters = chapters();
+    let opf = package("urn:x", "Note \"book\"", &chapters, "ja");
+    assert!(opf.contains("<dc:title>Note &quot;book&quot;</dc:title>"));
+    assert!(opf
+      .contains("<item id=\"c1\" href=\"post-0001.xhtml\" media-type=\"application/xhtml+xml\"/>"));
+    assert!(opf.contains("href=\"post-0002.xhtml\" media-type=\"application/xhtml+xml\" properties=\"remote-resources\"/>"));
+    assert!(opf.contains("<itemref idref=\"c1\"/>\n<itemref idref=\"c2\"/>"));
+    let nav = nav("Book", &chapters, "ja");
+    assert!(nav.contains("<li><a href=\"post-0001.xhtml\">A &amp; &lt;B&gt;</a></li>"));
+    let ncx = ncx("urn:x", "Book", &chapters);
+    assert!(ncx.contains("playOrder=\"2\""));
+    let first = chapter(&chapters[0], "ja");
+    assert!(
+      first.contains("<h1>A &amp; &lt;B&gt;</h1>\n<p class=\"date\">2026-01-02</p>\n<p>one</p>")
+    );
+    assert!(!chapter(&chapters[1], "ja").contains("class=\"date\""));
+  }
+
+  #[test]
+  fn encodes_file_names() {
+    assert_eq!(percent_encode("日記 a-b"), "%E6%97%A5%E8%A8%98%20a-b");
+  }
+
+  #[test]
+  fn puts_mimetype_first() {
+    let book = book("urn:x", "Book", &chapters(), "ja");
+    assert_eq!(&book[30..38], b"mimetype");
+    assert_eq!(&book[38..58], b"application/epub+zip");
+    let text = String::from_utf8_lossy(&book);
+    for name in [
+      "META-INF/container.xml",
+      "OEBPS/content.opf",
+      "OEBPS/nav.xhtml",
+      "OEBPS/toc.ncx",
+      "OEBPS/style.css",
+      "OEBPS/post-0001.xhtml",
+      "OEBPS/post-0002.xhtml",
+    ] {
+      assert!(text.contains(name), "{}", name);
+    }
+  }
+}
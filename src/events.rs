@@ -0,0 +1,118 @@
+use std::{sync::Arc, time::Duration};
+
+use hyper::{body::Bytes, header, Body, Error, Response};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::Tenant;
+
+// 受け取る側が追いつかない場合に溜めておくイベントの数
+const CHANNEL_CAPACITY: usize = 256;
+// 接続を切られないように/eventsでコメントを送る間隔
+const KEEPALIVE_SECONDS: u64 = 30;
+
+// 投稿に起きた出来事
+// 変更をコミットした後にTenant::publishで知らせる
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+  Created { id: Uuid },
+  Updated { id: Uuid },
+  Moved { id: Uuid },
+  Trashed { id: Uuid },
+  Purged { id: Uuid },
+}
+
+impl Event {
+  // 出来事の起きた投稿
+  pub fn id(&self) -> Uuid {
+    match self {
+      Event::Created { id }
+      | Event::Updated { id }
+      | Event::Moved { id }
+      | Event::Trashed { id }
+      | Event::Purged { id } => *id,
+    }
+  }
+}
+
+// どのテナントで起きたかを付けたイベント
+#[derive(Clone, Debug, Serialize)]
+pub struct Published {
+  pub tenant: String,
+  #[serde(flatten)]
+  pub event: Event,
+}
+
+// すべてのテナントのイベントを受け取る側に配る仕組み
+// 索引やキャッシュはTenant::publishの中で捨てるので，ここで配るのは後から非同期に処理してよいものだけ
+// （Webhookの送信，/eventsへの配信など）
+#[derive(Clone)]
+pub struct Bus(broadcast::Sender<Arc<Published>>);
+
+impl Bus {
+  pub fn new() -> Bus {
+    Bus(broadcast::channel(CHANNEL_CAPACITY).0)
+  }
+
+  // 受け取る側がいなくても失敗にしない
+  pub fn send(&self, tenant: &str, event: Event) {
+    let _ = self.0.send(Arc::new(Published {
+      tenant: tenant.to_string(),
+      event,
+    }));
+  }
+
+  pub fn subscribe(&self) -> broadcast::Receiver<Arc<Published>> {
+    self.0.subscribe()
+  }
+}
+
+// GET /events でテナントのイベントをServer-Sent Eventsとして送り続ける関数
+// 追いつけずに取りこぼした場合はlaggedを送るので，受け取る側は一覧を取り直す
+pub async fn stream(tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let mut events = tenant.events.subscribe();
+  let (mut sender, body) = Body::channel();
+  let name = tenant.name.clone();
+  // 接続が続いている間テナントを開いたままにしないように，名前だけを持つ
+  drop(tenant);
+  tokio::spawn(async move {
+    let mut keepalive = tokio::time::interval(Duration::from_secs(KEEPALIVE_SECONDS));
+    loop {
+      let chunk = tokio::select! {
+        received = events.recv() => match received {
+          Ok(published) if published.tenant == name => format!(
+            "event: {}\ndata: {}\n\n",
+            event_name(&published.event),
+            serde_json::to_string(&*published).unwrap()
+          ),
+          Ok(_) => continue,
+          Err(broadcast::error::RecvError::Lagged(_)) => "event: lagged\ndata: {}\n\n".to_string(),
+          Err(broadcast::error::RecvError::Closed) => break,
+        },
+        _ = keepalive.tick() => ":\n\n".to_string(),
+      };
+      if sender.send_data(Bytes::from(chunk)).await.is_err() {
+        break;
+      }
+    }
+  });
+  Ok(
+    Response::builder()
+      .header(header::CONTENT_TYPE, "text/event-stream")
+      .header(header::CACHE_CONTROL, "no-cache")
+      .body(body)
+      .unwrap(),
+  )
+}
+
+fn event_name(event: &Event) -> &'static str {
+  match event {
+    Event::Created { .. } => "created",
+    Event::Updated { .. } => "updated",
+    Event::Moved { .. } => "moved",
+    Event::Trashed { .. } => "trashed",
+    Event::Purged { .. } => "purged",
+  }
+}
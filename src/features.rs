@@ -0,0 +1,149 @@
+use std::{
+  collections::{BTreeMap, BTreeSet, HashMap},
+  env,
+  sync::Arc,
+};
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use tera::{Function, Tera, Value};
+
+use crate::{empty, json, State, Tenant};
+
+// 試験中の機能を再コンパイルせずに有効にするための設定
+// 配備ごとの値を環境変数で決め，テナントごとにDBの値で上書きできる
+pub struct Features {
+  configured: HashMap<String, bool>,
+}
+
+impl Features {
+  // FEATURES=graph,-federation のように有効にする機能を並べる（-を付けると無効）
+  // 指定しなかった機能は無効として扱う
+  pub fn from_env() -> Arc<Features> {
+    let configured = env::var("FEATURES")
+      .unwrap_or_default()
+      .split(',')
+      .map(str::trim)
+      .filter(|name| !name.is_empty())
+      .map(|name| match name.strip_prefix('-') {
+        Some(name) => (name.to_string(), false),
+        None => (name.to_string(), true),
+      })
+      .collect();
+    Arc::new(Features { configured })
+  }
+
+  fn configured(&self, name: &str) -> bool {
+    self.configured.get(name).copied().unwrap_or(false)
+  }
+}
+
+// DBの上書きを優先して機能が有効かを判定する関数
+// 処理の中からはこの関数で確かめる
+pub fn enabled(features: &Features, conn: &Connection, name: &str) -> bool {
+  conn
+    .query_row(
+      "SELECT enabled FROM feature_overrides WHERE name=?1",
+      params![name],
+      |row| row.get(0),
+    )
+    .optional()
+    .unwrap()
+    .unwrap_or_else(|| features.configured(name))
+}
+
+// 設定か上書きのどちらかにあるすべての機能の状態を返す関数
+pub async fn list(state: Arc<State>, tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let conn = tenant.conn.lock().await;
+  let mut stmt = conn.prepare("SELECT name FROM feature_overrides").unwrap();
+  let mut names = stmt
+    .query_map([], |row| row.get(0))
+    .unwrap()
+    .collect::<Result<BTreeSet<String>, _>>()
+    .unwrap();
+  names.extend(state.features.configured.keys().cloned());
+  let all: BTreeMap<_, _> = names
+    .into_iter()
+    .map(|name| {
+      let on = enabled(&state.features, &conn, &name);
+      (name, on)
+    })
+    .collect();
+  Ok(json(&all))
+}
+
+#[derive(Deserialize)]
+struct Query {
+  enabled: bool,
+}
+
+// PUT /admin/features/{name}?enabled=true でこのテナントの値を上書きする関数
+pub async fn set(
+  req: Request<Body>,
+  tenant: Arc<Tenant>,
+  name: &str,
+) -> Result<Response<Body>, Error> {
+  let query = match serde_urlencoded::from_str::<Query>(req.uri().query().unwrap_or_default()) {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let conn = tenant.conn.lock().await;
+  conn
+    .execute(
+      "INSERT INTO feature_overrides(name, enabled) VALUES (?1,?2)
+      ON CONFLICT(name) DO UPDATE SET enabled=excluded.enabled",
+      params![name, query.enabled],
+    )
+    .unwrap();
+  Ok(empty(StatusCode::NO_CONTENT))
+}
+
+// 上書きを取り消して設定の値に戻す関数
+pub async fn reset(tenant: Arc<Tenant>, name: &str) -> Result<Response<Body>, Error> {
+  let conn = tenant.conn.lock().await;
+  let deleted = conn
+    .execute("DELETE FROM feature_overrides WHERE name=?1", params![name])
+    .unwrap();
+  if deleted == 0 {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  Ok(empty(StatusCode::NO_CONTENT))
+}
+
+// テンプレートから{% if feature_enabled(name="graph") %}で呼び出すヘルパー
+// テンプレートはテナントのDBを参照できないので，配備ごとの設定だけで判定する
+struct Enabled(Arc<Features>);
+
+impl Function for Enabled {
+  fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let name = args
+      .get("name")
+      .and_then(Value::as_str)
+      .ok_or("feature_enabled requires a name")?;
+    Ok(Value::Bool(self.0.configured(name)))
+  }
+}
+
+// テンプレートヘルパーを登録する関数
+pub fn register(tera: &mut Tera, features: Arc<Features>) {
+  tera.register_function("feature_enabled", Enabled(features));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests;
+
+  #[tokio::test]
+  async fn rejects_bad_query() {
+    let state = tests::state();
+    for query in ["", "?enabled=yes"] {
+      let req = Request::put(format!("/admin/features/search{}", query))
+        .body(Body::empty())
+        .unwrap();
+      let res = set(req, tests::tenant(&state), "search").await.unwrap();
+      assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+  }
+}
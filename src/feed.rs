@@ -0,0 +1,298 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{TimeZone, Utc};
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{header_str, hex, preview, State, Tenant, Visibility};
+
+// フィードに載せる投稿の数
+pub const FEED_ENTRIES: usize = 20;
+// フィードリーダーやクローラーにキャッシュさせる時間（秒）
+// 変更はETagで確かめられるので短くする
+const MAX_AGE: u64 = 60;
+
+// フィードに載せる投稿
+pub struct Entry<'a> {
+  pub id: Uuid,
+  pub title: &'a str,
+  pub html: &'a str,
+  pub created_at: Option<i64>,
+  // 投稿を表示するページの絶対URL
+  pub url: String,
+}
+
+// XMLの本文や属性に入れる文字をエスケープする関数
+fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+fn timestamp(seconds: i64) -> String {
+  Utc
+    .timestamp(seconds, 0)
+    .format("%Y-%m-%dT%H:%M:%SZ")
+    .to_string()
+}
+
+// Atomのフィードを作る関数（entriesは新しい投稿から順に並べる）
+// homeは一覧のページの絶対URL
+pub fn atom(title: &str, home: &str, self_url: &str, entries: &[Entry]) -> String {
+  let updated = entries
+    .iter()
+    .filter_map(|entry| entry.created_at)
+    .max()
+    .unwrap_or(0);
+  let items = entries
+    .iter()
+    .map(|entry| {
+      format!(
+        "<entry>\n<id>urn:uuid:{id}</id>\n<title>{title}</title>\n<link href=\"{url}\"/>\n<updated>{updated}</updated>\n<content type=\"html\">{content}</content>\n</entry>\n",
+        id = entry.id,
+        title = escape(entry.title),
+        url = escape(&entry.url),
+        updated = timestamp(entry.created_at.unwrap_or(updated)),
+        content = escape(entry.html)
+      )
+    })
+    .collect::<String>();
+  format!(
+    r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+<id>{home}</id>
+<title>{title}</title>
+<link href="{home}"/>
+<link rel="self" href="{self_url}"/>
+<updated>{updated}</updated>
+{items}</feed>
+"#,
+    home = escape(home),
+    title = escape(title),
+    self_url = escape(self_url),
+    updated = timestamp(updated),
+    items = items
+  )
+}
+
+// サイトマップを作る関数
+// urlsは絶対URLと，分かる場合は最後に変更した日時
+pub fn sitemap(urls: &[(String, Option<i64>)]) -> String {
+  let items = urls
+    .iter()
+    .map(|(url, modified)| {
+      let lastmod = modified
+        .map(|modified| format!("<lastmod>{}</lastmod>", timestamp(modified)))
+        .unwrap_or_default();
+      format!("<url><loc>{}</loc>{}</url>\n", escape(url), lastmod)
+    })
+    .collect::<String>();
+  format!(
+    r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+{}</urlset>
+"#,
+    items
+  )
+}
+
+// 作った文書と，内容から求めたETag
+struct Rendered {
+  // 絶対URLを作るのに使ったスキームとホスト
+  origin: String,
+  body: String,
+  etag: String,
+}
+
+impl Rendered {
+  fn new(origin: &str, body: String) -> Arc<Rendered> {
+    let etag = format!(
+      "\"{}\"",
+      &hex::encode(&Sha256::digest(body.as_bytes()))[..16]
+    );
+    Arc::new(Rendered {
+      origin: origin.to_string(),
+      body,
+      etag,
+    })
+  }
+}
+
+// フィードとサイトマップのキャッシュ
+// 数分ごとに取りに来るフィードリーダーのたびにDBを読まないように，投稿が変わるまで同じものを返す
+pub struct Cache {
+  feed: Mutex<Option<Arc<Rendered>>>,
+  sitemap: Mutex<Option<Arc<Rendered>>>,
+}
+
+impl Cache {
+  pub fn new() -> Cache {
+    Cache {
+      feed: Mutex::new(None),
+      sitemap: Mutex::new(None),
+    }
+  }
+
+  pub fn invalidate(&self) {
+    *self.feed.lock().unwrap() = None;
+    *self.sitemap.lock().unwrap() = None;
+  }
+}
+
+// キャッシュにあればそれを，なければ作ってキャッシュしたものを返す関数
+// BASE_URLを設定していない場合はHostによってURLが変わるので，スキームとホストが同じときだけ使い回す
+async fn cached(
+  slot: &Mutex<Option<Arc<Rendered>>>,
+  tenant: &Tenant,
+  origin: &str,
+  render: impl FnOnce(&Connection) -> String,
+) -> Arc<Rendered> {
+  if let Some(rendered) = &*slot.lock().unwrap() {
+    if rendered.origin == origin {
+      return rendered.clone();
+    }
+  }
+  // 作っている間に投稿が変わって古いものが残らないように，DBのロックを持ったままキャッシュする
+  let conn = tenant.conn.lock().await;
+  let rendered = Rendered::new(origin, render(&conn));
+  *slot.lock().unwrap() = Some(rendered.clone());
+  rendered
+}
+
+// If-None-Matchが一致すれば304を，しなければ文書を返す関数
+fn respond(req: &Request<Body>, rendered: &Rendered, content_type: &str) -> Response<Body> {
+  let builder = Response::builder()
+    .header(header::ETAG, &rendered.etag)
+    .header(
+      header::CACHE_CONTROL,
+      format!("public, max-age={}", MAX_AGE),
+    );
+  let not_modified = header_str(req, header::IF_NONE_MATCH)
+    .split(',')
+    .any(|tag| tag.trim() == rendered.etag || tag.trim() == "*");
+  if not_modified {
+    return builder
+      .status(StatusCode::NOT_MODIFIED)
+      .body(Body::empty())
+      .unwrap();
+  }
+  builder
+    .header(header::CONTENT_TYPE, content_type)
+    .body(rendered.body.clone().into())
+    .unwrap()
+}
+
+// GET /feed.xml で新しい公開の投稿をAtomのフィードで返す関数
+pub async fn show_feed(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+) -> Result<Response<Body>, Error> {
+  let origin = state.base_url.origin(&req);
+  let base = format!("{}{}", origin, tenant.prefix);
+  let rendered = cached(&tenant.feeds.feed, &tenant, &origin, |conn| {
+    let mut stmt = conn
+      .prepare(
+        "SELECT id, title, content, encrypted, compressed, created_at FROM posts
+        WHERE kind = 'text' AND visibility=?1 AND trashed_at IS NULL
+        ORDER BY created_at IS NULL, created_at DESC, rowid DESC LIMIT ?2",
+      )
+      .unwrap();
+    let posts = stmt
+      .query_map(params![Visibility::Public, FEED_ENTRIES], |row| {
+        let content = state.codec.decode(row.get(2)?, row.get(3)?, row.get(4)?);
+        Ok((
+          row.get::<_, Uuid>(0)?,
+          row.get::<_, String>(1)?,
+          preview::render(&content),
+          row.get::<_, Option<i64>>(5)?,
+        ))
+      })
+      .unwrap()
+      .collect::<Result<Vec<_>, _>>()
+      .unwrap();
+    let entries = posts
+      .iter()
+      .map(|(id, title, html, created_at)| Entry {
+        id: *id,
+        title,
+        html,
+        created_at: *created_at,
+        url: format!("{}/posts/{}", base, id),
+      })
+      .collect::<Vec<_>>();
+    let title = state
+      .i18n
+      .translate(state.i18n.default(), "site-title", None);
+    atom(
+      &title,
+      &format!("{}/", base),
+      &format!("{}/feed.xml", base),
+      &entries,
+    )
+  })
+  .await;
+  Ok(respond(
+    &req,
+    &rendered,
+    "application/atom+xml; charset=utf-8",
+  ))
+}
+
+// GET /sitemap.xml で公開の投稿とアーカイブのページのサイトマップを返す関数
+pub async fn show_sitemap(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+) -> Result<Response<Body>, Error> {
+  let origin = state.base_url.origin(&req);
+  let base = format!("{}{}", origin, tenant.prefix);
+  let rendered = cached(&tenant.feeds.sitemap, &tenant, &origin, |conn| {
+    let mut stmt = conn
+      .prepare(
+        "SELECT id, created_at FROM posts
+        WHERE kind = 'text' AND visibility=?1 AND trashed_at IS NULL
+        ORDER BY created_at IS NULL, created_at DESC, rowid DESC",
+      )
+      .unwrap();
+    let mut urls = stmt
+      .query_map(params![Visibility::Public], |row| {
+        Ok((
+          format!("{}/posts/{}", base, row.get::<_, Uuid>(0)?),
+          row.get(1)?,
+        ))
+      })
+      .unwrap()
+      .collect::<Result<Vec<_>, _>>()
+      .unwrap();
+    // 作成日時はアーカイブと同じくUTCで期間に分ける
+    let mut stmt = conn
+      .prepare(
+        "SELECT DISTINCT strftime('%Y', created_at, 'unixepoch') FROM posts
+        WHERE kind = 'text' AND visibility=?1 AND trashed_at IS NULL AND created_at IS NOT NULL
+        UNION SELECT DISTINCT strftime('%Y/%m', created_at, 'unixepoch') FROM posts
+        WHERE kind = 'text' AND visibility=?1 AND trashed_at IS NULL AND created_at IS NOT NULL
+        ORDER BY 1",
+      )
+      .unwrap();
+    urls.extend(
+      stmt
+        .query_map(params![Visibility::Public], |row| {
+          Ok((
+            format!("{}/archive/{}", base, row.get::<_, String>(0)?),
+            None,
+          ))
+        })
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap(),
+    );
+    sitemap(&urls)
+  })
+  .await;
+  Ok(respond(&req, &rendered, "application/xml; charset=utf-8"))
+}
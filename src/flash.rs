@@ -0,0 +1,74 @@
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Arc,
+};
+
+use hyper::{header, Body, Request, Response};
+
+use crate::{cookie, signer::Signer};
+
+// 一度だけ表示するメッセージを入れるクッキーの名前
+const COOKIE: &str = "flash";
+
+// 書き込みの後の画面で一度だけ表示するメッセージ（「投稿しました」など）
+// 値は翻訳のキーで，改ざんされないように署名してクッキーに入れる
+// 画面に表示したらクッキーを消すので，再読み込みしても繰り返し表示されない
+#[derive(Clone)]
+pub struct Flash {
+  message: Option<String>,
+  shown: Arc<AtomicBool>,
+}
+
+impl Flash {
+  // リクエストのクッキーから署名が正しいメッセージを取り出す関数
+  pub fn read(req: &Request<Body>, signer: &Signer) -> Flash {
+    Flash {
+      message: cookie(req, COOKIE).and_then(|signed| signer.verify(&signed).map(str::to_string)),
+      shown: Arc::new(AtomicBool::new(false)),
+    }
+  }
+
+  // 表示したメッセージのクッキーを消す関数
+  // 同じレスポンスで新しいメッセージを設定した場合はそちらを残す
+  pub fn finish(&self, res: &mut Response<Body>) {
+    if !self.shown.load(Ordering::Relaxed) {
+      return;
+    }
+    let replaced = res
+      .headers()
+      .get_all(header::SET_COOKIE)
+      .iter()
+      .any(|v| v.as_bytes().starts_with(format!("{}=", COOKIE).as_bytes()));
+    if !replaced {
+      res.headers_mut().append(
+        header::SET_COOKIE,
+        format!("{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax", COOKIE)
+          .parse()
+          .unwrap(),
+      );
+    }
+  }
+}
+
+// 画面に表示するメッセージを取り出す関数
+// 取り出したものは表示したとみなしてレスポンスでクッキーを消す
+pub fn take(req: &Request<Body>) -> Option<String> {
+  let flash = req.extensions().get::<Flash>()?;
+  let message = flash.message.clone()?;
+  flash.shown.store(true, Ordering::Relaxed);
+  Some(message)
+}
+
+// 次に表示する画面のためのメッセージを設定する関数
+pub fn set(res: &mut Response<Body>, signer: &Signer, key: &str) {
+  res.headers_mut().append(
+    header::SET_COOKIE,
+    format!(
+      "{}={}; Path=/; HttpOnly; SameSite=Lax",
+      COOKIE,
+      signer.sign(key)
+    )
+    .parse()
+    .unwrap(),
+  );
+}
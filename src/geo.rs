@@ -0,0 +1,169 @@
+use std::{convert::TryInto, sync::Arc};
+
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Row};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{empty, json, Tenant, Visibility};
+
+// 1回で返す投稿の数の初期値と上限
+const DEFAULT_LIMIT: u32 = 500;
+const MAX_LIMIT: u32 = 5000;
+
+// 投稿した場所（世界測地系の度）
+#[derive(Serialize, Clone, Copy)]
+pub struct Location {
+  pub lat: f64,
+  pub lon: f64,
+}
+
+impl Location {
+  // 緯度と経度を確かめる関数
+  // どちらも省略した場合はNone，片方だけや範囲外の場合は理由を返す
+  pub fn new(lat: Option<f64>, lon: Option<f64>) -> Result<Option<Location>, String> {
+    match (lat, lon) {
+      (None, None) => Ok(None),
+      (Some(lat), Some(lon)) => {
+        if !(-90.0..=90.0).contains(&lat) {
+          return Err("lat must be between -90 and 90".to_string());
+        }
+        if !(-180.0..=180.0).contains(&lon) {
+          return Err("lon must be between -180 and 180".to_string());
+        }
+        Ok(Some(Location { lat, lon }))
+      }
+      _ => Err("lat and lon must be given together".to_string()),
+    }
+  }
+
+  // lat, lonの順に並んだ列から取り出す関数
+  pub fn from_row(row: &Row, start: usize) -> rusqlite::Result<Option<Location>> {
+    let lat: Option<f64> = row.get(start)?;
+    let lon: Option<f64> = row.get(start + 1)?;
+    Ok(match (lat, lon) {
+      (Some(lat), Some(lon)) => Some(Location { lat, lon }),
+      _ => None,
+    })
+  }
+}
+
+#[derive(Deserialize)]
+struct Query {
+  // 西端の経度,南端の緯度,東端の経度,北端の緯度（GeoJSONのbboxと同じ順）
+  bbox: Option<String>,
+  limit: Option<u32>,
+}
+
+// 範囲を表す4つの数を読む関数
+// 西端が東端より大きい場合は日付変更線をまたぐ範囲とみなす
+fn bbox(value: &str) -> Option<[f64; 4]> {
+  let values = value
+    .split(',')
+    .map(|value| value.trim().parse::<f64>().ok())
+    .collect::<Option<Vec<_>>>()?;
+  let [west, south, east, north]: [f64; 4] = values.try_into().ok()?;
+  let lon = -180.0..=180.0;
+  let lat = -90.0..=90.0;
+  if lon.contains(&west)
+    && lon.contains(&east)
+    && lat.contains(&south)
+    && lat.contains(&north)
+    && south <= north
+  {
+    Some([west, south, east, north])
+  } else {
+    None
+  }
+}
+
+#[derive(Serialize)]
+struct FeatureCollection {
+  #[serde(rename = "type")]
+  kind: &'static str,
+  features: Vec<Feature>,
+}
+
+#[derive(Serialize)]
+struct Feature {
+  #[serde(rename = "type")]
+  kind: &'static str,
+  id: Uuid,
+  geometry: Point,
+  properties: Properties,
+}
+
+#[derive(Serialize)]
+struct Point {
+  #[serde(rename = "type")]
+  kind: &'static str,
+  // GeoJSONの座標は経度，緯度の順
+  coordinates: [f64; 2],
+}
+
+#[derive(Serialize)]
+struct Properties {
+  title: String,
+  url: String,
+  created_at: Option<i64>,
+}
+
+// GET /map?bbox=... で場所のある公開の投稿をGeoJSONのFeatureCollectionとして返す関数
+// 新しい順に返し，範囲を指定した場合はその中にある投稿だけを返す
+pub async fn show(req: Request<Body>, tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let query = match serde_urlencoded::from_str::<Query>(req.uri().query().unwrap_or_default()) {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let bbox = match query.bbox.as_deref().map(bbox) {
+    Some(None) => return Ok(empty(StatusCode::BAD_REQUEST)),
+    Some(Some(bbox)) => Some(bbox),
+    None => None,
+  };
+  let [west, south, east, north] = bbox.unwrap_or([-180.0, -90.0, 180.0, 90.0]);
+  let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+  let conn = tenant.conn.lock().await;
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, title, created_at, lat, lon FROM posts
+      WHERE lat IS NOT NULL AND lon IS NOT NULL AND visibility=?1 AND trashed_at IS NULL
+        AND lat BETWEEN ?2 AND ?3
+        AND (CASE WHEN ?4 <= ?5 THEN lon BETWEEN ?4 AND ?5 ELSE lon >= ?4 OR lon <= ?5 END)
+      ORDER BY created_at DESC, rowid DESC
+      LIMIT ?6",
+    )
+    .unwrap();
+  let features = stmt
+    .query_map(
+      params![Visibility::Public, south, north, west, east, limit],
+      |row| {
+        let id: Uuid = row.get(0)?;
+        let location = Location::from_row(row, 3)?.unwrap();
+        Ok(Feature {
+          kind: "Feature",
+          id,
+          geometry: Point {
+            kind: "Point",
+            coordinates: [location.lon, location.lat],
+          },
+          properties: Properties {
+            title: row.get(1)?,
+            url: format!("{}/posts/{}", tenant.prefix, id),
+            created_at: row.get(2)?,
+          },
+        })
+      },
+    )
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  let mut res = json(&FeatureCollection {
+    kind: "FeatureCollection",
+    features,
+  });
+  res.headers_mut().insert(
+    header::CONTENT_TYPE,
+    "application/geo+json".parse().unwrap(),
+  );
+  Ok(res)
+}
@@ -0,0 +1,13 @@
+// バイト列を16進数の文字列に変換する関数
+pub fn encode(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// 16進数の文字列をバイト列に戻す関数
+// 奇数桁や16進数でない文字が含まれていればNoneを返す
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+  (0..s.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+    .collect()
+}
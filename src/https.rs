@@ -0,0 +1,130 @@
+use std::{
+  future::Future,
+  io,
+  net::{IpAddr, SocketAddr},
+  pin::Pin,
+  task::{Context, Poll},
+  time::Duration,
+};
+
+use hyper::{
+  client::{
+    connect::dns::{GaiResolver, Name},
+    HttpConnector,
+  },
+  service::Service,
+  Client,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+
+use crate::cidr::Cidr;
+
+// 外部サービスへの問い合わせに使うHTTPSクライアント
+pub type HttpsClient = Client<HttpsConnector<HttpConnector>>;
+// 利用者が書いたURLを取得するクライアント
+pub type PublicClient = Client<HttpsConnector<HttpConnector<PublicResolver>>>;
+
+// 接続に時間がかかる相手をあきらめるまでの時間
+const CONNECT_TIMEOUT_SECONDS: u64 = 3;
+
+// 外から指定されたURLで接続させないアドレスの範囲
+// 内部のサービスやクラウドのメタデータ（169.254.169.254）に届かないようにする
+const NON_PUBLIC: &[&str] = &[
+  "0.0.0.0/8",
+  "10.0.0.0/8",
+  "100.64.0.0/10",
+  "127.0.0.0/8",
+  "169.254.0.0/16",
+  "172.16.0.0/12",
+  "192.0.0.0/24",
+  "192.0.2.0/24",
+  "192.168.0.0/16",
+  "198.18.0.0/15",
+  "198.51.100.0/24",
+  "203.0.113.0/24",
+  "224.0.0.0/4",
+  "240.0.0.0/4",
+  "::/128",
+  "::1/128",
+  "64:ff9b::/96",
+  "100::/64",
+  "2001:db8::/32",
+  "fc00::/7",
+  "fe80::/10",
+  "ff00::/8",
+];
+
+// HTTPSのみを許可するクライアントを作成する関数
+// ルート証明書はバイナリに組み込まれたものを使う
+pub fn client() -> HttpsClient {
+  let https = HttpsConnectorBuilder::new()
+    .with_webpki_roots()
+    .https_only()
+    .enable_http1()
+    .build();
+  Client::builder().build(https)
+}
+
+// 運用者が設定したURLに送るためのHTTPとHTTPSのクライアントを作成する関数
+// 同じネットワーク内のサービスにも送れるように，HTTPと内部のアドレスも許可する
+pub fn trusted_client() -> HttpsClient {
+  let https = HttpsConnectorBuilder::new()
+    .with_webpki_roots()
+    .https_or_http()
+    .enable_http1()
+    .build();
+  Client::builder().build(https)
+}
+
+// インターネット上の公開アドレスかどうかを判定する関数
+pub fn is_public(ip: IpAddr) -> bool {
+  !NON_PUBLIC
+    .iter()
+    .any(|range| Cidr::parse(range).unwrap().contains(ip))
+}
+
+// 名前を解決した結果から公開アドレスだけを残すリゾルバ
+// 接続するアドレスそのものを確かめるので，確認した後に名前の指す先を変えられても内部に届かない
+#[derive(Clone)]
+pub struct PublicResolver(GaiResolver);
+
+impl Service<Name> for PublicResolver {
+  type Response = std::vec::IntoIter<SocketAddr>;
+  type Error = io::Error;
+  type Future = Pin<Box<dyn Future<Output = Result<Self::Response, io::Error>> + Send>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    self.0.poll_ready(cx)
+  }
+
+  fn call(&mut self, name: Name) -> Self::Future {
+    let resolving = self.0.call(name);
+    Box::pin(async move {
+      let addrs = resolving
+        .await?
+        .filter(|addr| is_public(addr.ip()))
+        .collect::<Vec<_>>();
+      if addrs.is_empty() {
+        return Err(io::Error::new(
+          io::ErrorKind::PermissionDenied,
+          "no public address",
+        ));
+      }
+      Ok(addrs.into_iter())
+    })
+  }
+}
+
+// 公開アドレスにだけ接続するHTTPとHTTPSのクライアントを作成する関数
+// アドレスを直接書いたURLは名前を解決しないので，呼び出す側でis_publicを確かめる
+pub fn public_client() -> PublicClient {
+  let mut http = HttpConnector::new_with_resolver(PublicResolver(GaiResolver::new()));
+  http.enforce_http(false);
+  http.set_connect_timeout(Some(Duration::from_secs(CONNECT_TIMEOUT_SECONDS)));
+  let https = HttpsConnectorBuilder::new()
+    .with_webpki_roots()
+    .https_or_http()
+    .enable_http1()
+    .wrap_connector(http);
+  Client::builder().build(https)
+}
@@ -0,0 +1,147 @@
+use std::{collections::HashMap, env, sync::Arc};
+
+use fluent_bundle::{concurrent::FluentBundle, FluentArgs, FluentResource, FluentValue};
+use hyper::{header, Body, Request};
+use include_dir::{include_dir, Dir};
+use tera::{Function, Tera, Value};
+use unic_langid::LanguageIdentifier;
+
+use crate::header_str;
+
+// 実行ファイルに組み込んだ言語ごとの文言（locales/ja.ftl など）
+static EMBEDDED: Dir = include_dir!("$CARGO_MANIFEST_DIR/locales");
+// LOCALEを指定しない場合の言語
+const DEFAULT_LOCALE: &str = "en";
+
+// 画面に表示する文言をFluent形式で言語ごとに持つ
+// ブラウザのAccept-Languageから言語を選び，文言がなければ既定の言語のものを使う
+pub struct I18n {
+  bundles: HashMap<String, FluentBundle<FluentResource>>,
+  default: String,
+}
+
+impl I18n {
+  // LOCALEで既定の言語を指定する（組み込みはenとja）
+  pub fn from_env() -> Arc<I18n> {
+    let mut bundles = HashMap::new();
+    for file in EMBEDDED.files() {
+      let path = file.path().to_string_lossy();
+      let locale = match path.strip_suffix(".ftl") {
+        Some(locale) => locale.to_string(),
+        None => continue,
+      };
+      let id: LanguageIdentifier = locale
+        .parse()
+        .unwrap_or_else(|_| panic!("invalid locale {}", locale));
+      let resource = FluentResource::try_new(file.contents_utf8().unwrap().to_string())
+        .unwrap_or_else(|(_, e)| panic!("invalid messages in {}: {:?}", path, e));
+      let mut bundle = FluentBundle::new_concurrent(vec![id]);
+      // HTMLに埋め込むので，引数の前後に方向を分離する制御文字を入れない
+      bundle.set_use_isolating(false);
+      bundle.add_resource(resource).unwrap();
+      bundles.insert(locale, bundle);
+    }
+    let default = env::var("LOCALE").unwrap_or_else(|_| DEFAULT_LOCALE.to_string());
+    if !bundles.contains_key(&default) {
+      panic!("unknown locale {}", default);
+    }
+    Arc::new(I18n { bundles, default })
+  }
+
+  // Accept-Language: ja-JP,ja;q=0.9,en;q=0.8 から使える言語を選ぶ関数
+  // ja-JPのように地域が付いていても，一致するものがなければjaとして扱う
+  pub fn locale(&self, req: &Request<Body>) -> &str {
+    let accept = header_str(req, header::ACCEPT_LANGUAGE);
+    let mut ranges: Vec<(&str, f32)> = accept
+      .split(',')
+      .filter_map(|range| {
+        let mut parts = range.split(';').map(str::trim);
+        let tag = parts.next().filter(|tag| !tag.is_empty() && *tag != "*")?;
+        let q = parts
+          .find_map(|param| param.strip_prefix("q="))
+          .map_or(Some(1.0), |q| q.parse().ok())?;
+        Some((tag, q))
+      })
+      .filter(|(_, q)| *q > 0.0)
+      .collect();
+    // 同じ重みの場合は並んでいる順を保つ
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranges
+      .iter()
+      .find_map(|(tag, _)| {
+        let tag = tag.to_ascii_lowercase();
+        let primary = tag.split('-').next().unwrap_or_default();
+        self
+          .bundles
+          .keys()
+          .find(|locale| locale.to_ascii_lowercase() == tag)
+          .or_else(|| self.bundles.keys().find(|locale| *locale == primary))
+      })
+      .unwrap_or(&self.default)
+  }
+
+  // リクエストによらない既定の言語（静的なサイトの書き出しに使う）
+  pub fn default(&self) -> &str {
+    &self.default
+  }
+
+  // 言語の文言に引数を埋め込む関数
+  // その言語に文言がなければ既定の言語のものを，どちらにもなければキーをそのまま返す
+  pub fn translate(&self, locale: &str, key: &str, args: Option<&FluentArgs>) -> String {
+    let found = [locale, self.default.as_str()].iter().find_map(|locale| {
+      let bundle = self.bundles.get(*locale)?;
+      Some((bundle, bundle.get_message(key)?.value()?))
+    });
+    let (bundle, pattern) = match found {
+      Some(found) => found,
+      None => return key.to_string(),
+    };
+    let mut errors = Vec::new();
+    bundle
+      .format_pattern(pattern, args, &mut errors)
+      .into_owned()
+  }
+}
+
+// テンプレートから{{ t(key="search-title", lang=lang) }}で呼び出すヘルパー
+// key，lang以外の引数は文言の{ $count }などに埋め込む
+struct Translate(Arc<I18n>);
+
+impl Function for Translate {
+  fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let key = args
+      .get("key")
+      .and_then(Value::as_str)
+      .ok_or("t requires a key")?;
+    let lang = args
+      .get("lang")
+      .and_then(Value::as_str)
+      .unwrap_or(&self.0.default);
+    let mut fluent_args = FluentArgs::new();
+    for (name, value) in args {
+      if name == "key" || name == "lang" {
+        continue;
+      }
+      let value = match value {
+        Value::Number(n) => FluentValue::from(n.as_f64().unwrap_or_default()),
+        Value::String(s) => FluentValue::from(s.clone()),
+        other => FluentValue::from(other.to_string()),
+      };
+      fluent_args.set(name.clone(), value);
+    }
+    Ok(Value::String(self.0.translate(
+      lang,
+      key,
+      Some(&fluent_args),
+    )))
+  }
+
+  fn is_safe(&self) -> bool {
+    true
+  }
+}
+
+// テンプレートヘルパーを登録する関数
+pub fn register(tera: &mut Tera, i18n: Arc<I18n>) {
+  tera.register_function("t", Translate(i18n));
+}
@@ -0,0 +1,668 @@
+use std::{borrow::Cow, env};
+
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::crc32;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_EXTENSION_HEADER: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+// EXIFのタグ
+const ORIENTATION: u16 = 0x0112;
+const GPS_IFD: u16 = 0x8825;
+// EXIFの型ごとの1つあたりのバイト数
+const TYPE_SIZES: [usize; 13] = [0, 1, 1, 2, 4, 8, 1, 1, 2, 4, 8, 4, 8];
+
+// 画像を保存するときに残すメタデータ
+#[derive(Clone, Copy, PartialEq)]
+pub enum Metadata {
+  // そのまま残す
+  Keep,
+  // 位置情報（EXIFのGPSとXMP）だけを取り除く
+  Location,
+  // 表示に必要な向き以外をすべて取り除く
+  Strip,
+}
+
+impl Metadata {
+  // ATTACHMENT_IMAGE_METADATAでkeep，location，strip（省略時）から選ぶ
+  pub fn from_env() -> Metadata {
+    match env::var("ATTACHMENT_IMAGE_METADATA").as_deref() {
+      Ok("keep") => Metadata::Keep,
+      Ok("location") => Metadata::Location,
+      Ok("strip") | Err(_) => Metadata::Strip,
+      Ok(other) => panic!("unknown ATTACHMENT_IMAGE_METADATA {}", other),
+    }
+  }
+}
+
+// 画像の表示される大きさ（向きを反映したもの）とEXIFの向き（1から8）
+pub struct Info {
+  pub width: u32,
+  pub height: u32,
+  pub orientation: u16,
+}
+
+// 画像のヘッダから大きさと向きを求める関数
+// 種類ごとに必要な部分だけを読み，求められない場合はNoneを返す
+pub fn info(kind: &str, data: &[u8]) -> Option<Info> {
+  let (width, height, orientation) = match kind {
+    "image/jpeg" => jpeg_info(data)?,
+    "image/png" => png_info(data)?,
+    "image/gif" => (read(data, 6, 2, true)?, read(data, 8, 2, true)?, None),
+    "image/webp" => webp_info(data)?,
+    _ => return None,
+  };
+  let orientation = orientation.unwrap_or(1);
+  // 5から8は90度回転して表示するので縦と横が入れ替わる
+  let (width, height) = if orientation >= 5 {
+    (height, width)
+  } else {
+    (width, height)
+  };
+  Some(Info {
+    width,
+    height,
+    orientation,
+  })
+}
+
+// 設定に合わせて画像からメタデータを取り除く関数
+// 画像でないものや読めない画像はそのまま返す
+pub fn strip(kind: &str, data: Vec<u8>, metadata: Metadata) -> Vec<u8> {
+  if metadata == Metadata::Keep {
+    return data;
+  }
+  let stripped = match kind {
+    "image/jpeg" => jpeg_strip(&data, metadata),
+    "image/png" => png_strip(&data, metadata),
+    "image/webp" => webp_strip(&data, metadata),
+    _ => None,
+  };
+  stripped.unwrap_or(data)
+}
+
+// テンプレートで<img>にする投稿の画像
+#[derive(Serialize)]
+pub struct Image {
+  pub id: Uuid,
+  pub url: String,
+  pub name: String,
+  pub width: u32,
+  pub height: u32,
+  pub content_type: String,
+  // 静的なサイトに書き出すときに中身を読む
+  #[serde(skip)]
+  pub key: String,
+}
+
+// 投稿に添付した画像のうち大きさのわかるものを古い順に返す関数
+pub fn for_post(conn: &Connection, prefix: &str, post_id: &Uuid) -> Vec<Image> {
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, name, width, height, content_type, storage_key FROM attachments
+      WHERE post_id=?1 AND width IS NOT NULL AND height IS NOT NULL
+      ORDER BY created_at, rowid",
+    )
+    .unwrap();
+  stmt
+    .query_map(params![post_id], |row| {
+      let id: Uuid = row.get(0)?;
+      Ok(Image {
+        id,
+        url: format!("{}/attachments/{}", prefix, id),
+        name: row.get(1)?,
+        width: row.get(2)?,
+        height: row.get(3)?,
+        content_type: row.get(4)?,
+        key: row.get(5)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap()
+}
+
+// 種類に合わせたファイルの拡張子
+pub fn extension(kind: &str) -> &'static str {
+  match kind {
+    "image/jpeg" => "jpg",
+    "image/png" => "png",
+    "image/gif" => "gif",
+    "image/webp" => "webp",
+    _ => "image",
+  }
+}
+
+// atから始まるlenバイト（4以下）の整数を読む関数
+fn read(data: &[u8], at: usize, len: usize, little_endian: bool) -> Option<u32> {
+  let bytes = data.get(at..at.checked_add(len)?)?;
+  let fold = |value: u32, b: &u8| value << 8 | u32::from(*b);
+  Some(if little_endian {
+    bytes.iter().rev().fold(0, fold)
+  } else {
+    bytes.iter().fold(0, fold)
+  })
+}
+
+// EXIFのTIFFの部分のバイト順（リトルエンディアンならtrue）
+fn byte_order(tiff: &[u8]) -> Option<bool> {
+  match tiff.get(..4)? {
+    b"II*\0" => Some(true),
+    b"MM\0*" => Some(false),
+    _ => None,
+  }
+}
+
+// IFDからタグの項目を探し，その位置を返す関数
+fn find_entry(tiff: &[u8], ifd: usize, tag: u16, le: bool) -> Option<usize> {
+  let count = read(tiff, ifd, 2, le)? as usize;
+  (0..count)
+    .map(|i| ifd + 2 + 12 * i)
+    .find(|&entry| read(tiff, entry, 2, le) == Some(u32::from(tag)))
+}
+
+fn orientation(tiff: &[u8]) -> Option<u16> {
+  let le = byte_order(tiff)?;
+  let ifd = read(tiff, 4, 4, le)? as usize;
+  let entry = find_entry(tiff, ifd, ORIENTATION, le)?;
+  let value = read(tiff, entry + 8, 2, le)? as u16;
+  if (1..=8).contains(&value) {
+    Some(value)
+  } else {
+    None
+  }
+}
+
+// 向きだけを持つTIFFの部分を作る関数
+fn orientation_only(orientation: u16) -> Vec<u8> {
+  let mut tiff = b"MM\0*\0\0\0\x08\0\x01".to_vec();
+  tiff.extend_from_slice(&ORIENTATION.to_be_bytes());
+  // SHORTを1つ
+  tiff.extend_from_slice(&[0, 3, 0, 0, 0, 1]);
+  tiff.extend_from_slice(&orientation.to_be_bytes());
+  tiff.extend_from_slice(&[0; 6]);
+  tiff
+}
+
+// GPSのIFDとそこから参照する値を0で埋める関数
+// 位置や大きさは変えないので，ほかのIFDはそのまま読める（GPSのIFDは空になる）
+fn without_location(tiff: &[u8]) -> Option<Vec<u8>> {
+  let mut tiff = tiff.to_vec();
+  let le = byte_order(&tiff)?;
+  let ifd = read(&tiff, 4, 4, le)? as usize;
+  // IFD0が読めることを確かめる
+  read(&tiff, ifd, 2, le)?;
+  let entry = match find_entry(&tiff, ifd, GPS_IFD, le) {
+    Some(entry) => entry,
+    None => return Some(tiff),
+  };
+  let gps = read(&tiff, entry + 8, 4, le)? as usize;
+  let count = read(&tiff, gps, 2, le)? as usize;
+  let end = gps + 2 + 12 * count + 4;
+  if end > tiff.len() {
+    return None;
+  }
+  for i in 0..count {
+    let entry = gps + 2 + 12 * i;
+    let kind = read(&tiff, entry + 2, 2, le)? as usize;
+    let size = TYPE_SIZES
+      .get(kind)
+      .copied()
+      .unwrap_or(0)
+      .saturating_mul(read(&tiff, entry + 4, 4, le)? as usize);
+    // 4バイトに収まらない値は別の場所に置かれている
+    if size > 4 {
+      let at = read(&tiff, entry + 8, 4, le)? as usize;
+      tiff.get_mut(at..at.checked_add(size)?)?.fill(0);
+    }
+  }
+  tiff[gps..end].fill(0);
+  Some(tiff)
+}
+
+// EXIFのTIFFの部分を設定に合わせて書き換える関数（残さない場合はNone）
+// 位置情報があるかどうか確かめられないものは残さない
+fn rewrite_exif(tiff: &[u8], metadata: Metadata) -> Option<Vec<u8>> {
+  match metadata {
+    Metadata::Keep => Some(tiff.to_vec()),
+    Metadata::Location => without_location(tiff),
+    Metadata::Strip => match orientation(tiff) {
+      Some(orientation) if orientation != 1 => Some(orientation_only(orientation)),
+      _ => None,
+    },
+  }
+}
+
+// JPEGのマーカーとセグメントの中身
+type Segment<'a> = (u8, &'a [u8]);
+
+// JPEG: 画像のデータ（SOS）より前のセグメントと，SOSの位置を返す関数
+fn jpeg_segments(data: &[u8]) -> Option<(Vec<Segment<'_>>, usize)> {
+  if !data.starts_with(&[0xff, 0xd8]) {
+    return None;
+  }
+  let mut at = 2;
+  let mut segments = Vec::new();
+  loop {
+    if *data.get(at)? != 0xff {
+      return None;
+    }
+    let marker = *data.get(at + 1)?;
+    match marker {
+      // 埋め草
+      0xff => at += 1,
+      0xda => return Some((segments, at)),
+      // 長さを持たないマーカー
+      0x01 | 0xd0..=0xd7 => at += 2,
+      _ => {
+        let len = read(data, at + 2, 2, false)? as usize;
+        if len < 2 {
+          return None;
+        }
+        segments.push((marker, data.get(at + 4..at + 2 + len)?));
+        at += 2 + len;
+      }
+    }
+  }
+}
+
+fn jpeg_info(data: &[u8]) -> Option<(u32, u32, Option<u16>)> {
+  let (segments, _) = jpeg_segments(data)?;
+  let mut size = None;
+  let mut orientation = None;
+  for (marker, payload) in segments {
+    match marker {
+      // DHT，JPG，DACを除くSOF
+      0xc4 | 0xc8 | 0xcc => {}
+      0xc0..=0xcf => {
+        size = Some((read(payload, 3, 2, false)?, read(payload, 1, 2, false)?));
+      }
+      0xe1 if payload.starts_with(EXIF_HEADER) => {
+        orientation = self::orientation(&payload[EXIF_HEADER.len()..]);
+      }
+      _ => {}
+    }
+  }
+  let (width, height) = size?;
+  Some((width, height, orientation))
+}
+
+fn jpeg_strip(data: &[u8], metadata: Metadata) -> Option<Vec<u8>> {
+  let (segments, sos) = jpeg_segments(data)?;
+  let mut out = data[..2].to_vec();
+  let mut push = |marker: u8, parts: &[&[u8]]| {
+    let len = 2 + parts.iter().map(|part| part.len()).sum::<usize>();
+    out.extend_from_slice(&[0xff, marker]);
+    out.extend_from_slice(&(len as u16).to_be_bytes());
+    for part in parts {
+      out.extend_from_slice(part);
+    }
+  };
+  for (marker, payload) in segments {
+    match marker {
+      0xe1 if payload.starts_with(EXIF_HEADER) => {
+        if let Some(tiff) = rewrite_exif(&payload[EXIF_HEADER.len()..], metadata) {
+          push(marker, &[EXIF_HEADER, &tiff]);
+        }
+      }
+      // XMPには位置情報も入る
+      0xe1 if payload.starts_with(XMP_HEADER) || payload.starts_with(XMP_EXTENSION_HEADER) => {}
+      // APP1のほかのもの，IPTC（APP13），コメント
+      0xe1 | 0xed | 0xfe if metadata == Metadata::Strip => {}
+      _ => push(marker, &[payload]),
+    }
+  }
+  out.extend_from_slice(&data[sos..]);
+  Some(out)
+}
+
+// PNG: IENDまでのチャンクの種類と中身を返す関数
+fn png_chunks(data: &[u8]) -> Option<Vec<(&[u8], &[u8])>> {
+  if !data.starts_with(b"\x89PNG\r\n\x1a\n") {
+    return None;
+  }
+  let mut at = 8;
+  let mut chunks = Vec::new();
+  loop {
+    let len = read(data, at, 4, false)? as usize;
+    let kind = data.get(at + 4..at + 8)?;
+    chunks.push((kind, data.get(at + 8..(at + 8).checked_add(len)?)?));
+    at += 12 + len;
+    if kind == b"IEND" {
+      return Some(chunks);
+    }
+  }
+}
+
+fn png_info(data: &[u8]) -> Option<(u32, u32, Option<u16>)> {
+  let chunks = png_chunks(data)?;
+  let (kind, header) = chunks.first()?;
+  if *kind != b"IHDR" {
+    return None;
+  }
+  let orientation = chunks
+    .iter()
+    .find(|(kind, _)| *kind == b"eXIf")
+    .and_then(|(_, tiff)| orientation(tiff));
+  Some((
+    read(header, 0, 4, false)?,
+    read(header, 4, 4, false)?,
+    orientation,
+  ))
+}
+
+fn png_strip(data: &[u8], metadata: Metadata) -> Option<Vec<u8>> {
+  let chunks = png_chunks(data)?;
+  let mut out = data[..8].to_vec();
+  let mut push = |kind: &[u8], body: &[u8]| {
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    let crc = crc32::checksum(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+  };
+  for (kind, body) in chunks {
+    match kind {
+      b"eXIf" => {
+        if let Some(tiff) = rewrite_exif(body, metadata) {
+          push(kind, &tiff);
+        }
+      }
+      b"iTXt" if body.starts_with(b"XML:com.adobe.xmp\0") => {}
+      // 文字情報と更新日時
+      b"tEXt" | b"zTXt" | b"iTXt" | b"tIME" if metadata == Metadata::Strip => {}
+      _ => push(kind, body),
+    }
+  }
+  Some(out)
+}
+
+// WebP: RIFFの中のチャンクの種類と中身を返す関数
+fn webp_chunks(data: &[u8]) -> Option<Vec<(&[u8], &[u8])>> {
+  if data.get(..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+    return None;
+  }
+  let mut at = 12;
+  let mut chunks = Vec::new();
+  while at < data.len() {
+    let size = read(data, at + 4, 4, true)? as usize;
+    chunks.push((
+      data.get(at..at + 4)?,
+      data.get(at + 8..(at + 8).checked_add(size)?)?,
+    ));
+    // チャンクは偶数バイトに揃える
+    at += 8 + size + size % 2;
+  }
+  Some(chunks)
+}
+
+// EXIFのチャンクの先頭にExif\0\0を付けるものもある
+fn webp_exif(body: &[u8]) -> &[u8] {
+  body.strip_prefix(EXIF_HEADER).unwrap_or(body)
+}
+
+fn webp_info(data: &[u8]) -> Option<(u32, u32, Option<u16>)> {
+  let chunks = webp_chunks(data)?;
+  let orientation = chunks
+    .iter()
+    .find(|(kind, _)| *kind == b"EXIF")
+    .and_then(|(_, body)| orientation(webp_exif(body)));
+  let (width, height) = chunks.iter().find_map(|(kind, body)| match *kind {
+    // 拡張形式のキャンバスの大きさ（1を引いた24ビットの値）
+    b"VP8X" => Some((read(body, 4, 3, true)? + 1, read(body, 7, 3, true)? + 1)),
+    b"VP8 " if body.get(3..6)? == [0x9d, 0x01, 0x2a] => Some((
+      read(body, 6, 2, true)? & 0x3fff,
+      read(body, 8, 2, true)? & 0x3fff,
+    )),
+    b"VP8L" if *body.first()? == 0x2f => {
+      let bits = read(body, 1, 4, true)?;
+      Some(((bits & 0x3fff) + 1, (bits >> 14 & 0x3fff) + 1))
+    }
+    _ => None,
+  })?;
+  Some((width, height, orientation))
+}
+
+fn webp_strip(data: &[u8], metadata: Metadata) -> Option<Vec<u8>> {
+  let mut chunks = Vec::new();
+  for (kind, body) in webp_chunks(data)? {
+    match kind {
+      b"EXIF" => {
+        if let Some(tiff) = rewrite_exif(webp_exif(body), metadata) {
+          chunks.push((kind, Cow::Owned(tiff)));
+        }
+      }
+      b"XMP " => {}
+      _ => chunks.push((kind, Cow::Borrowed(body))),
+    }
+  }
+  let has_exif = chunks.iter().any(|(kind, _)| *kind == b"EXIF");
+  let mut out = b"RIFF\0\0\0\0WEBP".to_vec();
+  for (kind, body) in chunks {
+    out.extend_from_slice(kind);
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    let start = out.len();
+    out.extend_from_slice(&body);
+    // 拡張形式のフラグを残したチャンクに合わせる（EXIFは0x08，XMPは0x04）
+    if kind == b"VP8X" && !body.is_empty() {
+      out[start] &= !0x0c;
+      if has_exif {
+        out[start] |= 0x08;
+      }
+    }
+    if body.len() % 2 == 1 {
+      out.push(0);
+    }
+  }
+  let size = (out.len() - 8) as u32;
+  out[4..8].copy_from_slice(&size.to_le_bytes());
+  Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // 向き（6）とGPSのIFD（緯度を別の場所に置く）を持つTIFFの部分
+  fn tiff() -> Vec<u8> {
+    let mut tiff = b"MM\0*\0\0\0\x08\0\x02".to_vec();
+    tiff.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+    tiff.extend_from_slice(&[0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 38]);
+    tiff.extend_from_slice(&[0; 4]);
+    // GPSのIFD: GPSLatitude（RATIONALを3つ）
+    tiff.extend_from_slice(&[0, 1, 0, 2, 0, 5, 0, 0, 0, 3, 0, 0, 0, 56]);
+    tiff.extend_from_slice(&[0; 4]);
+    tiff.extend_from_slice(&[0x11; 24]);
+    assert_eq!(tiff.len(), 80);
+    tiff
+  }
+
+  fn segment(marker: u8, parts: &[&[u8]]) -> Vec<u8> {
+    let body = parts.concat();
+    let mut out = vec![0xff, marker];
+    out.extend_from_slice(&(body.len() as u16 + 2).to_be_bytes());
+    out.extend_from_slice(&body);
+    out
+  }
+
+  const SCAN: &[u8] = b"\xff\xda\0\x02scan\xff\xd9";
+
+  fn jpeg() -> Vec<u8> {
+    [
+      vec![0xff, 0xd8],
+      segment(0xe1, &[EXIF_HEADER, &tiff()]),
+      segment(0xe1, &[XMP_HEADER, b"<x:xmpmeta/>"]),
+      segment(0xfe, &[b"comment"]),
+      // 高さ480，幅640
+      segment(0xc0, &[&[8, 0x01, 0xe0, 0x02, 0x80, 1, 1, 0x11, 0]]),
+      SCAN.to_vec(),
+    ]
+    .concat()
+  }
+
+  fn chunk(kind: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut out = (body.len() as u32).to_be_bytes().to_vec();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    let crc = crc32::checksum(&out[4..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+    out
+  }
+
+  fn png() -> Vec<u8> {
+    [
+      b"\x89PNG\r\n\x1a\n".to_vec(),
+      chunk(b"IHDR", &[0, 0, 0, 3, 0, 0, 0, 2, 8, 2, 0, 0, 0]),
+      chunk(b"eXIf", &tiff()),
+      chunk(b"tEXt", b"Comment\0hello"),
+      chunk(b"iTXt", b"XML:com.adobe.xmp\0\0\0\0\0<x:xmpmeta/>"),
+      chunk(b"IDAT", b"pixels"),
+      chunk(b"IEND", b""),
+    ]
+    .concat()
+  }
+
+  fn webp(tiff: &[u8]) -> Vec<u8> {
+    let mut out = b"RIFF\0\0\0\0WEBP".to_vec();
+    // EXIFとXMPのフラグ，キャンバスは100x50
+    let vp8x = [0x0c, 0, 0, 0, 99, 0, 0, 49, 0, 0];
+    for (kind, body) in [
+      (&b"VP8X"[..], &vp8x[..]),
+      (b"ALPH", b"abc"),
+      (b"VP8L", b"\x2fpixels"),
+      (b"EXIF", tiff),
+      (b"XMP ", b"<x:xmpmeta/>"),
+    ] {
+      out.extend_from_slice(kind);
+      out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+      out.extend_from_slice(body);
+      if body.len() % 2 == 1 {
+        out.push(0);
+      }
+    }
+    let size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&size.to_le_bytes());
+    out
+  }
+
+  fn size(kind: &str, data: &[u8]) -> (u32, u32, u16) {
+    let info = info(kind, data).unwrap();
+    (info.width, info.height, info.orientation)
+  }
+
+  #[test]
+  fn reads_size_and_orientation() {
+    // 向きが6なので縦と横を入れ替える
+    assert_eq!(size("image/jpeg", &jpeg()), (480, 640, 6));
+    assert_eq!(size("image/png", &png()), (2, 3, 6));
+    assert_eq!(size("image/webp", &webp(&tiff())), (50, 100, 6));
+    assert_eq!(size("image/gif", b"GIF89a\x0a\0\x05\0"), (10, 5, 1));
+    assert!(info("image/jpeg", b"\xff\xd8\xff").is_none());
+    assert!(info("image/png", &png()[..20]).is_none());
+  }
+
+  #[test]
+  fn removes_gps_from_jpeg() {
+    let jpeg = jpeg();
+    assert_eq!(strip("image/jpeg", jpeg.clone(), Metadata::Keep), jpeg);
+    let located = strip("image/jpeg", jpeg.clone(), Metadata::Location);
+    let (segments, sos) = jpeg_segments(&located).unwrap();
+    let markers = segments
+      .iter()
+      .map(|(marker, _)| *marker)
+      .collect::<Vec<_>>();
+    assert_eq!(markers, [0xe1, 0xfe, 0xc0]);
+    let exif = &segments[0].1[EXIF_HEADER.len()..];
+    // IFD0はそのままで，GPSのIFDと緯度の値は0になる
+    assert_eq!(exif.len(), 80);
+    assert_eq!(exif[..38], tiff()[..38]);
+    assert!(exif[38..].iter().all(|b| *b == 0));
+    assert_eq!(&located[sos..], SCAN);
+    assert_eq!(size("image/jpeg", &located), (480, 640, 6));
+
+    let stripped = strip("image/jpeg", jpeg, Metadata::Strip);
+    let (segments, sos) = jpeg_segments(&stripped).unwrap();
+    assert_eq!(segments.len(), 2);
+    assert_eq!(
+      segments[0],
+      (0xe1, &[EXIF_HEADER, &orientation_only(6)].concat()[..])
+    );
+    assert_eq!(&stripped[sos..], SCAN);
+    assert_eq!(size("image/jpeg", &stripped), (480, 640, 6));
+  }
+
+  #[test]
+  fn removes_exif_with_default_orientation() {
+    let mut tiff = tiff();
+    tiff[19] = 1;
+    let jpeg = [
+      vec![0xff, 0xd8],
+      segment(0xe1, &[EXIF_HEADER, &tiff]),
+      SCAN.to_vec(),
+    ]
+    .concat();
+    let stripped = strip("image/jpeg", jpeg, Metadata::Strip);
+    assert_eq!(stripped, [&[0xff, 0xd8][..], SCAN].concat());
+  }
+
+  #[test]
+  fn rewrites_png_chunks_with_valid_crc() {
+    let kinds = |data: &[u8]| {
+      png_chunks(data)
+        .unwrap()
+        .iter()
+        .map(|(kind, _)| String::from_utf8_lossy(kind).into_owned())
+        .collect::<Vec<_>>()
+    };
+    let located = strip("image/png", png(), Metadata::Location);
+    assert_eq!(kinds(&located), ["IHDR", "eXIf", "tEXt", "IDAT", "IEND"]);
+    let stripped = strip("image/png", png(), Metadata::Strip);
+    assert_eq!(kinds(&stripped), ["IHDR", "eXIf", "IDAT", "IEND"]);
+    assert_eq!(
+      png_chunks(&stripped).unwrap()[1].1,
+      &orientation_only(6)[..]
+    );
+    for data in [located, stripped] {
+      let mut at = 8;
+      while at < data.len() {
+        let len = read(&data, at, 4, false).unwrap() as usize;
+        let crc = read(&data, at + 8 + len, 4, false).unwrap();
+        assert_eq!(crc, crc32::checksum(&data[at + 4..at + 8 + len]));
+        at += 12 + len;
+      }
+      assert_eq!(at, data.len());
+    }
+  }
+
+  #[test]
+  fn updates_webp_flags_and_size() {
+    let stripped = strip("image/webp", webp(&tiff()), Metadata::Strip);
+    let chunks = webp_chunks(&stripped).unwrap();
+    let kinds = chunks.iter().map(|(kind, _)| *kind).collect::<Vec<_>>();
+    assert_eq!(kinds, [&b"VP8X"[..], b"ALPH", b"VP8L", b"EXIF"]);
+    // XMPのフラグだけが外れる
+    assert_eq!(chunks[0].1[0], 0x08);
+    assert_eq!(chunks[1].1, b"abc");
+    assert_eq!(read(&stripped, 4, 4, true), Some(stripped.len() as u32 - 8));
+    assert_eq!(size("image/webp", &stripped), (50, 100, 6));
+
+    let mut tiff = tiff();
+    tiff[19] = 1;
+    let stripped = strip("image/webp", webp(&tiff), Metadata::Strip);
+    let chunks = webp_chunks(&stripped).unwrap();
+    assert_eq!(chunks.len(), 3);
+    assert_eq!(chunks[0].1[0], 0);
+    assert_eq!(read(&stripped, 4, 4, true), Some(stripped.len() as u32 - 8));
+  }
+
+  #[test]
+  fn keeps_unreadable_images() {
+    let data = b"\xff\xd8\xff\xe1\0".to_vec();
+    assert_eq!(strip("image/jpeg", data.clone(), Metadata::Strip), data);
+    assert_eq!(strip("text/plain", data.clone(), Metadata::Strip), data);
+  }
+}
@@ -0,0 +1,28 @@
+use std::net::IpAddr;
+
+use crate::cidr::{self, Cidr};
+
+// ルーティングの前に接続元のアドレスで受け付けるかを決める設定
+pub struct IpFilter {
+  allow: Vec<Cidr>,
+  deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+  // IP_ALLOWとIP_DENYにカンマ区切りで範囲を指定する
+  // IP_ALLOWを指定した場合は，そこに含まれるアドレスだけを受け付ける
+  pub fn from_env() -> IpFilter {
+    IpFilter {
+      allow: cidr::list_from_env("IP_ALLOW"),
+      deny: cidr::list_from_env("IP_DENY"),
+    }
+  }
+
+  // 拒否の一覧を許可の一覧より優先する
+  pub fn permits(&self, ip: IpAddr) -> bool {
+    if self.deny.iter().any(|range| range.contains(ip)) {
+      return false;
+    }
+    self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip))
+  }
+}
@@ -0,0 +1,81 @@
+use std::env;
+
+// 上限を超えた場合の扱い
+enum Policy {
+  // 422で断る
+  Reject,
+  // 上限までで切り詰め，警告を付けて保存する
+  Truncate,
+}
+
+// 投稿のタイトルと本文の長さ（文字数）の上限
+// 設定されていなければ制限しない
+pub struct Limits {
+  title: Option<usize>,
+  content: Option<usize>,
+  policy: Policy,
+}
+
+fn limit(name: &str) -> Option<usize> {
+  env::var(name).ok().map(|limit| {
+    limit
+      .parse()
+      .unwrap_or_else(|_| panic!("{} must be a number", name))
+  })
+}
+
+impl Limits {
+  // MAX_TITLE_CHARSとMAX_CONTENT_CHARSで上限を，TOO_LONG_POLICY（reject|truncate）で扱いを指定する
+  pub fn from_env() -> Limits {
+    let policy = match env::var("TOO_LONG_POLICY").as_deref() {
+      Ok("truncate") => Policy::Truncate,
+      Ok("reject") | Err(_) => Policy::Reject,
+      Ok(other) => panic!("unknown TOO_LONG_POLICY {}", other),
+    };
+    Limits {
+      title: limit("MAX_TITLE_CHARS"),
+      content: limit("MAX_CONTENT_CHARS"),
+      policy,
+    }
+  }
+
+  // タイトルと本文を上限に合わせる関数
+  // 断る場合は理由を，切り詰めた場合は警告をwarningsに加える
+  pub fn apply<'a>(
+    &self,
+    title: &'a str,
+    content: &'a str,
+    warnings: &mut Vec<String>,
+  ) -> Result<(&'a str, &'a str), String> {
+    Ok((
+      self.fit("title", title, self.title, warnings)?,
+      self.fit("content", content, self.content, warnings)?,
+    ))
+  }
+
+  fn fit<'a>(
+    &self,
+    field: &str,
+    text: &'a str,
+    limit: Option<usize>,
+    warnings: &mut Vec<String>,
+  ) -> Result<&'a str, String> {
+    let limit = match limit {
+      Some(limit) => limit,
+      None => return Ok(text),
+    };
+    // 上限を超える文字があればその位置で切る
+    let end = match text.char_indices().nth(limit) {
+      Some((end, _)) => end,
+      None => return Ok(text),
+    };
+    match self.policy {
+      Policy::Reject => Err(format!("{} is longer than {} characters", field, limit)),
+      Policy::Truncate => {
+        warnings.push(format!("{} truncated to {} characters", field, limit));
+        // 参照の範囲を狭めるだけなのでコピーは発生しない
+        Ok(&text[..end])
+      }
+    }
+  }
+}
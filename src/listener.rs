@@ -0,0 +1,205 @@
+use std::{
+  env,
+  fs::{self, File},
+  io::{self, BufReader},
+  net,
+  os::unix::{
+    fs::{FileTypeExt, PermissionsExt},
+    io::{FromRawFd, OwnedFd},
+    net as unix,
+  },
+  pin::Pin,
+  process,
+  sync::Arc,
+  task::{Context, Poll},
+};
+
+use hyper::server::accept::Accept;
+use serde::Deserialize;
+use tokio::net::{UnixListener, UnixStream};
+use tokio_rustls::{rustls, TlsAcceptor};
+
+// LISTENを指定しない場合に待ち受けるアドレス
+const DEFAULT_LISTEN: &str = "http://127.0.0.1:3000";
+// Unixドメインソケットの権限の初期値
+const DEFAULT_SOCKET_MODE: u32 = 0o660;
+
+// 同じホストのnginxやcaddyから中継を受けるためのUnixドメインソケット
+pub struct UnixAccept(UnixListener);
+
+impl Accept for UnixAccept {
+  type Conn = UnixStream;
+  type Error = io::Error;
+
+  fn poll_accept(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+    self
+      .0
+      .poll_accept(cx)
+      .map(|res| Some(res.map(|(stream, _)| stream)))
+  }
+}
+
+// 待ち受けるソケット
+// どれも同じルーティングで処理する
+pub enum Listener {
+  Tcp(net::TcpListener),
+  // 証明書と秘密鍵は待ち受けるアドレスごとに設定する
+  Tls(tokio::net::TcpListener, TlsAcceptor),
+  Unix(UnixAccept),
+}
+
+// https://とunix://の設定
+#[derive(Deserialize)]
+struct Options {
+  cert: Option<String>,
+  key: Option<String>,
+  // 8進数で指定する
+  mode: Option<String>,
+}
+
+// パスでソケットを待ち受ける関数
+// 中継するサーバと同じグループから接続できるように権限を設定する
+fn bind_unix(path: &str, mode: u32) -> UnixAccept {
+  // 前回の起動で残ったソケットは消してから作り直す（ソケット以外のファイルは消さない）
+  if let Ok(meta) = fs::symlink_metadata(path) {
+    if !meta.file_type().is_socket() {
+      panic!("{} exists and is not a socket", path);
+    }
+    fs::remove_file(path).unwrap();
+  }
+  let listener = UnixListener::bind(path).unwrap();
+  fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+  UnixAccept(listener)
+}
+
+fn parse_mode(mode: &str) -> u32 {
+  u32::from_str_radix(mode, 8).unwrap_or_else(|_| panic!("socket mode {} must be octal", mode))
+}
+
+// PEM形式の証明書と秘密鍵からTLSの設定を作る関数
+fn tls_acceptor(cert: &str, key: &str) -> TlsAcceptor {
+  let open = |path: &str| {
+    BufReader::new(File::open(path).unwrap_or_else(|e| panic!("cannot open {}: {}", path, e)))
+  };
+  let certs = rustls_pemfile::certs(&mut open(cert))
+    .unwrap()
+    .into_iter()
+    .map(rustls::Certificate)
+    .collect();
+  let key = rustls_pemfile::read_all(&mut open(key))
+    .unwrap()
+    .into_iter()
+    .find_map(|item| match item {
+      rustls_pemfile::Item::PKCS8Key(key)
+      | rustls_pemfile::Item::RSAKey(key)
+      | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+      _ => None,
+    })
+    .unwrap_or_else(|| panic!("no private key in {}", key));
+  let mut config = rustls::ServerConfig::builder()
+    .with_safe_defaults()
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .unwrap();
+  config.alpn_protocols = vec![b"http/1.1".to_vec()];
+  TlsAcceptor::from(Arc::new(config))
+}
+
+// http://host:port，https://host:port?cert=...&key=...，unix:///path?mode=600 の1つを開く関数
+fn bind(spec: &str) -> Listener {
+  let (address, query) = spec.split_once('?').unwrap_or((spec, ""));
+  let options = serde_urlencoded::from_str::<Options>(query)
+    .unwrap_or_else(|e| panic!("invalid options in {}: {}", spec, e));
+  if let Some(addr) = address.strip_prefix("http://") {
+    return Listener::Tcp(net::TcpListener::bind(addr).unwrap());
+  }
+  if let Some(addr) = address.strip_prefix("https://") {
+    let (cert, key) = match (&options.cert, &options.key) {
+      (Some(cert), Some(key)) => (cert, key),
+      _ => panic!("{} needs cert and key", spec),
+    };
+    let tcp = net::TcpListener::bind(addr).unwrap();
+    tcp.set_nonblocking(true).unwrap();
+    return Listener::Tls(
+      tokio::net::TcpListener::from_std(tcp).unwrap(),
+      tls_acceptor(cert, key),
+    );
+  }
+  if let Some(path) = address.strip_prefix("unix://") {
+    let mode = options
+      .mode
+      .as_deref()
+      .map_or(DEFAULT_SOCKET_MODE, parse_mode);
+    return Listener::Unix(bind_unix(path, mode));
+  }
+  panic!("unknown listener {}", spec);
+}
+
+// systemdが渡すソケットの最初のファイルディスクリプタ（SD_LISTEN_FDS_START）
+const LISTEN_FDS_START: i32 = 3;
+
+// ソケット起動の場合にsystemdが開いたソケットを受け取る関数
+// LISTEN_PIDが自分のプロセスでなければ，親から引き継いだだけの値として無視する
+fn from_systemd() -> Vec<Listener> {
+  let pid = env::var("LISTEN_PID")
+    .ok()
+    .and_then(|pid| pid.parse::<u32>().ok());
+  if pid != Some(process::id()) {
+    return Vec::new();
+  }
+  let count: i32 = env::var("LISTEN_FDS")
+    .ok()
+    .and_then(|fds| fds.parse().ok())
+    .unwrap_or(0);
+  // 子プロセスが同じソケットを受け取ったと誤解しないように消しておく
+  env::remove_var("LISTEN_PID");
+  env::remove_var("LISTEN_FDS");
+  env::remove_var("LISTEN_FDNAMES");
+  (LISTEN_FDS_START..LISTEN_FDS_START + count)
+    .map(|fd| {
+      // systemdが開いたまま渡したディスクリプタで，他からは使わないので所有権を引き受けてよい
+      let tcp = unsafe { net::TcpListener::from_raw_fd(fd) };
+      // TCPでなければアドレスを取得できないので，Unixドメインソケットとして扱い直す
+      if tcp.local_addr().is_ok() {
+        tcp.set_nonblocking(true).unwrap();
+        return Listener::Tcp(tcp);
+      }
+      let unix = unix::UnixListener::from(OwnedFd::from(tcp));
+      unix.set_nonblocking(true).unwrap();
+      Listener::Unix(UnixAccept(UnixListener::from_std(unix).unwrap()))
+    })
+    .collect()
+}
+
+// 待ち受けるすべてのソケットを開く関数
+// LISTENにカンマ区切りで並べる（例: http://127.0.0.1:3000,unix:///run/web-memory.sock）
+// systemdからソケットを受け取った場合は，LISTENを指定しなければ自分ではTCPのポートを開かない
+// UNIX_SOCKETとUNIX_SOCKET_MODEでもUnixドメインソケットを追加できる
+pub fn all() -> Vec<Listener> {
+  let mut listeners = from_systemd();
+  let listen = env::var("LISTEN").ok().or_else(|| {
+    if listeners.is_empty() {
+      Some(DEFAULT_LISTEN.to_string())
+    } else {
+      None
+    }
+  });
+  listeners.extend(
+    listen
+      .iter()
+      .flat_map(|listen| listen.split(','))
+      .map(str::trim)
+      .filter(|spec| !spec.is_empty())
+      .map(bind),
+  );
+  if let Ok(path) = env::var("UNIX_SOCKET") {
+    let mode = env::var("UNIX_SOCKET_MODE")
+      .as_deref()
+      .map_or(DEFAULT_SOCKET_MODE, parse_mode);
+    listeners.push(Listener::Unix(bind_unix(&path, mode)));
+  }
+  listeners
+}
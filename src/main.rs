@@ -1,39 +1,294 @@
+use hyper::server::{
+  self,
+  conn::{AddrIncoming, AddrStream},
+};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Error, Request, Response, Server, StatusCode};
-use std::{convert::Infallible, net::SocketAddr, str, sync::Arc};
+use hyper::{header, Body, Error, Request, Response, Server, StatusCode};
+use std::{
+  collections::BTreeMap,
+  convert::Infallible,
+  env,
+  net::{IpAddr, SocketAddr},
+  str,
+  sync::Arc,
+  time::{SystemTime, UNIX_EPOCH},
+};
 use tera::{Context, Tera};
+use tokio::net::UnixStream;
+use tokio_rustls::TlsAcceptor;
 // データ型のインポート
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use rusqlite::{params, Connection, OptionalExtension};
-use tokio::sync::Mutex;
+use rusqlite::types::{ToSql, ToSqlOutput};
+use rusqlite::{params, OptionalExtension, Row};
+
+mod admin_auth;
+mod archive;
+mod assets;
+mod attachment;
+mod attachment_policy;
+mod audio;
+mod audit;
+mod blob;
+mod bookmark;
+mod captcha;
+mod changes;
+mod cidr;
+mod clip;
+mod conflict;
+mod content;
+mod crc32;
+mod db;
+mod digest;
+mod draft;
+mod duplicate;
+mod e2ee;
+mod enrich;
+mod epub;
+mod events;
+mod features;
+mod feed;
+mod flash;
+mod geo;
+mod hex;
+mod https;
+mod i18n;
+mod image;
+mod ipfilter;
+mod limits;
+mod listener;
+mod maintenance;
+mod maintenance_mode;
+mod merge;
+mod normalize;
+mod notebook;
+mod notify;
+mod pdf;
+mod plugin;
+mod preview;
+mod proxy;
+mod publish;
+mod qr;
+mod readonly;
+mod related;
+mod replica;
+mod saved_search;
+mod search;
+mod share;
+mod shed;
+mod shortlink;
+mod signer;
+mod site;
+mod spam;
+mod stats;
+mod suggest;
+mod sync;
+mod tags;
+mod template_ext;
+mod templates;
+mod tenant;
+mod themes;
+mod timezone;
+mod trash;
+mod unfurl;
+mod upload;
+mod urls;
+mod views;
+mod voice;
+mod webhook;
+mod wordcount;
+mod writer;
+mod zip;
+use captcha::Captcha;
+use content::Codec;
+use events::Event;
+use signer::Signer;
+use spam::{SpamCheck, Submission};
+use tenant::{Tenant, Tenants};
 
 // 自作テンプレートの定義
 static TEMPLATE: &str = "Hello, {{name}}!";
 // static DBTEMPLATE: &str = "id={{id}}, title={{title}}, content={{content}}";
 
+// 投稿の公開範囲
+// unlistedは一覧に出さずリンクを知っている人だけが見られる
+// privateは所有者のみが見られるが，認証がないため通常の経路では誰も見られない
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum Visibility {
+  #[default]
+  Public,
+  Unlisted,
+  Private,
+}
+
+impl Visibility {
+  fn as_str(self) -> &'static str {
+    match self {
+      Visibility::Public => "public",
+      Visibility::Unlisted => "unlisted",
+      Visibility::Private => "private",
+    }
+  }
+}
+
+// DBには文字列として保存する
+impl ToSql for Visibility {
+  fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+    Ok(self.as_str().into())
+  }
+}
+
 // リクエストから必要な情報を取り出す構造体の定義
-// 参照で取り出すため新たなメモリの確保を必要としない点がポイント
+// フォームの値は+や%xxで符号化されていて参照では取り出せないので，Stringで受け取る
+#[derive(Deserialize)]
+struct NewPost {
+  title: String,
+  content: String,
+  // ボット対策のハニーポット欄（人間は空のまま送信する）
+  #[serde(default)]
+  website: String,
+  // フォームを表示した時刻（UNIX秒）
+  #[serde(default)]
+  rendered_at: Option<u64>,
+  // CAPTCHAウィジェットが送信するトークン
+  #[serde(default, alias = "h-captcha-response", alias = "cf-turnstile-response")]
+  captcha_response: String,
+  #[serde(default)]
+  visibility: Visibility,
+  // 投稿先のノートブック名（省略時はどこにも属さない）
+  #[serde(default)]
+  notebook: Option<String>,
+  // 投稿した場所（緯度と経度は組で指定する）
+  #[serde(default)]
+  lat: Option<f64>,
+  #[serde(default)]
+  lon: Option<f64>,
+}
+
+// 投稿作成時のクエリ
 #[derive(Deserialize)]
-struct NewPost<'a> {
-  title: &'a str,
-  content: &'a str,
+struct CreateQuery {
+  // trueの場合は重複していても保存する
+  #[serde(default)]
+  force: bool,
+}
+
+// サーバ全体で共有する状態
+// Arcで包んでリクエストごとにcloneする
+struct State {
+  tera: Tera,
+  // テナントごとのDB接続
+  tenants: Tenants,
+  spam: Box<dyn SpamCheck>,
+  // 設定されていなければCAPTCHAは使わない
+  captcha: Option<Arc<Captcha>>,
+  // 共有リンクなどの署名に使う
+  signer: Signer,
+  // 本文の暗号化と復号を行う
+  codec: Codec,
+  // ゴミ箱の投稿を残す期間
+  retention: trash::Retention,
+  // 投稿の長さの上限
+  limits: limits::Limits,
+  // 書き込みを止めているかどうか
+  read_only: readonly::ReadOnly,
+  // メンテナンス中の画面を返しているかどうか
+  maintenance_mode: maintenance_mode::MaintenanceMode,
+  // 試験中の機能の設定
+  features: Arc<features::Features>,
+  // 処理中のリクエスト数
+  load: shed::Load,
+  // 接続元のアドレスによる制限
+  ip_filter: ipfilter::IpFilter,
+  proxies: proxy::TrustedProxies,
+  // /admin以下の認証
+  admin_auth: admin_auth::AdminAuth,
+  // 公開ブログとして動かす設定
+  publish: publish::Publish,
+  // 絶対URLに使う公開URL
+  base_url: urls::BaseUrl,
+  // 静的なファイル
+  assets: Arc<assets::Assets>,
+  // 画面の見た目
+  themes: themes::Themes,
+  // 画面に表示する文言
+  i18n: Arc<i18n::I18n>,
+  // 日時を表示するタイムゾーン
+  timezone: timezone::Timezone,
+  // 本文にあるリンクのプレビューの取得
+  unfurl: unfurl::Unfurler,
+  // ブラウザ拡張からの取り込み
+  clip: clip::Clip,
+  // 静的なサイトの書き出し
+  site: site::Site,
+  // 投稿の変更を知らせる送り先
+  webhooks: webhook::Webhooks,
+  // 投稿に添付したファイルの保存先
+  attachments: attachment::Attachments,
+  // 分割アップロードの途中の断片の置き場所
+  uploads: upload::Uploads,
+  // メールなどで知らせる経路
+  notifications: notify::Notifications,
+  // 自分のビルドで追加した拡張
+  plugins: plugin::Plugins,
 }
 
 struct Post {
   id: Uuid,
   title: String,
   content: String,
+  // 属するノートブックまでのパンくず（最上位から順に並ぶ）
+  breadcrumbs: Vec<String>,
+  // 共通する語の多い投稿
+  related: Arc<Vec<related::Related>>,
+  // 保存時に数えた語数など（記録していない投稿はNone）
+  counts: Option<wordcount::Counts>,
+  // 本文にあるリンクのプレビュー
+  links: Vec<unfurl::Preview>,
+  // 規則によって付いたタグ
+  tags: Vec<String>,
+  // 音声メモの投稿の音声
+  voice: Option<voice::Voice>,
+  // 投稿した場所
+  location: Option<geo::Location>,
+  // 場所と日付から補った天気などの情報
+  metadata: BTreeMap<String, String>,
 }
 
 impl Post {
+  // id, title, content, encrypted, compressedの順に並んだ行から投稿を作る関数
+  fn from_row(row: &Row, codec: &Codec) -> rusqlite::Result<Post> {
+    Ok(Post {
+      id: row.get(0)?,
+      title: row.get(1)?,
+      content: codec.decode(row.get(2)?, row.get(3)?, row.get(4)?),
+      breadcrumbs: Vec::new(),
+      related: Arc::default(),
+      counts: None,
+      links: Vec::new(),
+      tags: Vec::new(),
+      voice: None,
+      location: None,
+      metadata: BTreeMap::new(),
+    })
+  }
+
   // 投稿を文字列にレンダリングする関数
-  fn render(&self, tera: Arc<Tera>) -> String {
+  fn render(&self, tera: &Tera) -> String {
     let mut ctx = Context::new();
     ctx.insert("id", &self.id);
     ctx.insert("title", &self.title);
     ctx.insert("content", &self.content);
+    ctx.insert("breadcrumbs", &self.breadcrumbs);
+    ctx.insert("related", &*self.related);
+    ctx.insert("counts", &self.counts);
+    ctx.insert("links", &self.links);
+    ctx.insert("tags", &self.tags);
+    ctx.insert("voice", &self.voice);
+    ctx.insert("location", &self.location);
+    ctx.insert("metadata", &self.metadata);
     tera.render("post", &ctx).unwrap()
   }
 }
@@ -45,66 +300,331 @@ impl Post {
 // }
 
 // idから投稿を探す関数
+// 本文のpost_id=で指定されていなければパスのidを使う（投稿後の移動先などはこちら）
 async fn find_post(
   req: Request<Body>,
-  tera: Arc<Tera>,
-  conn: Arc<Mutex<Connection>>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  path_id: &str,
 ) -> Result<Response<Body>, Error> {
   let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
   let body = str::from_utf8(&body).unwrap();
-  let id = Uuid::parse_str(body.strip_prefix("post_id=").unwrap()).unwrap();
+  let id = match body.strip_prefix("post_id=") {
+    Some(id) => Uuid::parse_str(id).unwrap(),
+    None => match Uuid::parse_str(path_id) {
+      Ok(id) => id,
+      Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+    },
+  };
+  let conn = tenant.conn.lock().await;
+  // E2EEの投稿はサーバ側で描画せず暗号文をそのまま返す
+  if let Some(res) = e2ee::find(&conn, &id) {
+    tenant.views.record(id);
+    return Ok(res);
+  }
   let post = conn
-    .lock()
-    .await
     .query_row(
-      "SELECT id, title, content FROM posts WHERE id=?1",
-      params![id],
+      "SELECT id, title, content, encrypted, compressed, word_count, char_count, reading_minutes,
+        lat, lon
+      FROM posts WHERE id=?1 AND kind = 'text' AND visibility != ?2 AND trashed_at IS NULL",
+      params![id, Visibility::Private],
       |row| {
-        Ok(Post {
-          id: row.get(0)?,
-          title: row.get(1)?,
-          content: row.get(2)?,
-        })
+        let mut post = Post::from_row(row, &state.codec)?;
+        post.counts = wordcount::Counts::from_row(row, 5)?;
+        post.location = geo::Location::from_row(row, 8)?;
+        Ok(post)
       },
     )
     .optional()
     .unwrap();
   match post {
-    Some(post) => Ok(Response::new(post.render(tera).into())),
-    None => Ok(
-      Response::builder()
-        .status(StatusCode::NOT_FOUND)
-        .body(Body::empty())
-        .unwrap(),
-    ),
+    Some(mut post) => {
+      tenant.views.record(post.id);
+      post.breadcrumbs = notebook::breadcrumbs(&conn, &post.id);
+      post.tags = tags::for_post(&conn, &post.id);
+      post.voice = voice::for_post(&conn, &tenant.prefix, &post.id);
+      post.metadata = enrich::for_post(&conn, &post.id);
+      post.related = related::for_post(&tenant, &conn, &state.codec, &post);
+      post.links = unfurl::for_post(&state, &tenant, &conn, &post.content);
+      let mut rendered = post.render(&state.tera);
+      state.plugins.after_render(&post.id, &mut rendered);
+      Ok(Response::new(rendered.into()))
+    }
+    None => Ok(empty(StatusCode::NOT_FOUND)),
   }
 }
 
 // DBにデータを作成する関数
+// notebookはパスで指定された投稿先で，フォームの指定より優先する
 async fn create_post(
   req: Request<Body>,
-  _: Arc<Tera>,
-  // 排他制御されたDB接続
-  // spliteはシングルスレッド動作
-  conn: Arc<Mutex<Connection>>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  notebook: Option<&str>,
 ) -> Result<Response<Body>, Error> {
+  // スパム判定に使うリクエストの情報を先に取り出しておく
+  let ip = remote_ip(&req);
+  let user_agent = header_str(&req, header::USER_AGENT);
+  let referrer = header_str(&req, header::REFERER);
+  let html = wants_html(&req);
+  let query = match serde_urlencoded::from_str::<CreateQuery>(req.uri().query().unwrap_or_default())
+  {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
   // リクエストボディからバイト列のみを取り出す
   let body = hyper::body::to_bytes(req.into_body()).await?;
   // フォームデータのみを取り出す
-  let new_post = serde_urlencoded::from_bytes::<NewPost>(&body).unwrap();
+  let mut new_post = match serde_urlencoded::from_bytes::<NewPost>(&body) {
+    Ok(new_post) => new_post,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  // 長さの上限を確かめる
+  let mut warnings = Vec::new();
+  match state
+    .limits
+    .apply(&new_post.title, &new_post.content, &mut warnings)
+  {
+    Ok((title, content)) => {
+      let (title, content) = (title.to_owned(), content.to_owned());
+      new_post.title = title;
+      new_post.content = content;
+    }
+    Err(reason) => {
+      return Ok(
+        Response::builder()
+          .status(StatusCode::UNPROCESSABLE_ENTITY)
+          .body(reason.into())
+          .unwrap(),
+      )
+    }
+  }
+  let location = match geo::Location::new(new_post.lat, new_post.lon) {
+    Ok(location) => location,
+    Err(reason) => {
+      return Ok(
+        Response::builder()
+          .status(StatusCode::UNPROCESSABLE_ENTITY)
+          .body(reason.into())
+          .unwrap(),
+      )
+    }
+  };
+  // プラグインによる書き換えは切り詰めた後の内容に対して行う
+  let mut candidate = plugin::Candidate {
+    title: new_post.title.clone(),
+    content: new_post.content.clone(),
+  };
+  if let Err(reason) = state.plugins.before_create(&mut candidate) {
+    return Ok(
+      Response::builder()
+        .status(StatusCode::UNPROCESSABLE_ENTITY)
+        .body(reason.into())
+        .unwrap(),
+    );
+  }
+  new_post.title = candidate.title;
+  new_post.content = candidate.content;
+  // 作成した投稿のidを返すレスポンス（切り詰めた場合は警告を付ける）
+  // フォームから投稿した場合は再読み込みで再送信されないように投稿のページへ移動させる
+  let created = |id: Uuid| {
+    let mut res = Response::builder();
+    for warning in &warnings {
+      res = res.header(header::WARNING, format!("299 - {:?}", warning));
+    }
+    if html {
+      return res
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, format!("{}/posts/{}", tenant.prefix, id))
+        .body(Body::empty())
+        .unwrap();
+    }
+    res.body(id.to_string().into()).unwrap()
+  };
   // uuidを生成する
   let id = Uuid::new_v4();
-  conn
-    // ロックは処理終了時に自動で解除される
-    .lock()
-    .await
-    .execute(
-      "INSERT INTO posts(id, title, content) VALUES (?1,?2,?3)",
-      // 参照を使ってデータを作成するのでメモリアロケーションは発生しない
-      params![&id, &new_post.title, &new_post.content],
-    )
-    .unwrap();
-  Ok(Response::new(id.to_string().into()))
+  // CAPTCHAが有効な場合はトークンを検証してから保存する
+  if let Some(captcha) = &state.captcha {
+    if !captcha.verify(&new_post.captcha_response, ip).await {
+      return Ok(
+        Response::builder()
+          .status(StatusCode::FORBIDDEN)
+          .body("captcha verification failed".into())
+          .unwrap(),
+      );
+    }
+  }
+  let verdict = state
+    .spam
+    .check(&Submission {
+      post: &new_post,
+      ip,
+      user_agent: &user_agent,
+      referrer: &referrer,
+    })
+    .await;
+  let spam = verdict.spam;
+  let notebook = notebook.map(str::to_owned).or(new_post.notebook);
+  let title = new_post.title;
+  let content = new_post.content;
+  let visibility = new_post.visibility;
+  let shared = state.clone();
+  // 同時に届いた投稿とまとめて1つのトランザクションで保存する
+  // 重複の確認も同じトランザクションの中で行うので，先に保存された投稿も見える
+  let rejected = tenant
+    .writer
+    .run(move |conn| {
+      let state = shared;
+      let notebook_id = match notebook {
+        Some(name) => match notebook::id_by_name(conn, &name) {
+          Some(notebook_id) => Some(notebook_id),
+          None => return Some(empty(StatusCode::NOT_FOUND)),
+        },
+        None => None,
+      };
+      // 二重送信などで同じ内容の投稿が増えないように既存の投稿のidを返して断る
+      let content_hash = duplicate::hash(&state.signer, &content);
+      if !verdict.spam && !query.force {
+        if let Some(existing) = duplicate::find(conn, &content_hash) {
+          return Some(
+            Response::builder()
+              .status(StatusCode::CONFLICT)
+              .body(existing.to_string().into())
+              .unwrap(),
+          );
+        }
+      }
+      spam::record(conn, &id, &verdict);
+      // スパムは保存せず，成功したように見せかけて破棄する
+      if verdict.spam {
+        return None;
+      }
+      let stored = state.codec.encode(&content);
+      conn
+        .execute(
+          "INSERT INTO posts(id, title, content, encrypted, compressed, visibility, notebook_id,
+            content_hash, created_at, lat, lon)
+          VALUES (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11)",
+          params![
+            &id,
+            &title,
+            &stored.content,
+            &stored.encrypted,
+            &stored.compressed,
+            &visibility,
+            &notebook_id,
+            &content_hash,
+            &now(),
+            location.map(|location| location.lat),
+            location.map(|location| location.lon)
+          ],
+        )
+        .unwrap();
+      wordcount::record(conn, &id, &content);
+      tags::apply(conn, &id, &content);
+      audit::record(
+        conn,
+        audit::Entry {
+          actor: audit::ANONYMOUS,
+          action: "create",
+          post_id: &id,
+          summary: format!(
+            "title={:?}, {} chars, {}",
+            title,
+            content.chars().count(),
+            visibility.as_str()
+          ),
+          ip,
+        },
+      );
+      None
+    })
+    .await;
+  if let Some(res) = rejected {
+    return Ok(res);
+  }
+  if !spam {
+    tenant.publish(Event::Created { id });
+  }
+  let mut res = created(id);
+  // フォームから投稿した場合は次の画面で作成したことを知らせる
+  if html {
+    flash::set(&mut res, &state.signer, "flash-post-created");
+  }
+  Ok(res)
+}
+
+// 本文のないレスポンスを返す関数
+fn empty(status: StatusCode) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .body(Body::empty())
+    .unwrap()
+}
+
+// 値をJSONにしたレスポンスを返す関数
+fn json<T: Serialize>(value: &T) -> Response<Body> {
+  Response::builder()
+    .header(header::CONTENT_TYPE, "application/json")
+    .body(serde_json::to_string(value).unwrap().into())
+    .unwrap()
+}
+
+// 現在時刻をUNIX秒で返す関数
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap()
+    .as_secs()
+}
+
+// 接続元の情報を返す関数
+fn client(req: &Request<Body>) -> &proxy::Client {
+  req.extensions().get::<proxy::Client>().unwrap()
+}
+
+// 接続元のIPアドレスを返す関数
+fn remote_ip(req: &Request<Body>) -> IpAddr {
+  client(req).ip
+}
+
+// ヘッダの値を文字列として取り出す関数（なければ空文字列）
+fn header_str(req: &Request<Body>, name: header::HeaderName) -> String {
+  req
+    .headers()
+    .get(name)
+    .and_then(|v| v.to_str().ok())
+    .unwrap_or_default()
+    .to_string()
+}
+
+// Cookieヘッダから名前の一致する値を取り出す関数
+fn cookie(req: &Request<Body>, name: &str) -> Option<String> {
+  header_str(req, header::COOKIE)
+    .split(';')
+    .filter_map(|pair| pair.trim().split_once('='))
+    .find(|(key, _)| *key == name)
+    .map(|(_, value)| value.to_string())
+}
+
+// ブラウザからのリクエストかを判定する関数
+fn wants_html(req: &Request<Body>) -> bool {
+  header_str(req, header::ACCEPT).contains("text/html")
+}
+
+// 投稿フォームを返す関数
+async fn new_post_form(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+) -> Result<Response<Body>, Error> {
+  let mut ctx = Context::new();
+  ctx.insert("prefix", &tenant.prefix);
+  // 入力にかかった時間を判定するためにフォームの表示時刻を埋め込む
+  ctx.insert("rendered_at", &now());
+  Ok(Response::new(
+    templates::render(&state, &req, "new_post", &mut ctx).into(),
+  ))
 }
 
 // リクエストに対して固定文字列のレスポンスを返す関数
@@ -113,7 +633,7 @@ async fn handle(_: Request<Body>) -> Result<Response<Body>, Infallible> {
 }
 
 // テンプレートを使用してリクエストの文字列をレスポンスに組み込む関数
-async fn handle_with_body(req: Request<Body>, tera: Arc<Tera>) -> Result<Response<Body>, Error> {
+async fn handle_with_body(req: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Error> {
   // bodyからバイト列のみを抽出する．
   let body = hyper::body::to_bytes(req.into_body()).await?;
   // バイト列を文字列として解釈する（参照のみ）．
@@ -141,35 +661,285 @@ async fn handle_with_body(req: Request<Body>, tera: Arc<Tera>) -> Result<Respons
   // コンテキストにnameという名前でリクエストボディのnameの値を入れる
   ctx.insert("name", name);
   // helloテンプレートにコンテキストを適用する（毎回必要）
-  let rendered = tera.render("hello", &ctx).unwrap();
+  let rendered = state.tera.render("hello", &ctx).unwrap();
   // レスポンスにテンプレートを使用する
   Ok(Response::new(rendered.into()))
 }
 
-async fn route(
-  req: Request<Body>,
-  tera: Arc<Tera>,
-  conn: Arc<Mutex<Connection>>,
-) -> Result<Response<Body>, Error> {
-  match (req.uri().path(), req.method().as_str()) {
-    ("/", "GET") => handle_with_body(req, tera).await,
-    // 固定文字列のレスポンスを返す関数を実行
-    ("/", _) => handle(req).await.map_err(|e| match e {}),
-    ("/posts", "POST") => create_post(req, tera, conn).await,
-    (path, "GET") if path.starts_with("/posts/") => find_post(req, tera, conn).await,
-    _ => Ok(
+async fn route(req: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Error> {
+  // 許可していないアドレスからはテナントを調べる前に断る
+  if !state.ip_filter.permits(remote_ip(&req)) {
+    return Ok(empty(StatusCode::FORBIDDEN));
+  }
+  // 静的なファイルはテナントによらず同じURLで配信する
+  if let Some(res) = state.assets.serve(req.uri().path()) {
+    return Ok(res);
+  }
+  // テナントを決めてDB接続を選ぶ
+  let (tenant, path) = match state.tenants.resolve(&req) {
+    Some(resolved) => resolved,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  // リンクの書き方が揺れても同じURLになるように，重複した/や末尾の/を取り除いたパスに転送する
+  // メソッドと本文を保ったまま転送されるように308を使う
+  if let Some(normalized) = normalize::redirect_target(&path) {
+    let location = match req.uri().query() {
+      Some(query) => format!("{}{}?{}", tenant.prefix, normalized, query),
+      None => format!("{}{}", tenant.prefix, normalized),
+    };
+    return Ok(
       Response::builder()
-        .status(StatusCode::NOT_FOUND)
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(header::LOCATION, location)
         .body(Body::empty())
         .unwrap(),
-    ),
+    );
+  }
+  // パスを/で区切り，%エンコードを戻してから照合する
+  // reqは各処理に渡すので，照合に使う値は先に取り出しておく
+  let method = req.method().as_str().to_owned();
+  let decoded = match path
+    .split('/')
+    .skip(1)
+    .map(normalize::decode)
+    .collect::<Option<Vec<_>>>()
+  {
+    Some(decoded) => decoded,
+    None => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let segments: Vec<&str> = decoded.iter().map(String::as_str).collect();
+  if let Some(res) = state.admin_auth.challenge(&req, &segments) {
+    return Ok(res);
+  }
+  if let Some(res) = publish::challenge(&state.publish, &state.admin_auth, &req, &segments) {
+    return Ok(res);
+  }
+  if let Some(res) = maintenance_mode::intercept(&req, &state, &segments) {
+    return Ok(res);
+  }
+  if let Some(res) = state.load.shed(&tenant, &segments) {
+    return Ok(res);
+  }
+  if readonly::rejects(&state.read_only, &method, &segments) {
+    return Ok(readonly::rejection(&req, &state));
+  }
+  match (method.as_str(), segments.as_slice()) {
+    ("GET", [""]) => handle_with_body(req, state).await,
+    // 固定文字列のレスポンスを返す関数を実行
+    (_, [""]) => handle(req).await.map_err(|e| match e {}),
+    ("POST", ["posts"]) if header_str(&req, header::CONTENT_TYPE) == e2ee::CONTENT_TYPE => {
+      e2ee::create(req, tenant).await
+    }
+    ("POST", ["posts"]) => create_post(req, state, tenant, None).await,
+    ("GET", ["posts", "new"]) => new_post_form(req, state, tenant).await,
+    ("POST", ["preview"]) => preview::preview(req, state).await,
+    ("GET", ["preview", token]) => draft::show_preview(req, state, tenant, token).await,
+    ("POST", ["posts", id, "share"]) => share::create(req, state, tenant, id).await,
+    ("GET", ["posts", id, "shares"]) => share::list(req, state, tenant, id).await,
+    ("DELETE", ["posts", id, "shares", share_id]) => share::revoke(req, tenant, id, share_id).await,
+    ("GET", ["posts", id, "short"]) => shortlink::show(req, state, tenant, id).await,
+    ("POST", ["posts", id, "short"]) => shortlink::create(req, state, tenant, id).await,
+    ("GET", ["posts", id, "export.pdf"]) => pdf::export(req, state, tenant, id).await,
+    ("GET", ["posts", id, "qr.png"]) => qr::post(req, state, tenant, id).await,
+    ("POST", ["posts", id, "attachments"]) => attachment::upload(req, state, tenant, id).await,
+    ("GET", ["posts", id, "attachments"]) => attachment::list(tenant, id).await,
+    ("POST", ["posts", id, "uploads"]) => upload::start(req, state, tenant, id).await,
+    ("GET", ["posts", id, "related"]) => related::list(state, tenant, id).await,
+    ("POST", ["posts", id, "move"]) => notebook::move_post(req, tenant, id).await,
+    ("POST", ["posts", id, "copy"]) => notebook::copy_post(req, tenant, id).await,
+    ("POST", ["posts", id, "duplicate"]) => notebook::duplicate_post(req, tenant, id).await,
+    ("POST", ["posts", id, "merge"]) => merge::merge(req, state, tenant, id).await,
+    ("PUT", ["posts", id, "draft"]) => draft::save(req, state, tenant, id).await,
+    ("GET", ["posts", id, "draft"]) => draft::show(state, tenant, id).await,
+    ("GET", ["posts", id, "sync"]) => sync::connect(req, state, tenant, id).await,
+    ("DELETE", ["posts", id, "draft"]) => draft::discard(tenant, id).await,
+    ("POST", ["posts", id, "draft", "preview"]) => {
+      draft::issue_preview(req, state, tenant, id).await
+    }
+    ("GET", ["posts", id, ..]) => find_post(req, state, tenant, id).await,
+    ("GET", ["bookmarks"]) => bookmark::list(tenant).await,
+    ("POST", ["bookmarks"]) => bookmark::create(req, state, tenant).await,
+    ("OPTIONS", ["clip"]) => clip::preflight(req, state).await,
+    ("POST", ["voice-memos"]) => voice::create(req, state, tenant).await,
+    ("POST", ["clip"]) => clip::create(req, state, tenant).await,
+    ("GET", ["changes"]) => changes::feed(req, state, tenant).await,
+    ("POST", ["changes"]) => changes::upsert(req, state, tenant).await,
+    ("GET", ["conflicts"]) => conflict::list(tenant).await,
+    ("POST", ["conflicts", id, "resolve"]) => conflict::resolve(tenant, id).await,
+    ("GET", ["feed.xml"]) => feed::show_feed(req, state, tenant).await,
+    ("GET", ["sitemap.xml"]) => feed::show_sitemap(req, state, tenant).await,
+    ("GET", ["events"]) => events::stream(tenant).await,
+    ("GET", ["healthz"]) => Ok(Response::new("ok".into())),
+    ("GET", ["themes"]) => themes::list(req, state).await,
+    ("POST", ["themes", name]) => themes::select(state, name).await,
+    ("GET", ["timezone"]) => timezone::show(req, state).await,
+    ("POST", ["timezone"]) => timezone::select(req).await,
+    ("GET", ["search"]) => search::search(req, state, tenant).await,
+    ("GET", ["suggest"]) => suggest::suggest(req, tenant).await,
+    ("GET", ["stats"]) => stats::show(req, state, tenant).await,
+    ("GET", ["stats", "heatmap"]) => stats::heatmap(req, tenant).await,
+    ("GET", ["tags", tag]) => tags::posts(tenant, tag).await,
+    ("GET", ["digest", "latest"]) => digest::latest(req, state, tenant).await,
+    ("GET", ["popular"]) => views::popular(tenant).await,
+    ("GET", ["map"]) => geo::show(req, tenant).await,
+    ("GET", ["calendar", year, month]) => archive::calendar(req, state, tenant, year, month).await,
+    ("GET", ["archive", year]) => archive::show(req, state, tenant, year, None).await,
+    ("GET", ["archive", year, month]) => archive::show(req, state, tenant, year, Some(month)).await,
+    ("GET", ["searches"]) => saved_search::list(tenant).await,
+    ("POST", ["searches"]) => saved_search::create(req, tenant).await,
+    ("GET", ["searches", id]) => saved_search::show(req, state, tenant, id).await,
+    ("DELETE", ["searches", id]) => saved_search::delete(tenant, id).await,
+    ("GET", ["attachments", id]) => attachment::download(req, state, tenant, id).await,
+    ("DELETE", ["attachments", id]) => attachment::delete(req, state, tenant, id).await,
+    ("GET", ["uploads", id]) => upload::status(state, tenant, id).await,
+    ("PATCH", ["uploads", id]) => upload::append(req, state, tenant, id).await,
+    ("POST", ["uploads", id, "commit"]) => upload::commit(req, state, tenant, id).await,
+    ("DELETE", ["uploads", id]) => upload::cancel(state, tenant, id).await,
+    ("GET", ["r", code]) => shortlink::redirect(tenant, code).await,
+    ("GET", ["s", token, "qr.png"]) => share::qr(req, state, tenant, token).await,
+    ("GET", ["s", token]) => share::show(state, tenant, token).await,
+    ("GET", ["notebooks"]) => notebook::list(tenant).await,
+    ("POST", ["notebooks"]) => notebook::create(req, tenant).await,
+    ("GET", ["notebooks", name]) => notebook::show(tenant, name).await,
+    ("PUT", ["notebooks", name]) => notebook::rename(req, tenant, name).await,
+    ("DELETE", ["notebooks", name]) => notebook::delete(tenant, name).await,
+    ("GET", ["notebooks", name, "posts"]) => notebook::posts(req, tenant, name).await,
+    ("GET", ["notebooks", name, "export.epub"]) => epub::export(req, state, tenant, name).await,
+    ("PUT", ["notebooks", name, "parent"]) => notebook::set_parent(req, tenant, name).await,
+    ("GET", ["folders", "tree"]) => notebook::tree(tenant).await,
+    ("POST", ["notebooks", name, "posts"]) => create_post(req, state, tenant, Some(name)).await,
+    ("GET", ["admin", "features"]) => features::list(state, tenant).await,
+    ("PUT", ["admin", "features", name]) => features::set(req, tenant, name).await,
+    ("DELETE", ["admin", "features", name]) => features::reset(tenant, name).await,
+    ("GET", ["admin", "maintenance"]) => maintenance_mode::show(state).await,
+    ("POST", ["admin", "maintenance"]) => maintenance_mode::set(req, state).await,
+    ("GET", ["admin", "readonly"]) => readonly::show(state).await,
+    ("POST", ["admin", "readonly"]) => readonly::set(req, state).await,
+    ("GET", ["admin", "audit"]) => audit::list(req, tenant).await,
+    ("GET", ["admin", "db", "maintenance"]) => maintenance::last(tenant).await,
+    ("POST", ["admin", "db", "maintenance"]) => maintenance::run_now(state, tenant).await,
+    ("GET", ["admin", "db", "maintenance", "metrics"]) => maintenance::metrics(tenant).await,
+    ("GET", ["admin", "clip-tokens"]) => clip::list(tenant).await,
+    ("POST", ["admin", "clip-tokens"]) => clip::issue(req, tenant).await,
+    ("DELETE", ["admin", "clip-tokens", id]) => clip::revoke(tenant, id).await,
+    ("GET", ["admin", "rules"]) => tags::list_rules(tenant).await,
+    ("POST", ["admin", "rules"]) => tags::create_rule(req, tenant).await,
+    ("DELETE", ["admin", "rules", id]) => tags::delete_rule(tenant, id).await,
+    ("GET", ["admin", "outbox"]) => webhook::status(tenant).await,
+    ("POST", ["admin", "export-site"]) => site::export(req, state, tenant).await,
+    ("POST", ["admin", "trash", "purge"]) => trash::purge_now(req, state, tenant).await,
+    // 組み込みのURLに当てはまらなければプラグインが追加したURLを探す
+    (method, segments) => state.plugins.route(req, tenant, method, segments).await,
   }
 }
 
-#[tokio::main]
-async fn main() {
-  let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+// 接続元のアドレスをリクエストに持たせてから処理する関数
+// secureはTLSで受けた接続かどうか
+async fn serve(
+  mut req: Request<Body>,
+  state: Arc<State>,
+  remote_addr: SocketAddr,
+  secure: bool,
+) -> Result<Response<Body>, Error> {
+  // 各処理から参照できるように接続元のアドレスをリクエストに持たせる
+  req.extensions_mut().insert(remote_addr);
+  // プロキシを経由した場合は転送ヘッダから本当の接続元を求めておく
+  let client = state.proxies.resolve(&req, secure);
+  req.extensions_mut().insert(client);
+  // 負荷を判断するために処理中のリクエストを数える
+  let _in_flight = state.load.enter();
+  // 公開ブログのキャッシュの判断にはリクエストのメソッドとヘッダを使う
+  let method = req.method().clone();
+  let authorized = req.headers().contains_key(header::AUTHORIZATION);
+  // 前の書き込みで設定したメッセージは表示した画面のレスポンスで消す
+  let flash = flash::Flash::read(&req, &state.signer);
+  req.extensions_mut().insert(flash.clone());
+  let mut res = route(req, state.clone()).await?;
+  flash.finish(&mut res);
+  publish::finish(&state.publish, &method, authorized, &mut res);
+  Ok(res)
+}
+
+// TCPで待ち受けて処理する関数
+async fn serve_tcp(builder: server::Builder<AddrIncoming>, state: Arc<State>) {
+  let make_svc = make_service_fn(|stream: &AddrStream| {
+    // 接続元のアドレスは接続ごとに1回だけ取り出す
+    let remote_addr = stream.remote_addr();
+    // Arcを使うとコピーやアロケーションなしでcloneが使用できる
+    // cloneはスレッドの数だけ実行される
+    let state = state.clone();
+    async move {
+      Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+        //  ここでもcloneする．cloneは非同期ランタイムの実行スケジュール単位の数だけ実行される
+        serve(req, state.clone(), remote_addr, false)
+      }))
+    }
+  });
+  if let Err(e) = builder.serve(make_svc).await {
+    eprintln!("server error {}", e)
+  }
+}
+
+// TLSで待ち受けて処理する関数
+// 接続ごとにハンドシェイクしてからHTTPとして処理する
+async fn serve_tls(tcp: tokio::net::TcpListener, acceptor: TlsAcceptor, state: Arc<State>) {
+  loop {
+    let (stream, remote_addr) = match tcp.accept().await {
+      Ok(accepted) => accepted,
+      Err(e) => {
+        eprintln!("accept error {}", e);
+        continue;
+      }
+    };
+    let acceptor = acceptor.clone();
+    let state = state.clone();
+    tokio::spawn(async move {
+      let stream = match acceptor.accept(stream).await {
+        Ok(stream) => stream,
+        Err(e) => {
+          eprintln!("tls error {} from {}", e, remote_addr);
+          return;
+        }
+      };
+      let service =
+        service_fn(move |req: Request<Body>| serve(req, state.clone(), remote_addr, true));
+      if let Err(e) = server::conn::Http::new()
+        .serve_connection(stream, service)
+        // 共同編集のWebSocketに切り替えられるようにする
+        .with_upgrades()
+        .await
+      {
+        eprintln!("server error {}", e)
+      }
+    });
+  }
+}
+
+// Unixドメインソケットで待ち受けて処理する関数
+async fn serve_unix(unix: listener::UnixAccept, state: Arc<State>) {
+  let make_svc = make_service_fn(move |_: &UnixStream| {
+    let state = state.clone();
+    async move {
+      Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+        // ソケットには接続元のアドレスがないので同じホストからの接続として扱う
+        // 中継するサーバの転送ヘッダを使う場合はTRUSTED_PROXIESに127.0.0.1を含める
+        serve(
+          req,
+          state.clone(),
+          SocketAddr::from(([127, 0, 0, 1], 0)),
+          false,
+        )
+      }))
+    }
+  });
+  if let Err(e) = Server::builder(unix).serve(make_svc).await {
+    eprintln!("unix socket server error {}", e)
+  }
+}
 
+// 環境変数の設定からサーバ全体で共有する状態を作る関数
+// 投稿に起きた出来事を配る仕組みも一緒に返す
+fn state_from_env() -> (State, events::Bus) {
   // teraのアロケーションはサーバ立ち上げ時に1回必要なのみ
   // 新規テンプレートの作成
   let mut tera = Tera::default();
@@ -177,41 +947,192 @@ async fn main() {
   tera.add_raw_template("hello", TEMPLATE).unwrap();
   // postという名前で定義したテンプレートを呼び出す
   tera
-    .add_raw_template("post", "id: {{id}}\ntitle: {{title}}\ncontent: {{content}}")
-    .unwrap();
-  let tera = Arc::new(tera);
-
-  // DB接続関連の処理
-  let conn = Connection::open_in_memory().unwrap();
-  let conn = Arc::new(Mutex::new(conn));
-
-  conn
-    .lock()
-    .await
-    .execute(
-      "CREATE TABLE posts (
-    id BLOB PRIMARY KEY,
-    title TEXT NOT NULL,
-    content TEXT NOT NULL
-  )",
-      [],
+    .add_raw_template(
+      "post",
+      "{% if breadcrumbs %}notebook: {{breadcrumbs | join(sep=\" / \")}}\n{% endif %}\
+      id: {{id}}\ntitle: {{title}}\n\
+      {% if tags %}tags: {{tags | join(sep=\", \")}}\n{% endif %}\
+      {% if location %}location: {{location.lat}}, {{location.lon}}\n{% endif %}\
+      {% for key, value in metadata %}{{key}}: {{value}}\n{% endfor %}\
+      {% if voice %}voice: {{voice.url}}{% if voice.duration %} ({{voice.duration}}){% endif %}\n{% endif %}\
+      {% if counts %}length: {{counts.words}} word{{counts.words | pluralize}}, {{counts.chars}} char{{counts.chars | pluralize}}, {{counts.reading_minutes}} min read\n{% endif %}\
+      content: {{content}}\
+      {% if links %}\nlinks:{% for link in links %}\n- {{link.title}} <{{link.url}}>\
+      {% if link.description %}\n  {{link.description}}{% endif %}\
+      {% if link.image %}\n  image: {{link.image}}{% endif %}{% endfor %}{% endif %}\
+      {% if related %}\nrelated:{% for post in related %}\n- {{post.title}} ({{post.id}}){% endfor %}{% endif %}",
     )
     .unwrap();
+  // templates/以下のnew_post，searchなどの画面のテンプレートを呼び出す
+  templates::load(&mut tera);
+  // 日時や抜粋を整形するフィルタはすべてのテンプレートから使う
+  template_ext::register(&mut tera);
+  // CAPTCHAの設定はフォームのテンプレートからも参照する
+  let captcha = Captcha::from_env().map(Arc::new);
+  captcha::register(&mut tera, captcha.clone());
+  // 画面の文言は言語ごとに用意したものをテンプレートから参照する
+  let i18n = i18n::I18n::from_env();
+  i18n::register(&mut tera, i18n.clone());
+  // 読み取り専用の表示もテンプレートから参照する
+  let read_only = readonly::ReadOnly::from_env();
+  readonly::register(&mut tera, read_only.clone(), i18n.clone());
+  // 機能の設定もテンプレートから参照する
+  let features = features::Features::from_env();
+  features::register(&mut tera, features.clone());
+  // 絶対URLもテンプレートから作れるようにする
+  let base_url = urls::BaseUrl::from_env();
+  urls::register(&mut tera, base_url.clone());
+  // 静的なファイルのURLはハッシュを含めてテンプレートから作る
+  let assets = assets::Assets::from_env();
+  assets::register(&mut tera, assets.clone());
+  // 公開ブログとして動かす場合も編集する人は管理者として認証する
+  let admin_auth = admin_auth::AdminAuth::from_env();
+  // 投稿に起きた出来事はすべてのテナントで1つの仕組みで配る
+  let bus = events::Bus::new();
 
-  let make_svc = make_service_fn(|_conn| {
-    // Arcを使うとコピーやアロケーションなしでcloneが使用できる
-    // cloneはスレッドの数だけ実行される
-    let tera = tera.clone();
-    let conn = conn.clone();
-    async {
-      Ok::<_, Infallible>(service_fn(move |req| {
-        //  ここでもcloneする．cloneは非同期ランタイムの実行スケジュール単位の数だけ実行される
-        route(req, tera.clone(), conn.clone())
-      }))
-    }
-  });
-  let server = Server::bind(&addr).serve(make_svc);
-  if let Err(e) = server.await {
-    eprintln!("server error {}", e)
+  let state = State {
+    tera,
+    tenants: Tenants::from_env(bus.clone()),
+    // スパム判定の実装は環境変数で切り替える
+    spam: spam::from_env(),
+    captcha,
+    signer: Signer::from_env(),
+    codec: Codec::from_env(),
+    retention: trash::Retention::from_env(),
+    limits: limits::Limits::from_env(),
+    read_only,
+    maintenance_mode: maintenance_mode::MaintenanceMode::from_env(),
+    features,
+    load: shed::Load::from_env(),
+    ip_filter: ipfilter::IpFilter::from_env(),
+    proxies: proxy::TrustedProxies::from_env(),
+    publish: publish::Publish::from_env(&admin_auth),
+    admin_auth,
+    base_url,
+    themes: themes::Themes::from_env(&assets),
+    assets,
+    i18n,
+    timezone: timezone::Timezone::from_env(),
+    unfurl: unfurl::Unfurler::spawn(),
+    clip: clip::Clip::from_env(),
+    site: site::Site::from_env(),
+    webhooks: webhook::Webhooks::from_env(),
+    attachments: attachment::Attachments::from_env(),
+    uploads: upload::Uploads::from_env(),
+    notifications: notify::Notifications::from_env(),
+    // 独自の拡張はここにBox::new(...)で追加する
+    plugins: plugin::Plugins::new(vec![]),
+  };
+  (state, bus)
+}
+
+#[tokio::main]
+async fn main() {
+  let (state, bus) = state_from_env();
+  let state = Arc::new(state);
+
+  // web-memory export-site <テナント名> の場合はサーバを起動せずに静的なサイトを書き出して終わる
+  let args: Vec<String> = env::args().collect();
+  if args.get(1).map(String::as_str) == Some("export-site") {
+    site::command(&state, args.get(2).map(String::as_str)).await;
+    return;
+  }
+
+  views::spawn_flusher(state.clone());
+  trash::spawn_purger(state.clone());
+  upload::spawn_cleaner(state.clone());
+  maintenance::spawn_scheduler(state.clone());
+  digest::spawn_scheduler(state.clone());
+  webhook::spawn(state.clone(), &bus);
+  plugin::spawn(state.clone(), &bus);
+  notify::spawn(state.clone());
+  if let Some(enrichment) = enrich::Enrichment::from_env() {
+    enrich::spawn(enrichment, state.clone());
+  }
+  if let Some(replica) = replica::Replica::from_env() {
+    replica::spawn(replica, state.clone());
+  }
+
+  // 設定したすべてのソケットで同じように処理する
+  let servers: Vec<_> = listener::all()
+    .into_iter()
+    .map(|listener| match listener {
+      listener::Listener::Tcp(tcp) => {
+        tokio::spawn(serve_tcp(Server::from_tcp(tcp).unwrap(), state.clone()))
+      }
+      listener::Listener::Tls(tcp, acceptor) => {
+        tokio::spawn(serve_tls(tcp, acceptor, state.clone()))
+      }
+      listener::Listener::Unix(unix) => tokio::spawn(serve_unix(unix, state.clone())),
+    })
+    .collect();
+  for server in servers {
+    server.await.unwrap();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // テナントを使わない設定（メモリ上のDB）で作った状態
+  pub fn state() -> Arc<State> {
+    Arc::new(state_from_env().0)
+  }
+
+  // 127.0.0.1からのリクエストとして処理する関数
+  pub async fn send(state: &Arc<State>, req: Request<Body>) -> Response<Body> {
+    serve(req, state.clone(), ([127, 0, 0, 1], 40000).into(), false)
+      .await
+      .unwrap()
+  }
+
+  // テナントを使わない場合の唯一のテナント
+  pub fn tenant(state: &Arc<State>) -> Arc<Tenant> {
+    state
+      .tenants
+      .resolve(&Request::new(Body::empty()))
+      .unwrap()
+      .0
+  }
+
+  // 平文の投稿をDBに直接追加する関数
+  pub async fn insert_post(state: &Arc<State>, title: &str, visibility: Visibility) -> Uuid {
+    let id = Uuid::new_v4();
+    tenant(state)
+      .conn
+      .lock()
+      .await
+      .execute(
+        "INSERT INTO posts(id, title, content, visibility, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+          id,
+          title,
+          format!("{} content", title),
+          visibility,
+          now() as i64
+        ],
+      )
+      .unwrap();
+    id
+  }
+
+  #[test]
+  fn new_post_accepts_encoded_form_values() {
+    let body = "title=Hello+world&content=%E3%81%82%E3%81%84&notebook=My+notes";
+    let post = serde_urlencoded::from_bytes::<NewPost>(body.as_bytes()).unwrap();
+    assert_eq!(post.title, "Hello world");
+    assert_eq!(post.content, "あい");
+    assert_eq!(post.notebook.as_deref(), Some("My notes"));
+    assert!(post.website.is_empty());
+  }
+
+  #[tokio::test]
+  async fn rejects_bad_create_query() {
+    let state = state();
+    let req = Request::post("/posts?force=yes")
+      .body("title=a&content=b".into())
+      .unwrap();
+    assert_eq!(send(&state, req).await.status(), StatusCode::BAD_REQUEST);
   }
 }
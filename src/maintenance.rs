@@ -0,0 +1,184 @@
+use std::{
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use hyper::{header, Body, Error, Response, StatusCode};
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::{empty, json, now, State, Tenant};
+
+// 定期的に実行する間隔
+const MAINTENANCE_SECONDS: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Clone)]
+pub struct Report {
+  at: u64,
+  // 問題がなければ["ok"]
+  integrity: Vec<String>,
+  vacuum_ms: u128,
+  analyze_ms: u128,
+}
+
+// 最後に実行した結果
+pub struct Last {
+  report: Mutex<Option<Report>>,
+}
+
+impl Last {
+  pub fn new() -> Last {
+    Last {
+      report: Mutex::new(None),
+    }
+  }
+}
+
+// 整合性を確かめてから，DBの断片化を解消して統計情報を更新する関数
+// VACUUMはDB全体を書き直すので，壊れている場合は実行しない
+fn run(conn: &Connection) -> Report {
+  let mut stmt = conn.prepare("PRAGMA integrity_check").unwrap();
+  let integrity = stmt
+    .query_map([], |row| row.get(0))
+    .unwrap()
+    .collect::<Result<Vec<String>, _>>()
+    .unwrap();
+  let healthy = integrity == ["ok"];
+  let timed = |sql: &str| {
+    let start = Instant::now();
+    conn.execute_batch(sql).unwrap();
+    start.elapsed().as_millis()
+  };
+  let vacuum_ms = if healthy { timed("VACUUM") } else { 0 };
+  let analyze_ms = if healthy { timed("ANALYZE") } else { 0 };
+  if !healthy {
+    eprintln!("integrity check failed: {:?}", integrity);
+  }
+  Report {
+    at: now(),
+    integrity,
+    vacuum_ms,
+    analyze_ms,
+  }
+}
+
+// VACUUMは大きなDBでは時間がかかるので，非同期のランタイムを止めないように別のスレッドで実行する
+// 実行中はほかのリクエストがDB接続のロックをawaitして待つ
+async fn run_for(tenant: Arc<Tenant>) -> Report {
+  tokio::task::spawn_blocking(move || {
+    let report = run(&tenant.conn.blocking_lock());
+    *tenant.maintenance.report.lock().unwrap() = Some(report.clone());
+    report
+  })
+  .await
+  .unwrap()
+}
+
+// 一定の間隔で開いているすべてのテナントのDBを手入れする関数
+pub fn spawn_scheduler(state: Arc<State>) {
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(MAINTENANCE_SECONDS));
+    // 起動直後は実行しない
+    interval.tick().await;
+    loop {
+      interval.tick().await;
+      for tenant in state.tenants.all() {
+        // VACUUMはDBを書き直すので読み取り専用の間は行わない
+        // 途中で切り替わる場合もあるのでテナントごとに確かめる
+        if state.read_only.is_on() {
+          break;
+        }
+        run_for(tenant).await;
+      }
+    }
+  });
+}
+
+// すぐに手入れを実行して結果を返す関数
+// 定期的な実行と同じく読み取り専用の間は断る
+pub async fn run_now(state: Arc<State>, tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  if state.read_only.is_on() {
+    return Ok(empty(StatusCode::SERVICE_UNAVAILABLE));
+  }
+  Ok(json(&run_for(tenant).await))
+}
+
+// 最後に実行した結果を返す関数
+pub async fn last(tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  match &*tenant.maintenance.report.lock().unwrap() {
+    Some(report) => Ok(json(report)),
+    None => Ok(empty(StatusCode::NOT_FOUND)),
+  }
+}
+
+// 最後に実行した結果をPrometheusのテキスト形式で返す関数
+// まだ実行していない場合は何も返さない
+pub async fn metrics(tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let body = match &*tenant.maintenance.report.lock().unwrap() {
+    Some(report) => exposition(report),
+    None => String::new(),
+  };
+  Ok(
+    Response::builder()
+      .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+      .body(body.into())
+      .unwrap(),
+  )
+}
+
+fn exposition(report: &Report) -> String {
+  let gauges = [
+    (
+      "db_maintenance_last_run_timestamp_seconds",
+      "Unix time of the last database maintenance",
+      report.at as u128,
+    ),
+    (
+      "db_maintenance_integrity_ok",
+      "1 if the last integrity check passed",
+      u128::from(report.integrity == ["ok"]),
+    ),
+    (
+      "db_maintenance_vacuum_milliseconds",
+      "Duration of the last VACUUM",
+      report.vacuum_ms,
+    ),
+    (
+      "db_maintenance_analyze_milliseconds",
+      "Duration of the last ANALYZE",
+      report.analyze_ms,
+    ),
+  ];
+  gauges
+    .iter()
+    .map(|(name, help, value)| {
+      format!(
+        "# HELP {0} {1}\n# TYPE {0} gauge\n{0} {2}\n",
+        name, help, value
+      )
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reports_integrity_and_timings() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn
+      .execute_batch("CREATE TABLE t(x); INSERT INTO t VALUES (1);")
+      .unwrap();
+    let report = run(&conn);
+    assert_eq!(report.integrity, ["ok"]);
+    let text = exposition(&report);
+    assert!(
+      text.contains("# TYPE db_maintenance_integrity_ok gauge\ndb_maintenance_integrity_ok 1\n")
+    );
+    assert!(text.contains(&format!(
+      "db_maintenance_last_run_timestamp_seconds {}\n",
+      report.at
+    )));
+  }
+}
@@ -0,0 +1,104 @@
+use std::{env, sync::Arc, sync::Mutex};
+
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tera::Context;
+
+use crate::{empty, json, templates, State};
+
+// 再開までの目安（秒）の初期値
+const DEFAULT_RETRY_AFTER: u64 = 10 * 60;
+
+// サービス全体を止めてメンテナンス中の画面を返すための切り替え
+// 有効な間は再開までの目安（秒）を持つ
+pub struct MaintenanceMode {
+  retry_after: Mutex<Option<u64>>,
+}
+
+impl MaintenanceMode {
+  // MAINTENANCE_MODE=trueで起動すると最初からメンテナンス中にする
+  // 目安はMAINTENANCE_RETRY_AFTERで指定する
+  pub fn from_env() -> MaintenanceMode {
+    let retry_after = env::var("MAINTENANCE_RETRY_AFTER")
+      .map(|s| s.parse().expect("MAINTENANCE_RETRY_AFTER must be a number"))
+      .unwrap_or(DEFAULT_RETRY_AFTER);
+    let on = env::var("MAINTENANCE_MODE").as_deref() == Ok("true");
+    MaintenanceMode {
+      retry_after: Mutex::new(if on { Some(retry_after) } else { None }),
+    }
+  }
+}
+
+// メンテナンス中であれば，リクエストの代わりに返すレスポンスを作る関数
+// 状態の確認と元に戻すための管理用のAPIは受け付ける
+pub fn intercept(req: &Request<Body>, state: &State, segments: &[&str]) -> Option<Response<Body>> {
+  if matches!(segments.first(), Some(&"healthz") | Some(&"admin")) {
+    return None;
+  }
+  let retry_after = (*state.maintenance_mode.retry_after.lock().unwrap())?;
+  let mut ctx = Context::new();
+  ctx.insert("retry_after", &retry_after);
+  let rendered = templates::render(state, req, "maintenance", &mut ctx);
+  Some(
+    Response::builder()
+      .status(StatusCode::SERVICE_UNAVAILABLE)
+      .header(header::RETRY_AFTER, retry_after)
+      .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+      .body(rendered.into())
+      .unwrap(),
+  )
+}
+
+#[derive(Deserialize)]
+struct Query {
+  enabled: bool,
+  retry_after: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Status {
+  maintenance: bool,
+  retry_after: Option<u64>,
+}
+
+// 現在の状態を返す関数
+pub async fn show(state: Arc<State>) -> Result<Response<Body>, Error> {
+  let retry_after = *state.maintenance_mode.retry_after.lock().unwrap();
+  Ok(json(&Status {
+    maintenance: retry_after.is_some(),
+    retry_after,
+  }))
+}
+
+// POST /admin/maintenance?enabled=true&retry_after=300 で切り替える関数
+pub async fn set(req: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Error> {
+  let query = match serde_urlencoded::from_str::<Query>(req.uri().query().unwrap_or_default()) {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  *state.maintenance_mode.retry_after.lock().unwrap() = if query.enabled {
+    Some(query.retry_after.unwrap_or(DEFAULT_RETRY_AFTER))
+  } else {
+    None
+  };
+  show(state).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests;
+
+  #[tokio::test]
+  async fn rejects_bad_query() {
+    let state = tests::state();
+    for query in ["", "?enabled=true&retry_after=soon"] {
+      let req = Request::post(format!("/admin/maintenance{}", query))
+        .body(Body::empty())
+        .unwrap();
+      let res = set(req, state.clone()).await.unwrap();
+      assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+    assert!(state.maintenance_mode.retry_after.lock().unwrap().is_none());
+  }
+}
@@ -0,0 +1,111 @@
+use std::sync::Arc;
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+  audit, duplicate, empty, events::Event, now, remote_ip, tags, wordcount, Post, State, Tenant,
+};
+
+#[derive(Deserialize)]
+struct Query {
+  into: Uuid,
+}
+
+// 統合できる投稿を探す関数
+// E2EEの投稿はサーバで本文を読めないので対象外
+fn find(conn: &Connection, state: &State, id: &Uuid) -> Option<Post> {
+  conn
+    .query_row(
+      "SELECT id, title, content, encrypted, compressed FROM posts
+      WHERE id=?1 AND kind = 'text' AND trashed_at IS NULL",
+      params![id],
+      |row| Post::from_row(row, &state.codec),
+    )
+    .optional()
+    .unwrap()
+}
+
+// 投稿を別の投稿に統合する関数
+// 本文を統合先の末尾に追加し，共有リンクや添付ファイルを付け替えてから元の投稿をゴミ箱に入れる
+pub async fn merge(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  id: &str,
+) -> Result<Response<Body>, Error> {
+  let query = serde_urlencoded::from_str::<Query>(req.uri().query().unwrap_or_default());
+  let (id, into) = match (Uuid::parse_str(id), query) {
+    (Ok(id), Ok(query)) if id != query.into => (id, query.into),
+    _ => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let ip = remote_ip(&req);
+  let mut conn = tenant.conn.lock().await;
+  let tx = conn.transaction().unwrap();
+  let (source, target) = match (find(&tx, &state, &id), find(&tx, &state, &into)) {
+    (Some(source), Some(target)) => (source, target),
+    _ => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let content = format!("{}\n\n{}", target.content, source.content);
+  let stored = state.codec.encode(&content);
+  tx.execute(
+    "UPDATE posts SET content=?1, encrypted=?2, compressed=?3, content_hash=?4 WHERE id=?5",
+    params![
+      &stored.content,
+      &stored.encrypted,
+      &stored.compressed,
+      &duplicate::hash(&state.signer, &content),
+      &into
+    ],
+  )
+  .unwrap();
+  wordcount::record(&tx, &into, &content);
+  tags::apply(&tx, &into, &content);
+  // ゴミ箱を片付けるときに消えないように，元の投稿に付いていたものを統合先に付け替える
+  // 投稿ごとに1つしか持てないもの（音声メモ，短縮リンク，下書き）は統合先にない場合だけ移す
+  for sql in [
+    "UPDATE shares SET post_id=?1 WHERE post_id=?2",
+    "UPDATE attachments SET post_id=?1 WHERE post_id=?2",
+    "UPDATE OR IGNORE voice_memos SET post_id=?1 WHERE post_id=?2",
+    "UPDATE OR IGNORE short_links SET post_id=?1 WHERE post_id=?2",
+    "UPDATE OR IGNORE drafts SET post_id=?1 WHERE post_id=?2",
+  ] {
+    tx.execute(sql, params![into, id]).unwrap();
+  }
+  tx.execute(
+    "UPDATE posts SET trashed_at=?1 WHERE id=?2",
+    params![now(), id],
+  )
+  .unwrap();
+  audit::record(
+    &tx,
+    audit::Entry {
+      actor: audit::ANONYMOUS,
+      action: "merge",
+      post_id: &into,
+      summary: format!(
+        "from={}, {} -> {} chars",
+        id,
+        target.content.chars().count(),
+        content.chars().count()
+      ),
+      ip,
+    },
+  );
+  audit::record(
+    &tx,
+    audit::Entry {
+      actor: audit::ANONYMOUS,
+      action: "trash",
+      post_id: &id,
+      summary: format!("merged into={}", into),
+      ip,
+    },
+  );
+  tx.commit().unwrap();
+  tenant.publish(Event::Updated { id: into });
+  tenant.publish(Event::Trashed { id });
+  Ok(Response::new(into.to_string().into()))
+}
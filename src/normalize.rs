@@ -0,0 +1,32 @@
+// 同じ場所を指すパスの書き方を1つにそろえるための関数
+
+// 重複した/と末尾の/を取り除いたパスを返す関数
+// 元のパスと同じ場合はNoneを返す
+pub fn redirect_target(path: &str) -> Option<String> {
+  let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+  let normalized = format!("/{}", segments.join("/"));
+  if normalized == path {
+    None
+  } else {
+    Some(normalized)
+  }
+}
+
+// パスの1区切りの%エンコードを戻す関数
+// %の後が16進数2桁でない場合やUTF-8でない場合はNoneを返す
+pub fn decode(segment: &str) -> Option<String> {
+  let bytes = segment.as_bytes();
+  let mut out = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    if bytes[i] == b'%' {
+      let hex = segment.get(i + 1..i + 3)?;
+      out.push(u8::from_str_radix(hex, 16).ok()?);
+      i += 3;
+    } else {
+      out.push(bytes[i]);
+      i += 1;
+    }
+  }
+  String::from_utf8(out).ok()
+}
@@ -0,0 +1,721 @@
+use std::sync::Arc;
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+  attachment, audit, empty, events::Event, json, now, remote_ip, tags, wordcount::Counts, Tenant,
+  Visibility,
+};
+
+#[derive(Serialize)]
+struct Notebook {
+  id: i64,
+  name: String,
+  created_at: i64,
+  posts: i64,
+}
+
+#[derive(Deserialize)]
+struct NotebookForm {
+  name: String,
+}
+
+// 親を変更するときのフォーム（空の場合は最上位に移す）
+#[derive(Deserialize)]
+struct ParentForm {
+  #[serde(default)]
+  parent: String,
+}
+
+// 投稿を移動や複製するときのフォーム（空の場合はどのノートブックにも属さなくする）
+#[derive(Deserialize)]
+struct MoveForm {
+  #[serde(default)]
+  notebook: String,
+}
+
+// 階層を表すノード
+#[derive(Serialize)]
+struct Node {
+  name: String,
+  posts: i64,
+  children: Vec<Node>,
+}
+
+// 一覧で返す投稿の情報
+#[derive(Serialize)]
+struct PostSummary {
+  id: Uuid,
+  title: String,
+  views: u64,
+  // 語数などを記録していない投稿はnull
+  counts: Option<Counts>,
+}
+
+// 投稿の一覧の並べ替えと絞り込み
+#[derive(Deserialize, Default)]
+struct PostsQuery {
+  // words，reading_timeで昇順に，先頭に-を付けると降順に並べる（省略時は作成順）
+  #[serde(default)]
+  sort: String,
+  min_words: Option<u64>,
+  max_words: Option<u64>,
+  // 指定した分数以内で読める投稿に絞る
+  max_minutes: Option<u64>,
+}
+
+// パスに使うので空の名前と/を含む名前は受け付けない
+fn valid_name(name: &str) -> bool {
+  !name.is_empty() && !name.contains('/')
+}
+
+// 名前が重複した場合はfalseを返す
+fn unique(result: rusqlite::Result<usize>) -> bool {
+  match result {
+    Ok(_) => true,
+    Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => false,
+    Err(e) => panic!("{}", e),
+  }
+}
+
+// 名前からノートブックのidを探す関数
+pub fn id_by_name(conn: &Connection, name: &str) -> Option<i64> {
+  conn
+    .query_row(
+      "SELECT id FROM notebooks WHERE name=?1",
+      params![name],
+      |row| row.get(0),
+    )
+    .optional()
+    .unwrap()
+}
+
+// ノートブック自身から最上位までの祖先を順に返す関数
+// 親の変更時に循環を防いでいるので必ず終わる
+fn ancestors(conn: &Connection, id: i64) -> Vec<(i64, String)> {
+  let mut stmt = conn
+    .prepare(
+      "WITH RECURSIVE up(id, name, parent_id, depth) AS (
+        SELECT id, name, parent_id, 0 FROM notebooks WHERE id=?1
+        UNION ALL
+        SELECT notebooks.id, notebooks.name, notebooks.parent_id, depth + 1
+        FROM notebooks JOIN up ON notebooks.id = up.parent_id
+      )
+      SELECT id, name FROM up ORDER BY depth",
+    )
+    .unwrap();
+  stmt
+    .query_map(params![id], |row| Ok((row.get(0)?, row.get(1)?)))
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap()
+}
+
+// 投稿が属するノートブックまでのパンくずを最上位から順に返す関数
+pub fn breadcrumbs(conn: &Connection, post_id: &Uuid) -> Vec<String> {
+  let notebook_id: Option<i64> = conn
+    .query_row(
+      "SELECT notebook_id FROM posts WHERE id=?1",
+      params![post_id],
+      |row| row.get(0),
+    )
+    .unwrap();
+  match notebook_id {
+    Some(id) => ancestors(conn, id)
+      .into_iter()
+      .rev()
+      .map(|(_, name)| name)
+      .collect(),
+    None => Vec::new(),
+  }
+}
+
+// ノートブックを一覧する関数
+pub async fn list(tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let conn = tenant.conn.lock().await;
+  let mut stmt = conn
+    .prepare(
+      "SELECT notebooks.id, name, created_at, COUNT(posts.id) FROM notebooks
+      LEFT JOIN posts ON posts.notebook_id = notebooks.id AND posts.trashed_at IS NULL
+      GROUP BY notebooks.id ORDER BY name",
+    )
+    .unwrap();
+  let notebooks = stmt
+    .query_map([], |row| {
+      Ok(Notebook {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        created_at: row.get(2)?,
+        posts: row.get(3)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  Ok(json(&notebooks))
+}
+
+// ノートブックを作成する関数
+pub async fn create(req: Request<Body>, tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let form = match serde_urlencoded::from_bytes::<NotebookForm>(&body) {
+    Ok(form) => form,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  if !valid_name(&form.name) {
+    return Ok(empty(StatusCode::UNPROCESSABLE_ENTITY));
+  }
+  let conn = tenant.conn.lock().await;
+  let inserted = conn.execute(
+    "INSERT INTO notebooks(name) VALUES (?1)",
+    params![form.name],
+  );
+  if !unique(inserted) {
+    return Ok(empty(StatusCode::CONFLICT));
+  }
+  Ok(Response::new(conn.last_insert_rowid().to_string().into()))
+}
+
+// ノートブックの情報を返す関数
+pub async fn show(tenant: Arc<Tenant>, name: &str) -> Result<Response<Body>, Error> {
+  let notebook = tenant
+    .conn
+    .lock()
+    .await
+    .query_row(
+      "SELECT id, name, created_at,
+      (SELECT COUNT(*) FROM posts WHERE notebook_id = notebooks.id AND trashed_at IS NULL)
+      FROM notebooks WHERE name=?1",
+      params![name],
+      |row| {
+        Ok(Notebook {
+          id: row.get(0)?,
+          name: row.get(1)?,
+          created_at: row.get(2)?,
+          posts: row.get(3)?,
+        })
+      },
+    )
+    .optional()
+    .unwrap();
+  match notebook {
+    Some(notebook) => Ok(json(&notebook)),
+    None => Ok(empty(StatusCode::NOT_FOUND)),
+  }
+}
+
+// ノートブックの名前を変更する関数
+pub async fn rename(
+  req: Request<Body>,
+  tenant: Arc<Tenant>,
+  name: &str,
+) -> Result<Response<Body>, Error> {
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let form = match serde_urlencoded::from_bytes::<NotebookForm>(&body) {
+    Ok(form) => form,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  if !valid_name(&form.name) {
+    return Ok(empty(StatusCode::UNPROCESSABLE_ENTITY));
+  }
+  let updated = tenant.conn.lock().await.execute(
+    "UPDATE notebooks SET name=?1 WHERE name=?2",
+    params![form.name, name],
+  );
+  if let Ok(0) = updated {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  if !unique(updated) {
+    return Ok(empty(StatusCode::CONFLICT));
+  }
+  Ok(empty(StatusCode::NO_CONTENT))
+}
+
+// ノートブックを削除する関数
+// 投稿や子のノートブックが残っている場合は誤って失わないように削除しない
+pub async fn delete(tenant: Arc<Tenant>, name: &str) -> Result<Response<Body>, Error> {
+  let conn = tenant.conn.lock().await;
+  let id = match id_by_name(&conn, name) {
+    Some(id) => id,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  // ゴミ箱の投稿もノートブックを参照しているので数に含める
+  let children: i64 = conn
+    .query_row(
+      "SELECT (SELECT COUNT(*) FROM posts WHERE notebook_id=?1)
+      + (SELECT COUNT(*) FROM notebooks WHERE parent_id=?1)",
+      params![id],
+      |row| row.get(0),
+    )
+    .unwrap();
+  if children > 0 {
+    return Ok(empty(StatusCode::CONFLICT));
+  }
+  conn
+    .execute("DELETE FROM notebooks WHERE id=?1", params![id])
+    .unwrap();
+  Ok(empty(StatusCode::NO_CONTENT))
+}
+
+// ノートブックの投稿を一覧する関数
+// 一覧には公開の投稿のみを含める
+// 語数で絞り込んだ場合は，語数を記録していない投稿は含めない
+pub async fn posts(
+  req: Request<Body>,
+  tenant: Arc<Tenant>,
+  name: &str,
+) -> Result<Response<Body>, Error> {
+  let query = match serde_urlencoded::from_str::<PostsQuery>(req.uri().query().unwrap_or_default())
+  {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  // 記録していない投稿はどちらの順でも末尾に並べる
+  let order = match query.sort.as_str() {
+    "" => "posts.rowid",
+    "words" => "word_count IS NULL, word_count, posts.rowid",
+    "-words" => "word_count IS NULL, word_count DESC, posts.rowid",
+    "reading_time" => "reading_minutes IS NULL, reading_minutes, posts.rowid",
+    "-reading_time" => "reading_minutes IS NULL, reading_minutes DESC, posts.rowid",
+    _ => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let conn = tenant.conn.lock().await;
+  let id = match id_by_name(&conn, name) {
+    Some(id) => id,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let mut stmt = conn
+    .prepare(&format!(
+      "SELECT id, title, COALESCE(views, 0), word_count, char_count, reading_minutes FROM posts
+      LEFT JOIN post_views ON post_views.post_id = posts.id
+      WHERE notebook_id=?1 AND visibility=?2 AND trashed_at IS NULL
+      AND (?3 IS NULL OR word_count >= ?3) AND (?4 IS NULL OR word_count <= ?4)
+      AND (?5 IS NULL OR reading_minutes <= ?5)
+      ORDER BY {}",
+      order
+    ))
+    .unwrap();
+  let posts = stmt
+    .query_map(
+      params![
+        id,
+        Visibility::Public,
+        query.min_words,
+        query.max_words,
+        query.max_minutes
+      ],
+      |row| {
+        let id = row.get(0)?;
+        Ok(PostSummary {
+          id,
+          title: row.get(1)?,
+          views: row.get::<_, u64>(2)? + tenant.views.pending(&id),
+          counts: Counts::from_row(row, 3)?,
+        })
+      },
+    )
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  Ok(json(&posts))
+}
+
+// ノートブックの親を変更する関数
+// 自身や子孫の下に移すと循環するので受け付けない
+pub async fn set_parent(
+  req: Request<Body>,
+  tenant: Arc<Tenant>,
+  name: &str,
+) -> Result<Response<Body>, Error> {
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let form = match serde_urlencoded::from_bytes::<ParentForm>(&body) {
+    Ok(form) => form,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let conn = tenant.conn.lock().await;
+  let id = match id_by_name(&conn, name) {
+    Some(id) => id,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let parent_id = if form.parent.is_empty() {
+    None
+  } else {
+    match id_by_name(&conn, &form.parent) {
+      Some(parent_id) => Some(parent_id),
+      None => return Ok(empty(StatusCode::NOT_FOUND)),
+    }
+  };
+  if let Some(parent_id) = parent_id {
+    if ancestors(&conn, parent_id)
+      .iter()
+      .any(|(ancestor, _)| *ancestor == id)
+    {
+      return Ok(empty(StatusCode::CONFLICT));
+    }
+  }
+  conn
+    .execute(
+      "UPDATE notebooks SET parent_id=?1 WHERE id=?2",
+      params![parent_id, id],
+    )
+    .unwrap();
+  Ok(empty(StatusCode::NO_CONTENT))
+}
+
+// ノートブックの階層を返す関数
+pub async fn tree(tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let conn = tenant.conn.lock().await;
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, name, parent_id,
+      (SELECT COUNT(*) FROM posts WHERE notebook_id = notebooks.id AND trashed_at IS NULL)
+      FROM notebooks ORDER BY name",
+    )
+    .unwrap();
+  let rows: Vec<(i64, String, Option<i64>, i64)> = stmt
+    .query_map([], |row| {
+      Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  // 親のidが一致するものを子として再帰的に組み立てる
+  fn children(rows: &[(i64, String, Option<i64>, i64)], parent: Option<i64>) -> Vec<Node> {
+    rows
+      .iter()
+      .filter(|(_, _, parent_id, _)| *parent_id == parent)
+      .map(|(id, name, _, posts)| Node {
+        name: name.clone(),
+        posts: *posts,
+        children: children(rows, Some(*id)),
+      })
+      .collect()
+  }
+  Ok(json(&children(&rows, None)))
+}
+
+// 移動やコピーの後の投稿の場所
+#[derive(Serialize)]
+struct Location {
+  id: Uuid,
+  notebook: Option<String>,
+}
+
+// フォームで指定された移動先のノートブックのidを探す関数
+// 空の場合はどのノートブックにも属さないのでSome(None)を，存在しない場合はNoneを返す
+fn destination(conn: &Connection, form: &MoveForm) -> Option<Option<i64>> {
+  if form.notebook.is_empty() {
+    Some(None)
+  } else {
+    id_by_name(conn, &form.notebook).map(Some)
+  }
+}
+
+fn location(id: Uuid, form: MoveForm) -> Response<Body> {
+  json(&Location {
+    id,
+    notebook: Some(form.notebook).filter(|name| !name.is_empty()),
+  })
+}
+
+// 投稿を別のノートブックに移動する関数
+pub async fn move_post(
+  req: Request<Body>,
+  tenant: Arc<Tenant>,
+  post_id: &str,
+) -> Result<Response<Body>, Error> {
+  let post_id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let ip = remote_ip(&req);
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let form = match serde_urlencoded::from_bytes::<MoveForm>(&body) {
+    Ok(form) => form,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let mut conn = tenant.conn.lock().await;
+  // 投稿の更新と監査ログの記録はまとめて反映する
+  let tx = conn.transaction().unwrap();
+  let notebook_id = match destination(&tx, &form) {
+    Some(notebook_id) => notebook_id,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let moved = tx
+    .execute(
+      "UPDATE posts SET notebook_id=?1 WHERE id=?2 AND trashed_at IS NULL",
+      params![notebook_id, post_id],
+    )
+    .unwrap();
+  if moved == 0 {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  audit::record(
+    &tx,
+    audit::Entry {
+      actor: audit::ANONYMOUS,
+      action: "move",
+      post_id: &post_id,
+      summary: format!("notebook={:?}", form.notebook),
+      ip,
+    },
+  );
+  tx.commit().unwrap();
+  tenant.publish(Event::Moved { id: post_id });
+  Ok(location(post_id, form))
+}
+
+// 投稿を別のノートブックに複製する関数
+// 本文は保存された形式のまま複製するので，暗号化やE2EEの投稿もそのまま扱える
+// タグと添付ファイルは写し，共有リンクは元の投稿に対して発行されたものなので複製しない
+pub async fn copy_post(
+  req: Request<Body>,
+  tenant: Arc<Tenant>,
+  post_id: &str,
+) -> Result<Response<Body>, Error> {
+  let post_id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let ip = remote_ip(&req);
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let form = match serde_urlencoded::from_bytes::<MoveForm>(&body) {
+    Ok(form) => form,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let mut conn = tenant.conn.lock().await;
+  let tx = conn.transaction().unwrap();
+  let notebook_id = match destination(&tx, &form) {
+    Some(notebook_id) => notebook_id,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let id = Uuid::new_v4();
+  let copied = tx
+    .execute(
+      "INSERT INTO posts(id, title, content, encrypted, compressed, visibility, kind, client_metadata,
+        notebook_id, content_hash, word_count, char_count, reading_minutes, created_at, lat, lon)
+      SELECT ?1, title, content, encrypted, compressed, visibility, kind, client_metadata, ?2, content_hash,
+        word_count, char_count, reading_minutes, ?3, lat, lon
+      FROM posts WHERE id=?4 AND trashed_at IS NULL",
+      params![id, notebook_id, now(), post_id],
+    )
+    .unwrap();
+  if copied == 0 {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  tags::copy(&tx, &post_id, &id);
+  attachment::copy_all(&tx, &post_id, &id);
+  audit::record(
+    &tx,
+    audit::Entry {
+      actor: audit::ANONYMOUS,
+      action: "copy",
+      post_id: &id,
+      summary: format!("from={}, notebook={:?}", post_id, form.notebook),
+      ip,
+    },
+  );
+  tx.commit().unwrap();
+  tenant.publish(Event::Created { id });
+  Ok(location(id, form))
+}
+
+// 投稿を同じノートブックに複製する関数（タグと添付ファイルは写し，コメントは写さない）
+// 複製だと分かるようにタイトルの末尾に印を付ける
+// 元の投稿がない場合はNoneを返す
+fn duplicate(conn: &Connection, post_id: &Uuid) -> Option<Uuid> {
+  let id = Uuid::new_v4();
+  let duplicated = conn
+    .execute(
+      "INSERT INTO posts(id, title, content, encrypted, compressed, visibility, kind, client_metadata,
+        notebook_id, content_hash, word_count, char_count, reading_minutes, created_at, lat, lon)
+      SELECT ?1, title || ' (copy)', content, encrypted, compressed, visibility, kind, client_metadata,
+        notebook_id, content_hash, word_count, char_count, reading_minutes, ?2, lat, lon
+      FROM posts WHERE id=?3 AND trashed_at IS NULL",
+      params![id, now(), post_id],
+    )
+    .unwrap();
+  if duplicated == 0 {
+    return None;
+  }
+  tags::copy(conn, post_id, &id);
+  attachment::copy_all(conn, post_id, &id);
+  Some(id)
+}
+
+// 投稿を同じノートブックに複製して新しい投稿の下書きにする関数
+pub async fn duplicate_post(
+  req: Request<Body>,
+  tenant: Arc<Tenant>,
+  post_id: &str,
+) -> Result<Response<Body>, Error> {
+  let post_id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let mut conn = tenant.conn.lock().await;
+  let tx = conn.transaction().unwrap();
+  let id = match duplicate(&tx, &post_id) {
+    Some(id) => id,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  audit::record(
+    &tx,
+    audit::Entry {
+      actor: audit::ANONYMOUS,
+      action: "duplicate",
+      post_id: &id,
+      summary: format!("from={}", post_id),
+      ip: remote_ip(&req),
+    },
+  );
+  tx.commit().unwrap();
+  tenant.publish(Event::Created { id });
+  Ok(Response::new(id.to_string().into()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{db, tests};
+
+  // フォームを読めない場合は400を返す
+  async fn send_form(method: &str, path: &str, body: &'static str) -> StatusCode {
+    let state = tests::state();
+    let req = Request::builder()
+      .method(method)
+      .uri(path)
+      .body(Body::from(body))
+      .unwrap();
+    tests::send(&state, req).await.status()
+  }
+
+  #[tokio::test]
+  async fn rejects_bad_notebook_forms() {
+    assert_eq!(
+      send_form("POST", "/notebooks", "").await,
+      StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+      send_form("PUT", "/notebooks/old", "label=new").await,
+      StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+      send_form("PUT", "/notebooks/old/parent", "parent=a&parent=b").await,
+      StatusCode::BAD_REQUEST
+    );
+    let move_path = format!("/posts/{}/move", Uuid::new_v4());
+    assert_eq!(
+      send_form("POST", &move_path, "notebook=a&notebook=b").await,
+      StatusCode::BAD_REQUEST
+    );
+    let copy_path = format!("/posts/{}/copy", Uuid::new_v4());
+    assert_eq!(
+      send_form("POST", &copy_path, "notebook=a&notebook=b").await,
+      StatusCode::BAD_REQUEST
+    );
+  }
+
+  #[tokio::test]
+  async fn copy_shares_attachments() {
+    let state = tests::state();
+    let tenant = tests::tenant(&state);
+    let post_id = tests::insert_post(&state, "Trip", Visibility::Public).await;
+    tenant
+      .conn
+      .lock()
+      .await
+      .execute_batch(&format!(
+        "INSERT INTO attachments(id, post_id, name, content_type, size, storage_key, created_at)
+        VALUES (X'{}', X'{}', 'map.png', 'image/png', 3, 'key', 1);
+        INSERT INTO blobs(storage_key, refs) VALUES ('key', 1);",
+        Uuid::new_v4().to_simple(),
+        post_id.to_simple()
+      ))
+      .unwrap();
+    let req = Request::post(format!("/posts/{}/copy", post_id))
+      .body(Body::from("notebook="))
+      .unwrap();
+    let res = tests::send(&state, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: serde_json::Value =
+      serde_json::from_slice(&hyper::body::to_bytes(res).await.unwrap()).unwrap();
+    let id: Uuid = body["id"].as_str().unwrap().parse().unwrap();
+    let conn = tenant.conn.lock().await;
+    let (name, key): (String, String) = conn
+      .query_row(
+        "SELECT name, storage_key FROM attachments WHERE post_id=?1",
+        params![id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+      )
+      .unwrap();
+    assert_eq!((name.as_str(), key.as_str()), ("map.png", "key"));
+    let refs: i64 = conn
+      .query_row(
+        "SELECT refs FROM blobs WHERE storage_key='key'",
+        [],
+        |row| row.get(0),
+      )
+      .unwrap();
+    assert_eq!(refs, 2);
+  }
+
+  #[test]
+  fn duplicate_copies_tags_and_attachments() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    db::migrate(&mut conn);
+    let post_id = Uuid::new_v4();
+    conn
+      .execute(
+        "INSERT INTO posts(id, title, content, created_at) VALUES (?1, 'Trip', 'Kyoto', 1)",
+        params![post_id],
+      )
+      .unwrap();
+    for tag in ["kyoto", "travel"] {
+      conn
+        .execute(
+          "INSERT INTO post_tags(post_id, tag) VALUES (?1,?2)",
+          params![post_id, tag],
+        )
+        .unwrap();
+    }
+    conn
+      .execute(
+        "INSERT INTO attachments(id, post_id, name, content_type, size, storage_key, created_at)
+        VALUES (?1, ?2, 'map.png', 'image/png', 3, 'key', 1)",
+        params![Uuid::new_v4(), post_id],
+      )
+      .unwrap();
+    conn
+      .execute("INSERT INTO blobs(storage_key, refs) VALUES ('key', 1)", [])
+      .unwrap();
+    let id = duplicate(&conn, &post_id).unwrap();
+    assert_eq!(tags::for_post(&conn, &id), ["kyoto", "travel"]);
+    let refs: i64 = conn
+      .query_row(
+        "SELECT refs FROM blobs WHERE storage_key='key'",
+        [],
+        |row| row.get(0),
+      )
+      .unwrap();
+    assert_eq!(refs, 2);
+    let copied: i64 = conn
+      .query_row(
+        "SELECT COUNT(*) FROM attachments WHERE post_id=?1 AND storage_key='key'",
+        params![id],
+        |row| row.get(0),
+      )
+      .unwrap();
+    assert_eq!(copied, 1);
+    let title: String = conn
+      .query_row("SELECT title FROM posts WHERE id=?1", params![id], |row| {
+        row.get(0)
+      })
+      .unwrap();
+    assert_eq!(title, "Trip (copy)");
+    assert!(duplicate(&conn, &Uuid::new_v4()).is_none());
+  }
+}
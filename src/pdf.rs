@@ -0,0 +1,685 @@
+// 投稿を印刷や保存に使うPDFにする
+// Markdownの見出し・段落・リスト・引用・コードを，それぞれの書式でA4に組む
+// 英数字はHelveticaで，それ以外（日本語など）は埋め込まない和文フォントで表示する
+
+use std::sync::Arc;
+
+use chrono::{TimeZone, Utc};
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use pulldown_cmark::{Event, Options, Parser, Tag};
+use rusqlite::{params, OptionalExtension};
+use uuid::Uuid;
+
+use crate::{empty, notebook, State, Tenant, Visibility};
+
+// A4（ポイント）
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const MARGIN: f32 = 56.0;
+// 行の高さ（文字の大きさに対する倍率）
+const LEADING: f32 = 1.5;
+// 引用やリストを1段下げる幅
+const INDENT: f32 = 16.0;
+// 和文フォント（閲覧する環境のフォントで代替して表示される）
+const CJK_FONT: &str = "HeiseiKakuGo-W5";
+
+// HelveticaのASCII文字（0x20〜0x7e）の幅（1000分の1）
+const HELVETICA_WIDTHS: [u16; 95] = [
+  278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278, 556, 556, 556,
+  556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, 1015, 667, 667, 722, 722, 667,
+  611, 778, 722, 278, 500, 667, 556, 833, 722, 778, 667, 778, 722, 667, 611, 722, 667, 944, 667,
+  667, 611, 278, 278, 278, 469, 556, 333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500,
+  222, 833, 556, 556, 556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Face {
+  Sans,
+  Mono,
+}
+
+#[derive(Clone, Copy)]
+struct Style {
+  face: Face,
+  size: f32,
+  indent: f32,
+  // 段落の前に空ける高さ
+  space_before: f32,
+  // 文字の濃さ（0が黒）
+  gray: f32,
+}
+
+impl Style {
+  fn body(indent: f32) -> Style {
+    Style {
+      face: Face::Sans,
+      size: 11.0,
+      indent,
+      space_before: 6.0,
+      gray: 0.0,
+    }
+  }
+}
+
+// Helveticaで表示できる文字かどうか（それ以外は和文フォントで表示する）
+fn is_latin(c: char) -> bool {
+  (' '..='~').contains(&c)
+}
+
+fn char_width(face: Face, size: f32, c: char) -> f32 {
+  let width = match face {
+    Face::Mono if is_latin(c) => 600,
+    Face::Sans if is_latin(c) => HELVETICA_WIDTHS[c as usize - 0x20],
+    _ => 1000,
+  };
+  width as f32 * size / 1000.0
+}
+
+fn text_width(face: Face, size: f32, text: &str) -> f32 {
+  text.chars().map(|c| char_width(face, size, c)).sum()
+}
+
+// 折り返しの単位に分ける関数
+// 英数字は空白で区切った語ごとに，それ以外の文字は1文字ずつ折り返せる
+fn tokens(text: &str) -> Vec<String> {
+  let mut tokens: Vec<String> = Vec::new();
+  let mut word = String::new();
+  for c in text.chars() {
+    if is_latin(c) && c != ' ' {
+      word.push(c);
+      continue;
+    }
+    if !word.is_empty() {
+      tokens.push(std::mem::take(&mut word));
+    }
+    tokens.push(c.to_string());
+  }
+  if !word.is_empty() {
+    tokens.push(word);
+  }
+  tokens
+}
+
+// PDFの文字列にする関数
+// 英数字はそのまま，それ以外はUTF-16BEの16進数で書く（基本多言語面の外の文字は?にする）
+fn show(face: Face, size: f32, text: &str) -> String {
+  let mut out = String::new();
+  let mut run = String::new();
+  let mut latin = None;
+  let font = |latin: bool| match (latin, face) {
+    (true, Face::Sans) => "F1",
+    (true, Face::Mono) => "F2",
+    (false, _) => "F3",
+  };
+  let flush = |run: &mut String, latin: bool, out: &mut String| {
+    if run.is_empty() {
+      return;
+    }
+    if latin {
+      out.push_str(&format!("/{} {} Tf ({}) Tj ", font(true), size, run));
+    } else {
+      out.push_str(&format!("/{} {} Tf <{}> Tj ", font(false), size, run));
+    }
+    run.clear();
+  };
+  for c in text.chars() {
+    let c = if (c as u32) > 0xffff { '?' } else { c };
+    let is = is_latin(c);
+    if latin != Some(is) {
+      if let Some(previous) = latin {
+        flush(&mut run, previous, &mut out);
+      }
+      latin = Some(is);
+    }
+    match c {
+      '(' | ')' | '\\' if is => {
+        run.push('\\');
+        run.push(c);
+      }
+      _ if is => run.push(c),
+      _ => run.push_str(&format!("{:04X}", c as u32)),
+    }
+  }
+  if let Some(previous) = latin {
+    flush(&mut run, previous, &mut out);
+  }
+  out
+}
+
+// 上から順に行を置いていき，下の余白に届いたら次のページに移る
+struct Layout {
+  pages: Vec<String>,
+  y: f32,
+}
+
+impl Layout {
+  fn new() -> Layout {
+    Layout {
+      pages: vec![String::new()],
+      y: PAGE_HEIGHT - MARGIN,
+    }
+  }
+
+  fn ensure(&mut self, height: f32) {
+    if self.y - height < MARGIN {
+      self.pages.push(String::new());
+      self.y = PAGE_HEIGHT - MARGIN;
+    }
+  }
+
+  fn gap(&mut self, height: f32) {
+    // ページの先頭には空白を入れない
+    if self.y < PAGE_HEIGHT - MARGIN {
+      self.y -= height;
+    }
+  }
+
+  fn line(&mut self, style: Style, text: &str) {
+    let height = style.size * LEADING;
+    self.ensure(height);
+    self.y -= height;
+    let page = self.pages.last_mut().unwrap();
+    page.push_str(&format!(
+      "{} g BT {} {} Td {}ET\n",
+      style.gray,
+      MARGIN + style.indent,
+      self.y + (height - style.size) / 2.0,
+      show(style.face, style.size, text)
+    ));
+  }
+
+  // 幅に収まるように折り返して段落を置く関数
+  fn paragraph(&mut self, style: Style, text: &str) {
+    let max = PAGE_WIDTH - MARGIN * 2.0 - style.indent;
+    self.gap(style.space_before);
+    let mut line = String::new();
+    let mut width = 0.0;
+    for token in tokens(text) {
+      if token == " " && line.is_empty() {
+        continue;
+      }
+      let token_width = text_width(style.face, style.size, &token);
+      if width + token_width > max && !line.is_empty() {
+        self.line(style, line.trim_end());
+        line.clear();
+        width = 0.0;
+        if token == " " {
+          continue;
+        }
+      }
+      // 1行に収まらない長い語（URLなど）は文字の単位で折り返す
+      if token_width > max {
+        for c in token.chars() {
+          let w = char_width(style.face, style.size, c);
+          if width + w > max && !line.is_empty() {
+            self.line(style, &line);
+            line.clear();
+            width = 0.0;
+          }
+          line.push(c);
+          width += w;
+        }
+        continue;
+      }
+      line.push_str(&token);
+      width += token_width;
+    }
+    if !line.trim().is_empty() {
+      self.line(style, line.trim_end());
+    }
+  }
+
+  fn rule(&mut self) {
+    self.gap(8.0);
+    self.ensure(8.0);
+    self.y -= 4.0;
+    let page = self.pages.last_mut().unwrap();
+    page.push_str(&format!(
+      "0.7 G 0.5 w {} {} m {} {} l S\n",
+      MARGIN,
+      self.y,
+      PAGE_WIDTH - MARGIN,
+      self.y
+    ));
+    self.y -= 4.0;
+  }
+}
+
+// Markdownを組んでいる途中の状態
+struct Writer {
+  layout: Layout,
+  text: String,
+  style: Style,
+  // 入れ子になったリストごとの次の番号（番号なしはNone）
+  lists: Vec<Option<u64>>,
+  quotes: usize,
+  code: bool,
+  // リストの記号だけを書いて，項目の最初の段落を待っている
+  marker: bool,
+}
+
+impl Writer {
+  fn indent(&self) -> f32 {
+    (self.lists.len() + self.quotes) as f32 * INDENT
+  }
+
+  // 今いるリストや引用に合わせた本文の書式
+  fn base(&self) -> Style {
+    Style {
+      gray: if self.quotes > 0 { 0.35 } else { 0.0 },
+      ..Style::body(self.indent())
+    }
+  }
+
+  fn flush(&mut self) {
+    if !self.text.trim().is_empty() {
+      self.layout.paragraph(self.style, &self.text);
+    }
+    self.text.clear();
+    self.style = self.base();
+    self.marker = false;
+  }
+
+  fn event(&mut self, event: Event) {
+    match event {
+      Event::Start(Tag::Heading(level, ..)) => {
+        self.flush();
+        let size = match level as usize {
+          1 => 18.0,
+          2 => 15.0,
+          _ => 13.0,
+        };
+        self.style = Style {
+          size,
+          space_before: 10.0,
+          ..self.base()
+        };
+      }
+      Event::Start(Tag::List(start)) => {
+        self.flush();
+        self.lists.push(start);
+      }
+      Event::End(Tag::List(_)) => {
+        self.flush();
+        self.lists.pop();
+        self.style = self.base();
+      }
+      Event::Start(Tag::Item) => {
+        self.flush();
+        let marker = match self.lists.last_mut() {
+          Some(Some(n)) => {
+            *n += 1;
+            format!("{}. ", *n - 1)
+          }
+          _ => "- ".to_string(),
+        };
+        self.style = Style {
+          indent: self.indent() - INDENT / 2.0,
+          space_before: 2.0,
+          ..self.base()
+        };
+        self.text.push_str(&marker);
+        self.marker = true;
+      }
+      Event::Start(Tag::BlockQuote) => {
+        self.flush();
+        self.quotes += 1;
+        self.style = self.base();
+      }
+      Event::End(Tag::BlockQuote) => {
+        self.flush();
+        self.quotes -= 1;
+        self.style = self.base();
+      }
+      Event::Start(Tag::CodeBlock(_)) => {
+        self.flush();
+        self.code = true;
+      }
+      Event::End(Tag::CodeBlock(_)) => {
+        let code = std::mem::take(&mut self.text);
+        let style = Style {
+          face: Face::Mono,
+          size: 9.5,
+          space_before: 0.0,
+          ..self.base()
+        };
+        self.layout.gap(6.0);
+        for line in code.trim_end_matches('\n').lines() {
+          // 行頭の字下げは折り返した行にも残す
+          let line = line.replace('\t', "    ");
+          let body = line.trim_start();
+          let indent = text_width(Face::Mono, style.size, &line[..line.len() - body.len()]);
+          let style = Style {
+            indent: style.indent + INDENT / 2.0 + indent,
+            ..style
+          };
+          // 空行も高さを保つ
+          if body.is_empty() {
+            self.layout.line(style, "");
+          } else {
+            self.layout.paragraph(style, body);
+          }
+        }
+        self.code = false;
+        self.style = self.base();
+      }
+      // リストの項目の最初の段落は記号と同じ行に続ける
+      Event::Start(Tag::Paragraph) | Event::Start(Tag::TableHead) | Event::Start(Tag::TableRow)
+        if !self.marker =>
+      {
+        self.flush()
+      }
+      Event::End(Tag::Paragraph)
+      | Event::End(Tag::Heading(..))
+      | Event::End(Tag::Item)
+      | Event::End(Tag::TableHead)
+      | Event::End(Tag::TableRow)
+      | Event::HardBreak => {
+        let style = self.style;
+        self.flush();
+        // 改行だけのときは同じ書式で続ける
+        if let Event::HardBreak = event {
+          self.style = Style {
+            space_before: 0.0,
+            ..style
+          };
+        }
+      }
+      Event::End(Tag::TableCell) => self.text.push_str("   "),
+      // 印刷したときにリンク先が分かるようにURLを添える
+      Event::End(Tag::Link(_, url, _)) if !self.text.ends_with(&*url) => {
+        self.text.push_str(&format!(" ({})", url));
+      }
+      Event::Text(text) | Event::Code(text) | Event::Html(text) => {
+        if self.code {
+          self.text.push_str(&text);
+        } else {
+          self.text.push_str(&text.replace('\n', " "));
+        }
+      }
+      Event::SoftBreak => self.text.push(' '),
+      Event::Rule => {
+        self.flush();
+        self.layout.rule();
+      }
+      _ => {}
+    }
+  }
+}
+
+// PDFで使う文字列（日本語を含む場合があるのでUTF-16BEにする）
+fn text_string(text: &str) -> String {
+  let hex = text
+    .encode_utf16()
+    .map(|unit| format!("{:04X}", unit))
+    .collect::<String>();
+  format!("<FEFF{}>", hex)
+}
+
+// 組んだページをPDFのファイルにまとめる関数
+fn document(title: &str, pages: &[String]) -> Vec<u8> {
+  let mut objects = vec![
+    "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+    String::new(),
+    "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>".to_string(),
+    "<< /Type /Font /Subtype /Type1 /BaseFont /Courier /Encoding /WinAnsiEncoding >>".to_string(),
+    format!(
+      "<< /Type /Font /Subtype /Type0 /BaseFont /{} /Encoding /UniJIS-UCS2-H /DescendantFonts [6 0 R] >>",
+      CJK_FONT
+    ),
+    format!(
+      "<< /Type /Font /Subtype /CIDFontType0 /BaseFont /{}
+      /CIDSystemInfo << /Registry (Adobe) /Ordering (Japan1) /Supplement 2 >>
+      /FontDescriptor 7 0 R /DW 1000 >>",
+      CJK_FONT
+    ),
+    format!(
+      "<< /Type /FontDescriptor /FontName /{} /Flags 4 /FontBBox [-92 -250 1010 922]
+      /ItalicAngle 0 /Ascent 752 /Descent -221 /CapHeight 737 /StemV 114 >>",
+      CJK_FONT
+    ),
+    format!("<< /Title {} /Producer (web-memory) >>", text_string(title)),
+  ];
+  let mut kids = Vec::new();
+  for (i, content) in pages.iter().enumerate() {
+    // ページ番号を下の余白の中央に入れる
+    let number = format!("{} / {}", i + 1, pages.len());
+    let footer = format!(
+      "0.5 g BT {} {} Td {}ET\n",
+      (PAGE_WIDTH - text_width(Face::Sans, 9.0, &number)) / 2.0,
+      MARGIN / 2.0,
+      show(Face::Sans, 9.0, &number)
+    );
+    let stream = format!("{}{}", content, footer);
+    objects.push(format!(
+      "<< /Length {} >>\nstream\n{}endstream",
+      stream.len(),
+      stream
+    ));
+    let content_id = objects.len();
+    objects.push(format!(
+      "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Contents {} 0 R
+      /Resources << /Font << /F1 3 0 R /F2 4 0 R /F3 5 0 R >> >> >>",
+      PAGE_WIDTH, PAGE_HEIGHT, content_id
+    ));
+    kids.push(format!("{} 0 R", objects.len()));
+  }
+  objects[1] = format!(
+    "<< /Type /Pages /Kids [{}] /Count {} >>",
+    kids.join(" "),
+    kids.len()
+  );
+  let mut pdf = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+  let mut offsets = Vec::new();
+  for (i, object) in objects.iter().enumerate() {
+    offsets.push(pdf.len());
+    pdf.extend(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).as_bytes());
+  }
+  let xref = pdf.len();
+  pdf.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+  for offset in offsets {
+    pdf.extend(format!("{:010} 00000 n \n", offset).as_bytes());
+  }
+  pdf.extend(
+    format!(
+      "trailer\n<< /Size {} /Root 1 0 R /Info 8 0 R >>\nstartxref\n{}\n%%EOF\n",
+      objects.len() + 1,
+      xref
+    )
+    .as_bytes(),
+  );
+  pdf
+}
+
+// タイトル，日時などの情報，Markdownの本文を組んだページを返す関数
+fn pages(title: &str, meta: &str, content: &str) -> Vec<String> {
+  let mut writer = Writer {
+    layout: Layout::new(),
+    text: String::new(),
+    style: Style::body(0.0),
+    lists: Vec::new(),
+    quotes: 0,
+    code: false,
+    marker: false,
+  };
+  writer.layout.paragraph(
+    Style {
+      size: 20.0,
+      ..Style::body(0.0)
+    },
+    title,
+  );
+  writer.layout.paragraph(
+    Style {
+      size: 9.0,
+      space_before: 0.0,
+      gray: 0.4,
+      ..Style::body(0.0)
+    },
+    meta,
+  );
+  writer.layout.rule();
+  for event in Parser::new_ext(
+    content,
+    Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH,
+  ) {
+    writer.event(event);
+  }
+  writer.flush();
+  writer.layout.pages
+}
+
+// 投稿のタイトル，日時，ノートブックと本文をPDFにする関数
+// 表示する投稿と同じく，非公開の投稿やE2EEの投稿は書き出さない
+pub async fn export(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  id: &str,
+) -> Result<Response<Body>, Error> {
+  let id = match Uuid::parse_str(id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let conn = tenant.conn.lock().await;
+  let post = conn
+    .query_row(
+      "SELECT title, content, encrypted, compressed, created_at FROM posts
+      WHERE id=?1 AND kind = 'text' AND visibility != ?2 AND trashed_at IS NULL",
+      params![id, Visibility::Private],
+      |row| {
+        Ok((
+          row.get::<_, String>(0)?,
+          state.codec.decode(row.get(1)?, row.get(2)?, row.get(3)?),
+          row.get::<_, Option<i64>>(4)?,
+        ))
+      },
+    )
+    .optional()
+    .unwrap();
+  let (title, content, created_at) = match post {
+    Some(post) => post,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let breadcrumbs = notebook::breadcrumbs(&conn, &id);
+  drop(conn);
+  let mut meta = Vec::new();
+  if let Some(created_at) = created_at {
+    let tz = state.timezone.current(&req);
+    let date = Utc.timestamp(created_at, 0).with_timezone(&tz);
+    meta.push(date.format("%Y-%m-%d %H:%M %Z").to_string());
+  }
+  if !breadcrumbs.is_empty() {
+    meta.push(breadcrumbs.join(" / "));
+  }
+  let pages = pages(&title, &meta.join("  |  "), &content);
+  Ok(
+    Response::builder()
+      .header(header::CONTENT_TYPE, "application/pdf")
+      .header(
+        header::CONTENT_DISPOSITION,
+        format!("inline; filename=\"{}.pdf\"", id),
+      )
+      .body(document(&title, &pages).into())
+      .unwrap(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // ページの中の文字の位置（Tdの引数）を返す関数
+  fn positions(page: &str) -> Vec<(f32, f32)> {
+    page
+      .lines()
+      .filter_map(|line| {
+        let mut words = line.split(' ');
+        let at = words.position(|word| word == "BT")?;
+        let rest = line.split(' ').skip(at + 1).collect::<Vec<_>>();
+        Some((rest[0].parse().ok()?, rest[1].parse().ok()?))
+      })
+      .collect()
+  }
+
+  #[test]
+  fn measures_with_helvetica_metrics() {
+    // H 722, e 556, l 222, l 222, o 556
+    assert!((text_width(Face::Sans, 10.0, "Hello") - 22.78).abs() < 1e-3);
+    assert_eq!(text_width(Face::Mono, 10.0, "Hello"), 30.0);
+    assert_eq!(text_width(Face::Sans, 10.0, "日本"), 20.0);
+  }
+
+  #[test]
+  fn splits_latin_words_and_other_characters() {
+    assert_eq!(
+      tokens("go to 日本 now."),
+      ["go", " ", "to", " ", "日", "本", " ", "now."]
+    );
+  }
+
+  #[test]
+  fn shows_latin_and_cjk_with_their_fonts() {
+    assert_eq!(
+      show(Face::Sans, 12.0, "a(b)日本"),
+      r"/F1 12 Tf (a\(b\)) Tj /F3 12 Tf <65E5672C> Tj "
+    );
+    assert_eq!(show(Face::Mono, 9.0, "x😀"), "/F2 9 Tf (x?) Tj ");
+    assert_eq!(text_string("日"), "<FEFF65E5>");
+  }
+
+  #[test]
+  fn wraps_within_the_margins_and_paginates() {
+    let content = "word ".repeat(3000);
+    let pages = pages("Title", "2026-01-01", &content);
+    assert!(pages.len() > 1);
+    for page in &pages {
+      for (x, y) in positions(page) {
+        assert!(x >= MARGIN && (MARGIN..=PAGE_HEIGHT - MARGIN).contains(&y));
+      }
+    }
+    // 幅に収まらない語は文字で折り返す
+    let mut layout = Layout::new();
+    layout.paragraph(Style::body(0.0), &"x".repeat(200));
+    assert!(positions(&layout.pages[0]).len() > 1);
+  }
+
+  #[test]
+  fn writes_code_in_mono() {
+    let pages = pages("Title", "", "# Head\n\n- item\n\n```\nlet x = 1;\n```\n");
+    assert!(pages[0].contains("(Head)"));
+    assert!(pages[0].contains("/F2 "));
+    assert!(pages[0].contains("(let x = 1;)"));
+  }
+
+  #[test]
+  fn cross_reference_points_at_objects() {
+    let pdf = document("日記", &pages("日記", "", "本文"));
+    let text = String::from_utf8_lossy(&pdf);
+    assert!(pdf.starts_with(b"%PDF-1.4\n"));
+    assert!(text.ends_with("%%EOF\n"));
+    let startxref: usize = text
+      .rsplit("startxref\n")
+      .next()
+      .unwrap()
+      .lines()
+      .next()
+      .unwrap()
+      .parse()
+      .unwrap();
+    assert!(pdf[startxref..].starts_with(b"xref\n"));
+    let xref = String::from_utf8_lossy(&pdf[startxref..]);
+    let count: usize = xref.lines().nth(1).unwrap()[2..].parse().unwrap();
+    for (i, line) in xref.lines().skip(3).take(count - 1).enumerate() {
+      let offset: usize = line[..10].parse().unwrap();
+      assert!(pdf[offset..].starts_with(format!("{} 0 obj\n", i + 1).as_bytes()));
+    }
+    assert!(text.contains(&format!("/Size {} ", count)));
+    // ストリームの長さが中身と一致する
+    for part in text.split("<< /Length ").skip(1) {
+      let (length, rest) = part.split_once(" >>\nstream\n").unwrap();
+      let length: usize = length.parse().unwrap();
+      assert!(rest[length..].starts_with("endstream"));
+    }
+  }
+}
@@ -0,0 +1,154 @@
+use std::sync::Arc;
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::{empty, events, spam::BoxFuture, State, Tenant};
+
+// 作成する前の投稿
+// 上限による切り詰めを済ませた後のタイトルと本文を渡す
+pub struct Candidate {
+  pub title: String,
+  pub content: String,
+}
+
+// プラグインが追加するURLを処理する関数
+// パスの*に当てはまった部分を順に受け取る
+pub type Handler = Box<
+  dyn Fn(Request<Body>, Arc<Tenant>, Vec<String>) -> BoxFuture<'static, Response<Body>>
+    + Send
+    + Sync,
+>;
+
+// プラグインが追加するURL
+// pathは/で区切った各部分を照合し，*はどの1つにも当てはまる（例: "/hello/*"）
+pub struct Route {
+  pub method: &'static str,
+  pub path: &'static str,
+  pub handler: Handler,
+}
+
+impl Route {
+  // 当てはまれば*の部分を返す関数
+  fn matches(&self, method: &str, segments: &[&str]) -> Option<Vec<String>> {
+    if self.method != method {
+      return None;
+    }
+    let pattern: Vec<&str> = self.path.split('/').skip(1).collect();
+    if pattern.len() != segments.len() {
+      return None;
+    }
+    let mut captured = Vec::new();
+    for (expected, segment) in pattern.iter().zip(segments) {
+      match *expected {
+        "*" => captured.push(segment.to_string()),
+        expected if expected == *segment => {}
+        _ => return None,
+      }
+    }
+    Some(captured)
+  }
+}
+
+// 動作を拡張するためのインターフェース
+// 使う側は自分のビルドでこのトレイトを実装し，mainでPlugins::newに渡す
+// 必要なフックだけを実装すればよいように，どれも何もしないものを既定にしている
+pub trait Plugin: Send + Sync {
+  // ログや断った理由に使う名前
+  fn name(&self) -> &'static str;
+
+  // POST /posts で投稿を保存する前に呼ぶ
+  // タイトルや本文を書き換えられ，Errを返すとその理由を付けて422で断る
+  fn before_create(&self, _candidate: &mut Candidate) -> Result<(), String> {
+    Ok(())
+  }
+
+  // GET /posts/{id} で投稿を描画した後に呼び，描画した結果を書き換えられる
+  fn after_render(&self, _id: &Uuid, _rendered: &mut String) {}
+
+  // 投稿をゴミ箱に入れたときと完全に削除したときに呼ぶ
+  // 削除をコミットした後にイベントとして受け取るので，削除を止めることはできない
+  fn on_delete(&self, _tenant: &str, _id: &Uuid, _purged: bool) {}
+
+  // 追加するURL
+  // 組み込みのURLに当てはまらなかったリクエストだけを渡す
+  fn extra_routes(&self) -> Vec<Route> {
+    Vec::new()
+  }
+}
+
+// 登録したプラグインの一覧
+// フックは登録した順に呼ぶ
+pub struct Plugins {
+  plugins: Vec<Box<dyn Plugin>>,
+  // 起動時に1回だけ集めておく
+  routes: Vec<Route>,
+}
+
+impl Plugins {
+  pub fn new(plugins: Vec<Box<dyn Plugin>>) -> Plugins {
+    let routes = plugins
+      .iter()
+      .flat_map(|plugin| plugin.extra_routes())
+      .collect();
+    Plugins { plugins, routes }
+  }
+
+  // 断ったプラグインがあればその名前と理由を返す関数
+  pub fn before_create(&self, candidate: &mut Candidate) -> Result<(), String> {
+    for plugin in &self.plugins {
+      plugin
+        .before_create(candidate)
+        .map_err(|reason| format!("{}: {}", plugin.name(), reason))?;
+    }
+    Ok(())
+  }
+
+  pub fn after_render(&self, id: &Uuid, rendered: &mut String) {
+    for plugin in &self.plugins {
+      plugin.after_render(id, rendered);
+    }
+  }
+
+  // 当てはまるURLがなければ404を返す
+  pub async fn route(
+    &self,
+    req: Request<Body>,
+    tenant: Arc<Tenant>,
+    method: &str,
+    segments: &[&str],
+  ) -> Result<Response<Body>, Error> {
+    for route in &self.routes {
+      if let Some(captured) = route.matches(method, segments) {
+        return Ok((route.handler)(req, tenant, captured).await);
+      }
+    }
+    Ok(empty(StatusCode::NOT_FOUND))
+  }
+}
+
+// 削除のイベントを受け取ってon_deleteを呼び続ける
+pub fn spawn(state: Arc<State>, bus: &events::Bus) {
+  let mut events = bus.subscribe();
+  tokio::spawn(async move {
+    loop {
+      let published = match events.recv().await {
+        Ok(published) => published,
+        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+          eprintln!("plugin: missed {} events", skipped);
+          continue;
+        }
+        Err(broadcast::error::RecvError::Closed) => break,
+      };
+      let (id, purged) = match published.event {
+        events::Event::Trashed { id } => (id, false),
+        events::Event::Purged { id } => (id, true),
+        _ => continue,
+      };
+      for plugin in &state.plugins.plugins {
+        plugin.on_delete(&published.tenant, &id, purged);
+      }
+    }
+  });
+}
@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag};
+use serde::Deserialize;
+
+use crate::{empty, State};
+
+#[derive(Deserialize)]
+struct Preview {
+  content: String,
+}
+
+// リンクや画像に使わせないスキーム
+const UNSAFE_SCHEMES: &[&str] = &["javascript:", "vbscript:", "data:"];
+
+fn safe_url(url: CowStr) -> CowStr {
+  let lower = url.trim().to_ascii_lowercase();
+  if UNSAFE_SCHEMES
+    .iter()
+    .any(|scheme| lower.starts_with(scheme))
+  {
+    CowStr::Borrowed("#")
+  } else {
+    url
+  }
+}
+
+// MarkdownをHTMLにする関数
+// 本文に書かれたHTMLはタグとして扱わず，文字列としてエスケープして表示する
+pub fn render(markdown: &str) -> String {
+  let events = Parser::new_ext(
+    markdown,
+    Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH,
+  )
+  .map(|event| match event {
+    Event::Html(raw) => Event::Text(raw),
+    Event::Start(Tag::Link(kind, url, title)) => {
+      Event::Start(Tag::Link(kind, safe_url(url), title))
+    }
+    Event::Start(Tag::Image(kind, url, title)) => {
+      Event::Start(Tag::Image(kind, safe_url(url), title))
+    }
+    event => event,
+  });
+  let mut out = String::new();
+  html::push_html(&mut out, events);
+  out
+}
+
+// 投稿フォームのプレビュー欄に表示するHTMLを返す関数
+// 保存はしないので，読み取り専用の間も使える
+pub async fn preview(req: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Error> {
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let preview = match serde_urlencoded::from_bytes::<Preview>(&body) {
+    Ok(preview) => preview,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  // 保存するときと同じく長すぎる本文は断る（切り詰める設定なら切り詰めて表示する）
+  let content = match state.limits.apply("", &preview.content, &mut Vec::new()) {
+    Ok((_, content)) => content,
+    Err(reason) => {
+      return Ok(
+        Response::builder()
+          .status(StatusCode::UNPROCESSABLE_ENTITY)
+          .body(reason.into())
+          .unwrap(),
+      )
+    }
+  };
+  Ok(
+    Response::builder()
+      .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+      .body(render(content).into())
+      .unwrap(),
+  )
+}
@@ -0,0 +1,228 @@
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::{header, Body, Request};
+
+use crate::cidr::{self, Cidr};
+
+// リクエストを中継するリバースプロキシのアドレスの範囲
+pub struct TrustedProxies {
+  ranges: Vec<Cidr>,
+}
+
+// プロキシの情報から求めた本当の接続元
+pub struct Client {
+  pub ip: IpAddr,
+  // 利用者がアクセスしたスキーム（httpまたはhttps）
+  pub scheme: String,
+  // 利用者がアクセスしたホスト（Hostヘッダがなければ空文字列）
+  pub host: String,
+}
+
+impl Client {
+  // 絶対URLの先頭に付けるhttps://example.comの部分
+  pub fn origin(&self) -> String {
+    format!("{}://{}", self.scheme, self.host)
+  }
+}
+
+// 中継した1つのプロキシが伝えた情報
+#[derive(Default)]
+struct Hop {
+  ip: Option<IpAddr>,
+  proto: Option<String>,
+  host: Option<String>,
+}
+
+// Forwardedのfor=の値からアドレスを取り出す関数
+// "[2001:db8::1]:4711" や 192.0.2.60:8080 のようにポートが付く場合がある
+fn parse_node(node: &str) -> Option<IpAddr> {
+  if let Some(v6) = node.strip_prefix('[') {
+    return v6.split(']').next()?.parse().ok();
+  }
+  node
+    .parse()
+    .ok()
+    .or_else(|| node.rsplit_once(':')?.0.parse().ok())
+}
+
+// Forwarded: for=192.0.2.60;proto=https;host=example.com, for=10.0.0.1 を解釈する関数（RFC 7239）
+fn parse_forwarded(value: &str) -> Vec<Hop> {
+  value
+    .split(',')
+    .map(|element| {
+      let mut hop = Hop::default();
+      for pair in element.split(';') {
+        let (key, value) = match pair.split_once('=') {
+          Some(pair) => pair,
+          None => continue,
+        };
+        let value = value.trim().trim_matches('"');
+        match key.trim().to_ascii_lowercase().as_str() {
+          "for" => hop.ip = parse_node(value),
+          "proto" => hop.proto = Some(value.to_ascii_lowercase()),
+          "host" => hop.host = Some(value.to_string()),
+          _ => {}
+        }
+      }
+      hop
+    })
+    .collect()
+}
+
+// 複数行に分かれている場合も含めてヘッダの値をカンマで区切って並べる関数
+fn values<'a>(req: &'a Request<Body>, name: &str) -> Vec<&'a str> {
+  req
+    .headers()
+    .get_all(name)
+    .iter()
+    .filter_map(|v| v.to_str().ok())
+    .flat_map(|v| v.split(','))
+    .map(str::trim)
+    .collect()
+}
+
+// X-Forwarded-For，X-Forwarded-Proto，X-Forwarded-Hostを解釈する関数
+// スキームとホストは最後のプロキシが付けたものだけを使う
+fn parse_x_forwarded(req: &Request<Body>) -> Vec<Hop> {
+  let mut hops: Vec<Hop> = values(req, "x-forwarded-for")
+    .into_iter()
+    .map(|ip| Hop {
+      ip: ip.parse().ok(),
+      ..Hop::default()
+    })
+    .collect();
+  if let Some(last) = hops.last_mut() {
+    last.proto = values(req, "x-forwarded-proto")
+      .last()
+      .map(|s| s.to_ascii_lowercase());
+    last.host = values(req, "x-forwarded-host")
+      .last()
+      .map(|s| s.to_string());
+  }
+  hops
+}
+
+impl TrustedProxies {
+  // TRUSTED_PROXIES=10.0.0.0/8,::1 のように指定する
+  pub fn from_env() -> TrustedProxies {
+    TrustedProxies {
+      ranges: cidr::list_from_env("TRUSTED_PROXIES"),
+    }
+  }
+
+  fn trusted(&self, ip: IpAddr) -> bool {
+    self.ranges.iter().any(|range| range.contains(ip))
+  }
+
+  // 信頼するプロキシを経由した場合は転送ヘッダから本当の接続元を求める関数
+  // Forwardedがあればそれを，なければX-Forwarded-*を使う
+  // 偽装される可能性があるので，右から順に信頼するプロキシが付けた情報だけをたどる
+  pub fn resolve(&self, req: &Request<Body>, secure: bool) -> Client {
+    let mut client = Client {
+      ip: req.extensions().get::<SocketAddr>().unwrap().ip(),
+      scheme: if secure { "https" } else { "http" }.to_string(),
+      host: req
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string(),
+    };
+    if !self.trusted(client.ip) {
+      return client;
+    }
+    let hops = match req.headers().get(header::FORWARDED) {
+      Some(_) => parse_forwarded(&values(req, "forwarded").join(",")),
+      None => parse_x_forwarded(req),
+    };
+    for hop in hops.into_iter().rev() {
+      if !self.trusted(client.ip) {
+        break;
+      }
+      let ip = match hop.ip {
+        Some(ip) => ip,
+        None => break,
+      };
+      client.ip = ip;
+      if let Some(proto) = hop.proto {
+        client.scheme = proto;
+      }
+      if let Some(host) = hop.host {
+        client.host = host;
+      }
+    }
+    client
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn proxies(ranges: &str) -> TrustedProxies {
+    TrustedProxies {
+      ranges: ranges.split(',').map(|s| Cidr::parse(s).unwrap()).collect(),
+    }
+  }
+
+  fn request(peer: &str, headers: &[(&str, &str)]) -> Request<Body> {
+    let mut builder = Request::builder().header(header::HOST, "internal:3000");
+    for (name, value) in headers {
+      builder = builder.header(*name, *value);
+    }
+    let mut req = builder.body(Body::empty()).unwrap();
+    let peer: SocketAddr = peer.parse().unwrap();
+    req.extensions_mut().insert(peer);
+    req
+  }
+
+  #[test]
+  fn parses_nodes_with_ports() {
+    assert_eq!(parse_node("192.0.2.60:8080"), "192.0.2.60".parse().ok());
+    assert_eq!(parse_node("[2001:db8::1]:4711"), "2001:db8::1".parse().ok());
+    assert_eq!(parse_node("2001:db8::1"), "2001:db8::1".parse().ok());
+    assert_eq!(parse_node("_hidden"), None);
+  }
+
+  #[test]
+  fn ignores_headers_from_untrusted_peers() {
+    let req = request("203.0.113.9:1234", &[("x-forwarded-for", "192.0.2.1")]);
+    let client = proxies("10.0.0.0/8").resolve(&req, false);
+    assert_eq!(client.ip, "203.0.113.9".parse::<IpAddr>().unwrap());
+    assert_eq!(client.origin(), "http://internal:3000");
+  }
+
+  #[test]
+  fn follows_trusted_hops_from_the_right() {
+    let req = request(
+      "10.0.0.2:1234",
+      &[
+        ("x-forwarded-for", "198.51.100.7, 192.0.2.1, 10.0.0.1"),
+        ("x-forwarded-proto", "HTTPS"),
+        ("x-forwarded-host", "example.com"),
+      ],
+    );
+    let client = proxies("10.0.0.0/8").resolve(&req, false);
+    // 192.0.2.1は信頼しないので，その左の値は偽装かもしれない
+    assert_eq!(client.ip, "192.0.2.1".parse::<IpAddr>().unwrap());
+    assert_eq!(client.origin(), "https://example.com");
+  }
+
+  #[test]
+  fn prefers_forwarded_over_x_forwarded() {
+    let req = request(
+      "[::ffff:10.0.0.2]:1234",
+      &[
+        (
+          "forwarded",
+          "for=\"[2001:db8::1]:4711\";proto=https;host=example.com",
+        ),
+        ("forwarded", "for=fd00::1"),
+        ("x-forwarded-for", "192.0.2.1"),
+      ],
+    );
+    let client = proxies("10.0.0.0/8,fd00::/8").resolve(&req, false);
+    assert_eq!(client.ip, "2001:db8::1".parse::<IpAddr>().unwrap());
+    assert_eq!(client.origin(), "https://example.com");
+  }
+}
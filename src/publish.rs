@@ -0,0 +1,87 @@
+use std::env;
+
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+
+use crate::admin_auth::AdminAuth;
+
+// 公開している画面をキャッシュしてよい時間（秒）の初期値
+const DEFAULT_MAX_AGE: u64 = 5 * 60;
+
+// 閲覧は誰でもでき，編集には管理者の認証が必要な公開ブログとして動かす設定
+pub struct Publish {
+  enabled: bool,
+  max_age: u64,
+}
+
+impl Publish {
+  // PUBLISH_MODE=trueで有効にし，キャッシュしてよい時間はPUBLISH_MAX_AGEで指定する
+  // 編集する人をADMIN_HTPASSWDの管理者で確かめるので，設定していなければ起動しない
+  pub fn from_env(admin_auth: &AdminAuth) -> Publish {
+    let enabled = env::var("PUBLISH_MODE").as_deref() == Ok("true");
+    if enabled && !admin_auth.is_enabled() {
+      panic!("PUBLISH_MODE requires ADMIN_HTPASSWD");
+    }
+    let max_age = env::var("PUBLISH_MAX_AGE")
+      .map(|s| s.parse().expect("PUBLISH_MAX_AGE must be a number"))
+      .unwrap_or(DEFAULT_MAX_AGE);
+    Publish { enabled, max_age }
+  }
+}
+
+// 編集のための操作かを判定する関数
+// 読む人の見た目やタイムゾーンの選択（Cookieに保存するだけ）と保存しないプレビュー以外の書き込みと，
+// 投稿フォームや下書き，同期など編集する人だけが使う画面が当たる
+// ブラウザ拡張からの取り込みは独自のトークンで認証するので含めない
+fn is_editing(method: &str, segments: &[&str]) -> bool {
+  match (method, segments) {
+    ("GET" | "HEAD" | "OPTIONS", ["posts", "new"]) => true,
+    ("GET" | "HEAD" | "OPTIONS", ["posts", _, "draft" | "sync" | "shares" | "short"]) => true,
+    (
+      "GET" | "HEAD" | "OPTIONS",
+      ["changes" | "conflicts" | "bookmarks" | "searches" | "events", ..],
+    ) => true,
+    ("GET" | "HEAD" | "OPTIONS", _) => false,
+    (_, ["themes", _] | ["timezone"] | ["clip"]) => false,
+    _ => true,
+  }
+}
+
+// 公開ブログとして動かしているときに，認証していない編集の操作であれば401のレスポンスを返す関数
+pub fn challenge(
+  publish: &Publish,
+  admin_auth: &AdminAuth,
+  req: &Request<Body>,
+  segments: &[&str],
+) -> Option<Response<Body>> {
+  if !publish.enabled || !is_editing(req.method().as_str(), segments) {
+    return None;
+  }
+  admin_auth.require(req)
+}
+
+// 認証していない閲覧のレスポンスにキャッシュしてよいことを示すヘッダを付ける関数
+// authorizedはリクエストにAuthorizationヘッダがあったかどうか
+// 一度だけ表示するメッセージなどCookieを設定するレスポンスや，個別にキャッシュを指定したレスポンスには付けない
+// 画面の形式，言語，見た目はリクエストのヘッダで変わるので，Varyでキャッシュを分けさせる
+pub fn finish(publish: &Publish, method: &Method, authorized: bool, res: &mut Response<Body>) {
+  if !publish.enabled
+    || !matches!(*method, Method::GET | Method::HEAD)
+    || res.status() != StatusCode::OK
+    || authorized
+    || res.headers().contains_key(header::SET_COOKIE)
+    || res.headers().contains_key(header::CACHE_CONTROL)
+  {
+    return;
+  }
+  let headers = res.headers_mut();
+  headers.insert(
+    header::CACHE_CONTROL,
+    format!("public, max-age={}", publish.max_age)
+      .parse()
+      .unwrap(),
+  );
+  headers.append(
+    header::VARY,
+    "Accept, Accept-Language, Cookie".parse().unwrap(),
+  );
+}
@@ -0,0 +1,160 @@
+// 投稿のURLのQRコード（誤り訂正レベルM）をPNGの画像にする
+// 符号化はqrcodeクレートに任せ，ここでは白黒のPNGに書き出すだけを行う
+
+use std::sync::Arc;
+
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use qrcode::{Color, EcLevel, QrCode};
+use rusqlite::{params, OptionalExtension};
+use uuid::Uuid;
+
+use crate::{crc32, empty, State, Tenant};
+
+// 周りに空ける余白（モジュール数）
+const QUIET_ZONE: usize = 4;
+// 1モジュールのピクセル数
+const SCALE: usize = 8;
+
+// 1モジュールをscale×scaleピクセルにした白黒のPNGを返す関数
+fn png(code: &QrCode, scale: usize) -> Vec<u8> {
+  let size = code.width();
+  let colors = code.to_colors();
+  let width = (size + QUIET_ZONE * 2) * scale;
+  let row_bytes = width.div_ceil(8);
+  let mut raw = Vec::with_capacity((row_bytes + 1) * width);
+  for py in 0..width {
+    // フィルタなし
+    raw.push(0);
+    let mut row = vec![0xff_u8; row_bytes];
+    let y = py / scale;
+    for px in 0..width {
+      let x = px / scale;
+      let dark = (QUIET_ZONE..QUIET_ZONE + size).contains(&x)
+        && (QUIET_ZONE..QUIET_ZONE + size).contains(&y)
+        && colors[(y - QUIET_ZONE) * size + x - QUIET_ZONE] == Color::Dark;
+      if dark {
+        row[px / 8] &= !(0x80 >> (px % 8));
+      }
+    }
+    raw.extend(row);
+  }
+  let mut ihdr = Vec::new();
+  ihdr.extend(&(width as u32).to_be_bytes());
+  ihdr.extend(&(width as u32).to_be_bytes());
+  // ビット深度1のグレースケール
+  ihdr.extend(&[1, 0, 0, 0, 0]);
+  let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+  chunk(&mut png, b"IHDR", &ihdr);
+  chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+  chunk(&mut png, b"IEND", &[]);
+  png
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+  png.extend(&(data.len() as u32).to_be_bytes());
+  let start = png.len();
+  png.extend(kind);
+  png.extend(data);
+  let crc = crc32::checksum(&png[start..]);
+  png.extend(&crc.to_be_bytes());
+}
+
+// 圧縮しないブロックだけでzlib形式にする関数
+// 白黒の小さな画像なので圧縮しなくても十分に小さい
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+  let mut out = vec![0x78, 0x01];
+  let mut blocks = data.chunks(0xffff).peekable();
+  if blocks.peek().is_none() {
+    out.extend(&[1, 0, 0, 0xff, 0xff]);
+  }
+  while let Some(block) = blocks.next() {
+    out.push(blocks.peek().is_none() as u8);
+    let len = block.len() as u16;
+    out.extend(&len.to_le_bytes());
+    out.extend(&(!len).to_le_bytes());
+    out.extend(block);
+  }
+  let (mut a, mut b) = (1_u32, 0_u32);
+  for byte in data {
+    a = (a + *byte as u32) % 65521;
+    b = (b + a) % 65521;
+  }
+  out.extend(&(b << 16 | a).to_be_bytes());
+  out
+}
+
+// URLのQRコードをPNGで返す関数
+pub fn image(url: &str) -> Response<Body> {
+  match QrCode::with_error_correction_level(url, EcLevel::M) {
+    Ok(code) => Response::builder()
+      .header(header::CONTENT_TYPE, "image/png")
+      .body(png(&code, SCALE).into())
+      .unwrap(),
+    Err(_) => empty(StatusCode::PAYLOAD_TOO_LARGE),
+  }
+}
+
+// 投稿のURLのQRコードを返す関数
+// 公開範囲は開いた先で確かめるので，ここでは投稿があることだけを確かめる
+pub async fn post(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  id: &str,
+) -> Result<Response<Body>, Error> {
+  let id = match Uuid::parse_str(id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let exists = tenant
+    .conn
+    .lock()
+    .await
+    .query_row(
+      "SELECT 1 FROM posts WHERE id=?1 AND trashed_at IS NULL",
+      params![id],
+      |_| Ok(()),
+    )
+    .optional()
+    .unwrap();
+  if exists.is_none() {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  let url = format!(
+    "{}{}/posts/{}",
+    state.base_url.origin(&req),
+    tenant.prefix,
+    id
+  );
+  Ok(image(&url))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::convert::TryInto;
+
+  use super::*;
+
+  #[test]
+  fn draws_modules_inside_quiet_zone() {
+    let code = QrCode::with_error_correction_level("https://example.com/", EcLevel::M).unwrap();
+    let png = png(&code, 2);
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+    // 余白を含めて(25 + 8) * 2ピクセル
+    assert_eq!(&png[16..24], &[0, 0, 0, 66, 0, 0, 0, 66]);
+    let mut at = 8;
+    while at < png.len() {
+      let len = u32::from_be_bytes(png[at..at + 4].try_into().unwrap()) as usize;
+      let crc = u32::from_be_bytes(png[at + 8 + len..at + 12 + len].try_into().unwrap());
+      assert_eq!(crc32::checksum(&png[at + 4..at + 8 + len]), crc);
+      at += 12 + len;
+    }
+    assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+  }
+
+  #[test]
+  fn rejects_too_long_urls() {
+    let res = image(&"a".repeat(4000));
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+  }
+}
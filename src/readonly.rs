@@ -0,0 +1,145 @@
+use std::{
+  collections::HashMap,
+  env,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use tera::{Context, Function, Tera, Value};
+
+use crate::{empty, i18n::I18n, json, templates, wants_html, State};
+
+// 移行や復元の間に書き込みを止めるための切り替え
+// テンプレートのヘルパーからも参照するのでArcで共有する
+#[derive(Clone)]
+pub struct ReadOnly(Arc<AtomicBool>);
+
+impl ReadOnly {
+  // READ_ONLY=trueで起動すると最初から読み取り専用にする
+  pub fn from_env() -> ReadOnly {
+    let on = env::var("READ_ONLY").as_deref() == Ok("true");
+    ReadOnly(Arc::new(AtomicBool::new(on)))
+  }
+
+  pub fn is_on(&self) -> bool {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+// 読み取り専用のときに受け付けない操作かを判定する関数
+// 元に戻せるように読み取り専用とメンテナンス中の切り替えだけは受け付ける
+// 管理用のAPIでも削除や最適化などDBに書き込むものは断る
+// 保存しないプレビューとCORSのプリフライト（OPTIONS）も受け付ける
+pub fn rejects(read_only: &ReadOnly, method: &str, segments: &[&str]) -> bool {
+  read_only.is_on()
+    && !matches!(method, "GET" | "HEAD" | "OPTIONS")
+    && !matches!(
+      segments,
+      ["admin", "readonly"] | ["admin", "maintenance"] | ["preview", ..]
+    )
+}
+
+// 断った書き込みの代わりに返す503のレスポンス
+// ブラウザには読み取り専用であることを知らせる画面を返す
+pub fn rejection(req: &Request<Body>, state: &State) -> Response<Body> {
+  if !wants_html(req) {
+    return empty(StatusCode::SERVICE_UNAVAILABLE);
+  }
+  let rendered = templates::render(state, req, "read_only", &mut Context::new());
+  Response::builder()
+    .status(StatusCode::SERVICE_UNAVAILABLE)
+    .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+    .body(rendered.into())
+    .unwrap()
+}
+
+#[derive(Deserialize)]
+struct Query {
+  enabled: bool,
+}
+
+#[derive(Serialize)]
+struct Status {
+  read_only: bool,
+}
+
+// 現在の状態を返す関数
+pub async fn show(state: Arc<State>) -> Result<Response<Body>, Error> {
+  Ok(json(&Status {
+    read_only: state.read_only.is_on(),
+  }))
+}
+
+// POST /admin/readonly?enabled=true で切り替える関数
+pub async fn set(req: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Error> {
+  let query = match serde_urlencoded::from_str::<Query>(req.uri().query().unwrap_or_default()) {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  state.read_only.0.store(query.enabled, Ordering::Relaxed);
+  show(state).await
+}
+
+// テンプレートから{{ read_only_banner(lang=lang) }}で呼び出すヘルパー
+// 読み取り専用でない場合は何も出力しない
+struct Banner(ReadOnly, Arc<I18n>);
+
+impl Function for Banner {
+  fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    if !self.0.is_on() {
+      return Ok(Value::String(String::new()));
+    }
+    let lang = args.get("lang").and_then(Value::as_str).unwrap_or_default();
+    Ok(Value::String(format!(
+      r#"<p role="alert">{}</p>"#,
+      self.1.translate(lang, "read-only-banner", None)
+    )))
+  }
+
+  fn is_safe(&self) -> bool {
+    true
+  }
+}
+
+// テンプレートヘルパーを登録する関数
+pub fn register(tera: &mut Tera, read_only: ReadOnly, i18n: Arc<I18n>) {
+  tera.register_function("read_only_banner", Banner(read_only, i18n));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests;
+
+  #[test]
+  fn rejects_admin_writes_except_switches() {
+    let read_only = ReadOnly(Arc::new(AtomicBool::new(true)));
+    assert!(rejects(&read_only, "POST", &["admin", "trash", "purge"]));
+    assert!(rejects(&read_only, "POST", &["admin", "db", "maintenance"]));
+    assert!(rejects(&read_only, "DELETE", &["admin", "rules", "x"]));
+    assert!(rejects(&read_only, "POST", &["posts"]));
+    assert!(!rejects(&read_only, "POST", &["admin", "readonly"]));
+    assert!(!rejects(&read_only, "POST", &["admin", "maintenance"]));
+    assert!(!rejects(&read_only, "POST", &["preview"]));
+    assert!(!rejects(&read_only, "GET", &["admin", "trash"]));
+    read_only.0.store(false, Ordering::Relaxed);
+    assert!(!rejects(&read_only, "POST", &["admin", "trash", "purge"]));
+  }
+
+  #[tokio::test]
+  async fn rejects_bad_query() {
+    let state = tests::state();
+    for query in ["", "?enabled=yes"] {
+      let req = Request::post(format!("/admin/readonly{}", query))
+        .body(Body::empty())
+        .unwrap();
+      let res = set(req, state.clone()).await.unwrap();
+      assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+    assert!(!state.read_only.0.load(Ordering::Relaxed));
+  }
+}
@@ -0,0 +1,315 @@
+use std::{
+  collections::{HashMap, HashSet},
+  sync::{Arc, Mutex},
+};
+
+use hyper::{Body, Error, Response, StatusCode};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{content::Codec, empty, json, tags, Post, State, Tenant, Visibility};
+
+// 関連する投稿として返す件数
+const MAX_RELATED: usize = 5;
+// これより短い語は多くの投稿に現れるので比べない
+const MIN_WORD_CHARS: usize = 3;
+// 共通するタグ1つを共通する語いくつ分として数えるか
+const TAG_WEIGHT: f64 = 5.0;
+
+#[derive(Serialize, Clone)]
+pub struct Related {
+  id: Uuid,
+  title: String,
+  // 共通する語とタグの割合（0から1）
+  score: f64,
+}
+
+// 投稿から取り出した比べる語とタグ
+struct Features {
+  title: String,
+  words: HashSet<String>,
+  tags: HashSet<String>,
+}
+
+impl Features {
+  // タイトルと本文から比べる語を取り出す
+  fn new(post: &Post, tags: impl IntoIterator<Item = String>) -> Features {
+    Features {
+      title: post.title.clone(),
+      words: format!("{} {}", post.title, post.content)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_WORD_CHARS)
+        .map(str::to_lowercase)
+        .collect(),
+      tags: tags.into_iter().collect(),
+    }
+  }
+
+  // 共通する語とタグの割合を返す
+  // タグは投稿者が付けたものなので，1つをTAG_WEIGHT語分として数える
+  fn score(&self, other: &Features) -> f64 {
+    let weighted = |words: usize, tags: usize| words as f64 + tags as f64 * TAG_WEIGHT;
+    let shared = weighted(
+      self.words.intersection(&other.words).count(),
+      self.tags.intersection(&other.tags).count(),
+    );
+    if shared == 0.0 {
+      return 0.0;
+    }
+    shared
+      / weighted(
+        self.words.union(&other.words).count(),
+        self.tags.union(&other.tags).count(),
+      )
+  }
+}
+
+// 求めた関連する投稿と，そのときの投稿の語とタグ
+struct Entry {
+  features: Features,
+  related: Arc<Vec<Related>>,
+}
+
+impl Entry {
+  // 関連する投稿に入る値か
+  fn admits(&self, score: f64) -> bool {
+    score > 0.0
+      && self
+        .related
+        .get(MAX_RELATED - 1)
+        .is_none_or(|last| score > last.score)
+  }
+}
+
+#[derive(Default)]
+struct Index {
+  // 比べる相手になる公開の投稿の語とタグ
+  // 最初に使うときにすべて復号して作り，その後は変わった投稿だけを読み直す
+  candidates: Option<HashMap<Uuid, Features>>,
+  // 変わったので次に使うときに読み直す投稿
+  dirty: HashSet<Uuid>,
+  entries: HashMap<Uuid, Entry>,
+}
+
+// 投稿ごとの関連する投稿のキャッシュ
+// 投稿を開くたびに全件を復号しないように，比べる語とタグも投稿ごとに持っておく
+pub struct Cache {
+  index: Mutex<Index>,
+}
+
+impl Cache {
+  pub fn new() -> Cache {
+    Cache {
+      index: Mutex::new(Index::default()),
+    }
+  }
+
+  // 投稿が変わったときに呼び出す関数
+  // 他の投稿の結果は，次に使うときに変わった投稿の影響を受けるものだけを捨てる
+  pub fn invalidate(&self, id: Uuid) {
+    let mut index = self.index.lock().unwrap();
+    index.dirty.insert(id);
+    index.entries.remove(&id);
+  }
+}
+
+// 比べる相手になる公開の投稿の語とタグを読む関数
+// onlyを指定した場合はその投稿だけを読む
+fn load(conn: &Connection, codec: &Codec, only: Option<Uuid>) -> HashMap<Uuid, Features> {
+  let mut tags: HashMap<Uuid, Vec<String>> = HashMap::new();
+  let mut stmt = conn
+    .prepare("SELECT post_id, tag FROM post_tags WHERE ?1 IS NULL OR post_id=?1")
+    .unwrap();
+  let rows = stmt
+    .query_map(params![only], |row| Ok((row.get(0)?, row.get(1)?)))
+    .unwrap();
+  for row in rows {
+    let (id, tag) = row.unwrap();
+    tags.entry(id).or_default().push(tag);
+  }
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, title, content, encrypted, compressed FROM posts
+      WHERE kind = 'text' AND visibility=?1 AND trashed_at IS NULL AND (?2 IS NULL OR id=?2)",
+    )
+    .unwrap();
+  stmt
+    .query_map(params![Visibility::Public, only], |row| {
+      Post::from_row(row, codec)
+    })
+    .unwrap()
+    .map(Result::unwrap)
+    .map(|post| {
+      let features = Features::new(&post, tags.remove(&post.id).unwrap_or_default());
+      (post.id, features)
+    })
+    .collect()
+}
+
+// 変わった投稿を読み直し，その影響を受ける結果を捨てる関数
+// 変わった投稿を含む結果と，変わった投稿が新たに入る結果が対象になる
+fn refresh(index: &mut Index, conn: &Connection, codec: &Codec) {
+  if index.candidates.is_none() {
+    index.candidates = Some(load(conn, codec, None));
+    index.dirty.clear();
+    return;
+  }
+  for id in index.dirty.drain().collect::<Vec<_>>() {
+    let features = load(conn, codec, Some(id)).remove(&id);
+    index.entries.retain(|_, entry| {
+      !entry.related.iter().any(|related| related.id == id)
+        && features
+          .as_ref()
+          .is_none_or(|features| !entry.admits(features.score(&entry.features)))
+    });
+    let candidates = index.candidates.as_mut().unwrap();
+    match features {
+      Some(features) => candidates.insert(id, features),
+      None => candidates.remove(&id),
+    };
+  }
+}
+
+// 共通する語とタグの多い公開の投稿を求める関数
+fn compute(candidates: &HashMap<Uuid, Features>, id: &Uuid, mine: &Features) -> Vec<Related> {
+  let mut related: Vec<Related> = candidates
+    .iter()
+    .filter(|(other, _)| *other != id)
+    .filter_map(|(other, theirs)| {
+      let score = mine.score(theirs);
+      if score == 0.0 {
+        return None;
+      }
+      Some(Related {
+        id: *other,
+        title: theirs.title.clone(),
+        score,
+      })
+    })
+    .collect();
+  related.sort_by(|a, b| b.score.total_cmp(&a.score));
+  related.truncate(MAX_RELATED);
+  related
+}
+
+// 投稿に関連する投稿を返す関数
+// 復号するのは変わった投稿だけなので，DBのロック中に呼び出しても長くは待たせない
+pub fn for_post(
+  tenant: &Tenant,
+  conn: &Connection,
+  codec: &Codec,
+  post: &Post,
+) -> Arc<Vec<Related>> {
+  let mut index = tenant.related.index.lock().unwrap();
+  refresh(&mut index, conn, codec);
+  if let Some(entry) = index.entries.get(&post.id) {
+    return entry.related.clone();
+  }
+  let features = Features::new(post, tags::for_post(conn, &post.id));
+  let related = Arc::new(compute(
+    index.candidates.as_ref().unwrap(),
+    &post.id,
+    &features,
+  ));
+  index.entries.insert(
+    post.id,
+    Entry {
+      features,
+      related: related.clone(),
+    },
+  );
+  related
+}
+
+// 投稿に関連する投稿を一覧する関数
+pub async fn list(
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  id: &str,
+) -> Result<Response<Body>, Error> {
+  let id = match Uuid::parse_str(id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let conn = tenant.conn.lock().await;
+  let post = conn
+    .query_row(
+      "SELECT id, title, content, encrypted, compressed FROM posts
+      WHERE id=?1 AND kind = 'text' AND visibility != ?2 AND trashed_at IS NULL",
+      params![id, Visibility::Private],
+      |row| Post::from_row(row, &state.codec),
+    )
+    .optional()
+    .unwrap();
+  match post {
+    Some(post) => Ok(json(&*for_post(&tenant, &conn, &state.codec, &post))),
+    None => Ok(empty(StatusCode::NOT_FOUND)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use hyper::{body, Request};
+  use serde_json::Value;
+
+  use super::*;
+  use crate::{events::Event, tests};
+
+  async fn related(state: &Arc<State>, id: &Uuid) -> Vec<Uuid> {
+    let req = Request::get(format!("/posts/{}/related", id))
+      .body(Body::empty())
+      .unwrap();
+    let res = tests::send(state, req).await;
+    let body: Value = serde_json::from_slice(&body::to_bytes(res).await.unwrap()).unwrap();
+    body
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|related| related["id"].as_str().unwrap().parse().unwrap())
+      .collect()
+  }
+
+  #[tokio::test]
+  async fn weighs_tags_and_follows_changes() {
+    let state = tests::state();
+    let tenant = tests::tenant(&state);
+    let mine = tests::insert_post(&state, "alpha beta", Visibility::Public).await;
+    let words = tests::insert_post(&state, "alpha gamma", Visibility::Public).await;
+    let tagged = tests::insert_post(&state, "delta epsilon", Visibility::Public).await;
+    let hidden = tests::insert_post(&state, "alpha beta", Visibility::Private).await;
+    for id in [mine, tagged, hidden] {
+      tenant
+        .conn
+        .lock()
+        .await
+        .execute(
+          "INSERT INTO post_tags(post_id, tag) VALUES (?1, 'rust')",
+          params![id],
+        )
+        .unwrap();
+    }
+    assert_eq!(related(&state, &mine).await, [tagged, words]);
+    // 変わった投稿だけを読み直して結果に反映する
+    tenant
+      .conn
+      .lock()
+      .await
+      .execute_batch(&format!(
+        "UPDATE posts SET title='alpha beta', content='alpha beta content' WHERE id=X'{0}';
+        INSERT INTO post_tags(post_id, tag) VALUES (X'{0}', 'rust');",
+        words.to_simple()
+      ))
+      .unwrap();
+    tenant.publish(Event::Updated { id: words });
+    assert_eq!(related(&state, &mine).await, [words, tagged]);
+    tenant
+      .conn
+      .lock()
+      .await
+      .execute("UPDATE posts SET trashed_at=1 WHERE id=?1", params![words])
+      .unwrap();
+    tenant.publish(Event::Trashed { id: words });
+    assert_eq!(related(&state, &mine).await, [tagged]);
+  }
+}
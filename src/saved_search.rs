@@ -0,0 +1,140 @@
+use std::sync::Arc;
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::{empty, json, now, search, State, Tenant};
+
+#[derive(Deserialize)]
+struct NewSavedSearch {
+  name: String,
+  q: String,
+  // ピン留めしたものは一覧の先頭に並べる
+  #[serde(default)]
+  pinned: bool,
+}
+
+#[derive(Serialize)]
+pub struct SavedSearch {
+  id: i64,
+  name: String,
+  q: String,
+  pinned: bool,
+  created_at: i64,
+}
+
+// 保存した検索をすべて返す関数
+pub fn all(conn: &Connection) -> Vec<SavedSearch> {
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, name, query, pinned, created_at FROM saved_searches
+      ORDER BY pinned DESC, name",
+    )
+    .unwrap();
+  stmt
+    .query_map([], |row| {
+      Ok(SavedSearch {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        q: row.get(2)?,
+        pinned: row.get(3)?,
+        created_at: row.get(4)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap()
+}
+
+// 保存した検索を一覧する関数
+pub async fn list(tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let conn = tenant.conn.lock().await;
+  Ok(json(&all(&conn)))
+}
+
+// 検索を名前を付けて保存する関数
+// 実行時に失敗しないように保存する前に検索式を検証する
+pub async fn create(req: Request<Body>, tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let form = match serde_urlencoded::from_bytes::<NewSavedSearch>(&body) {
+    Ok(form) => form,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  if form.name.is_empty() {
+    return Ok(empty(StatusCode::UNPROCESSABLE_ENTITY));
+  }
+  if let Err(e) = search::parse(&form.q) {
+    let mut res = json(&e);
+    *res.status_mut() = StatusCode::BAD_REQUEST;
+    return Ok(res);
+  }
+  let conn = tenant.conn.lock().await;
+  conn
+    .execute(
+      "INSERT INTO saved_searches(name, query, pinned, created_at) VALUES (?1,?2,?3,?4)",
+      params![form.name, form.q, form.pinned, now()],
+    )
+    .unwrap();
+  Ok(Response::new(conn.last_insert_rowid().to_string().into()))
+}
+
+// 保存した検索を実行する関数
+pub async fn show(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  id: &str,
+) -> Result<Response<Body>, Error> {
+  let id: i64 = match id.parse() {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let conn = tenant.conn.lock().await;
+  let q: Option<String> = conn
+    .query_row(
+      "SELECT query FROM saved_searches WHERE id=?1",
+      params![id],
+      |row| row.get(0),
+    )
+    .optional()
+    .unwrap();
+  match q {
+    Some(q) => Ok(search::run(&state, &tenant, &conn, &q, &req)),
+    None => Ok(empty(StatusCode::NOT_FOUND)),
+  }
+}
+
+// 保存した検索を削除する関数
+pub async fn delete(tenant: Arc<Tenant>, id: &str) -> Result<Response<Body>, Error> {
+  let id: i64 = match id.parse() {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let deleted = tenant
+    .conn
+    .lock()
+    .await
+    .execute("DELETE FROM saved_searches WHERE id=?1", params![id])
+    .unwrap();
+  if deleted == 0 {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  Ok(empty(StatusCode::NO_CONTENT))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests;
+
+  #[tokio::test]
+  async fn rejects_bad_form() {
+    let state = tests::state();
+    let req = Request::post("/searches")
+      .body(Body::from("name=Rust"))
+      .unwrap();
+    let res = tests::send(&state, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+  }
+}
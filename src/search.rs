@@ -0,0 +1,599 @@
+use std::sync::Arc;
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use tera::Context;
+use uuid::Uuid;
+
+use crate::{
+  content::Codec, empty, json, saved_search, templates, wants_html, wordcount::Counts, Post, State,
+  Tenant, Visibility,
+};
+
+// 返す件数の上限
+const MAX_RESULTS: usize = 50;
+// SQLで絞り込んだ投稿を1回に読む数
+const PAGE: usize = 200;
+// 1回の検索で読む投稿の数の上限（新しいものから）
+// これに達しても足りない場合は，それより古い投稿を確かめていないことを結果で知らせる
+const MAX_SCANNED: usize = 5000;
+// 抜粋の長さと，最初に一致した語より前に含める文字数
+const SNIPPET_CHARS: usize = 160;
+const SNIPPET_LEAD: usize = 40;
+
+#[derive(Deserialize)]
+struct Query {
+  #[serde(default)]
+  q: String,
+}
+
+// 検索式を解釈した結果
+// 語はすべて小文字にしておき，大文字小文字を区別せずに照合する
+#[derive(Default)]
+pub struct Parsed {
+  include: Vec<String>,
+  exclude: Vec<String>,
+  // 付いている（いない）タグ（タグは大文字小文字を区別する）
+  tags: Vec<String>,
+  without_tags: Vec<String>,
+  // 作成日時（UNIX秒）の範囲
+  before: Option<i64>,
+  after: Option<i64>,
+}
+
+// 検索式の誤りと，その位置（バイト単位）
+#[derive(Serialize)]
+pub struct ParseError {
+  error: String,
+  offset: usize,
+}
+
+#[derive(Serialize)]
+struct Hit {
+  id: Uuid,
+  title: String,
+  // 一致した語を<mark>で囲んだ本文の抜粋（HTMLとしてエスケープ済み）
+  snippet: String,
+  counts: Option<Counts>,
+}
+
+fn error(offset: usize, message: &str) -> ParseError {
+  ParseError {
+    error: message.to_string(),
+    offset,
+  }
+}
+
+// 1970-01-01からの日数を求める関数
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+  let y = if m <= 2 { y - 1 } else { y };
+  let era = if y >= 0 { y } else { y - 399 } / 400;
+  let yoe = y - era * 400;
+  let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  era * 146097 + doe - 719468
+}
+
+// YYYY-MM-DDの日付をその日の始まり（UTC）のUNIX秒にする関数
+fn parse_date(date: &str) -> Option<i64> {
+  let mut parts = date.splitn(3, '-');
+  let y = parts.next()?.parse().ok()?;
+  let m = parts
+    .next()?
+    .parse()
+    .ok()
+    .filter(|m| (1..=12).contains(m))?;
+  let d = parts
+    .next()?
+    .parse()
+    .ok()
+    .filter(|d| (1..=31).contains(d))?;
+  Some(days_from_civil(y, m, d) * 24 * 60 * 60)
+}
+
+// 検索式を解釈する関数
+// 語を空白で区切り，"..."は1つの語として，-を付けた語は除外として扱う
+// before:YYYY-MM-DDはその日より前，after:YYYY-MM-DDはその日以降に作成された投稿に絞り込む
+// tag:名前はそのタグの付いた投稿に，-tag:名前は付いていない投稿に絞り込む
+pub fn parse(q: &str) -> Result<Parsed, ParseError> {
+  let mut parsed = Parsed::default();
+  let mut chars = q.char_indices().peekable();
+  while let Some(&(start, c)) = chars.peek() {
+    if c.is_whitespace() {
+      chars.next();
+      continue;
+    }
+    let negated = c == '-';
+    if negated {
+      chars.next();
+    }
+    let quoted = matches!(chars.peek(), Some((_, '"')));
+    let mut word = String::new();
+    if quoted {
+      chars.next();
+      loop {
+        match chars.next() {
+          Some((_, '"')) => break,
+          Some((_, c)) => word.push(c),
+          None => return Err(error(start, "unterminated quote")),
+        }
+      }
+    } else {
+      while let Some(&(_, c)) = chars.peek() {
+        if c.is_whitespace() {
+          break;
+        }
+        word.push(c);
+        chars.next();
+      }
+    }
+    if word.is_empty() {
+      return Err(error(start, "empty term"));
+    }
+    if !quoted {
+      if let Some((key, value)) = word.split_once(':') {
+        if key == "tag" {
+          if value.is_empty() {
+            return Err(error(start, "empty tag"));
+          }
+          let tags = if negated {
+            &mut parsed.without_tags
+          } else {
+            &mut parsed.tags
+          };
+          tags.push(value.to_string());
+          continue;
+        }
+        let filter = match key {
+          "before" => Some(&mut parsed.before),
+          "after" => Some(&mut parsed.after),
+          // 知らないものは普通の語として扱う（URLなど）
+          _ => None,
+        };
+        if let Some(filter) = filter {
+          if negated {
+            return Err(error(start, "filters cannot be negated"));
+          }
+          let at = parse_date(value).ok_or_else(|| error(start, "date must be YYYY-MM-DD"))?;
+          *filter = Some(at);
+          continue;
+        }
+      }
+    }
+    let word = word.to_lowercase();
+    if negated {
+      parsed.exclude.push(word);
+    } else {
+      parsed.include.push(word);
+    }
+  }
+  if parsed.include.is_empty()
+    && parsed.tags.is_empty()
+    && parsed.before.is_none()
+    && parsed.after.is_none()
+  {
+    return Err(error(0, "empty query"));
+  }
+  Ok(parsed)
+}
+
+// 大文字小文字を区別せずに比べるための文字
+// 位置がずれないように1文字を1文字に対応させる
+fn fold(c: char) -> char {
+  c.to_lowercase().next().unwrap_or(c)
+}
+
+// 2つの語の編集距離を求める関数
+fn distance(a: &[char], b: &[char]) -> usize {
+  let mut row: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.iter().enumerate() {
+    let mut prev = row[0];
+    row[0] = i + 1;
+    for (j, cb) in b.iter().enumerate() {
+      let next = (prev + usize::from(ca != cb))
+        .min(row[j] + 1)
+        .min(row[j + 1] + 1);
+      prev = row[j + 1];
+      row[j + 1] = next;
+    }
+  }
+  row[b.len()]
+}
+
+// 綴りの誤りとみなせるほど近い語かを判定する関数
+// 短い語ほど偶然近くなりやすいので許す距離を小さくする
+fn similar(word: &str, other: &str) -> bool {
+  let word: Vec<char> = word.chars().map(fold).collect();
+  let other: Vec<char> = other.chars().map(fold).collect();
+  let allowed = match word.len() {
+    0..=3 => 0,
+    4..=7 => 1,
+    _ => 2,
+  };
+  distance(&word, &other) <= allowed
+}
+
+// 本文から一致した語の周辺を抜き出し，語を強調した抜粋を作る関数
+// SQLiteのFTS5のsnippet()やhighlight()は使わない．FTS5の索引は語を（抜粋を作るには本文も）平文で持つので，
+// CONTENT_KEY_FILEなどで本文を暗号化していても索引から内容が読めてしまう．
+// 本文を外部に置くcontent=の表にしても，FTS5は暗号化や圧縮をしたcontent列を読めない．
+// そのため抜粋と強調は，Codecで復号した本文からここで作る
+fn snippet(content: &str, words: &[String]) -> String {
+  let chars: Vec<char> = content.chars().collect();
+  let folded: Vec<char> = chars.iter().copied().map(fold).collect();
+  // 一致した範囲を集めて，重なるものはまとめる
+  let mut ranges: Vec<(usize, usize)> = Vec::new();
+  for word in words {
+    let word: Vec<char> = word.chars().map(fold).collect();
+    if word.is_empty() || word.len() > folded.len() {
+      continue;
+    }
+    for start in 0..=folded.len() - word.len() {
+      if folded[start..start + word.len()] == word[..] {
+        ranges.push((start, start + word.len()));
+      }
+    }
+  }
+  ranges.sort_unstable();
+  let mut merged: Vec<(usize, usize)> = Vec::new();
+  for (start, end) in ranges {
+    match merged.last_mut() {
+      Some(last) if start <= last.1 => last.1 = last.1.max(end),
+      _ => merged.push((start, end)),
+    }
+  }
+  let begin = merged
+    .first()
+    .map_or(0, |(start, _)| start.saturating_sub(SNIPPET_LEAD));
+  let end = chars.len().min(begin + SNIPPET_CHARS);
+  let text =
+    |from: usize, to: usize| tera::escape_html(&chars[from..to].iter().collect::<String>());
+  let mut out = String::new();
+  if begin > 0 {
+    out.push('…');
+  }
+  let mut at = begin;
+  for (start, stop) in merged {
+    if stop <= at || start >= end {
+      continue;
+    }
+    let (start, stop) = (start.max(at), stop.min(end));
+    out.push_str(&text(at, start));
+    out.push_str("<mark>");
+    out.push_str(&text(start, stop));
+    out.push_str("</mark>");
+    at = stop;
+  }
+  out.push_str(&text(at, end));
+  if end < chars.len() {
+    out.push('…');
+  }
+  out
+}
+
+// 投稿を検索する関数
+pub async fn search(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+) -> Result<Response<Body>, Error> {
+  let query = match serde_urlencoded::from_str::<Query>(req.uri().query().unwrap_or_default()) {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let conn = tenant.conn.lock().await;
+  Ok(run(&state, &tenant, &conn, &query.q, &req))
+}
+
+// 条件に当てはまる投稿を新しい順にMAX_RESULTS件まで探した結果
+struct Scan {
+  posts: Vec<Post>,
+  // 読む上限に達したため，それより古い投稿を確かめていない
+  truncated: bool,
+}
+
+// 条件に当てはまる投稿を新しい順に探す関数
+// 日付，タグ，語の条件はSQLで絞り込み，平文で保存された本文はSQLの中で照合する
+// 圧縮や暗号化した本文はSQLでは読めないので，タイトルだけで除外できるもの以外を残して復号してから確かめる
+// そのためSQLの結果を少しずつ読み，MAX_RESULTS件見つかるか，max_scanned件読むまで続ける
+// includeに渡した語はすべて含むもの（空なら語で絞り込まない）のうち，keepが真になるものを返す
+fn scan(
+  conn: &Connection,
+  codec: &Codec,
+  parsed: &Parsed,
+  include: &[String],
+  keep: impl Fn(&Post) -> bool,
+  max_scanned: usize,
+) -> Scan {
+  let words = |words: &[String]| serde_json::to_string(words).unwrap();
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, title, content, encrypted, compressed, word_count, char_count, reading_minutes
+      FROM posts WHERE kind = 'text' AND visibility=?1 AND trashed_at IS NULL
+      AND (?2 IS NULL OR created_at < ?2) AND (?3 IS NULL OR created_at >= ?3)
+      AND NOT EXISTS (SELECT 1 FROM json_each(?4) AS word
+        WHERE typeof(content) = 'text'
+          AND instr(fold_case(title || char(10) || content), word.value) = 0)
+      AND NOT EXISTS (SELECT 1 FROM json_each(?5) AS word
+        WHERE instr(fold_case(title || char(10)
+          || CASE WHEN typeof(content) = 'text' THEN content ELSE '' END), word.value) > 0)
+      AND NOT EXISTS (SELECT 1 FROM json_each(?6) AS tag
+        WHERE NOT EXISTS (SELECT 1 FROM post_tags
+          WHERE post_tags.post_id = posts.id AND post_tags.tag = tag.value))
+      AND NOT EXISTS (SELECT 1 FROM post_tags
+        WHERE post_tags.post_id = posts.id AND post_tags.tag IN (SELECT value FROM json_each(?7)))
+      ORDER BY created_at DESC, rowid DESC
+      LIMIT ?8 OFFSET ?9",
+    )
+    .unwrap();
+  let matches = |post: &Post| {
+    let text = format!("{}\n{}", post.title, post.content).to_lowercase();
+    include.iter().all(|word| text.contains(word.as_str()))
+      && !parsed
+        .exclude
+        .iter()
+        .any(|word| text.contains(word.as_str()))
+  };
+  let mut posts = Vec::new();
+  let mut scanned = 0;
+  // 検索の間はDB接続をロックしているので，ページの間で内容は変わらない
+  loop {
+    let limit = PAGE.min(max_scanned - scanned);
+    let page = stmt
+      .query_map(
+        params![
+          Visibility::Public,
+          parsed.before,
+          parsed.after,
+          words(include),
+          words(&parsed.exclude),
+          words(&parsed.tags),
+          words(&parsed.without_tags),
+          limit as i64,
+          scanned as i64
+        ],
+        |row| {
+          let mut post = Post::from_row(row, codec)?;
+          post.counts = Counts::from_row(row, 5)?;
+          Ok(post)
+        },
+      )
+      .unwrap()
+      .collect::<Result<Vec<_>, _>>()
+      .unwrap();
+    let exhausted = page.len() < limit;
+    scanned += page.len();
+    posts.extend(page.into_iter().filter(|post| matches(post) && keep(post)));
+    if posts.len() >= MAX_RESULTS || exhausted {
+      posts.truncate(MAX_RESULTS);
+      return Scan {
+        posts,
+        truncated: false,
+      };
+    }
+    if scanned >= max_scanned {
+      return Scan {
+        posts,
+        truncated: true,
+      };
+    }
+  }
+}
+
+// 検索を実行してレスポンスを作る関数
+// 一覧と同じく公開の投稿のみを対象にする
+// ブラウザからの検索にはHTMLで，それ以外にはJSONで結果を返す
+pub fn run(
+  state: &State,
+  tenant: &Tenant,
+  conn: &Connection,
+  q: &str,
+  req: &Request<Body>,
+) -> Response<Body> {
+  let html = wants_html(req);
+  let mut ctx = Context::new();
+  ctx.insert("prefix", &tenant.prefix);
+  ctx.insert("q", q);
+  if html {
+    // 保存した検索はサイドバーに並べる
+    ctx.insert("saved", &saved_search::all(conn));
+  }
+  let parsed = match parse(q) {
+    Ok(parsed) => parsed,
+    Err(e) => {
+      let mut res = if html {
+        ctx.insert("error", &e);
+        Response::new(templates::render(state, req, "search", &mut ctx).into())
+      } else {
+        json(&e)
+      };
+      *res.status_mut() = StatusCode::BAD_REQUEST;
+      return res;
+    }
+  };
+  let mut found = scan(
+    conn,
+    &state.codec,
+    &parsed,
+    &parsed.include,
+    |_| true,
+    MAX_SCANNED,
+  );
+  // 見つからなければ綴りの誤りを疑い，語以外の条件に当てはまる投稿からタイトルの単語と近いものを探す
+  let fuzzy = found.posts.is_empty() && !parsed.include.is_empty();
+  if fuzzy {
+    let similar_title = |post: &Post| {
+      let title: Vec<String> = post
+        .title
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect();
+      parsed
+        .include
+        .iter()
+        .all(|word| title.iter().any(|other| similar(word, other)))
+    };
+    found = scan(conn, &state.codec, &parsed, &[], similar_title, MAX_SCANNED);
+  }
+  let truncated = found.truncated;
+  let hits = found
+    .posts
+    .into_iter()
+    .map(|post| Hit {
+      id: post.id,
+      snippet: snippet(&post.content, &parsed.include),
+      title: post.title,
+      counts: post.counts,
+    })
+    .collect::<Vec<_>>();
+  let mut res = if html {
+    ctx.insert("hits", &hits);
+    ctx.insert("fuzzy", &fuzzy);
+    ctx.insert("truncated", &truncated);
+    ctx.insert("scanned", &MAX_SCANNED);
+    Response::new(templates::render(state, req, "search", &mut ctx).into())
+  } else {
+    json(&hits)
+  };
+  // JSONは配列のまま返すので，古い投稿を確かめていないことはヘッダで知らせる
+  if truncated {
+    res
+      .headers_mut()
+      .insert("x-search-truncated", "true".parse().unwrap());
+  }
+  res
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{db, tests};
+
+  fn insert(conn: &Connection, codec: &Codec, title: &str, content: &str, tags: &[&str]) -> Uuid {
+    let id = Uuid::new_v4();
+    let stored = codec.encode(content);
+    conn
+      .execute(
+        "INSERT INTO posts(id, title, content, encrypted, compressed, visibility, created_at)
+        VALUES (?1,?2,?3,?4,?5,?6,?7)",
+        params![
+          id,
+          title,
+          stored.content,
+          stored.encrypted,
+          stored.compressed,
+          Visibility::Public,
+          0
+        ],
+      )
+      .unwrap();
+    for tag in tags {
+      conn
+        .execute(
+          "INSERT INTO post_tags(post_id, tag) VALUES (?1,?2)",
+          params![id, tag],
+        )
+        .unwrap();
+    }
+    id
+  }
+
+  fn ids(posts: Vec<Post>) -> Vec<Uuid> {
+    let mut ids: Vec<Uuid> = posts.into_iter().map(|post| post.id).collect();
+    ids.sort();
+    ids
+  }
+
+  #[test]
+  fn parses_tags() {
+    let parsed = parse("rust tag:Lang -tag:old").unwrap_or_else(|e| panic!("{}", e.error));
+    assert_eq!(parsed.include, ["rust"]);
+    assert_eq!(parsed.tags, ["Lang"]);
+    assert_eq!(parsed.without_tags, ["old"]);
+    assert!(parse("tag:Lang").is_ok());
+    assert_eq!(parse("tag:").err().unwrap().error, "empty tag");
+  }
+
+  #[test]
+  fn filters_words_and_tags_in_sql() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    db::migrate(&mut conn);
+    db::register_functions(&conn);
+    let codec = Codec::from_env();
+    let greek = insert(&conn, &codec, "Notes", "ΚΑΛΗΜΕΡΑ world", &["lang"]);
+    let long = insert(
+      &conn,
+      &codec,
+      "Long",
+      &"world ".repeat(2000),
+      &["lang", "old"],
+    );
+    let other = insert(&conn, &codec, "Other", "nothing here", &[]);
+    let search = |q: &str| {
+      let parsed = parse(q).unwrap_or_else(|e| panic!("{}", e.error));
+      ids(
+        scan(
+          &conn,
+          &codec,
+          &parsed,
+          &parsed.include,
+          |_| true,
+          MAX_SCANNED,
+        )
+        .posts,
+      )
+    };
+    // 圧縮した本文も復号して照合する
+    let mut both = vec![greek, long];
+    both.sort();
+    assert_eq!(search("WORLD"), both);
+    assert_eq!(search("καλημερα"), [greek]);
+    assert_eq!(search("world -καλημερα"), [long]);
+    assert_eq!(search("tag:lang -tag:old"), [greek]);
+    assert_eq!(search("here"), [other]);
+    assert!(search("tag:missing").is_empty());
+  }
+
+  #[test]
+  fn scans_past_newer_encoded_posts() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    db::migrate(&mut conn);
+    db::register_functions(&conn);
+    let codec = Codec::from_env();
+    let old = insert(&conn, &codec, "Old", "needle", &[]);
+    // 圧縮した本文はSQLで除外できないので，すべて読んで確かめることになる
+    let filler = "hay ".repeat(2000);
+    for at in 1..=PAGE * 2 {
+      let id = insert(&conn, &codec, "Filler", &filler, &[]);
+      conn
+        .execute(
+          "UPDATE posts SET created_at=?1 WHERE id=?2",
+          params![at as i64, id],
+        )
+        .unwrap();
+    }
+    let parsed = parse("needle").unwrap_or_else(|e| panic!("{}", e.error));
+    let found = scan(
+      &conn,
+      &codec,
+      &parsed,
+      &parsed.include,
+      |_| true,
+      MAX_SCANNED,
+    );
+    assert_eq!(ids(found.posts), [old]);
+    assert!(!found.truncated);
+    let found = scan(&conn, &codec, &parsed, &parsed.include, |_| true, PAGE + 1);
+    assert!(found.posts.is_empty());
+    assert!(found.truncated);
+  }
+
+  #[tokio::test]
+  async fn rejects_bad_query() {
+    let state = tests::state();
+    let req = Request::get("/search?q=a&q=b").body(Body::empty()).unwrap();
+    let res = tests::send(&state, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+  }
+}
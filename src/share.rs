@@ -0,0 +1,260 @@
+use std::sync::Arc;
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{audit, empty, json, now, qr, remote_ip, Post, State, Tenant};
+
+// 共有リンクの有効期間の初期値（7日）
+const DEFAULT_TTL: u64 = 7 * 24 * 60 * 60;
+// 指定できる有効期間の上限（1年）
+const MAX_TTL: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Deserialize)]
+struct NewShare {
+  // 有効期間（秒，上限より長い場合は上限にする）
+  expires_in: Option<u64>,
+}
+
+// 有効な共有リンクの一覧で返す情報
+#[derive(Serialize)]
+struct Share {
+  id: Uuid,
+  url: String,
+  expires_at: u64,
+}
+
+// 共有リンクの絶対URLを作る関数
+// 共有のidと期限に署名を付けることで推測や改ざんを防ぐ
+// リンクは他の人に渡すので公開URLを先頭に付ける
+fn url(origin: &str, state: &State, tenant: &Tenant, id: &Uuid, expires_at: u64) -> String {
+  let token = state.signer.sign(&format!("{}.{}", id, expires_at));
+  format!("{}{}/s/{}", origin, tenant.prefix, token)
+}
+
+// 署名を検証してトークンから共有のidと期限を取り出す関数
+fn parse_token(state: &State, token: &str) -> Option<(Uuid, u64)> {
+  let (id, expires_at) = state.signer.verify(token)?.split_once('.')?;
+  Some((Uuid::parse_str(id).ok()?, expires_at.parse().ok()?))
+}
+
+// 投稿の共有リンクを発行する関数
+pub async fn create(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  post_id: &str,
+) -> Result<Response<Body>, Error> {
+  let post_id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let ip = remote_ip(&req);
+  let origin = state.base_url.origin(&req);
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let new_share = match serde_urlencoded::from_bytes::<NewShare>(&body) {
+    Ok(new_share) => new_share,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let ttl = new_share.expires_in.unwrap_or(DEFAULT_TTL).min(MAX_TTL);
+  let expires_at = match now().checked_add(ttl) {
+    Some(expires_at) => expires_at,
+    None => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let conn = tenant.conn.lock().await;
+  let exists = conn
+    .query_row(
+      "SELECT 1 FROM posts WHERE id=?1 AND trashed_at IS NULL",
+      params![post_id],
+      |_| Ok(()),
+    )
+    .optional()
+    .unwrap();
+  if exists.is_none() {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  let id = Uuid::new_v4();
+  conn
+    .execute(
+      "INSERT INTO shares(id, post_id, expires_at) VALUES (?1,?2,?3)",
+      params![id, post_id, expires_at],
+    )
+    .unwrap();
+  audit::record(
+    &conn,
+    audit::Entry {
+      actor: audit::ANONYMOUS,
+      action: "share",
+      post_id: &post_id,
+      summary: format!("share={}, expires_at={}", id, expires_at),
+      ip,
+    },
+  );
+  Ok(Response::new(
+    url(&origin, &state, &tenant, &id, expires_at).into(),
+  ))
+}
+
+// 投稿の有効な共有リンクを一覧する関数
+pub async fn list(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  post_id: &str,
+) -> Result<Response<Body>, Error> {
+  let post_id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let origin = state.base_url.origin(&req);
+  let conn = tenant.conn.lock().await;
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, expires_at FROM shares
+      WHERE post_id=?1 AND revoked = 0 AND expires_at > ?2
+      ORDER BY expires_at",
+    )
+    .unwrap();
+  let shares = stmt
+    .query_map(params![post_id, now()], |row| {
+      let id = row.get(0)?;
+      let expires_at = row.get(1)?;
+      Ok(Share {
+        id,
+        url: url(&origin, &state, &tenant, &id, expires_at),
+        expires_at,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  Ok(json(&shares))
+}
+
+// 共有リンクを失効させる関数
+pub async fn revoke(
+  req: Request<Body>,
+  tenant: Arc<Tenant>,
+  post_id: &str,
+  share_id: &str,
+) -> Result<Response<Body>, Error> {
+  let (post_id, share_id) = match (Uuid::parse_str(post_id), Uuid::parse_str(share_id)) {
+    (Ok(post_id), Ok(share_id)) => (post_id, share_id),
+    _ => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let conn = tenant.conn.lock().await;
+  let revoked = conn
+    .execute(
+      "UPDATE shares SET revoked = 1 WHERE id=?1 AND post_id=?2 AND revoked = 0",
+      params![share_id, post_id],
+    )
+    .unwrap();
+  if revoked == 0 {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  audit::record(
+    &conn,
+    audit::Entry {
+      actor: audit::ANONYMOUS,
+      action: "revoke_share",
+      post_id: &post_id,
+      summary: format!("share={}", share_id),
+      ip: remote_ip(&req),
+    },
+  );
+  Ok(empty(StatusCode::NO_CONTENT))
+}
+
+// 共有リンクから投稿を読み取り専用で表示する関数
+// 公開範囲に関係なく表示する
+pub async fn show(
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  token: &str,
+) -> Result<Response<Body>, Error> {
+  let id = match parse_token(&state, token) {
+    Some((id, expires_at)) if expires_at > now() => id,
+    _ => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let post = tenant
+    .conn
+    .lock()
+    .await
+    .query_row(
+      "SELECT posts.id, title, content, encrypted, compressed FROM shares
+      JOIN posts ON posts.id = shares.post_id
+      WHERE shares.id=?1 AND revoked = 0 AND kind = 'text' AND trashed_at IS NULL",
+      params![id],
+      |row| Post::from_row(row, &state.codec),
+    )
+    .optional()
+    .unwrap();
+  match post {
+    Some(post) => {
+      tenant.views.record(post.id);
+      Ok(Response::new(post.render(&state.tera).into()))
+    }
+    None => Ok(empty(StatusCode::NOT_FOUND)),
+  }
+}
+
+// 共有リンクのQRコードを返す関数
+// 失効したり期限が切れたりしたリンクのQRコードは作らない
+pub async fn qr(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  token: &str,
+) -> Result<Response<Body>, Error> {
+  let (id, expires_at) = match parse_token(&state, token) {
+    Some((id, expires_at)) if expires_at > now() => (id, expires_at),
+    _ => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let active = tenant
+    .conn
+    .lock()
+    .await
+    .query_row(
+      "SELECT 1 FROM shares JOIN posts ON posts.id = shares.post_id
+      WHERE shares.id=?1 AND revoked = 0 AND trashed_at IS NULL",
+      params![id],
+      |_| Ok(()),
+    )
+    .optional()
+    .unwrap();
+  if active.is_none() {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  let origin = state.base_url.origin(&req);
+  Ok(qr::image(&url(&origin, &state, &tenant, &id, expires_at)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{tests, Visibility};
+
+  fn share(id: &Uuid, body: &str) -> Request<Body> {
+    Request::builder()
+      .method("POST")
+      .uri(format!("/posts/{}/share", id))
+      .body(body.to_string().into())
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn rejects_bad_lifetimes() {
+    let state = tests::state();
+    let id = tests::insert_post(&state, "open", Visibility::Public).await;
+    let res = tests::send(&state, share(&id, "expires_in=abc")).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = tests::send(&state, share(&id, "expires_in=18446744073709551615")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let url = String::from_utf8(body.to_vec()).unwrap();
+    let (_, expires_at) = parse_token(&state, url.rsplit('/').next().unwrap()).unwrap();
+    assert!(expires_at > now() && expires_at <= now() + MAX_TTL);
+  }
+}
@@ -0,0 +1,77 @@
+use std::{
+  env,
+  sync::atomic::{AtomicUsize, Ordering},
+};
+
+use hyper::{header, Body, Response, StatusCode};
+
+use crate::Tenant;
+
+// 処理中のリクエスト数の上限の初期値
+const DEFAULT_MAX_IN_FLIGHT: usize = 256;
+// 書き込み待ちの数の上限の初期値
+const DEFAULT_MAX_QUEUE: usize = 128;
+// 混雑時に後回しにしても困らない重い処理
+const LOW_PRIORITY: &[&str] = &[
+  "search", "suggest", "stats", "popular", "archive", "calendar",
+];
+
+// 負荷が高いときに重い処理を早めに断り，閲覧とヘルスチェックの余裕を残すための仕組み
+pub struct Load {
+  in_flight: AtomicUsize,
+  max_in_flight: usize,
+  max_queue: usize,
+}
+
+// 処理中のリクエストを数え，終わったら（途中で失敗しても）数から外す
+pub struct Guard<'a>(&'a Load);
+
+impl Drop for Guard<'_> {
+  fn drop(&mut self) {
+    self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+fn var(name: &str, default: usize) -> usize {
+  env::var(name)
+    .map(|v| {
+      v.parse()
+        .unwrap_or_else(|_| panic!("{} must be a number", name))
+    })
+    .unwrap_or(default)
+}
+
+impl Load {
+  // SHED_MAX_IN_FLIGHTとSHED_MAX_QUEUEで上限を指定する
+  pub fn from_env() -> Load {
+    Load {
+      in_flight: AtomicUsize::new(0),
+      max_in_flight: var("SHED_MAX_IN_FLIGHT", DEFAULT_MAX_IN_FLIGHT),
+      max_queue: var("SHED_MAX_QUEUE", DEFAULT_MAX_QUEUE),
+    }
+  }
+
+  pub fn enter(&self) -> Guard<'_> {
+    self.in_flight.fetch_add(1, Ordering::Relaxed);
+    Guard(self)
+  }
+
+  // 後回しにできる処理を断るべきときは503のレスポンスを返す関数
+  pub fn shed(&self, tenant: &Tenant, segments: &[&str]) -> Option<Response<Body>> {
+    if !segments.first().is_some_and(|s| LOW_PRIORITY.contains(s)) {
+      return None;
+    }
+    let overloaded = self.in_flight.load(Ordering::Relaxed) > self.max_in_flight
+      || tenant.writer.pending() > self.max_queue;
+    if !overloaded {
+      return None;
+    }
+    Some(
+      Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(header::RETRY_AFTER, 1)
+        .body(Body::empty())
+        .unwrap(),
+    )
+  }
+}
@@ -0,0 +1,151 @@
+use std::sync::Arc;
+
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use rand::Rng;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{empty, json, now, State, Tenant};
+
+// 短縮コードに使う文字
+const ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+// 短縮コードの長さ（62の7乗で約3.5兆通り）
+const CODE_LENGTH: usize = 7;
+
+#[derive(Serialize)]
+struct ShortLink {
+  code: String,
+  url: String,
+  clicks: u64,
+}
+
+fn generate() -> String {
+  let mut rng = rand::thread_rng();
+  (0..CODE_LENGTH)
+    .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+    .collect()
+}
+
+// 投稿の短縮コードとクリック数を返す関数
+fn find(conn: &Connection, post_id: &Uuid) -> Option<(String, u64)> {
+  conn
+    .query_row(
+      "SELECT code, clicks FROM short_links WHERE post_id=?1",
+      params![post_id],
+      |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+    .unwrap()
+}
+
+fn exists(conn: &Connection, post_id: &Uuid) -> bool {
+  conn
+    .query_row(
+      "SELECT 1 FROM posts WHERE id=?1 AND trashed_at IS NULL",
+      params![post_id],
+      |_| Ok(()),
+    )
+    .optional()
+    .unwrap()
+    .is_some()
+}
+
+fn link(
+  req: &Request<Body>,
+  state: &State,
+  tenant: &Tenant,
+  code: String,
+  clicks: u64,
+) -> Response<Body> {
+  let url = format!("{}{}/r/{}", state.base_url.origin(req), tenant.prefix, code);
+  json(&ShortLink { code, url, clicks })
+}
+
+// 投稿の短縮リンクを発行する関数
+// 投稿ごとに1つだけで，発行済みであれば同じリンクを返す
+pub async fn create(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  post_id: &str,
+) -> Result<Response<Body>, Error> {
+  let post_id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let conn = tenant.conn.lock().await;
+  if !exists(&conn, &post_id) {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  if let Some((code, clicks)) = find(&conn, &post_id) {
+    return Ok(link(&req, &state, &tenant, code, clicks));
+  }
+  // まれにコードが重複した場合は作り直す
+  let code = loop {
+    let code = generate();
+    let inserted = conn
+      .execute(
+        "INSERT OR IGNORE INTO short_links(code, post_id, created_at) VALUES (?1,?2,?3)",
+        params![code, post_id, now()],
+      )
+      .unwrap();
+    if inserted == 1 {
+      break code;
+    }
+  };
+  Ok(link(&req, &state, &tenant, code, 0))
+}
+
+// 投稿の短縮リンクとクリック数を返す関数
+pub async fn show(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  post_id: &str,
+) -> Result<Response<Body>, Error> {
+  let post_id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let found = find(&*tenant.conn.lock().await, &post_id);
+  match found {
+    Some((code, clicks)) => Ok(link(&req, &state, &tenant, code, clicks)),
+    None => Ok(empty(StatusCode::NOT_FOUND)),
+  }
+}
+
+// 短縮リンクを投稿のURLに転送する関数
+// 投稿の公開範囲は転送先で確かめるので，ここではクリック数を数えるだけにする
+pub async fn redirect(tenant: Arc<Tenant>, code: &str) -> Result<Response<Body>, Error> {
+  let conn = tenant.conn.lock().await;
+  let post_id: Option<Uuid> = conn
+    .query_row(
+      "SELECT post_id FROM short_links JOIN posts ON posts.id = short_links.post_id
+      WHERE code=?1 AND trashed_at IS NULL",
+      params![code],
+      |row| row.get(0),
+    )
+    .optional()
+    .unwrap();
+  let post_id = match post_id {
+    Some(post_id) => post_id,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  conn
+    .execute(
+      "UPDATE short_links SET clicks = clicks + 1, last_clicked_at=?1 WHERE code=?2",
+      params![now(), code],
+    )
+    .unwrap();
+  Ok(
+    Response::builder()
+      .status(StatusCode::FOUND)
+      .header(
+        header::LOCATION,
+        format!("{}/posts/{}", tenant.prefix, post_id),
+      )
+      .body(Body::empty())
+      .unwrap(),
+  )
+}
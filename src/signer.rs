@@ -0,0 +1,50 @@
+use std::env;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::hex;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// 文字列に署名を付けて改ざんを検出できるようにする構造体
+pub struct Signer {
+  key: Vec<u8>,
+}
+
+impl Signer {
+  // SECRET_KEYが設定されていなければ起動ごとにランダムな鍵を使う
+  // その場合は再起動すると発行済みの署名はすべて無効になる
+  pub fn from_env() -> Signer {
+    let key = match env::var("SECRET_KEY") {
+      Ok(key) => key.into_bytes(),
+      Err(_) => rand::random::<[u8; 32]>().to_vec(),
+    };
+    Signer { key }
+  }
+
+  fn mac(&self, payload: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(&self.key).unwrap();
+    mac.update(payload.as_bytes());
+    mac
+  }
+
+  // payload.署名 の形式の文字列を返す関数
+  pub fn sign(&self, payload: &str) -> String {
+    let tag = self.mac(payload).finalize().into_bytes();
+    format!("{}.{}", payload, hex::encode(&tag))
+  }
+
+  // 鍵付きのハッシュを16進数で返す関数
+  pub fn digest(&self, payload: &str) -> String {
+    hex::encode(&self.mac(payload).finalize().into_bytes())
+  }
+
+  // 署名が正しければ元のpayloadを返す関数
+  pub fn verify<'a>(&self, signed: &'a str) -> Option<&'a str> {
+    let (payload, tag) = signed.rsplit_once('.')?;
+    // 比較は定数時間で行う
+    self.mac(payload).verify_slice(&hex::decode(tag)?).ok()?;
+    Some(payload)
+  }
+}
@@ -0,0 +1,375 @@
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  env, fs,
+  path::{Path, PathBuf},
+  process,
+  sync::Arc,
+};
+
+use chrono::{Datelike, TimeZone, Utc};
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use serde_json::json;
+use tera::Context;
+use uuid::Uuid;
+
+use crate::{
+  attachment, attachment_policy::essence, audio, enrich, feed, image, json, notebook, preview,
+  templates, voice, State, Tenant, Visibility,
+};
+
+// 書き出したディレクトリに置く目印
+// 目印のないディレクトリは別の用途のものかもしれないので置き換えない
+const MARKER: &str = ".web-memory-site";
+
+// 公開している投稿を静的なHTMLに書き出す設定
+pub struct Site {
+  dir: PathBuf,
+  // 書き出したサイトを配信するURL（フィードとサイトマップの絶対URLに使う）
+  url: Option<String>,
+}
+
+impl Site {
+  // SITE_DIRで書き出すディレクトリを指定する（既定はdist）
+  // テナントごとに<SITE_DIR>/<テナント名>に書き出す
+  // SITE_URLを設定しない場合はBASE_URLか，リクエストのスキームとホストで配信するものとする
+  pub fn from_env() -> Site {
+    Site {
+      dir: PathBuf::from(env::var("SITE_DIR").unwrap_or_else(|_| "dist".to_string())),
+      url: env::var("SITE_URL")
+        .ok()
+        .map(|url| url.trim_end_matches('/').to_string()),
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct Page {
+  id: Uuid,
+  title: String,
+  content: String,
+  html: String,
+  created_at: Option<i64>,
+  breadcrumbs: Vec<String>,
+  // 音声メモの投稿の音声（書き出したサイトの中に置き直す）
+  voice: Option<voice::Voice>,
+  // 添付した画像（音声と同じく書き出したサイトの中に置き直す）
+  images: Vec<image::Image>,
+  // 場所と日付から補った天気などの情報
+  metadata: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct Summary {
+  dir: String,
+  posts: usize,
+  pages: usize,
+}
+
+// 公開している投稿を新しい順に返す関数
+fn pages(state: &State, conn: &Connection) -> Vec<Page> {
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, title, content, encrypted, compressed, created_at FROM posts
+      WHERE kind = 'text' AND visibility=?1 AND trashed_at IS NULL
+      ORDER BY created_at IS NULL, created_at DESC, rowid DESC",
+    )
+    .unwrap();
+  let mut pages = stmt
+    .query_map(params![Visibility::Public], |row| {
+      let content = state.codec.decode(row.get(2)?, row.get(3)?, row.get(4)?);
+      Ok(Page {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        html: preview::render(&content),
+        content,
+        created_at: row.get(5)?,
+        breadcrumbs: Vec::new(),
+        voice: None,
+        images: Vec::new(),
+        metadata: BTreeMap::new(),
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  for page in &mut pages {
+    page.breadcrumbs = notebook::breadcrumbs(conn, &page.id);
+    page.voice = voice::for_post(conn, "", &page.id);
+    page.images = image::for_post(conn, "", &page.id);
+    page.metadata = enrich::for_post(conn, &page.id);
+  }
+  pages
+}
+
+fn write(dir: &Path, path: &str, bytes: &[u8]) {
+  let file = dir.join(path);
+  fs::create_dir_all(file.parent().unwrap()).unwrap();
+  fs::write(file, bytes).unwrap();
+}
+
+// 年または月ごとの一覧のページ
+// サーバのアーカイブと同じテンプレートを使い，前後の期間へのリンクは投稿のある期間だけにする
+fn archive(
+  state: &State,
+  period: String,
+  prev: Option<&String>,
+  next: Option<&String>,
+  pages: &[&Page],
+) -> String {
+  let link =
+    |period: Option<&String>| period.map_or_else(String::new, |period| archive_path(period));
+  let posts = pages
+    .iter()
+    .rev()
+    .map(|page| json!({"id": page.id, "title": page.title, "created_at": page.created_at}))
+    .collect::<Vec<_>>();
+  let mut ctx = Context::new();
+  ctx.insert("prefix", "");
+  ctx.insert(
+    "archive",
+    &json!({"period": period, "prev": link(prev), "next": link(next), "posts": posts}),
+  );
+  templates::render_default(state, "archive", &mut ctx)
+}
+
+// 2024を/archive/2024/に，2024-05を/archive/2024/05/にする関数
+fn archive_path(period: &str) -> String {
+  format!("/archive/{}/", period.replace('-', "/"))
+}
+
+// Atomのフィード（新しい投稿から順に載せる）
+fn atom(state: &State, origin: &str, pages: &[Page]) -> String {
+  let entries = pages
+    .iter()
+    .take(feed::FEED_ENTRIES)
+    .map(|page| feed::Entry {
+      id: page.id,
+      title: &page.title,
+      html: &page.html,
+      created_at: page.created_at,
+      url: format!("{}/posts/{}/", origin, page.id),
+    })
+    .collect::<Vec<_>>();
+  let title = state
+    .i18n
+    .translate(state.i18n.default(), "site-title", None);
+  feed::atom(
+    &title,
+    &format!("{}/", origin),
+    &format!("{}/feed.xml", origin),
+    &entries,
+  )
+}
+
+// 書き出したディレクトリを置き換える関数
+// 途中で失敗しても前回の内容が残るように，別の場所に書き出してから入れ替える
+fn replace(dir: &Path, built: &Path) -> Result<(), String> {
+  if dir.exists() {
+    if !dir.join(MARKER).exists() {
+      fs::remove_dir_all(built).unwrap();
+      return Err(format!(
+        "{} exists and was not written by export-site",
+        dir.display()
+      ));
+    }
+    let old = dir.with_file_name(format!(
+      ".{}.old",
+      dir.file_name().unwrap().to_string_lossy()
+    ));
+    if old.exists() {
+      fs::remove_dir_all(&old).unwrap();
+    }
+    fs::rename(dir, &old).unwrap();
+    fs::rename(built, dir).unwrap();
+    fs::remove_dir_all(&old).unwrap();
+  } else {
+    fs::rename(built, dir).unwrap();
+  }
+  Ok(())
+}
+
+// テナントの公開している投稿，一覧，アーカイブ，フィード，サイトマップを静的なファイルに書き出す関数
+// どのような静的ホスティングでも配信できるように，各ページは<パス>/index.htmlに置き，サイトのルートに置く前提でリンクする
+async fn build(state: &State, tenant: &Tenant, origin: &str) -> Result<Summary, String> {
+  let mut pages = {
+    let conn = tenant.conn.lock().await;
+    pages(state, &conn)
+  };
+  let dir = state.site.dir.join(&tenant.name);
+  fs::create_dir_all(dir.parent().unwrap()).unwrap();
+  let built = dir.with_file_name(format!(
+    ".{}.partial",
+    dir.file_name().unwrap().to_string_lossy()
+  ));
+  if built.exists() {
+    fs::remove_dir_all(&built).unwrap();
+  }
+  fs::create_dir_all(&built).unwrap();
+  write(&built, MARKER, b"");
+  // サイトマップに載せるパスと投稿の作成日時
+  let mut paths = vec![("/".to_string(), None)];
+
+  for page in &mut pages {
+    // 音声メモの音声は投稿のページと同じディレクトリに置く
+    if let Some(voice) = &mut page.voice {
+      let file = format!(
+        "posts/{}/voice.{}",
+        page.id,
+        audio::extension(&essence(&voice.content_type))
+      );
+      match attachment::content(state, &voice.key).await {
+        Some(data) => {
+          write(&built, &file, &data);
+          voice.url = format!("/{}", file);
+        }
+        None => page.voice = None,
+      }
+    }
+    let mut images = Vec::new();
+    for mut image in page.images.drain(..) {
+      let file = format!(
+        "posts/{}/{}.{}",
+        page.id,
+        image.id,
+        image::extension(&essence(&image.content_type))
+      );
+      if let Some(data) = attachment::content(state, &image.key).await {
+        write(&built, &file, &data);
+        image.url = format!("/{}", file);
+        images.push(image);
+      }
+    }
+    page.images = images;
+    let mut ctx = Context::new();
+    ctx.insert("post", page);
+    ctx.insert("voice", &page.voice);
+    ctx.insert("images", &page.images);
+    ctx.insert("metadata", &page.metadata);
+    let html = templates::render_default(state, "site_post", &mut ctx);
+    write(
+      &built,
+      &format!("posts/{}/index.html", page.id),
+      html.as_bytes(),
+    );
+    paths.push((format!("/posts/{}/", page.id), page.created_at));
+  }
+
+  // 作成日時はサーバのアーカイブと同じくUTCで期間に分ける
+  let period = |page: &Page, month: bool| {
+    page.created_at.map(|created_at| {
+      let date = Utc.timestamp(created_at, 0);
+      if month {
+        format!("{:04}-{:02}", date.year(), date.month())
+      } else {
+        format!("{:04}", date.year())
+      }
+    })
+  };
+  let mut years = BTreeSet::new();
+  for month in [false, true] {
+    let periods = pages
+      .iter()
+      .filter_map(|page| period(page, month))
+      .collect::<BTreeSet<_>>()
+      .into_iter()
+      .collect::<Vec<_>>();
+    for (i, current) in periods.iter().enumerate() {
+      let in_period = pages
+        .iter()
+        .filter(|page| period(page, month).as_ref() == Some(current))
+        .collect::<Vec<_>>();
+      let html = archive(
+        state,
+        current.clone(),
+        i.checked_sub(1).map(|prev| &periods[prev]),
+        periods.get(i + 1),
+        &in_period,
+      );
+      let path = archive_path(current);
+      write(
+        &built,
+        &format!("{}index.html", &path[1..]),
+        html.as_bytes(),
+      );
+      paths.push((path, None));
+      if !month {
+        years.insert(current.clone());
+      }
+    }
+  }
+
+  let mut ctx = Context::new();
+  ctx.insert("posts", &pages);
+  ctx.insert("years", &years.iter().rev().collect::<Vec<_>>());
+  let html = templates::render_default(state, "site_index", &mut ctx);
+  write(&built, "index.html", html.as_bytes());
+  write(&built, "feed.xml", atom(state, origin, &pages).as_bytes());
+  let urls = paths
+    .iter()
+    .map(|(path, created_at)| (format!("{}{}", origin, path), *created_at))
+    .collect::<Vec<_>>();
+  write(&built, "sitemap.xml", feed::sitemap(&urls).as_bytes());
+  for (name, bytes) in state.assets.files() {
+    write(&built, &format!("static/{}", name), bytes);
+  }
+
+  replace(&dir, &built)?;
+  Ok(Summary {
+    dir: dir.display().to_string(),
+    posts: pages.len(),
+    pages: paths.len(),
+  })
+}
+
+// POST /admin/export-site でリクエストを受けたテナントを書き出す関数
+pub async fn export(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+) -> Result<Response<Body>, Error> {
+  let origin = state
+    .site
+    .url
+    .clone()
+    .unwrap_or_else(|| state.base_url.origin(&req));
+  match build(&state, &tenant, &origin).await {
+    Ok(summary) => Ok(json(&summary)),
+    Err(message) => Ok(
+      Response::builder()
+        .status(StatusCode::CONFLICT)
+        .body(message.into())
+        .unwrap(),
+    ),
+  }
+}
+
+// web-memory export-site <テナント名> でサーバを起動せずに書き出す関数
+// テナントを使わない場合のDBはメモリ上にしかないので，TENANT_MODEを設定して使う
+pub async fn command(state: &State, name: Option<&str>) {
+  let origin = match state.site.url.as_deref().or(state.base_url.configured()) {
+    Some(origin) => origin,
+    None => {
+      eprintln!("export-site requires SITE_URL or BASE_URL");
+      process::exit(1);
+    }
+  };
+  let tenant = match name.and_then(|name| state.tenants.by_name(name)) {
+    Some(tenant) => tenant,
+    None => {
+      eprintln!("usage: web-memory export-site <tenant> (with TENANT_MODE and an existing tenant)");
+      process::exit(1);
+    }
+  };
+  match build(state, &tenant, origin).await {
+    Ok(summary) => println!(
+      "wrote {} posts ({} pages) to {}",
+      summary.posts, summary.pages, summary.dir
+    ),
+    Err(message) => {
+      eprintln!("{}", message);
+      process::exit(1);
+    }
+  }
+}
@@ -0,0 +1,103 @@
+use std::{future::Future, net::IpAddr, pin::Pin};
+
+use rusqlite::{params, Connection};
+use uuid::Uuid;
+
+use crate::{now, NewPost};
+
+#[cfg(feature = "akismet")]
+mod akismet;
+
+// フォームの表示から送信までにかかる最短時間（秒）
+// これより早い送信は人間によるものではないとみなす
+const MIN_FILL_SECONDS: u64 = 3;
+// 本文とタイトルに含まれてよいリンクの最大数
+const MAX_LINKS: usize = 5;
+
+// トレイトオブジェクトとして扱えるように非同期処理の結果をBoxに包む
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// 判定にかける投稿とリクエストの情報
+// リクエストの情報は外部サービスに問い合わせる場合のみ使う
+#[cfg_attr(not(feature = "akismet"), allow(dead_code))]
+pub struct Submission<'a> {
+  pub post: &'a NewPost,
+  pub ip: IpAddr,
+  pub user_agent: &'a str,
+  pub referrer: &'a str,
+}
+
+// 判定結果
+pub struct Verdict {
+  pub spam: bool,
+  // 判定した実装の名前
+  pub checker: &'static str,
+  pub reason: String,
+}
+
+// スパム判定の共通インターフェース
+// 外部サービスへの問い合わせを想定して非同期にしている
+pub trait SpamCheck: Send + Sync {
+  fn check<'a>(&'a self, submission: &'a Submission<'a>) -> BoxFuture<'a, Verdict>;
+}
+
+// 外部サービスを使わないローカルの判定
+pub struct Heuristic;
+
+impl Heuristic {
+  // 明らかにボットによる投稿であればその理由を返す関数
+  fn reason(post: &NewPost) -> Option<&'static str> {
+    // 人間には見えないハニーポット欄に入力があればボット
+    if !post.website.is_empty() {
+      return Some("honeypot");
+    }
+    // フォームから送信された場合のみ入力時間をチェックする
+    if let Some(rendered_at) = post.rendered_at {
+      if now().saturating_sub(rendered_at) < MIN_FILL_SECONDS {
+        return Some("filled too fast");
+      }
+    }
+    if count_links(&post.title) + count_links(&post.content) > MAX_LINKS {
+      return Some("too many links");
+    }
+    None
+  }
+}
+
+impl SpamCheck for Heuristic {
+  fn check<'a>(&'a self, submission: &'a Submission<'a>) -> BoxFuture<'a, Verdict> {
+    let reason = Heuristic::reason(submission.post);
+    Box::pin(async move {
+      Verdict {
+        spam: reason.is_some(),
+        checker: "heuristic",
+        reason: reason.unwrap_or_default().to_string(),
+      }
+    })
+  }
+}
+
+// 環境変数から判定の実装を選ぶ関数
+// Akismetが設定されていなければローカルの判定を使う
+pub fn from_env() -> Box<dyn SpamCheck> {
+  #[cfg(feature = "akismet")]
+  if let (Ok(key), Ok(blog)) = (std::env::var("AKISMET_KEY"), std::env::var("AKISMET_BLOG")) {
+    return Box::new(akismet::Akismet::new(key, blog));
+  }
+  Box::new(Heuristic)
+}
+
+// 後から監査できるように判定結果を保存する関数
+pub fn record(conn: &Connection, post_id: &Uuid, verdict: &Verdict) {
+  conn
+    .execute(
+      "INSERT INTO spam_verdicts(post_id, checker, spam, reason) VALUES (?1,?2,?3,?4)",
+      params![post_id, verdict.checker, verdict.spam, verdict.reason],
+    )
+    .unwrap();
+}
+
+// 文字列に含まれるリンクの数を数える関数
+fn count_links(text: &str) -> usize {
+  text.matches("http://").count() + text.matches("https://").count()
+}
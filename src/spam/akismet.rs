@@ -0,0 +1,78 @@
+use hyper::{Body, Method, Request};
+
+use super::{BoxFuture, Heuristic, SpamCheck, Submission, Verdict};
+use crate::https::{self, HttpsClient};
+
+static ENDPOINT: &str = "https://rest.akismet.com/1.1/comment-check";
+
+// Akismetに問い合わせる判定
+// 問い合わせに失敗した場合はローカルの判定に切り替える
+pub struct Akismet {
+  key: String,
+  blog: String,
+  client: HttpsClient,
+}
+
+impl Akismet {
+  pub fn new(key: String, blog: String) -> Self {
+    Akismet {
+      key,
+      blog,
+      client: https::client(),
+    }
+  }
+
+  // Akismetがスパムと判定したかどうかを返す関数
+  async fn ask(&self, submission: &Submission<'_>) -> Result<bool, String> {
+    let ip = submission.ip.to_string();
+    let form = serde_urlencoded::to_string([
+      ("api_key", self.key.as_str()),
+      ("blog", self.blog.as_str()),
+      ("user_ip", ip.as_str()),
+      ("user_agent", submission.user_agent),
+      ("referrer", submission.referrer),
+      ("comment_type", "blog-post"),
+      ("comment_content", submission.post.content.as_str()),
+    ])
+    .map_err(|e| e.to_string())?;
+    let req = Request::builder()
+      .method(Method::POST)
+      .uri(ENDPOINT)
+      .header("content-type", "application/x-www-form-urlencoded")
+      .body(Body::from(form))
+      .unwrap();
+    let res = self.client.request(req).await.map_err(|e| e.to_string())?;
+    let body = hyper::body::to_bytes(res.into_body())
+      .await
+      .map_err(|e| e.to_string())?;
+    // 本文はtrueかfalseのみで，それ以外はエラーの説明になっている
+    match &body[..] {
+      b"true" => Ok(true),
+      b"false" => Ok(false),
+      other => Err(String::from_utf8_lossy(other).into_owned()),
+    }
+  }
+}
+
+impl SpamCheck for Akismet {
+  fn check<'a>(&'a self, submission: &'a Submission<'a>) -> BoxFuture<'a, Verdict> {
+    Box::pin(async move {
+      // ハニーポットなどで明らかなものは問い合わせるまでもない
+      let local = Heuristic.check(submission).await;
+      if local.spam {
+        return local;
+      }
+      match self.ask(submission).await {
+        Ok(spam) => Verdict {
+          spam,
+          checker: "akismet",
+          reason: String::new(),
+        },
+        Err(e) => {
+          eprintln!("akismet error {}", e);
+          local
+        }
+      }
+    })
+  }
+}
@@ -0,0 +1,350 @@
+use std::sync::{Arc, Mutex};
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use tera::Context;
+use uuid::Uuid;
+
+use crate::{content::Codec, empty, json, now, templates, wants_html, State, Tenant, Visibility};
+
+// 集計結果を使い回す秒数
+const CACHE_SECONDS: u64 = 60;
+
+#[derive(Serialize)]
+struct Month {
+  // YYYY-MM
+  month: String,
+  posts: i64,
+}
+
+// 一覧に含めるタグと投稿の数
+const TOP: u32 = 10;
+
+#[derive(Serialize)]
+struct TagCount {
+  tag: String,
+  posts: i64,
+}
+
+#[derive(Serialize)]
+struct Viewed {
+  id: Uuid,
+  title: String,
+  views: i64,
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+  total_posts: i64,
+  // 作成日時のない古い投稿は含まない
+  per_month: Vec<Month>,
+  // 本文の平均文字数（E2EEの投稿は読めないので含まない）
+  average_length: f64,
+  // よく使われているタグ（非公開の投稿に付いたものは数えない）
+  top_tags: Vec<TagCount>,
+  // 閲覧数の多い公開範囲が非公開でない投稿（書き出す前の閲覧数は含まない）
+  top_viewed: Vec<Viewed>,
+}
+
+// 集計結果のキャッシュ
+// 表示のたびに全件を数えないように，短い間だけ同じ結果を返す
+pub struct Cache {
+  cached: Mutex<Option<(u64, Arc<Stats>)>>,
+}
+
+impl Cache {
+  pub fn new() -> Cache {
+    Cache {
+      cached: Mutex::new(None),
+    }
+  }
+
+  fn get(&self, conn: &Connection, codec: &Codec) -> Arc<Stats> {
+    let mut cached = self.cached.lock().unwrap();
+    match &*cached {
+      Some((at, stats)) if now() < at + CACHE_SECONDS => stats.clone(),
+      _ => {
+        let stats = Arc::new(compute(conn, codec));
+        *cached = Some((now(), stats.clone()));
+        stats
+      }
+    }
+  }
+}
+
+// ゴミ箱に入れた投稿を除いて集計する関数
+fn compute(conn: &Connection, codec: &Codec) -> Stats {
+  let total_posts = conn
+    .query_row(
+      "SELECT COUNT(*) FROM posts WHERE trashed_at IS NULL",
+      [],
+      |row| row.get(0),
+    )
+    .unwrap();
+  let mut stmt = conn
+    .prepare(
+      "SELECT strftime('%Y-%m', created_at, 'unixepoch') AS month, COUNT(*) FROM posts
+      WHERE trashed_at IS NULL AND created_at IS NOT NULL
+      GROUP BY month ORDER BY month",
+    )
+    .unwrap();
+  let per_month = stmt
+    .query_map([], |row| {
+      Ok(Month {
+        month: row.get(0)?,
+        posts: row.get(1)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  // 本文は暗号化されている場合があるので長さは復号してから数える
+  let mut stmt = conn
+    .prepare(
+      "SELECT content, encrypted, compressed FROM posts WHERE kind = 'text' AND trashed_at IS NULL",
+    )
+    .unwrap();
+  let lengths = stmt
+    .query_map([], |row| {
+      Ok(
+        codec
+          .decode(row.get(0)?, row.get(1)?, row.get(2)?)
+          .chars()
+          .count(),
+      )
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  let average_length = if lengths.is_empty() {
+    0.0
+  } else {
+    lengths.iter().sum::<usize>() as f64 / lengths.len() as f64
+  };
+  let mut stmt = conn
+    .prepare(
+      "SELECT tag, COUNT(*) AS posts FROM post_tags JOIN posts ON posts.id = post_tags.post_id
+      WHERE visibility != ?1 AND trashed_at IS NULL
+      GROUP BY tag ORDER BY posts DESC, tag LIMIT ?2",
+    )
+    .unwrap();
+  let top_tags = stmt
+    .query_map(params![Visibility::Private, TOP], |row| {
+      Ok(TagCount {
+        tag: row.get(0)?,
+        posts: row.get(1)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, title, views FROM post_views JOIN posts ON posts.id = post_views.post_id
+      WHERE visibility != ?1 AND trashed_at IS NULL
+      ORDER BY views DESC, posts.rowid DESC LIMIT ?2",
+    )
+    .unwrap();
+  let top_viewed = stmt
+    .query_map(params![Visibility::Private, TOP], |row| {
+      Ok(Viewed {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        views: row.get(2)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  Stats {
+    total_posts,
+    per_month,
+    average_length,
+    top_tags,
+    top_viewed,
+  }
+}
+
+// 投稿の統計を返す関数
+pub async fn show(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+) -> Result<Response<Body>, Error> {
+  let stats = tenant.stats.get(&*tenant.conn.lock().await, &state.codec);
+  if wants_html(&req) {
+    let mut ctx = Context::new();
+    ctx.insert("stats", &*stats);
+    ctx.insert("prefix", &tenant.prefix);
+    return Ok(Response::new(
+      templates::render(&state, &req, "stats", &mut ctx).into(),
+    ));
+  }
+  Ok(json(&*stats))
+}
+
+#[derive(Deserialize)]
+struct HeatmapQuery {
+  // 省略時は今年
+  year: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct Day {
+  // YYYY-MM-DD
+  date: String,
+  posts: i64,
+}
+
+#[derive(Serialize)]
+struct Heatmap {
+  year: u32,
+  // 投稿のあった日のみを含む
+  days: Vec<Day>,
+}
+
+// 1年間の日ごとの投稿数を返す関数
+pub async fn heatmap(req: Request<Body>, tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let query =
+    match serde_urlencoded::from_str::<HeatmapQuery>(req.uri().query().unwrap_or_default()) {
+      Ok(query) => query,
+      Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+    };
+  let conn = tenant.conn.lock().await;
+  let year = match query.year {
+    Some(year) if (1970..=9999).contains(&year) => year,
+    Some(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+    None => conn
+      .query_row("SELECT CAST(strftime('%Y', 'now') AS INTEGER)", [], |row| {
+        row.get(0)
+      })
+      .unwrap(),
+  };
+  let mut stmt = conn
+    .prepare(
+      "SELECT date(created_at, 'unixepoch') AS day, COUNT(*) FROM posts
+      WHERE trashed_at IS NULL AND strftime('%Y', created_at, 'unixepoch') = ?1
+      GROUP BY day ORDER BY day",
+    )
+    .unwrap();
+  let days = stmt
+    .query_map(params![format!("{:04}", year)], |row| {
+      Ok(Day {
+        date: row.get(0)?,
+        posts: row.get(1)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  Ok(json(&Heatmap { year, days }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{db, tests};
+
+  #[test]
+  fn counts_tags_and_views_of_visible_posts() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    db::migrate(&mut conn);
+    for (id, title, visibility, tags, views) in [
+      (
+        Uuid::new_v4(),
+        "a",
+        Visibility::Public,
+        &["rust", "web"][..],
+        3,
+      ),
+      (Uuid::new_v4(), "b", Visibility::Unlisted, &["rust"][..], 7),
+      (
+        Uuid::new_v4(),
+        "c",
+        Visibility::Private,
+        &["secret", "rust"][..],
+        9,
+      ),
+    ] {
+      conn
+        .execute(
+          "INSERT INTO posts(id, title, content, visibility) VALUES (?1,?2,'',?3)",
+          params![id, title, visibility],
+        )
+        .unwrap();
+      for tag in tags {
+        conn
+          .execute(
+            "INSERT INTO post_tags(post_id, tag) VALUES (?1,?2)",
+            params![id, tag],
+          )
+          .unwrap();
+      }
+      conn
+        .execute(
+          "INSERT INTO post_views(post_id, views) VALUES (?1,?2)",
+          params![id, views],
+        )
+        .unwrap();
+    }
+    let stats = compute(&conn, &Codec::from_env());
+    let tags = stats
+      .top_tags
+      .iter()
+      .map(|tag| (tag.tag.as_str(), tag.posts))
+      .collect::<Vec<_>>();
+    assert_eq!(tags, [("rust", 2), ("web", 1)]);
+    let viewed = stats
+      .top_viewed
+      .iter()
+      .map(|post| (post.title.as_str(), post.views))
+      .collect::<Vec<_>>();
+    assert_eq!(viewed, [("b", 7), ("a", 3)]);
+  }
+
+  #[tokio::test]
+  async fn rejects_bad_heatmap_year() {
+    let state = tests::state();
+    for query in ["year=abc", "year=-1", "year=1969"] {
+      let req = Request::get(format!("/stats/heatmap?{}", query))
+        .body(Body::empty())
+        .unwrap();
+      let res = tests::send(&state, req).await;
+      assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+  }
+
+  #[tokio::test]
+  async fn renders_tags_and_views() {
+    let state = tests::state();
+    let id = tests::insert_post(&state, "<viewed>", Visibility::Public).await;
+    tests::tenant(&state)
+      .conn
+      .lock()
+      .await
+      .execute_batch(&format!(
+        "INSERT INTO post_tags(post_id, tag) VALUES (X'{0}', 'a&b');
+        INSERT INTO post_views(post_id, views) VALUES (X'{0}', 2);",
+        id.to_simple()
+      ))
+      .unwrap();
+    let req = Request::get("/stats")
+      .header(hyper::header::ACCEPT, "text/html")
+      .body(Body::empty())
+      .unwrap();
+    let res = tests::send(&state, req).await;
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let html = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+      html.contains("/search?q=tag:a%26b\">a&amp;b</a>"),
+      "{}",
+      html
+    );
+    assert!(
+      html.contains(&format!("/posts/{}\">&lt;viewed&gt;</a>", id)),
+      "{}",
+      html
+    );
+  }
+}
@@ -0,0 +1,192 @@
+use std::sync::{Arc, Mutex};
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{empty, json, Tenant, Visibility};
+
+// 返す候補の数
+const MAX_SUGGESTIONS: usize = 10;
+// そのうちタグの候補の数の上限（タイトルの候補を押し出さないように）
+const MAX_TAG_SUGGESTIONS: usize = 3;
+
+#[derive(Deserialize)]
+struct Query {
+  #[serde(default)]
+  q: String,
+}
+
+// 候補はタグ，タイトルの順に並べる
+#[derive(Serialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Suggestion {
+  Post { id: Uuid, title: String },
+  // postsは公開している投稿のうちタグの付いたものの数
+  Tag { tag: String, posts: i64 },
+}
+
+struct Entry {
+  // 照合用に小文字にしたタイトルかタグ
+  key: String,
+  suggestion: Suggestion,
+}
+
+// 照合する対象
+struct Entries {
+  titles: Vec<Entry>,
+  // 付いている投稿の多い順
+  tags: Vec<Entry>,
+}
+
+// 入力補完に使うタイトルとタグの索引
+// 入力のたびにDBを読まないようにメモリに持ち，投稿が変わったら作り直す
+pub struct Index {
+  entries: Mutex<Option<Arc<Entries>>>,
+}
+
+impl Index {
+  pub fn new() -> Index {
+    Index {
+      entries: Mutex::new(None),
+    }
+  }
+
+  // 投稿を変更したときに呼び出し，次の補完で作り直させる
+  pub fn invalidate(&self) {
+    *self.entries.lock().unwrap() = None;
+  }
+
+  fn get(&self, conn: &Connection) -> Arc<Entries> {
+    let mut entries = self.entries.lock().unwrap();
+    entries.get_or_insert_with(|| Arc::new(load(conn))).clone()
+  }
+}
+
+// 補完の対象になる公開の投稿のタイトルと，それに付いたタグを読み込む関数
+fn load(conn: &Connection) -> Entries {
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, title FROM posts
+      WHERE kind = 'text' AND visibility=?1 AND trashed_at IS NULL ORDER BY title",
+    )
+    .unwrap();
+  let titles = stmt
+    .query_map(params![Visibility::Public], |row| {
+      let title: String = row.get(1)?;
+      Ok(Entry {
+        key: title.to_lowercase(),
+        suggestion: Suggestion::Post {
+          id: row.get(0)?,
+          title,
+        },
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  let mut stmt = conn
+    .prepare(
+      "SELECT tag, COUNT(*) FROM post_tags JOIN posts ON posts.id = post_tags.post_id
+      WHERE kind = 'text' AND visibility=?1 AND trashed_at IS NULL
+      GROUP BY tag ORDER BY COUNT(*) DESC, tag",
+    )
+    .unwrap();
+  let tags = stmt
+    .query_map(params![Visibility::Public], |row| {
+      let tag: String = row.get(0)?;
+      Ok(Entry {
+        key: tag.to_lowercase(),
+        suggestion: Suggestion::Tag {
+          tag,
+          posts: row.get(1)?,
+        },
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  Entries { titles, tags }
+}
+
+// 入力途中の語で始まるものを，先頭で一致するもの，途中の単語で一致するものの順に返す関数
+fn matching<'a>(entries: &'a [Entry], q: &str) -> Vec<&'a Entry> {
+  let (mut first, mut rest): (Vec<&Entry>, Vec<&Entry>) = entries
+    .iter()
+    .filter(|entry| entry.key.split_whitespace().any(|word| word.starts_with(q)))
+    .partition(|entry| entry.key.starts_with(q));
+  first.append(&mut rest);
+  first
+}
+
+// 入力途中の語で始まるタグとタイトルを返す関数
+pub async fn suggest(req: Request<Body>, tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let query = match serde_urlencoded::from_str::<Query>(req.uri().query().unwrap_or_default()) {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let q = query.q.trim().to_lowercase();
+  if q.is_empty() {
+    return Ok(json(&Vec::<Suggestion>::new()));
+  }
+  // 投稿の変更と作り直しが重ならないようにDBのロック中に読み込む
+  let entries = tenant.suggest.get(&*tenant.conn.lock().await);
+  let suggestions: Vec<Suggestion> = matching(&entries.tags, &q)
+    .into_iter()
+    .take(MAX_TAG_SUGGESTIONS)
+    .chain(matching(&entries.titles, &q))
+    .take(MAX_SUGGESTIONS)
+    .map(|entry| entry.suggestion.clone())
+    .collect();
+  Ok(json(&suggestions))
+}
+
+#[cfg(test)]
+mod tests {
+  use hyper::body;
+  use serde_json::{json, Value};
+
+  use super::*;
+  use crate::{tests, State};
+
+  async fn suggest(state: &Arc<State>, query: &str) -> (StatusCode, Value) {
+    let req = Request::get(format!("/suggest?{}", query))
+      .body(Body::empty())
+      .unwrap();
+    let res = tests::send(state, req).await;
+    let status = res.status();
+    let body = body::to_bytes(res).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+  }
+
+  #[tokio::test]
+  async fn suggests_tags_and_titles() {
+    let state = tests::state();
+    let tenant = tests::tenant(&state);
+    let public = tests::insert_post(&state, "Rust notes", Visibility::Public).await;
+    let private = tests::insert_post(&state, "Secret", Visibility::Private).await;
+    for (id, tag) in [(public, "rust"), (private, "rustic")] {
+      tenant
+        .conn
+        .lock()
+        .await
+        .execute(
+          "INSERT INTO post_tags(post_id, tag) VALUES (?1,?2)",
+          params![id, tag],
+        )
+        .unwrap();
+    }
+    let (status, body) = suggest(&state, "q=ru").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+      body,
+      json!([
+        {"kind": "tag", "tag": "rust", "posts": 1},
+        {"kind": "post", "id": public.to_string(), "title": "Rust notes"},
+      ])
+    );
+    let (status, _) = suggest(&state, "q=a&q=b").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+  }
+}
@@ -0,0 +1,320 @@
+use std::{
+  collections::HashMap,
+  net::IpAddr,
+  sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use hyper::{header, upgrade::Upgraded, Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, OptionalExtension};
+use tokio::sync::broadcast;
+use tokio_tungstenite::{
+  tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+  WebSocketStream,
+};
+use uuid::Uuid;
+use yrs::{
+  updates::decoder::Decode, Doc, GetString, ReadTxn, StateVector, Text, TextRef, Transact, Update,
+};
+
+use crate::{
+  audit, duplicate, empty, events::Event, header_str, remote_ip, tags, wordcount, State, Tenant,
+  Visibility,
+};
+
+// 編集中の本文を投稿に書き戻す間隔
+const MATERIALIZE_SECONDS: u64 = 10;
+// 他の接続へ送る前にためておける更新の数
+const CHANNEL_CAPACITY: usize = 256;
+// ドキュメントの中で本文を持つ共有型の名前
+const TEXT: &str = "content";
+
+// 同時に編集している投稿ごとの共有ドキュメント
+struct Room {
+  doc: Doc,
+  text: TextRef,
+  // 受け取った更新を他の接続に配る（送った接続の番号と更新）
+  updates: broadcast::Sender<(u64, Arc<Vec<u8>>)>,
+  peers: AtomicUsize,
+  // 最後に投稿へ書き戻してから変更があったかどうか
+  dirty: AtomicBool,
+  closed: AtomicBool,
+  // 監査ログに残す最後に編集した接続元
+  last_ip: Mutex<Option<IpAddr>>,
+}
+
+// テナントの中で編集中の投稿
+pub struct Rooms {
+  rooms: Mutex<HashMap<Uuid, Arc<Room>>>,
+  next_peer: AtomicU64,
+}
+
+impl Rooms {
+  pub fn new() -> Rooms {
+    Rooms {
+      rooms: Mutex::new(HashMap::new()),
+      next_peer: AtomicU64::new(0),
+    }
+  }
+}
+
+impl Room {
+  fn new(content: &str) -> Room {
+    let doc = Doc::new();
+    let text = doc.get_or_insert_text(TEXT);
+    text.insert(&mut doc.transact_mut(), 0, content);
+    Room {
+      doc,
+      text,
+      updates: broadcast::channel(CHANNEL_CAPACITY).0,
+      peers: AtomicUsize::new(0),
+      dirty: AtomicBool::new(false),
+      closed: AtomicBool::new(false),
+      last_ip: Mutex::new(None),
+    }
+  }
+
+  // ドキュメント全体を1つの更新として返す関数
+  fn snapshot(&self) -> Vec<u8> {
+    self
+      .doc
+      .transact()
+      .encode_state_as_update_v1(&StateVector::default())
+  }
+
+  // 受け取った更新を適用する関数（不正な更新なら何もせずにfalseを返す）
+  fn apply(&self, data: &[u8], ip: IpAddr) -> bool {
+    let update = match Update::decode_v1(data) {
+      Ok(update) => update,
+      Err(_) => return false,
+    };
+    if self.doc.transact_mut().apply_update(update).is_err() {
+      return false;
+    }
+    self.dirty.store(true, Ordering::Relaxed);
+    *self.last_ip.lock().unwrap() = Some(ip);
+    true
+  }
+}
+
+// 共有ドキュメントの本文を投稿に書き戻す関数
+async fn materialize(state: &State, tenant: &Tenant, id: &Uuid, room: &Room) {
+  if state.read_only.is_on() || !room.dirty.swap(false, Ordering::Relaxed) {
+    return;
+  }
+  let content = room.text.get_string(&room.doc.transact());
+  let ip = match *room.last_ip.lock().unwrap() {
+    Some(ip) => ip,
+    None => return,
+  };
+  let stored = state.codec.encode(&content);
+  let content_hash = duplicate::hash(&state.signer, &content);
+  let conn = tenant.conn.lock().await;
+  let updated = conn
+    .execute(
+      "UPDATE posts SET content=?1, encrypted=?2, compressed=?3, content_hash=?4
+      WHERE id=?5 AND trashed_at IS NULL",
+      params![
+        stored.content,
+        stored.encrypted,
+        stored.compressed,
+        content_hash,
+        id
+      ],
+    )
+    .unwrap();
+  if updated == 0 {
+    return;
+  }
+  wordcount::record(&conn, id, &content);
+  tags::apply(&conn, id, &content);
+  audit::record(
+    &conn,
+    audit::Entry {
+      actor: audit::ANONYMOUS,
+      action: "sync",
+      post_id: id,
+      summary: format!("{} chars", content.chars().count()),
+      ip,
+    },
+  );
+  drop(conn);
+  tenant.publish(Event::Updated { id: *id });
+}
+
+// 投稿の共有ドキュメントに参加する関数
+// 最初の参加者が来たときに投稿の本文からドキュメントを作り，定期的に書き戻す
+fn join(state: &Arc<State>, tenant: &Arc<Tenant>, id: Uuid, content: &str) -> Arc<Room> {
+  let mut rooms = tenant.sync.rooms.lock().unwrap();
+  if let Some(room) = rooms.get(&id) {
+    room.peers.fetch_add(1, Ordering::Relaxed);
+    return room.clone();
+  }
+  let room = Arc::new(Room::new(content));
+  room.peers.store(1, Ordering::Relaxed);
+  rooms.insert(id, room.clone());
+  let (state, tenant, shared) = (state.clone(), tenant.clone(), room.clone());
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(MATERIALIZE_SECONDS));
+    while !shared.closed.load(Ordering::Relaxed) {
+      interval.tick().await;
+      materialize(&state, &tenant, &id, &shared).await;
+    }
+  });
+  room
+}
+
+// 共有ドキュメントから抜ける関数
+// 最後の参加者であれば書き戻してからドキュメントを捨てる
+async fn leave(state: &State, tenant: &Tenant, id: Uuid, room: &Arc<Room>) {
+  if room.peers.fetch_sub(1, Ordering::Relaxed) > 1 {
+    return;
+  }
+  // 書き戻している間に参加した接続があれば，ドキュメントを残してそのまま使わせる
+  materialize(state, tenant, &id, room).await;
+  let mut rooms = tenant.sync.rooms.lock().unwrap();
+  if room.peers.load(Ordering::Relaxed) == 0 {
+    room.closed.store(true, Ordering::Relaxed);
+    rooms.remove(&id);
+  }
+}
+
+// 1つのWebSocket接続で更新を交換する関数
+// 接続直後にドキュメント全体を送り，以降は互いにYjs互換の更新（v1形式のバイナリ）を送り合う
+async fn session(
+  ws: WebSocketStream<Upgraded>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  id: Uuid,
+  content: String,
+  ip: IpAddr,
+) {
+  let room = join(&state, &tenant, id, &content);
+  let peer = tenant.sync.next_peer.fetch_add(1, Ordering::Relaxed);
+  // 取りこぼさないように購読してから全体を送る（同じ更新を2回適用しても結果は変わらない）
+  let mut updates = room.updates.subscribe();
+  let (mut sink, mut stream) = ws.split();
+  let mut open = sink.send(Message::Binary(room.snapshot())).await.is_ok();
+  while open {
+    tokio::select! {
+      message = stream.next() => match message {
+        Some(Ok(Message::Binary(data))) => {
+          if !room.apply(&data, ip) {
+            break;
+          }
+          // 受け取る接続がなくても構わない
+          let _ = room.updates.send((peer, Arc::new(data)));
+        }
+        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+        Some(Ok(_)) => {}
+      },
+      update = updates.recv() => match update {
+        Ok((from, data)) if from != peer => {
+          open = sink.send(Message::Binary(data.to_vec())).await.is_ok();
+        }
+        Ok(_) => {}
+        // 送りきれなかった更新がある場合は全体を送り直す
+        Err(broadcast::error::RecvError::Lagged(_)) => {
+          open = sink.send(Message::Binary(room.snapshot())).await.is_ok();
+        }
+        Err(broadcast::error::RecvError::Closed) => break,
+      },
+    }
+  }
+  leave(&state, &tenant, id, &room).await;
+}
+
+// 投稿を複数人で同時に編集するWebSocketを開く関数
+// 本文はCRDT（yrs）で統合し，一定時間ごとと全員が抜けたときに投稿へ書き戻す
+pub async fn connect(
+  mut req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  post_id: &str,
+) -> Result<Response<Body>, Error> {
+  let id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  // 書き戻せないので読み取り専用の間は編集させない
+  if state.read_only.is_on() {
+    return Ok(empty(StatusCode::SERVICE_UNAVAILABLE));
+  }
+  let key = header_str(&req, header::SEC_WEBSOCKET_KEY);
+  if !header_str(&req, header::UPGRADE).eq_ignore_ascii_case("websocket") || key.is_empty() {
+    return Ok(empty(StatusCode::UPGRADE_REQUIRED));
+  }
+  // E2EEの投稿はサーバが平文を持たないので扱わない
+  // 非公開の投稿は詳細と同じく存在しないものとして扱う
+  let content = tenant
+    .conn
+    .lock()
+    .await
+    .query_row(
+      "SELECT content, encrypted, compressed FROM posts
+      WHERE id=?1 AND kind = 'text' AND visibility != ?2 AND trashed_at IS NULL",
+      params![id, Visibility::Private],
+      |row| Ok(state.codec.decode(row.get(0)?, row.get(1)?, row.get(2)?)),
+    )
+    .optional()
+    .unwrap();
+  let content = match content {
+    Some(content) => content,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let ip = remote_ip(&req);
+  let on_upgrade = hyper::upgrade::on(&mut req);
+  tokio::spawn(async move {
+    match on_upgrade.await {
+      Ok(upgraded) => {
+        let ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+        session(ws, state, tenant, id, content, ip).await;
+      }
+      Err(e) => eprintln!("websocket upgrade error {}", e),
+    }
+  });
+  Ok(
+    Response::builder()
+      .status(StatusCode::SWITCHING_PROTOCOLS)
+      .header(header::UPGRADE, "websocket")
+      .header(header::CONNECTION, "Upgrade")
+      .header(
+        header::SEC_WEBSOCKET_ACCEPT,
+        derive_accept_key(key.as_bytes()),
+      )
+      .body(Body::empty())
+      .unwrap(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{tests, Visibility};
+  use hyper::{header, Body, Request, StatusCode};
+
+  fn upgrade(id: &uuid::Uuid) -> Request<Body> {
+    Request::builder()
+      .uri(format!("/posts/{}/sync", id))
+      .header(header::UPGRADE, "websocket")
+      .header(header::CONNECTION, "Upgrade")
+      .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+      .header(header::SEC_WEBSOCKET_VERSION, "13")
+      .body(Body::empty())
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn hides_private_posts() {
+    let state = tests::state();
+    let private = tests::insert_post(&state, "secret", Visibility::Private).await;
+    let res = tests::send(&state, upgrade(&private)).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let public = tests::insert_post(&state, "open", Visibility::Public).await;
+    let res = tests::send(&state, upgrade(&public)).await;
+    assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+  }
+}
@@ -0,0 +1,242 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use regex::{Regex, RegexBuilder};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{empty, json, now, Tenant, Visibility};
+
+// 正規表現を組み立てるときのメモリの上限（バイト）
+// 大きな繰り返しなどで書き込みのたびに時間がかからないようにする
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+// 規則の種類
+// keywordは大文字と小文字を区別せずに含まれているかどうか，regexは正規表現に当てはまるかどうかを見る
+#[derive(Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+  Keyword,
+  Regex,
+}
+
+impl Kind {
+  fn as_str(self) -> &'static str {
+    match self {
+      Kind::Keyword => "keyword",
+      Kind::Regex => "regex",
+    }
+  }
+
+  fn parse(kind: &str) -> Kind {
+    match kind {
+      "regex" => Kind::Regex,
+      _ => Kind::Keyword,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct NewRule {
+  kind: Kind,
+  pattern: String,
+  tag: String,
+}
+
+#[derive(Serialize)]
+struct Rule {
+  id: i64,
+  kind: Kind,
+  pattern: String,
+  tag: String,
+  created_at: i64,
+}
+
+#[derive(Serialize)]
+struct Tagged {
+  id: Uuid,
+  title: String,
+}
+
+// 照合できる形にした規則
+enum Matcher {
+  Keyword(String),
+  Regex(Regex),
+}
+
+impl Matcher {
+  fn new(kind: Kind, pattern: &str) -> Result<Matcher, regex::Error> {
+    Ok(match kind {
+      Kind::Keyword => Matcher::Keyword(pattern.to_lowercase()),
+      Kind::Regex => Matcher::Regex(
+        RegexBuilder::new(pattern)
+          .size_limit(REGEX_SIZE_LIMIT)
+          .build()?,
+      ),
+    })
+  }
+
+  // keywordの場合は小文字にした文章を使う
+  fn matches(&self, text: &str, lowered: &str) -> bool {
+    match self {
+      Matcher::Keyword(keyword) => lowered.contains(keyword.as_str()),
+      Matcher::Regex(regex) => regex.is_match(text),
+    }
+  }
+}
+
+fn rules(conn: &Connection) -> Vec<Rule> {
+  let mut stmt = conn
+    .prepare("SELECT id, kind, pattern, tag, created_at FROM tag_rules ORDER BY id")
+    .unwrap();
+  stmt
+    .query_map([], |row| {
+      Ok(Rule {
+        id: row.get(0)?,
+        kind: Kind::parse(&row.get::<_, String>(1)?),
+        pattern: row.get(2)?,
+        tag: row.get(3)?,
+        created_at: row.get(4)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap()
+}
+
+// 規則に当てはめて投稿のタグを付け直す関数
+// タイトルはDBから読むので，投稿を書き換えたのと同じトランザクションの中で本文を書いた後に呼び出す
+// 規則を変えても保存済みの投稿には付け直さず，次に書き換えたときに反映する
+pub fn apply(conn: &Connection, id: &Uuid, content: &str) {
+  let title: Option<String> = conn
+    .query_row("SELECT title FROM posts WHERE id=?1", params![id], |row| {
+      row.get(0)
+    })
+    .optional()
+    .unwrap();
+  let text = format!("{}\n{}", title.unwrap_or_default(), content);
+  let lowered = text.to_lowercase();
+  // 保存する前に検証しているので，組み立てられない規則はない
+  let tags = rules(conn)
+    .into_iter()
+    .filter(|rule| {
+      Matcher::new(rule.kind, &rule.pattern)
+        .map(|matcher| matcher.matches(&text, &lowered))
+        .unwrap_or(false)
+    })
+    .map(|rule| rule.tag)
+    .collect::<BTreeSet<_>>();
+  conn
+    .execute("DELETE FROM post_tags WHERE post_id=?1", params![id])
+    .unwrap();
+  for tag in tags {
+    conn
+      .execute(
+        "INSERT INTO post_tags(post_id, tag) VALUES (?1,?2)",
+        params![id, tag],
+      )
+      .unwrap();
+  }
+}
+
+// 複製した投稿に元の投稿のタグをそのまま付ける関数
+// 本文を読めないE2EEの投稿も扱えるように，規則に当てはめ直さずに行を写す
+pub fn copy(conn: &Connection, from: &Uuid, to: &Uuid) {
+  conn
+    .execute(
+      "INSERT INTO post_tags(post_id, tag) SELECT ?1, tag FROM post_tags WHERE post_id=?2",
+      params![to, from],
+    )
+    .unwrap();
+}
+
+// 投稿に付いたタグを名前の順に返す関数
+pub fn for_post(conn: &Connection, id: &Uuid) -> Vec<String> {
+  let mut stmt = conn
+    .prepare("SELECT tag FROM post_tags WHERE post_id=?1 ORDER BY tag")
+    .unwrap();
+  stmt
+    .query_map(params![id], |row| row.get(0))
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap()
+}
+
+// タグの付いた公開している投稿を新しい順に返す関数
+pub async fn posts(tenant: Arc<Tenant>, tag: &str) -> Result<Response<Body>, Error> {
+  let conn = tenant.conn.lock().await;
+  let mut stmt = conn
+    .prepare(
+      "SELECT posts.id, posts.title FROM post_tags JOIN posts ON posts.id = post_tags.post_id
+      WHERE post_tags.tag=?1 AND posts.visibility=?2 AND posts.trashed_at IS NULL
+      ORDER BY posts.created_at DESC, posts.rowid DESC",
+    )
+    .unwrap();
+  let tagged = stmt
+    .query_map(params![tag, Visibility::Public], |row| {
+      Ok(Tagged {
+        id: row.get(0)?,
+        title: row.get(1)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  Ok(json(&tagged))
+}
+
+// 規則を一覧する関数
+pub async fn list_rules(tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let conn = tenant.conn.lock().await;
+  Ok(json(&rules(&conn)))
+}
+
+// 規則を追加する関数
+// 書き込みのたびに失敗しないように，正規表現は保存する前に組み立てて確かめる
+pub async fn create_rule(req: Request<Body>, tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let body = hyper::body::to_bytes(req.into_body()).await?;
+  let form = match serde_urlencoded::from_bytes::<NewRule>(&body) {
+    Ok(form) => form,
+    Err(_) => return Ok(empty(StatusCode::UNPROCESSABLE_ENTITY)),
+  };
+  let tag = form.tag.trim();
+  if tag.is_empty() || form.pattern.is_empty() {
+    return Ok(empty(StatusCode::UNPROCESSABLE_ENTITY));
+  }
+  if let Err(e) = Matcher::new(form.kind, &form.pattern) {
+    return Ok(
+      Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(e.to_string().into())
+        .unwrap(),
+    );
+  }
+  let conn = tenant.conn.lock().await;
+  conn
+    .execute(
+      "INSERT INTO tag_rules(kind, pattern, tag, created_at) VALUES (?1,?2,?3,?4)",
+      params![form.kind.as_str(), form.pattern, tag, now()],
+    )
+    .unwrap();
+  Ok(Response::new(conn.last_insert_rowid().to_string().into()))
+}
+
+// 規則を削除する関数
+// 規則によって付いたタグは投稿を次に書き換えたときに外れる
+pub async fn delete_rule(tenant: Arc<Tenant>, id: &str) -> Result<Response<Body>, Error> {
+  let id: i64 = match id.parse() {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let deleted = tenant
+    .conn
+    .lock()
+    .await
+    .execute("DELETE FROM tag_rules WHERE id=?1", params![id])
+    .unwrap();
+  if deleted == 0 {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  Ok(empty(StatusCode::NO_CONTENT))
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use tera::{Tera, Value};
+
+use crate::{now, wordcount};
+
+// 抜粋の長さ（文字数）の初期値
+const DEFAULT_EXCERPT_LENGTH: usize = 200;
+
+// フィルタに渡されたUNIX秒を取り出す関数
+// 作成日時を記録していない投稿はnullになる
+fn seconds(value: &Value, filter: &str) -> tera::Result<Option<i64>> {
+  match value {
+    Value::Null => Ok(None),
+    Value::Number(n) => n
+      .as_i64()
+      .map(Some)
+      .ok_or_else(|| format!("{} requires unix seconds", filter).into()),
+    _ => Err(format!("{} requires unix seconds", filter).into()),
+  }
+}
+
+// 引数のtimezone（Asia/Tokyoなど）で日時を表す関数（省略時はUTC）
+fn local(seconds: i64, args: &HashMap<String, Value>, filter: &str) -> tera::Result<DateTime<Tz>> {
+  let tz = match args.get("timezone").and_then(Value::as_str) {
+    Some(name) => name
+      .parse::<Tz>()
+      .map_err(|_| format!("{}: unknown timezone {}", filter, name))?,
+    None => Tz::UTC,
+  };
+  Ok(Utc.timestamp(seconds, 0).with_timezone(&tz))
+}
+
+// {{ post.created_at | humandate(timezone=tz) }} でOct 14, 2026のように表示するフィルタ
+fn humandate(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+  let seconds = match seconds(value, "humandate")? {
+    Some(seconds) => seconds,
+    None => return Ok(Value::String(String::new())),
+  };
+  let date = local(seconds, args, "humandate")?;
+  Ok(Value::String(date.format("%b %-d, %Y").to_string()))
+}
+
+// {{ post.created_at | localtime(timezone=tz) }} で2026-10-14 15:10のように表示するフィルタ
+fn localtime(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+  let seconds = match seconds(value, "localtime")? {
+    Some(seconds) => seconds,
+    None => return Ok(Value::String(String::new())),
+  };
+  let time = local(seconds, args, "localtime")?;
+  Ok(Value::String(time.format("%Y-%m-%d %H:%M").to_string()))
+}
+
+// {{ post.content | excerpt(length=200) }} で本文の先頭を抜き出すフィルタ
+// 改行や連続する空白は1つにまとめ，長い場合は単語の途中で切らずに…を付ける
+fn excerpt(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+  let text = value.as_str().ok_or("excerpt requires a string")?;
+  let length = match args.get("length") {
+    Some(length) => length
+      .as_u64()
+      .ok_or("excerpt length must be a positive integer")? as usize,
+    None => DEFAULT_EXCERPT_LENGTH,
+  };
+  let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+  let cut = match text.char_indices().nth(length) {
+    Some((cut, _)) => cut,
+    None => return Ok(Value::String(text)),
+  };
+  // 区切りの空白が見つからない（日本語など）場合は文字数で切る
+  let head = &text[..cut];
+  let head = match head.rfind(' ') {
+    Some(space) if space > 0 => &head[..space],
+    _ => head,
+  };
+  Ok(Value::String(format!("{}…", head)))
+}
+
+// {{ post.content | reading_time }} で3 min readのように読了時間の目安を表示するフィルタ
+// 保存時に記録する読了時間と同じ方法で見積もる
+fn reading_time(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+  let text = value.as_str().ok_or("reading_time requires a string")?;
+  let minutes = wordcount::count(text).reading_minutes;
+  Ok(Value::String(format!("{} min read", minutes)))
+}
+
+// 経過時間を一番大きい単位で表す関数
+fn ago(seconds: u64) -> String {
+  const UNITS: [(u64, &str); 6] = [
+    (365 * 24 * 60 * 60, "year"),
+    (30 * 24 * 60 * 60, "month"),
+    (7 * 24 * 60 * 60, "week"),
+    (24 * 60 * 60, "day"),
+    (60 * 60, "hour"),
+    (60, "minute"),
+  ];
+  for (unit, name) in UNITS {
+    if seconds >= unit {
+      let n = seconds / unit;
+      return format!("{} {}{}", n, name, if n == 1 { "" } else { "s" });
+    }
+  }
+  String::new()
+}
+
+// {{ post.created_at | relative_time }} で3 days agoのように現在からの差を表示するフィルタ
+// 1分未満はjust now，未来の日時はin 2 hoursのように表示する
+fn relative_time(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+  let seconds = match seconds(value, "relative_time")? {
+    Some(seconds) => seconds,
+    None => return Ok(Value::String(String::new())),
+  };
+  let diff = now() as i64 - seconds;
+  let text = match ago(diff.unsigned_abs()) {
+    span if span.is_empty() => "just now".to_string(),
+    span if diff < 0 => format!("in {}", span),
+    span => format!("{} ago", span),
+  };
+  Ok(Value::String(text))
+}
+
+// すべてのテンプレートで使えるフィルタを登録する関数
+pub fn register(tera: &mut Tera) {
+  tera.register_filter("humandate", humandate);
+  tera.register_filter("localtime", localtime);
+  tera.register_filter("excerpt", excerpt);
+  tera.register_filter("reading_time", reading_time);
+  tera.register_filter("relative_time", relative_time);
+}
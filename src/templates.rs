@@ -0,0 +1,95 @@
+use std::{env, fs, path::Path};
+
+use hyper::{Body, Request};
+use include_dir::{include_dir, Dir};
+use tera::{Context, Tera};
+
+use crate::{flash, themes, State};
+
+// 実行ファイルに組み込んだ標準のテンプレート
+static EMBEDDED: Dir = include_dir!("$CARGO_MANIFEST_DIR/templates");
+
+// 組み込みのテンプレートを上書きするディレクトリを指定する環境変数
+// 後のものほど優先する（パッケージが置くシステム全体の設定 < 運用者がその環境だけで変更したもの）
+const OVERRIDE_DIRS: &[&str] = &["TEMPLATE_SYSTEM_DIR", "TEMPLATE_DIR"];
+
+// templates/search.html を search という名前で登録する関数
+// 拡張子を付けずに登録するので自動エスケープされない（各テンプレートで明示的にエスケープする）
+fn add(tera: &mut Tera, file: &str, source: &str) {
+  if let Some(name) = file.strip_suffix(".html") {
+    tera
+      .add_raw_template(name, source)
+      .unwrap_or_else(|e| panic!("invalid template {}: {:?}", file, e));
+  }
+}
+
+// 組み込んだディレクトリ以下のテンプレートを再帰的に登録する関数
+fn add_embedded(tera: &mut Tera, dir: &Dir) {
+  for file in dir.files() {
+    add(
+      tera,
+      &file.path().to_string_lossy(),
+      file.contents_utf8().unwrap(),
+    );
+  }
+  for dir in dir.dirs() {
+    add_embedded(tera, dir);
+  }
+}
+
+// ディレクトリ以下のテンプレートで同じ名前のものを置き換える関数
+// themes/dark/search.html は themes/dark/search という名前になる
+fn add_dir(tera: &mut Tera, dir: &Path, base: &str) {
+  let entries =
+    fs::read_dir(dir).unwrap_or_else(|e| panic!("cannot read {}: {}", dir.display(), e));
+  for entry in entries {
+    let entry = entry.unwrap();
+    let name = format!("{}{}", base, entry.file_name().to_string_lossy());
+    if entry.file_type().unwrap().is_dir() {
+      add_dir(tera, &entry.path(), &format!("{}/", name));
+    } else {
+      add(tera, &name, &fs::read_to_string(entry.path()).unwrap());
+    }
+  }
+}
+
+// 組み込みのテンプレートを登録し，TEMPLATE_SYSTEM_DIR，TEMPLATE_DIRの順に同じ名前のファイルで置き換える関数
+// 実行ファイル1つで動かせるようにしつつ，必要なテンプレートだけを手元で変更できる
+pub fn load(tera: &mut Tera) {
+  add_embedded(tera, &EMBEDDED);
+  for var in OVERRIDE_DIRS {
+    if let Ok(dir) = env::var(var) {
+      add_dir(tera, Path::new(&dir), "");
+    }
+  }
+}
+
+// 画面のテンプレートをリクエストに合わせてレンダリングする関数
+// テンプレートからは{{ theme }}で見た目の名前を，{{ lang }}で表示する言語を，{{ tz }}でタイムゾーンを参照できる
+// 一度だけ表示するメッセージがあれば{{ flash }}に翻訳のキーが入る
+pub fn render(state: &State, req: &Request<Body>, name: &str, ctx: &mut Context) -> String {
+  let theme = state.themes.current(req);
+  ctx.insert("theme", theme);
+  ctx.insert("lang", state.i18n.locale(req));
+  ctx.insert("tz", state.timezone.current(req).name());
+  if let Some(message) = flash::take(req) {
+    ctx.insert("flash", &message);
+  }
+  state
+    .tera
+    .render(&themes::template(&state.tera, theme, name), ctx)
+    .unwrap()
+}
+
+// リクエストによらず既定の見た目，言語，タイムゾーンでレンダリングする関数
+// 静的なサイトに書き出すときに使う
+pub fn render_default(state: &State, name: &str, ctx: &mut Context) -> String {
+  let theme = state.themes.default();
+  ctx.insert("theme", theme);
+  ctx.insert("lang", state.i18n.default());
+  ctx.insert("tz", state.timezone.default().name());
+  state
+    .tera
+    .render(&themes::template(&state.tera, theme, name), ctx)
+    .unwrap()
+}
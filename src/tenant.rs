@@ -0,0 +1,193 @@
+use std::{
+  collections::HashMap,
+  env,
+  path::PathBuf,
+  sync::{Arc, Mutex as StdMutex},
+};
+
+use hyper::{header, Body, Request};
+use rusqlite::{Connection, OpenFlags};
+use tokio::sync::Mutex;
+
+use crate::{
+  db,
+  events::{self, Event},
+  feed, maintenance, related, stats, suggest, sync, views, writer,
+};
+
+// テナントごとのDBとURLの接頭辞
+pub struct Tenant {
+  // 排他制御されたDB接続
+  // spliteはシングルスレッド動作
+  pub conn: Mutex<Connection>,
+  // DBファイルの名前（テナントを使わない場合はdefault）
+  pub name: String,
+  // パスでテナントを分ける場合に生成するURLの先頭に付ける（例: /t/alice）
+  pub prefix: String,
+  // 入力補完に使うタイトルの索引
+  pub suggest: suggest::Index,
+  // 統計の集計結果
+  pub stats: stats::Cache,
+  // まだDBに書き出していない閲覧数
+  pub views: views::Buffer,
+  // 投稿ごとの関連する投稿
+  pub related: related::Cache,
+  // フィードとサイトマップ
+  pub feeds: feed::Cache,
+  // 最後にDBを手入れした結果
+  pub maintenance: maintenance::Last,
+  // 投稿の作成をまとめてコミットする
+  pub writer: writer::Writer,
+  // 共同編集中の投稿
+  pub sync: sync::Rooms,
+  // 投稿に起きた出来事を配る先（すべてのテナントで共有する）
+  pub events: events::Bus,
+}
+
+impl Tenant {
+  fn new(mut conn: Connection, name: &str, prefix: String, events: events::Bus) -> Arc<Tenant> {
+    db::migrate(&mut conn);
+    db::register_functions(&conn);
+    Arc::new_cyclic(|tenant| Tenant {
+      conn: Mutex::new(conn),
+      name: name.to_string(),
+      prefix,
+      suggest: suggest::Index::new(),
+      stats: stats::Cache::new(),
+      views: views::Buffer::new(),
+      related: related::Cache::new(),
+      feeds: feed::Cache::new(),
+      maintenance: maintenance::Last::new(),
+      writer: writer::Writer::spawn(tenant.clone()),
+      sync: sync::Rooms::new(),
+      events,
+    })
+  }
+
+  // 投稿の変更をコミットした後に呼び出す関数
+  // 次の読み込みで古い内容を返さないように投稿から作った索引やキャッシュはその場で捨て，
+  // Webhookの送信などそれ以外の処理はイベントを受け取る側に任せる
+  pub fn publish(&self, event: Event) {
+    self.suggest.invalidate();
+    self.related.invalidate(event.id());
+    self.feeds.invalidate();
+    self.events.send(&self.name, event);
+  }
+}
+
+// テナントを見分ける方法
+enum Mode {
+  // テナントを使わずメモリ上のDB1つで動かす
+  Single,
+  // alice.example.com のようにサブドメインで見分ける
+  Subdomain(String),
+  // /t/alice/... のようにパスの先頭で見分ける
+  Path,
+}
+
+pub struct Tenants {
+  mode: Mode,
+  // テナントごとのDBファイル（<name>.sqlite）を置くディレクトリ
+  dir: PathBuf,
+  single: Option<Arc<Tenant>>,
+  events: events::Bus,
+  // 開いたDBは使い回す
+  // ロック中にawaitしないので標準ライブラリのMutexで十分
+  opened: StdMutex<HashMap<String, Arc<Tenant>>>,
+}
+
+impl Tenants {
+  // TENANT_MODEがsubdomainの場合はTENANT_DOMAINでベースのドメインを指定する
+  pub fn from_env(events: events::Bus) -> Tenants {
+    let mode = match env::var("TENANT_MODE").as_deref() {
+      Ok("subdomain") => Mode::Subdomain(env::var("TENANT_DOMAIN").unwrap()),
+      Ok("path") => Mode::Path,
+      Ok(other) => panic!("unknown TENANT_MODE {}", other),
+      Err(_) => Mode::Single,
+    };
+    let single = match mode {
+      Mode::Single => Some(Tenant::new(
+        Connection::open_in_memory().unwrap(),
+        "default",
+        String::new(),
+        events.clone(),
+      )),
+      _ => None,
+    };
+    Tenants {
+      mode,
+      dir: PathBuf::from(env::var("TENANT_DIR").unwrap_or_else(|_| "tenants".to_string())),
+      single,
+      events,
+      opened: StdMutex::new(HashMap::new()),
+    }
+  }
+
+  // リクエストからテナントを決め，テナントを表す部分を除いたパスと一緒に返す関数
+  // 存在しないテナントの場合はNoneを返す
+  pub fn resolve(&self, req: &Request<Body>) -> Option<(Arc<Tenant>, String)> {
+    let path = req.uri().path();
+    match &self.mode {
+      Mode::Single => Some((self.single.clone()?, path.to_string())),
+      Mode::Subdomain(domain) => {
+        let host = req.headers().get(header::HOST)?.to_str().ok()?;
+        // ポート番号は無視する
+        let host = host.split(':').next()?;
+        let name = host.strip_suffix(domain)?.strip_suffix('.')?;
+        Some((self.open(name, String::new())?, path.to_string()))
+      }
+      Mode::Path => {
+        let rest = path.strip_prefix("/t/")?;
+        let (name, rest) = rest.split_once('/').unwrap_or((rest, ""));
+        let tenant = self.open(name, format!("/t/{}", name))?;
+        Some((tenant, format!("/{}", rest)))
+      }
+    }
+  }
+
+  // DBをファイルに置いているか（テナントを使わない場合はメモリ上にしかない）
+  pub fn persistent(&self) -> bool {
+    !matches!(self.mode, Mode::Single)
+  }
+
+  // 開いているすべてのテナントを返す関数
+  pub fn all(&self) -> Vec<Arc<Tenant>> {
+    let opened = self.opened.lock().unwrap();
+    self.single.iter().chain(opened.values()).cloned().collect()
+  }
+
+  // 名前を指定してテナントを開く関数（リクエストを受けずに使うコマンド向け）
+  // テナントを使わない場合のDBはメモリ上にしかないので開けない
+  pub fn by_name(&self, name: &str) -> Option<Arc<Tenant>> {
+    match &self.mode {
+      Mode::Single => None,
+      Mode::Subdomain(_) => self.open(name, String::new()),
+      Mode::Path => self.open(name, format!("/t/{}", name)),
+    }
+  }
+
+  // テナントのDBを開く関数
+  // ファイルが用意されていないテナントは存在しないものとして扱う
+  fn open(&self, name: &str, prefix: String) -> Option<Arc<Tenant>> {
+    // パスに使うので英小文字，数字，ハイフンのみを許可する
+    if name.is_empty()
+      || !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+      return None;
+    }
+    let mut opened = self.opened.lock().unwrap();
+    if let Some(tenant) = opened.get(name) {
+      return Some(tenant.clone());
+    }
+    let conn = Connection::open_with_flags(
+      self.dir.join(format!("{}.sqlite", name)),
+      OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .ok()?;
+    let tenant = Tenant::new(conn, name, prefix, self.events.clone());
+    opened.insert(name.to_string(), tenant.clone());
+    Some(tenant)
+  }
+}
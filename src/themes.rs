@@ -0,0 +1,102 @@
+use std::{env, sync::Arc};
+
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use serde::Serialize;
+use tera::Tera;
+
+use crate::{assets::Assets, cookie, empty, json, State};
+
+// 見た目を選んだブラウザが送るクッキーの名前
+const COOKIE: &str = "theme";
+// THEMEを指定しない場合の見た目
+const DEFAULT_THEME: &str = "light";
+
+// 画面の見た目の組み合わせ
+// 静的なファイルの themes/<name>/theme.css があるものを見た目として扱う（STATIC_DIRで追加できる）
+// テンプレートのディレクトリに themes/<name>/search.html のように置くと，その見た目のときだけ置き換わる
+pub struct Themes {
+  names: Vec<String>,
+  default: String,
+}
+
+#[derive(Serialize)]
+struct Theme<'a> {
+  name: &'a str,
+  default: bool,
+  current: bool,
+}
+
+impl Themes {
+  // THEMEで全体の見た目を指定する（組み込みはlightとdark）
+  pub fn from_env(assets: &Assets) -> Themes {
+    let mut names: Vec<String> = assets
+      .names()
+      .filter_map(|name| name.strip_prefix("themes/")?.strip_suffix("/theme.css"))
+      .map(str::to_string)
+      .collect();
+    names.sort();
+    let default = env::var("THEME").unwrap_or_else(|_| DEFAULT_THEME.to_string());
+    if !names.contains(&default) {
+      panic!("unknown theme {}", default);
+    }
+    Themes { names, default }
+  }
+
+  // 利用者の登録がないので，ブラウザごとにクッキーで選んだ見た目を優先する
+  pub fn current(&self, req: &Request<Body>) -> &str {
+    cookie(req, COOKIE)
+      .and_then(|name| self.names.iter().find(|theme| **theme == name))
+      .unwrap_or(&self.default)
+  }
+
+  // リクエストによらない既定の見た目
+  pub fn default(&self) -> &str {
+    &self.default
+  }
+}
+
+// 見た目ごとのテンプレートがあればその名前を返す関数
+pub fn template(tera: &Tera, theme: &str, name: &str) -> String {
+  let themed = format!("themes/{}/{}", theme, name);
+  if tera.get_template_names().any(|name| name == themed) {
+    themed
+  } else {
+    name.to_string()
+  }
+}
+
+// 選べる見た目の一覧を返す関数
+pub async fn list(req: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Error> {
+  let current = state.themes.current(&req);
+  let themes: Vec<Theme> = state
+    .themes
+    .names
+    .iter()
+    .map(|name| Theme {
+      name,
+      default: *name == state.themes.default,
+      current: name == current,
+    })
+    .collect();
+  Ok(json(&themes))
+}
+
+// このブラウザで使う見た目を選ぶ関数
+pub async fn select(state: Arc<State>, name: &str) -> Result<Response<Body>, Error> {
+  if !state.themes.names.iter().any(|theme| theme == name) {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  Ok(
+    Response::builder()
+      .status(StatusCode::NO_CONTENT)
+      .header(
+        header::SET_COOKIE,
+        format!(
+          "{}={}; Path=/; Max-Age=31536000; SameSite=Lax",
+          COOKIE, name
+        ),
+      )
+      .body(Body::empty())
+      .unwrap(),
+  )
+}
@@ -0,0 +1,89 @@
+use std::{env, sync::Arc};
+
+use chrono_tz::Tz;
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{cookie, empty, json, State};
+
+// タイムゾーンを選んだブラウザが送るクッキーの名前
+const COOKIE: &str = "tz";
+
+// 日時を表示するタイムゾーン
+// DBにはUTCのUNIX秒で保存し，表示するときだけ変換する
+pub struct Timezone {
+  default: Tz,
+}
+
+#[derive(Deserialize)]
+struct Select {
+  // Asia/Tokyoのような名前
+  name: String,
+}
+
+#[derive(Serialize)]
+struct Current {
+  timezone: String,
+  default: String,
+}
+
+impl Timezone {
+  // TIMEZONE=Asia/Tokyo のようにIANAの名前で指定する（省略時はUTC）
+  pub fn from_env() -> Timezone {
+    let default = env::var("TIMEZONE")
+      .map(|name| {
+        name
+          .parse()
+          .unwrap_or_else(|_| panic!("unknown timezone {}", name))
+      })
+      .unwrap_or(Tz::UTC);
+    Timezone { default }
+  }
+
+  // 利用者の登録がないので，ブラウザごとにクッキーで選んだタイムゾーンを優先する
+  pub fn current(&self, req: &Request<Body>) -> Tz {
+    cookie(req, COOKIE)
+      .and_then(|name| name.parse().ok())
+      .unwrap_or(self.default)
+  }
+
+  // リクエストによらない既定のタイムゾーン
+  pub fn default(&self) -> Tz {
+    self.default
+  }
+}
+
+// 表示に使うタイムゾーンを返す関数
+pub async fn show(req: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Error> {
+  Ok(json(&Current {
+    timezone: state.timezone.current(&req).name().to_string(),
+    default: state.timezone.default.name().to_string(),
+  }))
+}
+
+// このブラウザで使うタイムゾーンを選ぶ関数
+// 名前に/を含むのでパスではなくクエリで受け取る（例: ?name=Asia/Tokyo）
+pub async fn select(req: Request<Body>) -> Result<Response<Body>, Error> {
+  let select = match serde_urlencoded::from_str::<Select>(req.uri().query().unwrap_or_default()) {
+    Ok(select) => select,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let tz: Tz = match select.name.parse() {
+    Ok(tz) => tz,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  Ok(
+    Response::builder()
+      .status(StatusCode::NO_CONTENT)
+      .header(
+        header::SET_COOKIE,
+        format!(
+          "{}={}; Path=/; Max-Age=31536000; SameSite=Lax",
+          COOKIE,
+          tz.name()
+        ),
+      )
+      .body(Body::empty())
+      .unwrap(),
+  )
+}
@@ -0,0 +1,215 @@
+use std::{
+  env,
+  net::{IpAddr, Ipv4Addr},
+  sync::Arc,
+  time::Duration,
+};
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{attachment, audit, empty, events::Event, json, now, remote_ip, State, Tenant};
+
+// ゴミ箱の投稿を残す日数の初期値
+const DEFAULT_RETENTION_DAYS: u64 = 30;
+// 期限切れの投稿を探す間隔
+const PURGE_SECONDS: u64 = 60 * 60;
+
+// ゴミ箱に入れた投稿を完全に削除するまでの期間
+pub struct Retention {
+  seconds: u64,
+}
+
+impl Retention {
+  // TRASH_RETENTION_DAYSで日数を指定する
+  pub fn from_env() -> Retention {
+    let days = env::var("TRASH_RETENTION_DAYS")
+      .map(|days| days.parse().expect("TRASH_RETENTION_DAYS must be a number"))
+      .unwrap_or(DEFAULT_RETENTION_DAYS);
+    Retention {
+      seconds: days * 24 * 60 * 60,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct Query {
+  // trueの場合は削除せずに対象だけを返す
+  #[serde(default)]
+  dry_run: bool,
+}
+
+#[derive(Serialize)]
+struct Purged {
+  dry_run: bool,
+  posts: Vec<Uuid>,
+}
+
+// 保存期間を過ぎたゴミ箱の投稿を返す関数
+fn expired(conn: &Connection, retention: &Retention) -> Vec<Uuid> {
+  let mut stmt = conn
+    .prepare("SELECT id FROM posts WHERE trashed_at IS NOT NULL AND trashed_at < ?1")
+    .unwrap();
+  stmt
+    .query_map(params![now().saturating_sub(retention.seconds)], |row| {
+      row.get(0)
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap()
+}
+
+// 投稿と投稿に付随するデータを完全に削除する関数
+// 監査ログは削除した記録として残す
+// 添付ファイルはコミットした後に保存先から消すので，その名前を返す
+fn purge(conn: &mut Connection, ids: &[Uuid], actor: &str, ip: IpAddr) -> Vec<String> {
+  let tx = conn.transaction().unwrap();
+  let mut blobs = Vec::new();
+  for id in ids {
+    blobs.extend(attachment::detach_all(&tx, id));
+    for sql in [
+      "DELETE FROM shares WHERE post_id=?1",
+      "DELETE FROM post_views WHERE post_id=?1",
+      "DELETE FROM spam_verdicts WHERE post_id=?1",
+      "DELETE FROM drafts WHERE post_id=?1",
+      "DELETE FROM conflicts WHERE post_id=?1 OR original_id=?1",
+      "DELETE FROM bookmarks WHERE post_id=?1",
+      "DELETE FROM short_links WHERE post_id=?1",
+      "DELETE FROM post_tags WHERE post_id=?1",
+      "DELETE FROM post_metadata WHERE post_id=?1",
+      "DELETE FROM enrichments WHERE post_id=?1",
+      "DELETE FROM posts WHERE id=?1",
+    ] {
+      tx.execute(sql, params![id]).unwrap();
+    }
+    audit::record(
+      &tx,
+      audit::Entry {
+        actor,
+        action: "purge",
+        post_id: id,
+        summary: "removed from trash".to_string(),
+        ip,
+      },
+    );
+  }
+  tx.commit().unwrap();
+  blobs
+}
+
+// 一定の間隔で開いているすべてのテナントのゴミ箱を片付ける関数
+pub fn spawn_purger(state: Arc<State>) {
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(PURGE_SECONDS));
+    loop {
+      interval.tick().await;
+      // 読み取り専用の間は次の機会に回す
+      if state.read_only.is_on() {
+        continue;
+      }
+      for tenant in state.tenants.all() {
+        let mut conn = tenant.conn.lock().await;
+        let ids = expired(&conn, &state.retention);
+        if !ids.is_empty() {
+          let blobs = purge(&mut conn, &ids, audit::SYSTEM, Ipv4Addr::LOCALHOST.into());
+          drop(conn);
+          attachment::remove_blobs(&state, &tenant, blobs).await;
+          for id in ids {
+            tenant.publish(Event::Purged { id });
+          }
+        }
+      }
+    }
+  });
+}
+
+// 保存期間を過ぎたゴミ箱の投稿をすぐに削除する関数
+// dry_runを付けると削除される投稿を確認できる
+pub async fn purge_now(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+) -> Result<Response<Body>, Error> {
+  let query = match serde_urlencoded::from_str::<Query>(req.uri().query().unwrap_or_default()) {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let mut conn = tenant.conn.lock().await;
+  let ids = expired(&conn, &state.retention);
+  if !query.dry_run {
+    let blobs = purge(&mut conn, &ids, audit::ANONYMOUS, remote_ip(&req));
+    drop(conn);
+    attachment::remove_blobs(&state, &tenant, blobs).await;
+    for id in &ids {
+      tenant.publish(Event::Purged { id: *id });
+    }
+  }
+  Ok(json(&Purged {
+    dry_run: query.dry_run,
+    posts: ids,
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{tests, Visibility};
+
+  #[tokio::test]
+  async fn rejects_bad_purge_query() {
+    let state = tests::state();
+    let req = Request::post("/admin/trash/purge?dry_run=maybe")
+      .body(Body::empty())
+      .unwrap();
+    let res = purge_now(req, state.clone(), tests::tenant(&state))
+      .await
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn keeps_attachments_of_merged_posts() {
+    let state = tests::state();
+    let source = tests::insert_post(&state, "source", Visibility::Public).await;
+    let into = tests::insert_post(&state, "into", Visibility::Public).await;
+    let tenant = tests::tenant(&state);
+    tenant
+      .conn
+      .lock()
+      .await
+      .execute_batch(&format!(
+        "INSERT INTO attachments(id, post_id, name, content_type, size, storage_key, created_at)
+        VALUES (X'{0}', X'{1}', 'a.txt', 'text/plain', 1, 'key', 0);
+        INSERT INTO blobs(storage_key, refs) VALUES ('key', 1);
+        INSERT INTO short_links(code, post_id, created_at) VALUES ('abc', X'{1}', 0);",
+        Uuid::new_v4().to_simple(),
+        source.to_simple()
+      ))
+      .unwrap();
+    let req = Request::builder()
+      .method("POST")
+      .uri(format!("/posts/{}/merge?into={}", source, into))
+      .body(Body::empty())
+      .unwrap();
+    assert!(tests::send(&state, req).await.status().is_success());
+    let mut conn = tenant.conn.lock().await;
+    let blobs = purge(
+      &mut conn,
+      &[source],
+      audit::ANONYMOUS,
+      Ipv4Addr::LOCALHOST.into(),
+    );
+    assert!(blobs.is_empty());
+    let owners = |table: &str| -> Uuid {
+      conn
+        .query_row(&format!("SELECT post_id FROM {}", table), [], |row| {
+          row.get(0)
+        })
+        .unwrap()
+    };
+    assert_eq!(owners("attachments"), into);
+    assert_eq!(owners("short_links"), into);
+  }
+}
@@ -0,0 +1,459 @@
+use std::{
+  collections::{HashMap, HashSet},
+  net::IpAddr,
+  sync::{Arc, Mutex, Weak},
+  time::Duration,
+};
+
+use hyper::{body::HttpBody, header, Body, Request, Uri};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::{
+  features,
+  https::{self, PublicClient},
+  now, State, Tenant,
+};
+
+// テナントごとに有効にする機能の名前（外部に接続するので初期値は無効）
+pub const FEATURE: &str = "link_previews";
+
+// 1つのURLの取得にかける時間の上限
+const FETCH_TIMEOUT_SECONDS: u64 = 5;
+// 読み込むページの先頭の大きさ（<head>にあるmetaタグが読めれば足りる）
+const MAX_BODY_BYTES: usize = 256 * 1024;
+const MAX_REDIRECTS: usize = 3;
+// 1つの投稿でプレビューを作るURLの数
+const MAX_LINKS: usize = 5;
+// 取得し直すまでの期間（失敗した場合も同じ）
+const REFRESH_SECONDS: u64 = 7 * 24 * 60 * 60;
+const MAX_TITLE_CHARS: usize = 200;
+const MAX_DESCRIPTION_CHARS: usize = 300;
+
+// 投稿のページに表示するリンクのプレビュー
+#[derive(Serialize)]
+pub struct Preview {
+  url: String,
+  title: String,
+  description: Option<String>,
+  image: Option<String>,
+}
+
+// ページから取り出した情報
+pub struct Fetched {
+  pub title: String,
+  description: Option<String>,
+  image: Option<String>,
+}
+
+// 取得したHTMLのページ
+pub struct Page {
+  // リダイレクトした場合は最後のURL
+  pub url: Uri,
+  pub html: String,
+}
+
+// リンクのプレビューを裏で取得する仕組み
+// 同じURLを取得中に何度も依頼しないように，依頼済みのものを覚えておく
+pub struct Unfurler {
+  jobs: mpsc::UnboundedSender<(Weak<Tenant>, String)>,
+  queued: Arc<Mutex<HashSet<(String, String)>>>,
+  // 公開アドレスにだけ接続するクライアント（ブックマークの取得にも使う）
+  client: PublicClient,
+}
+
+impl Unfurler {
+  // 依頼を1件ずつ取得するタスクを起動する関数
+  pub fn spawn() -> Unfurler {
+    let (jobs, mut rx) = mpsc::unbounded_channel::<(Weak<Tenant>, String)>();
+    let queued = Arc::new(Mutex::new(HashSet::new()));
+    let done = queued.clone();
+    let client = https::public_client();
+    let shared = client.clone();
+    tokio::spawn(async move {
+      while let Some((tenant, url)) = rx.recv().await {
+        let fetched = get(&shared, &url, MAX_BODY_BYTES)
+          .await
+          .and_then(|page| parse(&page.html, &page.url));
+        if let Some(tenant) = tenant.upgrade() {
+          store(&*tenant.conn.lock().await, &url, fetched);
+          done.lock().unwrap().remove(&(tenant.name.clone(), url));
+        }
+      }
+    });
+    Unfurler {
+      jobs,
+      queued,
+      client,
+    }
+  }
+
+  // ページを取得する関数（max_bytesより後は読まない）
+  pub async fn page(&self, url: &str, max_bytes: usize) -> Option<Page> {
+    get(&self.client, url, max_bytes).await
+  }
+
+  fn request(&self, tenant: &Arc<Tenant>, url: &str) {
+    let key = (tenant.name.clone(), url.to_string());
+    if self.queued.lock().unwrap().insert(key.clone()) {
+      // 取得するタスクが止まっている場合は，投稿の表示を妨げないように記録だけを残す
+      if let Err(e) = self.jobs.send((Arc::downgrade(tenant), url.to_string())) {
+        eprintln!("unfurl error {} for {}", e, url);
+        self.queued.lock().unwrap().remove(&key);
+      }
+    }
+  }
+}
+
+// 本文から空白で区切られたURLを取り出す関数
+// 文末の句読点や閉じ括弧はURLに含めない
+fn links(content: &str) -> Vec<String> {
+  let mut links = Vec::new();
+  for word in content.split_whitespace() {
+    if !(word.starts_with("http://") || word.starts_with("https://")) {
+      continue;
+    }
+    let url = word.trim_end_matches(|c: char| ".,;:!?)]}>'\"".contains(c));
+    if url.parse::<Uri>().is_ok() && !links.iter().any(|link| link == url) {
+      links.push(url.to_string());
+    }
+    if links.len() == MAX_LINKS {
+      break;
+    }
+  }
+  links
+}
+
+// 接続してよいURLかを確かめる関数
+// 名前の解決結果はクライアントのリゾルバが確かめるので，ここではアドレスを直接書いたものを確かめる
+fn allowed(uri: &Uri) -> bool {
+  let scheme_ok = matches!(uri.scheme_str(), Some("http") | Some("https"));
+  let port_ok = matches!(uri.port_u16(), None | Some(80) | Some(443));
+  let host = match uri.host() {
+    Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+    None => return false,
+  };
+  let host_ok = match host.parse::<IpAddr>() {
+    Ok(ip) => https::is_public(ip),
+    Err(_) => host != "localhost" && !host.ends_with(".localhost"),
+  };
+  scheme_ok && port_ok && host_ok
+}
+
+// リダイレクト先などの相対的なURLを絶対URLにする関数
+fn absolute(base: &Uri, reference: &str) -> Option<Uri> {
+  if reference.starts_with("http://") || reference.starts_with("https://") {
+    return reference.parse().ok();
+  }
+  let scheme = base.scheme_str()?;
+  if let Some(rest) = reference.strip_prefix("//") {
+    return format!("{}://{}", scheme, rest).parse().ok();
+  }
+  if reference.starts_with('/') {
+    return format!("{}://{}{}", scheme, base.authority()?, reference)
+      .parse()
+      .ok();
+  }
+  None
+}
+
+// HTMLのページを取得する関数
+// HTML以外のページや時間内に取得できなかったページはNoneにする
+async fn get(client: &PublicClient, url: &str, max_bytes: usize) -> Option<Page> {
+  tokio::time::timeout(
+    Duration::from_secs(FETCH_TIMEOUT_SECONDS),
+    follow(client, url, max_bytes),
+  )
+  .await
+  .ok()
+  .flatten()
+}
+
+// リダイレクトをたどってページを読み込む関数
+async fn follow(client: &PublicClient, url: &str, max_bytes: usize) -> Option<Page> {
+  let mut uri: Uri = url.parse().ok()?;
+  for _ in 0..=MAX_REDIRECTS {
+    if !allowed(&uri) {
+      return None;
+    }
+    let req = Request::get(uri.clone())
+      .header(header::USER_AGENT, "web-memory")
+      .header(header::ACCEPT, "text/html")
+      .body(Body::empty())
+      .unwrap();
+    let res = client.request(req).await.ok()?;
+    if res.status().is_redirection() {
+      let location = res.headers().get(header::LOCATION)?.to_str().ok()?;
+      uri = absolute(&uri, location)?;
+      continue;
+    }
+    let html = res
+      .headers()
+      .get(header::CONTENT_TYPE)
+      .and_then(|v| v.to_str().ok())
+      .is_some_and(|v| v.starts_with("text/html"));
+    if !res.status().is_success() || !html {
+      return None;
+    }
+    // 大きなページは先頭だけを読む
+    let mut body = res.into_body();
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+      data.extend_from_slice(&chunk.ok()?);
+      if data.len() >= max_bytes {
+        data.truncate(max_bytes);
+        break;
+      }
+    }
+    return Some(Page {
+      url: uri,
+      html: String::from_utf8_lossy(&data).into_owned(),
+    });
+  }
+  None
+}
+
+// &amp;などの文字参照を戻す関数
+pub fn unescape(text: &str) -> String {
+  let mut out = String::new();
+  let mut rest = text;
+  while let Some(start) = rest.find('&') {
+    out.push_str(&rest[..start]);
+    rest = &rest[start..];
+    let decoded = rest.find(';').filter(|&end| end <= 10).and_then(|end| {
+      let c = match &rest[1..end] {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        entity => {
+          let code = match entity.strip_prefix("#x").or(entity.strip_prefix("#X")) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => entity.strip_prefix('#')?.parse().ok()?,
+          };
+          char::from_u32(code)?
+        }
+      };
+      Some((c, end))
+    });
+    match decoded {
+      Some((c, end)) => {
+        out.push(c);
+        rest = &rest[end + 1..];
+      }
+      None => {
+        out.push('&');
+        rest = &rest[1..];
+      }
+    }
+  }
+  out.push_str(rest);
+  out
+}
+
+// 空白をまとめて長すぎる場合は切り詰める関数
+fn clean(text: &str, max_chars: usize) -> Option<String> {
+  let text = unescape(text)
+    .split_whitespace()
+    .collect::<Vec<_>>()
+    .join(" ");
+  if text.is_empty() {
+    return None;
+  }
+  Some(text.chars().take(max_chars).collect())
+}
+
+// <meta property="og:title" content="...">のようなタグの属性を取り出す関数
+fn attributes(tag: &str) -> HashMap<String, String> {
+  let mut attrs = HashMap::new();
+  let mut chars = tag.chars().peekable();
+  loop {
+    while chars.next_if(|c| c.is_whitespace() || *c == '/').is_some() {}
+    let mut name = String::new();
+    while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '=' && *c != '/') {
+      name.push(c.to_ascii_lowercase());
+    }
+    if name.is_empty() {
+      return attrs;
+    }
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    let mut value = String::new();
+    if chars.next_if_eq(&'=').is_some() {
+      while chars.next_if(|c| c.is_whitespace()).is_some() {}
+      match chars.next_if(|c| *c == '"' || *c == '\'') {
+        Some(quote) => {
+          for c in chars.by_ref() {
+            if c == quote {
+              break;
+            }
+            value.push(c);
+          }
+        }
+        None => {
+          while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            value.push(c);
+          }
+        }
+      }
+    }
+    attrs.entry(name).or_insert(value);
+  }
+}
+
+// ページのOpen Graphのmetaタグと<title>から情報を取り出す関数
+// og:がなければ<title>と<meta name="description">を使う（タイトルが見つからなければNone）
+pub fn parse(html: &str, base: &Uri) -> Option<Fetched> {
+  let lower = html.to_ascii_lowercase();
+  // metaタグは<head>の中にあるので，本文にある例などを拾わないようにそこまでで止める
+  let end = lower.find("</head").unwrap_or(lower.len());
+  let mut meta = HashMap::new();
+  let mut title = None;
+  let mut at = 0;
+  // タグの>が</headより後ろにある場合（<!-- </head> -->など）はそこで止める
+  while let Some(start) = lower[at..end].find('<').map(|i| at + i) {
+    let close = match lower[start..end].find('>') {
+      Some(i) => start + i,
+      None => break,
+    };
+    let tag = &html[start + 1..close];
+    if lower[start + 1..].starts_with("meta") {
+      let attrs = attributes(&tag[4..]);
+      let key = attrs.get("property").or_else(|| attrs.get("name"));
+      if let (Some(key), Some(content)) = (key, attrs.get("content")) {
+        meta
+          .entry(key.to_ascii_lowercase())
+          .or_insert(content.clone());
+      }
+    } else if lower[start + 1..].starts_with("title") && title.is_none() {
+      if let Some(i) = lower[close..].find("</title") {
+        title = Some(html[close + 1..close + i].to_string());
+      }
+    }
+    at = close + 1;
+  }
+  let title = meta
+    .get("og:title")
+    .or(title.as_ref())
+    .and_then(|title| clean(title, MAX_TITLE_CHARS))?;
+  let description = meta
+    .get("og:description")
+    .or_else(|| meta.get("description"))
+    .and_then(|description| clean(description, MAX_DESCRIPTION_CHARS));
+  let image = meta
+    .get("og:image")
+    .and_then(|image| absolute(base, &unescape(image)))
+    .filter(|image| matches!(image.scheme_str(), Some("http") | Some("https")))
+    .map(|image| image.to_string());
+  Some(Fetched {
+    title,
+    description,
+    image,
+  })
+}
+
+// 取得した結果を保存する関数
+// 失敗した場合もしばらく取得し直さないように時刻だけを残す
+fn store(conn: &Connection, url: &str, fetched: Option<Fetched>) {
+  let (title, description, image) = match fetched {
+    Some(fetched) => (Some(fetched.title), fetched.description, fetched.image),
+    None => (None, None, None),
+  };
+  conn
+    .execute(
+      "INSERT INTO link_previews(url, title, description, image, fetched_at)
+      VALUES (?1,?2,?3,?4,?5)
+      ON CONFLICT(url) DO UPDATE SET title=excluded.title, description=excluded.description,
+        image=excluded.image, fetched_at=excluded.fetched_at",
+      params![url, title, description, image, now()],
+    )
+    .unwrap();
+}
+
+// 投稿の本文にあるURLのうち，取得済みのもののプレビューを返す関数
+// 本文はどの経路でも書き換わるので，表示するときに未取得や古くなったURLを探して取得を依頼する
+// 機能が無効な場合や読み取り専用の間は何もしない
+pub fn for_post(
+  state: &State,
+  tenant: &Arc<Tenant>,
+  conn: &Connection,
+  content: &str,
+) -> Vec<Preview> {
+  if !features::enabled(&state.features, conn, FEATURE) {
+    return Vec::new();
+  }
+  let mut previews = Vec::new();
+  for url in links(content) {
+    let row = conn
+      .query_row(
+        "SELECT title, description, image, fetched_at FROM link_previews WHERE url=?1",
+        params![url],
+        |row| {
+          Ok((
+            row.get::<_, Option<String>>(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get::<_, u64>(3)?,
+          ))
+        },
+      )
+      .optional()
+      .unwrap();
+    let stale = row
+      .as_ref()
+      .is_none_or(|row| row.3 + REFRESH_SECONDS < now());
+    if stale && !state.read_only.is_on() {
+      state.unfurl.request(tenant, &url);
+    }
+    if let Some((Some(title), description, image, _)) = row {
+      previews.push(Preview {
+        url,
+        title,
+        description,
+        image,
+      });
+    }
+  }
+  previews
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn base() -> Uri {
+    "https://example.com/page".parse().unwrap()
+  }
+
+  #[test]
+  fn parse_reads_open_graph() {
+    let html = r#"<html><head><title>Fallback</title>
+      <meta property="og:title" content="Hello &amp; welcome">
+      <meta name="description" content="  A   page ">
+      <meta property="og:image" content="/image.png"></head><body></body></html>"#;
+    let fetched = parse(html, &base()).unwrap();
+    assert_eq!(fetched.title, "Hello & welcome");
+    assert_eq!(fetched.description.as_deref(), Some("A page"));
+    assert_eq!(
+      fetched.image.as_deref(),
+      Some("https://example.com/image.png")
+    );
+  }
+
+  #[test]
+  fn parse_stops_at_commented_out_head_end() {
+    let html = "<html><head><title>Title</title><!-- </head> --><meta name=\"description\" content=\"x\"></head></html>";
+    let fetched = parse(html, &base()).unwrap();
+    assert_eq!(fetched.title, "Title");
+    assert_eq!(fetched.description, None);
+  }
+
+  #[test]
+  fn parse_tolerates_head_end_inside_attribute() {
+    let html = r#"<head><title>Title</title><meta content="</head>"></head>"#;
+    assert_eq!(parse(html, &base()).unwrap().title, "Title");
+    assert!(parse(r#"<head><meta content="</head>">"#, &base()).is_none());
+  }
+}
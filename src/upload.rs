@@ -0,0 +1,367 @@
+use std::{
+  env, fs,
+  io::{Seek, SeekFrom, Write},
+  path::PathBuf,
+  sync::Arc,
+  time::Duration,
+};
+
+use hyper::{Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{attachment, e2ee, empty, header_str, hex, json, now, remote_ip, State, Tenant};
+
+// 1回に送れる断片の大きさの上限
+const MAX_CHUNK_BYTES: usize = 8 * 1024 * 1024;
+// 途中で止まったアップロードを残しておく時間の初期値（秒）
+const DEFAULT_EXPIRE_SECONDS: u64 = 24 * 60 * 60;
+// 期限切れのアップロードを片付ける間隔
+const CLEAN_SECONDS: u64 = 10 * 60;
+
+// 分割アップロードの途中の断片を置く場所と残しておく時間
+pub struct Uploads {
+  dir: PathBuf,
+  expire_seconds: u64,
+}
+
+impl Uploads {
+  // UPLOAD_DIRで置き場所（省略時はuploads）を，UPLOAD_EXPIRE_SECONDSで残しておく時間を指定する
+  pub fn from_env() -> Uploads {
+    Uploads {
+      dir: env::var("UPLOAD_DIR")
+        .unwrap_or_else(|_| "uploads".to_string())
+        .into(),
+      expire_seconds: env::var("UPLOAD_EXPIRE_SECONDS")
+        .map(|seconds| {
+          seconds
+            .parse()
+            .expect("UPLOAD_EXPIRE_SECONDS must be a number")
+        })
+        .unwrap_or(DEFAULT_EXPIRE_SECONDS),
+    }
+  }
+
+  fn path(&self, tenant: &Tenant, id: &Uuid) -> PathBuf {
+    self.dir.join(&tenant.name).join(id.to_string())
+  }
+}
+
+#[derive(Deserialize)]
+struct Query {
+  #[serde(default)]
+  name: String,
+  size: u64,
+}
+
+struct Session {
+  post_id: Uuid,
+  name: String,
+  content_type: String,
+  size: u64,
+  received: u64,
+  created_at: u64,
+  updated_at: u64,
+}
+
+// アップロードの進み具合
+// offsetから続きを送る
+#[derive(Serialize)]
+struct Progress {
+  id: Uuid,
+  url: String,
+  size: u64,
+  offset: u64,
+  expires_at: u64,
+}
+
+fn progress(state: &State, tenant: &Tenant, id: Uuid, session: &Session) -> Progress {
+  Progress {
+    id,
+    url: format!("{}/uploads/{}", tenant.prefix, id),
+    size: session.size,
+    offset: session.received,
+    expires_at: session.updated_at + state.uploads.expire_seconds,
+  }
+}
+
+// 位置が合わないときや断片が足りないときに今の進み具合を付けて断る
+fn conflict(state: &State, tenant: &Tenant, id: Uuid, session: &Session) -> Response<Body> {
+  let mut res = json(&progress(state, tenant, id, session));
+  *res.status_mut() = StatusCode::CONFLICT;
+  res
+}
+
+fn find(conn: &Connection, id: &Uuid) -> Option<Session> {
+  conn
+    .query_row(
+      "SELECT post_id, name, content_type, size, received, created_at, updated_at
+      FROM upload_sessions WHERE id=?1",
+      params![id],
+      |row| {
+        Ok(Session {
+          post_id: row.get(0)?,
+          name: row.get(1)?,
+          content_type: row.get(2)?,
+          size: row.get(3)?,
+          received: row.get(4)?,
+          created_at: row.get(5)?,
+          updated_at: row.get(6)?,
+        })
+      },
+    )
+    .optional()
+    .unwrap()
+}
+
+fn insert(conn: &Connection, id: &Uuid, session: &Session) {
+  conn
+    .execute(
+      "INSERT INTO upload_sessions(id, post_id, name, content_type, size, received, created_at, updated_at)
+      VALUES (?1,?2,?3,?4,?5,?6,?7,?8)",
+      params![
+        id,
+        session.post_id,
+        session.name,
+        session.content_type,
+        session.size,
+        session.received,
+        session.created_at,
+        session.updated_at
+      ],
+    )
+    .unwrap();
+}
+
+// POST /posts/{id}/uploads?name=...&size=... で分割アップロードを始める関数
+// 種類はContent-Typeで，全体の大きさはsizeで指定する
+pub async fn start(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  post_id: &str,
+) -> Result<Response<Body>, Error> {
+  let post_id = match Uuid::parse_str(post_id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let query = match serde_urlencoded::from_str::<Query>(req.uri().query().unwrap_or_default()) {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::UNPROCESSABLE_ENTITY)),
+  };
+  let limit = state.attachments.max_bytes;
+  if query.size > limit as u64 {
+    return Ok(e2ee::too_large(limit));
+  }
+  // 種類と大きさは断片を受け取る前に確かめておく
+  let content_type = attachment::content_type(&req);
+  if let Err(rejection) = state
+    .attachments
+    .policy
+    .precheck(&content_type, query.size as usize)
+  {
+    return Ok(rejection.response());
+  }
+  let conn = tenant.conn.lock().await;
+  if !attachment::exists(&conn, &post_id) {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  let id = Uuid::new_v4();
+  let session = Session {
+    post_id,
+    name: attachment::file_name(&query.name),
+    content_type,
+    size: query.size,
+    received: 0,
+    created_at: now(),
+    updated_at: now(),
+  };
+  let path = state.uploads.path(&tenant, &id);
+  fs::create_dir_all(path.parent().unwrap()).unwrap();
+  fs::write(&path, []).unwrap();
+  insert(&conn, &id, &session);
+  let mut res = json(&progress(&state, &tenant, id, &session));
+  *res.status_mut() = StatusCode::CREATED;
+  Ok(res)
+}
+
+// GET /uploads/{id} で進み具合を返す関数
+// 接続が切れた後はこれで続きの位置を確かめる
+pub async fn status(
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  id: &str,
+) -> Result<Response<Body>, Error> {
+  let id = match Uuid::parse_str(id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  match find(&*tenant.conn.lock().await, &id) {
+    Some(session) => Ok(json(&progress(&state, &tenant, id, &session))),
+    None => Ok(empty(StatusCode::NOT_FOUND)),
+  }
+}
+
+// PATCH /uploads/{id} で断片を受け取る関数
+// Upload-Offsetには断片の位置を指定し，受け取り済みの大きさと違えば409で今の位置を返す
+// Chunk-Sha256を付けると断片のSHA-256（16進数）と一致するかを確かめる
+pub async fn append(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  id: &str,
+) -> Result<Response<Body>, Error> {
+  let id = match Uuid::parse_str(id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let offset = match header_str(&req, "upload-offset".parse().unwrap()).parse::<u64>() {
+    Ok(offset) => offset,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let digest = header_str(&req, "chunk-sha256".parse().unwrap()).to_ascii_lowercase();
+  let remaining = match find(&*tenant.conn.lock().await, &id) {
+    Some(session) if session.received != offset => {
+      return Ok(conflict(&state, &tenant, id, &session));
+    }
+    Some(session) => (session.size - session.received) as usize,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let limit = remaining.min(MAX_CHUNK_BYTES);
+  let chunk = match e2ee::read_limited(req.into_body(), limit).await? {
+    Some(chunk) => chunk,
+    None => return Ok(e2ee::too_large(limit)),
+  };
+  if !digest.is_empty() && hex::encode(&Sha256::digest(&chunk)) != digest {
+    return Ok(empty(StatusCode::BAD_REQUEST));
+  }
+  let conn = tenant.conn.lock().await;
+  // 受け取っている間に別の断片が書き込まれていないかを確かめ直す
+  let mut session = match find(&conn, &id) {
+    Some(session) if session.received != offset => {
+      return Ok(conflict(&state, &tenant, id, &session));
+    }
+    Some(session) => session,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  // 前回の書き込みが途中で止まっていても，受け取り済みの位置から書き直す
+  let mut file = fs::OpenOptions::new()
+    .write(true)
+    .open(state.uploads.path(&tenant, &id))
+    .unwrap();
+  file.set_len(offset).unwrap();
+  file.seek(SeekFrom::End(0)).unwrap();
+  file.write_all(&chunk).unwrap();
+  session.received += chunk.len() as u64;
+  session.updated_at = now();
+  conn
+    .execute(
+      "UPDATE upload_sessions SET received=?1, updated_at=?2 WHERE id=?3",
+      params![session.received, session.updated_at, id],
+    )
+    .unwrap();
+  Ok(json(&progress(&state, &tenant, id, &session)))
+}
+
+// POST /uploads/{id}/commit ですべての断片を受け取ったアップロードを添付ファイルにする関数
+pub async fn commit(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  id: &str,
+) -> Result<Response<Body>, Error> {
+  let id = match Uuid::parse_str(id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let path = state.uploads.path(&tenant, &id);
+  let conn = tenant.conn.lock().await;
+  let session = match find(&conn, &id) {
+    Some(session) if session.received < session.size => {
+      return Ok(conflict(&state, &tenant, id, &session));
+    }
+    Some(session) => session,
+    None => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let data = fs::read(&path).unwrap();
+  // 同じアップロードを二重に確定しないように先にセッションを消しておく
+  conn
+    .execute("DELETE FROM upload_sessions WHERE id=?1", params![id])
+    .unwrap();
+  drop(conn);
+  let res = attachment::attach(
+    &state,
+    &tenant,
+    session.post_id,
+    session.name.clone(),
+    session.content_type.clone(),
+    data,
+    remote_ip(&req),
+  )
+  .await;
+  // 保存先の障害などで保存できなかった場合はセッションを戻し，もう一度確定できるようにする
+  if res.status().is_server_error() {
+    insert(&*tenant.conn.lock().await, &id, &session);
+  } else {
+    let _ = fs::remove_file(&path);
+  }
+  Ok(res)
+}
+
+// DELETE /uploads/{id} でアップロードをやめる関数
+pub async fn cancel(
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+  id: &str,
+) -> Result<Response<Body>, Error> {
+  let id = match Uuid::parse_str(id) {
+    Ok(id) => id,
+    Err(_) => return Ok(empty(StatusCode::NOT_FOUND)),
+  };
+  let removed = tenant
+    .conn
+    .lock()
+    .await
+    .execute("DELETE FROM upload_sessions WHERE id=?1", params![id])
+    .unwrap();
+  if removed == 0 {
+    return Ok(empty(StatusCode::NOT_FOUND));
+  }
+  let _ = fs::remove_file(state.uploads.path(&tenant, &id));
+  Ok(empty(StatusCode::NO_CONTENT))
+}
+
+// 一定の間隔で開いているすべてのテナントの期限切れのアップロードを片付ける関数
+pub fn spawn_cleaner(state: Arc<State>) {
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(CLEAN_SECONDS));
+    loop {
+      interval.tick().await;
+      if state.read_only.is_on() {
+        continue;
+      }
+      for tenant in state.tenants.all() {
+        let conn = tenant.conn.lock().await;
+        let mut stmt = conn
+          .prepare("SELECT id FROM upload_sessions WHERE updated_at < ?1")
+          .unwrap();
+        let expired = stmt
+          .query_map(
+            params![now().saturating_sub(state.uploads.expire_seconds)],
+            |row| row.get(0),
+          )
+          .unwrap()
+          .collect::<Result<Vec<Uuid>, _>>()
+          .unwrap();
+        for id in expired {
+          conn
+            .execute("DELETE FROM upload_sessions WHERE id=?1", params![id])
+            .unwrap();
+          let _ = fs::remove_file(state.uploads.path(&tenant, &id));
+        }
+      }
+    }
+  });
+}
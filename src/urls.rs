@@ -0,0 +1,56 @@
+use std::{collections::HashMap, env};
+
+use hyper::{Body, Request};
+use tera::{Function, Tera, Value};
+
+use crate::client;
+
+// 絶対URLを作るときに使う公開URL（例: https://memo.example.com）
+// 設定しない場合はリクエストのスキームとホスト（プロキシ経由なら転送ヘッダの値）を使う
+// サブドメインでテナントを分ける場合はテナントごとにホストが変わるので設定しない
+#[derive(Clone)]
+pub struct BaseUrl(Option<String>);
+
+impl BaseUrl {
+  // BASE_URLで指定する（末尾の/は取り除く）
+  pub fn from_env() -> BaseUrl {
+    BaseUrl(
+      env::var("BASE_URL")
+        .ok()
+        .map(|url| url.trim_end_matches('/').to_string()),
+    )
+  }
+
+  // 絶対URLの先頭に付けるhttps://example.comの部分を返す関数
+  pub fn origin(&self, req: &Request<Body>) -> String {
+    match &self.0 {
+      Some(base) => base.clone(),
+      None => client(req).origin(),
+    }
+  }
+
+  // 設定したBASE_URL（リクエストを受けずに絶対URLを作るときに使う）
+  pub fn configured(&self) -> Option<&str> {
+    self.0.as_deref()
+  }
+}
+
+// テンプレートから{{ url_for(path=prefix ~ "/search") }}で呼び出すヘルパー
+// テンプレートからはリクエストを参照できないので，BASE_URLがなければパスをそのまま返す
+struct UrlFor(BaseUrl);
+
+impl Function for UrlFor {
+  fn call(&self, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let path = args
+      .get("path")
+      .and_then(Value::as_str)
+      .ok_or("url_for requires a path")?;
+    let base = self.0 .0.as_deref().unwrap_or_default();
+    Ok(Value::String(format!("{}{}", base, path)))
+  }
+}
+
+// テンプレートヘルパーを登録する関数
+pub fn register(tera: &mut Tera, base_url: BaseUrl) {
+  tera.register_function("url_for", UrlFor(base_url));
+}
@@ -0,0 +1,111 @@
+use std::{collections::HashMap, sync::Arc, sync::Mutex, time::Duration};
+
+use hyper::{Body, Error, Response};
+use rusqlite::params;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{json, State, Tenant, Visibility};
+
+// メモリ上の閲覧数をDBに書き出す間隔
+const FLUSH_SECONDS: u64 = 30;
+// 人気の投稿として返す件数
+const POPULAR_LIMIT: usize = 20;
+
+// まだDBに書き出していない閲覧数
+// 閲覧のたびに書き込まないようにまとめてから書き出す
+pub struct Buffer {
+  pending: Mutex<HashMap<Uuid, u64>>,
+}
+
+impl Buffer {
+  pub fn new() -> Buffer {
+    Buffer {
+      pending: Mutex::new(HashMap::new()),
+    }
+  }
+
+  // 閲覧を1回数える関数
+  // 誰が見たかは記録しない
+  pub fn record(&self, id: Uuid) {
+    *self.pending.lock().unwrap().entry(id).or_insert(0) += 1;
+  }
+
+  // 書き出していない閲覧数を返す関数
+  pub fn pending(&self, id: &Uuid) -> u64 {
+    self.pending.lock().unwrap().get(id).copied().unwrap_or(0)
+  }
+}
+
+// テナントの閲覧数をDBに書き出す関数
+async fn flush(tenant: &Tenant) {
+  let mut conn = tenant.conn.lock().await;
+  // DBのロック中に取り出すので，書き出す前の閲覧数が二重に数えられることはない
+  let pending = std::mem::take(&mut *tenant.views.pending.lock().unwrap());
+  if pending.is_empty() {
+    return;
+  }
+  let tx = conn.transaction().unwrap();
+  for (id, views) in pending {
+    tx.execute(
+      "INSERT INTO post_views(post_id, views) VALUES (?1, ?2)
+      ON CONFLICT(post_id) DO UPDATE SET views = views + excluded.views",
+      params![id, views],
+    )
+    .unwrap();
+  }
+  tx.commit().unwrap();
+}
+
+// 一定の間隔で開いているすべてのテナントの閲覧数を書き出す関数
+// 再起動すると最後に書き出してからの閲覧数は失われる
+pub fn spawn_flusher(state: Arc<State>) {
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(FLUSH_SECONDS));
+    loop {
+      interval.tick().await;
+      // 読み取り専用の間はメモリ上に溜めておく
+      if state.read_only.is_on() {
+        continue;
+      }
+      for tenant in state.tenants.all() {
+        flush(&tenant).await;
+      }
+    }
+  });
+}
+
+#[derive(Serialize)]
+struct Popular {
+  id: Uuid,
+  title: String,
+  views: u64,
+}
+
+// 閲覧数の多い公開の投稿を返す関数
+pub async fn popular(tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let conn = tenant.conn.lock().await;
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, title, COALESCE(views, 0) FROM posts
+      LEFT JOIN post_views ON post_views.post_id = posts.id
+      WHERE kind = 'text' AND visibility=?1 AND trashed_at IS NULL",
+    )
+    .unwrap();
+  let mut posts = stmt
+    .query_map(params![Visibility::Public], |row| {
+      let id = row.get(0)?;
+      Ok(Popular {
+        id,
+        title: row.get(1)?,
+        views: row.get::<_, u64>(2)? + tenant.views.pending(&id),
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+  posts.retain(|post| post.views > 0);
+  posts.sort_by_key(|post| std::cmp::Reverse(post.views));
+  posts.truncate(POPULAR_LIMIT);
+  Ok(json(&posts))
+}
@@ -0,0 +1,190 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use hyper::{header, Body, Error, Request, Response, StatusCode};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+  attachment::{self, Attachment},
+  attachment_policy::essence,
+  audio, audit, e2ee, empty,
+  events::Event,
+  json, now, remote_ip, tags, wordcount, State, Tenant,
+};
+
+#[derive(Deserialize)]
+struct Query {
+  #[serde(default)]
+  title: String,
+}
+
+#[derive(Serialize)]
+struct Created<'a> {
+  id: Uuid,
+  url: String,
+  attachment: &'a Attachment,
+}
+
+// 投稿の音声メモ（テンプレートのvoice_memoで表示する）
+#[derive(Serialize)]
+pub struct Voice {
+  pub url: String,
+  pub content_type: String,
+  pub duration_ms: Option<u64>,
+  // 1:05のように表示する長さ
+  pub duration: Option<String>,
+  // 静的なサイトに書き出すときに中身を読む
+  #[serde(skip)]
+  pub key: String,
+}
+
+// 音声メモの投稿であればその音声を返す関数
+pub fn for_post(conn: &Connection, prefix: &str, post_id: &Uuid) -> Option<Voice> {
+  conn
+    .query_row(
+      "SELECT attachments.id, content_type, duration_ms, storage_key FROM voice_memos
+      JOIN attachments ON attachments.id = voice_memos.attachment_id
+      WHERE voice_memos.post_id=?1",
+      params![post_id],
+      |row| {
+        let id: Uuid = row.get(0)?;
+        let duration_ms: Option<u64> = row.get(2)?;
+        Ok(Voice {
+          url: format!("{}/attachments/{}", prefix, id),
+          content_type: row.get(1)?,
+          duration_ms,
+          duration: duration_ms.map(audio::format_duration),
+          key: row.get(3)?,
+        })
+      },
+    )
+    .optional()
+    .unwrap()
+}
+
+// POST /voice-memos?title=... で本文の音声をそのまま音声メモの投稿にする関数
+// 種類はContent-Typeで指定し，タイトルを省略した場合は録音した日時にする
+pub async fn create(
+  req: Request<Body>,
+  state: Arc<State>,
+  tenant: Arc<Tenant>,
+) -> Result<Response<Body>, Error> {
+  let ip = remote_ip(&req);
+  let tz = state.timezone.current(&req);
+  let query = match serde_urlencoded::from_str::<Query>(req.uri().query().unwrap_or_default()) {
+    Ok(query) => query,
+    Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+  };
+  let content_type = attachment::content_type(&req);
+  let kind = essence(&content_type);
+  if !kind.starts_with("audio/") {
+    return Ok(
+      Response::builder()
+        .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        .body("voice memos must be audio".into())
+        .unwrap(),
+    );
+  }
+  let limit = state.attachments.max_bytes;
+  let data = match e2ee::read_limited(req.into_body(), limit).await? {
+    Some(data) => data,
+    None => return Ok(e2ee::too_large(limit)),
+  };
+  let title = if query.title.trim().is_empty() {
+    format!(
+      "Voice memo {}",
+      Utc::now().with_timezone(&tz).format("%Y-%m-%d %H:%M")
+    )
+  } else {
+    query.title
+  };
+  let mut warnings = Vec::new();
+  let title = match state.limits.apply(&title, "", &mut warnings) {
+    Ok((title, _)) => title.to_string(),
+    Err(reason) => {
+      return Ok(
+        Response::builder()
+          .status(StatusCode::UNPROCESSABLE_ENTITY)
+          .body(reason.into())
+          .unwrap(),
+      )
+    }
+  };
+  let name = format!("voice-memo.{}", audio::extension(&kind));
+  let stored = match attachment::store(&state, &tenant, &name, &content_type, data).await {
+    Ok(stored) => stored,
+    Err(res) => return Ok(res),
+  };
+  let id = Uuid::new_v4();
+  let content = state.codec.encode("");
+  let mut conn = tenant.conn.lock().await;
+  let tx = conn.transaction().unwrap();
+  // 音声が本体なので本文による重複の判定はしない
+  tx.execute(
+    "INSERT INTO posts(id, title, content, encrypted, compressed, created_at)
+    VALUES (?1,?2,?3,?4,?5,?6)",
+    params![
+      id,
+      title,
+      content.content,
+      content.encrypted,
+      content.compressed,
+      now()
+    ],
+  )
+  .unwrap();
+  wordcount::record(&tx, &id, "");
+  tags::apply(&tx, &id, "");
+  audit::record(
+    &tx,
+    audit::Entry {
+      actor: audit::ANONYMOUS,
+      action: "create",
+      post_id: &id,
+      summary: "voice memo".to_string(),
+      ip,
+    },
+  );
+  let attachment = attachment::insert(&tx, &tenant, &stored, &id, name, content_type, ip);
+  tx.execute(
+    "INSERT INTO voice_memos(post_id, attachment_id) VALUES (?1,?2)",
+    params![id, attachment.id],
+  )
+  .unwrap();
+  tx.commit().unwrap();
+  drop(conn);
+  drop(stored);
+  tenant.publish(Event::Created { id });
+  let mut res = json(&Created {
+    id,
+    url: format!("{}/posts/{}", tenant.prefix, id),
+    attachment: &attachment,
+  });
+  *res.status_mut() = StatusCode::CREATED;
+  for warning in &warnings {
+    res.headers_mut().append(
+      header::WARNING,
+      format!("299 - {:?}", warning).parse().unwrap(),
+    );
+  }
+  Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests;
+
+  #[tokio::test]
+  async fn rejects_bad_query() {
+    let state = tests::state();
+    let req = Request::post("/voice-memos?title=a&title=b")
+      .header(header::CONTENT_TYPE, "audio/wav")
+      .body(Body::empty())
+      .unwrap();
+    let res = tests::send(&state, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+  }
+}
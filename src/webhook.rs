@@ -0,0 +1,250 @@
+use std::{env, sync::Arc, time::Duration};
+
+use hmac::{Hmac, Mac};
+use hyper::{header, Body, Error, Method, Request, Response};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::{
+  events, hex,
+  https::{self, HttpsClient},
+  json, now, State, Tenant,
+};
+
+// 送信待ちのイベントを確かめる間隔
+const POLL_SECONDS: u64 = 2;
+// 1回に送るイベントの上限
+const BATCH: u32 = 50;
+// 失敗したときに次に送るまでの待ち時間の最初の値と上限（秒）
+// 失敗するたびに倍にする
+const RETRY_MIN_SECONDS: u64 = 10;
+const RETRY_MAX_SECONDS: u64 = 60 * 60;
+// 応答を待つ時間
+const TIMEOUT_SECONDS: u64 = 10;
+
+// 投稿の変更を知らせるWebhookの送り先
+pub struct Webhooks {
+  urls: Vec<String>,
+  // 設定されていれば本文のHMAC-SHA256をX-Webhook-Signatureに付ける
+  secret: Option<String>,
+  client: HttpsClient,
+}
+
+impl Webhooks {
+  // WEBHOOK_URLSにカンマ区切りで送り先を，WEBHOOK_SECRETで署名の鍵を指定する
+  pub fn from_env() -> Webhooks {
+    Webhooks {
+      urls: env::var("WEBHOOK_URLS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(String::from)
+        .collect(),
+      secret: env::var("WEBHOOK_SECRET").ok(),
+      client: https::trusted_client(),
+    }
+  }
+
+  fn signature(&self, body: &str) -> Option<String> {
+    let secret = self.secret.as_ref()?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body.as_bytes());
+    Some(format!(
+      "sha256={}",
+      hex::encode(&mac.finalize().into_bytes())
+    ))
+  }
+
+  // すべての送り先に送る関数
+  // 1つでも失敗すれば後でまとめて送り直すので，受け取る側はidで重複を取り除く
+  async fn deliver(&self, body: &str) -> Result<(), String> {
+    for url in &self.urls {
+      let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(header::CONTENT_TYPE, "application/json");
+      if let Some(signature) = self.signature(body) {
+        req = req.header("x-webhook-signature", signature);
+      }
+      let req = req.body(Body::from(body.to_string())).unwrap();
+      let sent = tokio::time::timeout(
+        Duration::from_secs(TIMEOUT_SECONDS),
+        self.client.request(req),
+      )
+      .await;
+      match sent {
+        Ok(Ok(res)) if res.status().is_success() => {}
+        Ok(Ok(res)) => return Err(format!("{} from {}", res.status(), url)),
+        Ok(Err(e)) => return Err(format!("{} from {}", e, url)),
+        Err(_) => return Err(format!("timeout from {}", url)),
+      }
+    }
+    Ok(())
+  }
+}
+
+// 送り先に渡すイベント
+#[derive(Serialize)]
+struct Event {
+  // 送り直しても変わらないので重複の判定に使える
+  id: i64,
+  // post.create，post.trashなど
+  event: String,
+  tenant: String,
+  post_id: Uuid,
+  summary: String,
+  at: i64,
+}
+
+// イベントを送信待ちとして書き込む関数
+// 投稿の変更と同じトランザクションの中で呼び出すので，変更したのにイベントが失われることはない
+pub fn enqueue(conn: &Connection, action: &str, post_id: &Uuid, summary: &str) {
+  let at = now();
+  conn
+    .execute(
+      "INSERT INTO outbox(event, post_id, summary, created_at, next_attempt_at)
+      VALUES (?1,?2,?3,?4,?4)",
+      params![format!("post.{}", action), post_id, summary, at],
+    )
+    .unwrap();
+}
+
+// 送信待ちのイベントを古い順に返す関数
+// 最も古いイベントが送り直しを待っている間は，後のイベントも送らない
+fn due(conn: &Connection, tenant: &str) -> Vec<Event> {
+  let mut stmt = conn
+    .prepare(
+      "SELECT id, event, post_id, summary, created_at FROM outbox
+      WHERE (SELECT next_attempt_at FROM outbox ORDER BY id LIMIT 1) <= ?1
+      ORDER BY id LIMIT ?2",
+    )
+    .unwrap();
+  stmt
+    .query_map(params![now(), BATCH], |row| {
+      Ok(Event {
+        id: row.get(0)?,
+        event: row.get(1)?,
+        tenant: tenant.to_string(),
+        post_id: row.get(2)?,
+        summary: row.get(3)?,
+        at: row.get(4)?,
+      })
+    })
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap()
+}
+
+// テナントの送信待ちのイベントを古い順に送る関数
+// 順番が入れ替わらないように，失敗したらそれより後のイベントも次の機会に回す
+async fn flush(webhooks: &Webhooks, tenant: &Tenant) {
+  let events = due(&*tenant.conn.lock().await, &tenant.name);
+  for event in events {
+    let body = serde_json::to_string(&event).unwrap();
+    let delivered = webhooks.deliver(&body).await;
+    let conn = tenant.conn.lock().await;
+    match delivered {
+      Ok(()) => {
+        conn
+          .execute("DELETE FROM outbox WHERE id=?1", params![event.id])
+          .unwrap();
+      }
+      Err(error) => {
+        eprintln!("webhook error {} for {}", error, tenant.name);
+        conn
+          .execute(
+            "UPDATE outbox SET attempts = attempts + 1, last_error=?1 WHERE id=?2",
+            params![error, event.id],
+          )
+          .unwrap();
+        let attempts: u32 = conn
+          .query_row(
+            "SELECT attempts FROM outbox WHERE id=?1",
+            params![event.id],
+            |row| row.get(0),
+          )
+          .unwrap();
+        let wait = RETRY_MIN_SECONDS
+          .saturating_mul(1 << (attempts - 1).min(16))
+          .min(RETRY_MAX_SECONDS);
+        conn
+          .execute(
+            "UPDATE outbox SET next_attempt_at=?1 WHERE id=?2",
+            params![now() + wait, event.id],
+          )
+          .unwrap();
+        return;
+      }
+    }
+  }
+}
+
+// 開いているすべてのテナントの送信待ちのイベントを送り続ける関数
+// 投稿が変わったと知らされたテナントはすぐに，それ以外は一定の間隔で送信待ちを確かめる
+// 送信に成功してから消すので，途中でプロセスが止まっても次の起動で送り直す
+// 送り先を設定していない場合は送る相手がいないので，書き込まれたイベントはそのまま消える
+pub fn spawn(state: Arc<State>, bus: &events::Bus) {
+  let mut events = bus.subscribe();
+  tokio::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(POLL_SECONDS));
+    loop {
+      let only = tokio::select! {
+        _ = interval.tick() => None,
+        received = events.recv() => match received {
+          Ok(published) => Some(published.tenant.clone()),
+          // 取りこぼした場合はすべてのテナントを確かめる
+          Err(broadcast::error::RecvError::Lagged(_)) => None,
+          Err(broadcast::error::RecvError::Closed) => break,
+        },
+      };
+      // 読み取り専用の間は送信済みのイベントを消せないので次の機会に回す
+      if state.read_only.is_on() {
+        continue;
+      }
+      for tenant in state.tenants.all() {
+        if only.as_ref().is_none_or(|name| *name == tenant.name) {
+          flush(&state.webhooks, &tenant).await;
+        }
+      }
+    }
+  });
+}
+
+#[derive(Serialize)]
+struct Status {
+  pending: u64,
+  // 最も古い送信待ちのイベント
+  oldest_at: Option<i64>,
+  attempts: Option<u32>,
+  last_error: Option<String>,
+}
+
+// GET /admin/outbox で送信待ちのイベントの状態を返す関数
+pub async fn status(tenant: Arc<Tenant>) -> Result<Response<Body>, Error> {
+  let conn = tenant.conn.lock().await;
+  let pending = conn
+    .query_row("SELECT COUNT(*) FROM outbox", [], |row| row.get(0))
+    .unwrap();
+  let oldest = conn
+    .query_row(
+      "SELECT created_at, attempts, last_error FROM outbox ORDER BY id LIMIT 1",
+      [],
+      |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )
+    .optional()
+    .unwrap();
+  let (oldest_at, attempts, last_error) = match oldest {
+    Some((at, attempts, last_error)) => (Some(at), Some(attempts), last_error),
+    None => (None, None, None),
+  };
+  Ok(json(&Status {
+    pending,
+    oldest_at,
+    attempts,
+    last_error,
+  }))
+}
@@ -0,0 +1,65 @@
+use rusqlite::{params, Connection, Row};
+use serde::Serialize;
+use uuid::Uuid;
+
+// 読了時間の見積もりに使う1分あたりの語数
+const WORDS_PER_MINUTE: u64 = 200;
+
+// 本文の語数・文字数と読了時間の目安（分）
+#[derive(Serialize, Clone, Copy)]
+pub struct Counts {
+  pub words: u64,
+  pub chars: u64,
+  pub reading_minutes: u64,
+}
+
+impl Counts {
+  // word_count, char_count, reading_minutesの順に並んだ列から取り出す関数
+  // 記録する前からある投稿とE2EEの投稿はNULLなのでNoneになる
+  pub fn from_row(row: &Row, start: usize) -> rusqlite::Result<Option<Counts>> {
+    let words: Option<u64> = row.get(start)?;
+    let chars: Option<u64> = row.get(start + 1)?;
+    let reading_minutes: Option<u64> = row.get(start + 2)?;
+    Ok(match (words, chars, reading_minutes) {
+      (Some(words), Some(chars), Some(reading_minutes)) => Some(Counts {
+        words,
+        chars,
+        reading_minutes,
+      }),
+      _ => None,
+    })
+  }
+}
+
+// 本文を数える関数
+// 空白で区切らない文章（日本語など）は2文字を1語として数える
+pub fn count(content: &str) -> Counts {
+  let words = content
+    .split_whitespace()
+    .map(|word| {
+      if word.is_ascii() {
+        1
+      } else {
+        (word.chars().count() as u64).div_ceil(2)
+      }
+    })
+    .sum::<u64>();
+  Counts {
+    words,
+    chars: content.chars().count() as u64,
+    reading_minutes: words.div_ceil(WORDS_PER_MINUTE).max(1),
+  }
+}
+
+// 保存した本文の数を記録する関数
+// 本文は暗号化して保存する場合があり，一覧でSQLから並べ替えられるように平文から数えておく
+// 本文を書き換えたのと同じトランザクションの中で呼び出す
+pub fn record(conn: &Connection, id: &Uuid, content: &str) {
+  let counts = count(content);
+  conn
+    .execute(
+      "UPDATE posts SET word_count=?1, char_count=?2, reading_minutes=?3 WHERE id=?4",
+      params![counts.words, counts.chars, counts.reading_minutes, id],
+    )
+    .unwrap();
+}
@@ -0,0 +1,93 @@
+use std::{
+  panic::{self, AssertUnwindSafe},
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Weak,
+  },
+};
+
+use rusqlite::Connection;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::Tenant;
+
+// 1つのトランザクションにまとめる書き込みの上限
+const MAX_BATCH: usize = 64;
+
+// トランザクションの中で実行し，コミットした後に結果を返す処理
+type Job = Box<dyn FnOnce(&Connection) -> Box<dyn FnOnce() + Send> + Send>;
+
+// 書き込みを1つのタスクに集め，溜まっている分をまとめてコミットする仕組み
+// SQLiteは書き込みが1つずつなので，同時に多くの投稿が来てもコミットの回数を抑えられる
+pub struct Writer {
+  jobs: mpsc::UnboundedSender<Job>,
+  // 依頼されてまだ実行していない書き込みの数
+  pending: Arc<AtomicUsize>,
+}
+
+impl Writer {
+  // テナントを所有し続けないように弱い参照で受け取る
+  pub fn spawn(tenant: Weak<Tenant>) -> Writer {
+    let (jobs, mut rx) = mpsc::unbounded_channel::<Job>();
+    let pending = Arc::new(AtomicUsize::new(0));
+    let remaining = pending.clone();
+    tokio::spawn(async move {
+      while let Some(job) = rx.recv().await {
+        let mut batch = vec![job];
+        while batch.len() < MAX_BATCH {
+          match rx.try_recv() {
+            Ok(job) => batch.push(job),
+            Err(_) => break,
+          }
+        }
+        remaining.fetch_sub(batch.len(), Ordering::Relaxed);
+        let tenant = match tenant.upgrade() {
+          Some(tenant) => tenant,
+          None => break,
+        };
+        let mut conn = tenant.conn.lock().await;
+        let mut tx = conn.transaction().unwrap();
+        let mut replies = Vec::new();
+        for job in batch {
+          // 1件が失敗しても他の書き込みは残るようにセーブポイントで区切る
+          // 失敗した処理の結果は返らず，待っている側が失敗する
+          let sp = tx.savepoint().unwrap();
+          if let Ok(reply) = panic::catch_unwind(AssertUnwindSafe(|| job(&sp))) {
+            sp.commit().unwrap();
+            replies.push(reply);
+          }
+        }
+        tx.commit().unwrap();
+        drop(conn);
+        for reply in replies {
+          reply();
+        }
+      }
+    });
+    Writer { jobs, pending }
+  }
+
+  pub fn pending(&self) -> usize {
+    self.pending.load(Ordering::Relaxed)
+  }
+
+  // 書き込みを依頼し，コミットされるまで待って結果を返す関数
+  pub async fn run<R, F>(&self, f: F) -> R
+  where
+    F: FnOnce(&Connection) -> R + Send + 'static,
+    R: Send + 'static,
+  {
+    let (tx, rx) = oneshot::channel();
+    let job: Job = Box::new(move |conn| {
+      let result = f(conn);
+      Box::new(move || {
+        let _ = tx.send(result);
+      })
+    });
+    self.pending.fetch_add(1, Ordering::Relaxed);
+    if self.jobs.send(job).is_err() {
+      panic!("writer stopped");
+    }
+    rx.await.expect("write failed")
+  }
+}
@@ -0,0 +1,131 @@
+use crate::crc32;
+
+// 圧縮しないZIPのアーカイブを作る
+// EPUBのように小さなテキストを束ねるだけなので，圧縮やZIP64には対応しない
+pub struct Zip {
+  out: Vec<u8>,
+  // 中央ディレクトリに書く項目
+  entries: Vec<Vec<u8>>,
+}
+
+impl Zip {
+  pub fn new() -> Zip {
+    Zip {
+      out: Vec::new(),
+      entries: Vec::new(),
+    }
+  }
+
+  // ファイルを追加する関数（追加した順に並ぶ）
+  pub fn add(&mut self, name: &str, data: &[u8]) {
+    let crc = crc32::checksum(data);
+    let offset = self.out.len() as u32;
+    let mut common = Vec::new();
+    // 展開に必要なバージョン（2.0），フラグ（名前がUTF-8），無圧縮，更新日時（1980-01-01）
+    common.extend(&20_u16.to_le_bytes());
+    common.extend(&0x0800_u16.to_le_bytes());
+    common.extend(&0_u16.to_le_bytes());
+    common.extend(&0_u16.to_le_bytes());
+    common.extend(&0x0021_u16.to_le_bytes());
+    common.extend(&crc.to_le_bytes());
+    common.extend(&(data.len() as u32).to_le_bytes());
+    common.extend(&(data.len() as u32).to_le_bytes());
+    common.extend(&(name.len() as u16).to_le_bytes());
+    common.extend(&0_u16.to_le_bytes());
+    self.out.extend(&0x0403_4b50_u32.to_le_bytes());
+    self.out.extend(&common);
+    self.out.extend(name.as_bytes());
+    self.out.extend(data);
+    let mut entry = Vec::new();
+    entry.extend(&0x0201_4b50_u32.to_le_bytes());
+    // 作成したバージョン
+    entry.extend(&20_u16.to_le_bytes());
+    entry.extend(&common);
+    // コメントの長さ，ディスク番号，内部属性，外部属性
+    entry.extend(&[0; 10]);
+    entry.extend(&offset.to_le_bytes());
+    entry.extend(name.as_bytes());
+    self.entries.push(entry);
+  }
+
+  // 中央ディレクトリを付けてアーカイブを返す関数
+  pub fn finish(mut self) -> Vec<u8> {
+    let start = self.out.len() as u32;
+    for entry in &self.entries {
+      self.out.extend(entry);
+    }
+    let size = self.out.len() as u32 - start;
+    let count = self.entries.len() as u16;
+    self.out.extend(&0x0605_4b50_u32.to_le_bytes());
+    self.out.extend(&[0; 4]);
+    self.out.extend(&count.to_le_bytes());
+    self.out.extend(&count.to_le_bytes());
+    self.out.extend(&size.to_le_bytes());
+    self.out.extend(&start.to_le_bytes());
+    self.out.extend(&0_u16.to_le_bytes());
+    self.out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::convert::TryInto;
+
+  use super::*;
+
+  fn u16_at(data: &[u8], at: usize) -> usize {
+    u16::from_le_bytes(data[at..at + 2].try_into().unwrap()) as usize
+  }
+
+  fn u32_at(data: &[u8], at: usize) -> usize {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as usize
+  }
+
+  #[test]
+  fn writes_local_and_central_headers() {
+    let files: [(&str, &[u8]); 3] = [
+      ("mimetype", b"application/epub+zip"),
+      ("日本/a.txt", b"hello"),
+      ("empty", b""),
+    ];
+    let mut zip = Zip::new();
+    for (name, data) in files {
+      zip.add(name, data);
+    }
+    let archive = zip.finish();
+    // 終端レコード
+    let end = archive.len() - 22;
+    assert_eq!(u32_at(&archive, end), 0x0605_4b50);
+    assert_eq!(u16_at(&archive, end + 8), files.len());
+    assert_eq!(u16_at(&archive, end + 10), files.len());
+    let (size, start) = (u32_at(&archive, end + 12), u32_at(&archive, end + 16));
+    assert_eq!(start + size, end);
+    let mut central = start;
+    for (name, data) in files {
+      assert_eq!(u32_at(&archive, central), 0x0201_4b50);
+      assert_eq!(
+        u32_at(&archive, central + 16),
+        crc32::checksum(data) as usize
+      );
+      assert_eq!(u16_at(&archive, central + 28), name.len());
+      assert_eq!(
+        &archive[central + 46..central + 46 + name.len()],
+        name.as_bytes()
+      );
+      // 中央ディレクトリが指す位置にローカルヘッダと中身がある
+      let local = u32_at(&archive, central + 42);
+      assert_eq!(u32_at(&archive, local), 0x0403_4b50);
+      assert_eq!(u16_at(&archive, local + 6), 0x0800);
+      assert_eq!(u16_at(&archive, local + 8), 0);
+      assert_eq!(u32_at(&archive, local + 18), data.len());
+      assert_eq!(u32_at(&archive, local + 22), data.len());
+      let body = local + 30 + name.len();
+      assert_eq!(&archive[local + 30..body], name.as_bytes());
+      assert_eq!(&archive[body..body + data.len()], data);
+      central += 46 + name.len();
+    }
+    assert_eq!(central, end);
+    // EPUBはmimetypeが先頭の38バイト目から始まることを求める
+    assert_eq!(&archive[38..58], b"application/epub+zip");
+  }
+}
@@ -0,0 +1,15 @@
+// 投稿フォームの本文をPOST /previewで描画してプレビュー欄に表示する
+document.addEventListener("DOMContentLoaded", () => {
+  const button = document.querySelector("[data-preview]");
+  if (!button) {
+    return;
+  }
+  const form = button.closest("form");
+  const pane = form.querySelector(".preview");
+  button.addEventListener("click", async () => {
+    const body = new URLSearchParams({ content: form.elements.content.value });
+    const res = await fetch(button.dataset.preview, { method: "POST", body });
+    pane.innerHTML = res.ok ? await res.text() : "";
+    pane.hidden = !res.ok;
+  });
+});